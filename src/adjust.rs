@@ -0,0 +1,456 @@
+use crate::discipline::{ClockCorrection, DisciplineAction};
+use crate::error::ClockAdjustmentError;
+use crate::packet::LeapIndicator;
+use crate::result::{SntpDuration, SynchronizationResult};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default offset above which the clock is stepped instead of slewed (RFC 5905 STEPT)
+const DEFAULT_STEP_THRESHOLD: Duration = Duration::from_millis(128);
+
+/// Method of a system clock adjustment
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AdjustmentMethod {
+    /// The clock is set to the new time at once (`clock_settime`)
+    Step,
+    /// The clock is sped up or slowed down gradually until the offset is corrected (`adjtimex`)
+    Slew,
+}
+
+/// Adjustment applied to the system clock
+///
+/// In dry-run mode, it describes the adjustment which would have been applied.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockAdjustment {
+    method: AdjustmentMethod,
+    offset_s: f64,
+    leap_indicator: LeapIndicator,
+    dry_run: bool,
+}
+
+impl ClockAdjustment {
+    /// Returns with the method of the adjustment
+    pub fn method(&self) -> AdjustmentMethod {
+        self.method
+    }
+
+    /// Returns with the offset added to the system clock
+    pub fn offset(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.offset_s)
+    }
+
+    /// Returns with the leap second warning passed to the kernel
+    pub fn leap_indicator(&self) -> LeapIndicator {
+        self.leap_indicator
+    }
+
+    /// Returns true if the system clock has not been touched because of the dry-run mode
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+/// Adjusts the system clock with the result of a synchronization
+///
+/// The clock can be stepped with `clock_settime` or slewed with `adjtimex`. The leap indicator of
+/// the result is passed to the kernel as well, so it inserts or deletes the leap second at the end
+/// of the day. Results with an alarm condition are rejected, as the server is not synchronized.
+///
+/// Adjusting the system clock requires the `CAP_SYS_TIME` capability, otherwise
+/// [`ClockAdjustmentError::PermissionDenied`] is returned. The dry-run mode can be used to check
+/// the adjustment without changing the clock.
+///
+/// Only available on Linux, when the `clock-adjust` feature is enabled.
+///
+/// As the clock offset is relative to the system clock at the time of synchronization, the
+/// result should be used right after the synchronization.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ClockAdjuster, SntpClient};
+///
+/// let client = SntpClient::new();
+/// let result = client.synchronize("pool.ntp.org").unwrap();
+///
+/// let adjustment = ClockAdjuster::new().adjust(&result).unwrap();
+///
+/// println!("Clock adjusted by {:?}", adjustment.offset());
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct ClockAdjuster {
+    dry_run: bool,
+    step_threshold: Duration,
+}
+
+impl ClockAdjuster {
+    /// Creates a clock adjuster with the default settings
+    pub fn new() -> ClockAdjuster {
+        ClockAdjuster::default()
+    }
+
+    /// Enables or disables the dry-run mode
+    ///
+    /// In dry-run mode, the adjustment is validated and returned, but the system clock is not
+    /// changed, so no privileges are needed. Default is false.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ClockAdjuster, SntpClient};
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// let adjustment = ClockAdjuster::new().dry_run(true).adjust(&result).unwrap();
+    ///
+    /// println!("Clock would be adjusted with {:?}", adjustment.method());
+    /// ```
+    pub fn dry_run(self, dry_run: bool) -> ClockAdjuster {
+        ClockAdjuster { dry_run, ..self }
+    }
+
+    /// Sets the offset above which [`ClockAdjuster::adjust`] steps the clock instead of slewing it
+    ///
+    /// Default is 128 ms, as recommended by RFC 5905.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ClockAdjuster;
+    /// use std::time::Duration;
+    ///
+    /// let adjuster = ClockAdjuster::new().step_threshold(Duration::from_secs(1));
+    /// ```
+    pub fn step_threshold(self, step_threshold: Duration) -> ClockAdjuster {
+        ClockAdjuster {
+            step_threshold,
+            ..self
+        }
+    }
+
+    /// Steps or slews the system clock, depending on the clock offset
+    ///
+    /// Offsets above the step threshold are stepped, smaller ones are slewed.
+    pub fn adjust(
+        &self,
+        result: &SynchronizationResult,
+    ) -> Result<ClockAdjustment, ClockAdjustmentError> {
+        let method =
+            if result.clock_offset().as_secs_f64().abs() > self.step_threshold.as_secs_f64() {
+                AdjustmentMethod::Step
+            } else {
+                AdjustmentMethod::Slew
+            };
+
+        self.apply(result, method)
+    }
+
+    /// Steps the system clock with `clock_settime`
+    pub fn step(
+        &self,
+        result: &SynchronizationResult,
+    ) -> Result<ClockAdjustment, ClockAdjustmentError> {
+        self.apply(result, AdjustmentMethod::Step)
+    }
+
+    /// Slews the system clock with `adjtimex`
+    ///
+    /// The kernel corrects the offset gradually, with a rate of 0.5 ms per second.
+    pub fn slew(
+        &self,
+        result: &SynchronizationResult,
+    ) -> Result<ClockAdjustment, ClockAdjustmentError> {
+        self.apply(result, AdjustmentMethod::Slew)
+    }
+
+    /// Applies a correction computed by [`crate::ClockDiscipline`] to the system clock
+    ///
+    /// The frequency correction is set in the kernel, then the clock is stepped or the phase
+    /// correction is slewed, depending on the action. Ignored corrections do not touch the clock,
+    /// [`ClockAdjustmentError::InvalidOffset`] is returned if the discipline panicked.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ClockAdjuster, ClockDiscipline, SntpClient};
+    ///
+    /// let client = SntpClient::new();
+    /// let adjuster = ClockAdjuster::new();
+    /// let mut discipline = ClockDiscipline::new();
+    ///
+    /// loop {
+    ///     let result = client.synchronize("pool.ntp.org").unwrap();
+    ///     let correction = discipline.update(&result);
+    ///
+    ///     adjuster.apply_correction(&correction).unwrap();
+    ///     std::thread::sleep(correction.poll_interval());
+    /// }
+    /// ```
+    pub fn apply_correction(
+        &self,
+        correction: &ClockCorrection,
+    ) -> Result<(), ClockAdjustmentError> {
+        let phase_s = correction.phase_correction().as_secs_f64();
+
+        match correction.action() {
+            DisciplineAction::Panic => return Err(ClockAdjustmentError::InvalidOffset),
+            DisciplineAction::Ignore => return Ok(()),
+            _ if self.dry_run => return Ok(()),
+            DisciplineAction::Step => step_clock(phase_s)?,
+            DisciplineAction::Slew => slew_clock(phase_s)?,
+        }
+
+        set_frequency(correction.frequency_correction())
+    }
+
+    fn apply(
+        &self,
+        result: &SynchronizationResult,
+        method: AdjustmentMethod,
+    ) -> Result<ClockAdjustment, ClockAdjustmentError> {
+        let leap_indicator = result.leap_indicator();
+
+        if leap_indicator == LeapIndicator::AlarmCondition {
+            return Err(ClockAdjustmentError::UnsynchronizedServer);
+        }
+
+        let offset_s = result.clock_offset().as_secs_f64();
+
+        if !offset_s.is_finite() {
+            return Err(ClockAdjustmentError::InvalidOffset);
+        }
+
+        if !self.dry_run {
+            match method {
+                AdjustmentMethod::Step => step_clock(offset_s)?,
+                AdjustmentMethod::Slew => slew_clock(offset_s)?,
+            }
+
+            set_leap_second(leap_indicator)?;
+        }
+
+        Ok(ClockAdjustment {
+            method,
+            offset_s,
+            leap_indicator,
+            dry_run: self.dry_run,
+        })
+    }
+}
+
+impl Default for ClockAdjuster {
+    fn default() -> ClockAdjuster {
+        ClockAdjuster {
+            dry_run: false,
+            step_threshold: DEFAULT_STEP_THRESHOLD,
+        }
+    }
+}
+
+fn last_os_error() -> ClockAdjustmentError {
+    let error = std::io::Error::last_os_error();
+
+    if error.raw_os_error() == Some(libc::EPERM) {
+        ClockAdjustmentError::PermissionDenied
+    } else {
+        ClockAdjustmentError::IOError(error)
+    }
+}
+
+fn step_clock(offset_s: f64) -> Result<(), ClockAdjustmentError> {
+    let offset = Duration::try_from_secs_f64(offset_s.abs())
+        .map_err(|_| ClockAdjustmentError::InvalidOffset)?;
+    let now = SystemTime::now();
+
+    let new_time = if offset_s >= 0.0 {
+        now.checked_add(offset)
+    } else {
+        now.checked_sub(offset)
+    };
+
+    let since_epoch = new_time
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .ok_or(ClockAdjustmentError::InvalidOffset)?;
+
+    let timespec = libc::timespec {
+        tv_sec: since_epoch
+            .as_secs()
+            .try_into()
+            .map_err(|_| ClockAdjustmentError::InvalidOffset)?,
+        tv_nsec: since_epoch.subsec_nanos() as _,
+    };
+
+    // SAFETY: the timespec is valid for the duration of the call
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &timespec) } != 0 {
+        return Err(last_os_error());
+    }
+
+    Ok(())
+}
+
+fn slew_clock(offset_s: f64) -> Result<(), ClockAdjustmentError> {
+    // SAFETY: timex is a plain C struct, all zero is a valid value
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+
+    timex.modes = libc::ADJ_OFFSET_SINGLESHOT as _;
+    timex.offset = (offset_s * 1e6).round() as _;
+
+    adjtimex(&mut timex)
+}
+
+fn set_frequency(frequency: f64) -> Result<(), ClockAdjustmentError> {
+    // SAFETY: timex is a plain C struct, all zero is a valid value
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+
+    // the kernel expects the frequency in PPM with a 16 bit fraction
+    timex.modes = libc::ADJ_FREQUENCY as _;
+    timex.freq = (frequency * 1e6 * 65536.0).round() as _;
+
+    adjtimex(&mut timex)
+}
+
+/// Returns with the kernel status bit of the leap second announced by the leap indicator
+fn leap_status(leap_indicator: LeapIndicator) -> libc::c_int {
+    match leap_indicator {
+        LeapIndicator::LastMinuteHas61Seconds => libc::STA_INS,
+        LeapIndicator::LastMinuteHas59Seconds => libc::STA_DEL,
+        _ => 0,
+    }
+}
+
+fn set_leap_second(leap_indicator: LeapIndicator) -> Result<(), ClockAdjustmentError> {
+    let leap_status = leap_status(leap_indicator);
+
+    // SAFETY: timex is a plain C struct, all zero is a valid value
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+
+    // modes are zero, so the current status is only read
+    adjtimex(&mut timex)?;
+
+    let status = (timex.status & !(libc::STA_INS | libc::STA_DEL)) | leap_status;
+
+    if status == timex.status {
+        return Ok(());
+    }
+
+    timex.modes = libc::ADJ_STATUS as _;
+    timex.status = status;
+
+    adjtimex(&mut timex)
+}
+
+fn adjtimex(timex: &mut libc::timex) -> Result<(), ClockAdjustmentError> {
+    // SAFETY: the timex struct is valid for the duration of the call
+    if unsafe { libc::adjtimex(timex) } == -1 {
+        return Err(last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Mode, Packet};
+    use crate::ClockDiscipline;
+    use std::time::Instant;
+
+    fn result(clock_offset_s: f64, leap_indicator: LeapIndicator) -> SynchronizationResult {
+        let packet = Packet::new(Mode::Server)
+            .stratum(1)
+            .leap_indicator(leap_indicator);
+
+        SynchronizationResult::new(clock_offset_s, 0.01, &packet)
+    }
+
+    #[test]
+    fn dry_run_does_not_touch_the_clock() {
+        let adjuster = ClockAdjuster::new().dry_run(true);
+        let adjustment = adjuster
+            .step(&result(-2.5, LeapIndicator::NoWarning))
+            .unwrap();
+
+        assert_eq!(adjustment.method(), AdjustmentMethod::Step);
+        assert_eq!(adjustment.offset().as_secs_f64(), -2.5);
+        assert!(adjustment.is_dry_run());
+
+        let adjustment = adjuster
+            .slew(&result(2.5, LeapIndicator::NoWarning))
+            .unwrap();
+
+        assert_eq!(adjustment.method(), AdjustmentMethod::Slew);
+        assert_eq!(adjustment.offset().as_secs_f64(), 2.5);
+    }
+
+    #[test]
+    fn adjust_steps_only_above_threshold() {
+        let adjuster = ClockAdjuster::new().dry_run(true);
+
+        let small = adjuster.adjust(&result(-0.1, LeapIndicator::NoWarning));
+        let large = adjuster.adjust(&result(-0.2, LeapIndicator::NoWarning));
+
+        assert_eq!(small.unwrap().method(), AdjustmentMethod::Slew);
+        assert_eq!(large.unwrap().method(), AdjustmentMethod::Step);
+
+        let adjuster = adjuster.step_threshold(Duration::from_secs(1));
+        let large = adjuster.adjust(&result(-0.2, LeapIndicator::NoWarning));
+
+        assert_eq!(large.unwrap().method(), AdjustmentMethod::Slew);
+    }
+
+    #[test]
+    fn discipline_corrections_are_not_applied_in_dry_run() {
+        let adjuster = ClockAdjuster::new().dry_run(true);
+        let mut discipline = ClockDiscipline::new();
+        let now = Instant::now();
+
+        let step = discipline.update_with_offset(-2.0, now);
+        let panic = discipline.update_with_offset(2000.0, now);
+
+        assert!(adjuster.apply_correction(&step).is_ok());
+        assert!(matches!(
+            adjuster.apply_correction(&panic),
+            Err(ClockAdjustmentError::InvalidOffset)
+        ));
+    }
+
+    #[test]
+    fn leap_indicator_is_passed_and_alarm_is_rejected() {
+        let adjuster = ClockAdjuster::new().dry_run(true);
+
+        let adjustment = adjuster
+            .adjust(&result(0.0, LeapIndicator::LastMinuteHas61Seconds))
+            .unwrap();
+
+        assert_eq!(
+            adjustment.leap_indicator(),
+            LeapIndicator::LastMinuteHas61Seconds
+        );
+
+        assert!(matches!(
+            adjuster.adjust(&result(0.0, LeapIndicator::AlarmCondition)),
+            Err(ClockAdjustmentError::UnsynchronizedServer)
+        ));
+    }
+
+    #[test]
+    fn leap_indicators_on_the_wire_are_mapped_to_kernel_status() {
+        let mut raw = Packet::new(Mode::Server).stratum(1).encode();
+
+        // LI = 1, version 4, server mode
+        raw[0] = 0x64;
+        let packet = Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
+        let inserted = SynchronizationResult::new(0.0, 0.01, &packet).leap_indicator();
+
+        assert_eq!(inserted, LeapIndicator::LastMinuteHas61Seconds);
+        assert_eq!(leap_status(inserted), libc::STA_INS);
+
+        // LI = 2, version 4, server mode
+        raw[0] = 0xa4;
+        let packet = Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
+        let deleted = SynchronizationResult::new(0.0, 0.01, &packet).leap_indicator();
+
+        assert_eq!(deleted, LeapIndicator::LastMinuteHas59Seconds);
+        assert_eq!(leap_status(deleted), libc::STA_DEL);
+    }
+}
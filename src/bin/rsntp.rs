@@ -0,0 +1,430 @@
+use rsntp::{
+    Config, LeapIndicator, ProtocolError, RetryPolicy, SntpClient, SynchronizationError,
+    SynchronizationResult,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const SNTP_PORT: u16 = 123;
+
+const USAGE: &str = "\
+Usage: rsntp [OPTIONS] <SERVER>...
+
+Queries SNTP servers and prints the clock offset of the local clock, without adjusting it.
+
+Options:
+  -4                    Use IPv4 addresses only
+  -6                    Use IPv6 addresses only
+  -t, --timeout <SECS>  Timeout of a single request in seconds [default: 3]
+  -r, --retries <N>     Number of retries after a failed request [default: 0]
+  -s, --samples <N>     Number of samples per server, the best is selected [default: 1]
+  -j, --json            Print the results in JSON format
+  -h, --help            Print help
+  -V, --version         Print version
+
+Exit codes:
+  0  All servers have been queried successfully
+  1  Input/output error, like a timeout or name resolution failure
+  2  Invalid command line arguments
+  3  Invalid reply from a server
+  4  Kiss-o'-Death reply from a server
+  5  No suitable server found
+  6  Request suppressed";
+
+/// Exit code of invalid command line arguments
+const USAGE_ERROR: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AddressFamily {
+    Any,
+    V4,
+    V6,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    family: AddressFamily,
+    timeout: Option<Duration>,
+    retries: u32,
+    samples: usize,
+    json: bool,
+    servers: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Query(Options),
+    Help,
+    Version,
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {option}"))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {option}: {value}"))
+}
+
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut options = Options {
+        family: AddressFamily::Any,
+        timeout: None,
+        retries: 0,
+        samples: 1,
+        json: false,
+        servers: Vec::new(),
+    };
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                (option.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+
+        let takes_value = matches!(
+            option.as_str(),
+            "-t" | "--timeout" | "-r" | "--retries" | "-s" | "--samples"
+        );
+
+        if value.is_some() && !takes_value {
+            return Err(format!("Unexpected value for {option}"));
+        }
+
+        match option.as_str() {
+            "-4" => options.family = AddressFamily::V4,
+            "-6" => options.family = AddressFamily::V6,
+            "-t" | "--timeout" => {
+                let secs: f64 = parse_value(&option, value.or_else(|| args.next()))?;
+                let timeout = Duration::try_from_secs_f64(secs)
+                    .map_err(|_| format!("Invalid value for {option}: {secs}"))?;
+
+                options.timeout = Some(timeout);
+            }
+            "-r" | "--retries" => {
+                options.retries = parse_value(&option, value.or_else(|| args.next()))?;
+            }
+            "-s" | "--samples" => {
+                options.samples = parse_value(&option, value.or_else(|| args.next()))?;
+
+                if options.samples == 0 {
+                    return Err(format!("Invalid value for {option}: 0"));
+                }
+            }
+            "-j" | "--json" => options.json = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            _ if option.starts_with('-') => return Err(format!("Unknown option: {option}")),
+            _ => options.servers.push(option),
+        }
+    }
+
+    if options.servers.is_empty() {
+        return Err("No server specified".to_string());
+    }
+
+    Ok(Command::Query(options))
+}
+
+fn resolve(server: &str, family: AddressFamily) -> std::io::Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = if let Ok(ip) = server.parse::<IpAddr>() {
+        vec![SocketAddr::new(ip, SNTP_PORT)]
+    } else if server.contains(':') {
+        server.to_socket_addrs()?.collect()
+    } else {
+        (server, SNTP_PORT).to_socket_addrs()?.collect()
+    };
+
+    addresses
+        .into_iter()
+        .find(|address| match family {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => address.is_ipv4(),
+            AddressFamily::V6 => address.is_ipv6(),
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No suitable address found for {server}"),
+            )
+        })
+}
+
+fn query(
+    server: &str,
+    options: &Options,
+) -> Result<(SocketAddr, SynchronizationResult), SynchronizationError> {
+    let address = resolve(server, options.family)?;
+    let bind_address = if address.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+
+    let mut config = Config::default()
+        .bind_address(bind_address)
+        .retry_policy(RetryPolicy::default().attempts(options.retries.saturating_add(1)));
+
+    if let Some(timeout) = options.timeout {
+        config = config.timeout(timeout);
+    }
+
+    let client = SntpClient::with_config(config);
+    let result = client.synchronize_with_samples(address, options.samples)?;
+
+    Ok((address, result))
+}
+
+fn exit_code(error: &SynchronizationError) -> u8 {
+    match error {
+        SynchronizationError::IOError(_) => 1,
+        SynchronizationError::ProtocolError(ProtocolError::KissODeath(_)) => 4,
+        SynchronizationError::ProtocolError(_) => 3,
+        SynchronizationError::SelectionFailed => 5,
+        SynchronizationError::Suppressed(_) => 6,
+        // errors added in later versions
+        _ => 1,
+    }
+}
+
+fn leap_indicator_name(leap_indicator: LeapIndicator) -> &'static str {
+    match leap_indicator {
+        LeapIndicator::NoWarning => "no_warning",
+        LeapIndicator::LastMinuteHas61Seconds => "insert_second",
+        LeapIndicator::LastMinuteHas59Seconds => "delete_second",
+        LeapIndicator::AlarmCondition => "alarm",
+    }
+}
+
+fn print_human(
+    server: &str,
+    result: &Result<(SocketAddr, SynchronizationResult), SynchronizationError>,
+) {
+    match result {
+        Ok((address, result)) => {
+            println!("{server} ({address})");
+            println!(
+                "  offset           {:+.6} s",
+                result.clock_offset().as_secs_f64()
+            );
+            println!(
+                "  delay            {:.6} s",
+                result.round_trip_delay().as_secs_f64()
+            );
+            println!("  stratum          {}", result.stratum());
+            println!("  reference id     {}", result.reference_identifier());
+            println!(
+                "  leap indicator   {}",
+                leap_indicator_name(result.leap_indicator()).replace('_', " ")
+            );
+            println!(
+                "  root delay       {:.6} s",
+                result.root_delay().as_secs_f64()
+            );
+            println!(
+                "  root dispersion  {:.6} s",
+                result.root_dispersion().as_secs_f64()
+            );
+        }
+        Err(error) => eprintln!("rsntp: {server}: {error}"),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+fn to_json(
+    server: &str,
+    result: &Result<(SocketAddr, SynchronizationResult), SynchronizationError>,
+) -> String {
+    match result {
+        Ok((address, result)) => format!(
+            "{{\"server\":{},\"address\":{},\"offset\":{},\"delay\":{},\"stratum\":{},\
+             \"reference_id\":{},\"leap_indicator\":{},\"root_delay\":{},\"root_dispersion\":{}}}",
+            json_string(server),
+            json_string(&address.to_string()),
+            result.clock_offset().as_secs_f64(),
+            result.round_trip_delay().as_secs_f64(),
+            result.stratum(),
+            json_string(&result.reference_identifier().to_string()),
+            json_string(leap_indicator_name(result.leap_indicator())),
+            result.root_delay().as_secs_f64(),
+            result.root_dispersion().as_secs_f64(),
+        ),
+        Err(error) => format!(
+            "{{\"server\":{},\"error\":{},\"exit_code\":{}}}",
+            json_string(server),
+            json_string(&error.to_string()),
+            exit_code(error)
+        ),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Query(options)) => options,
+        Ok(Command::Help) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Version) => {
+            println!("rsntp {}", env!("CARGO_PKG_VERSION"));
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("rsntp: {message}\n\n{USAGE}");
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+
+    let mut code = 0;
+    let mut json = Vec::new();
+
+    for server in &options.servers {
+        let result = query(server, &options);
+
+        if let Err(error) = &result {
+            if code == 0 {
+                code = exit_code(error);
+            }
+        }
+
+        if options.json {
+            json.push(to_json(server, &result));
+        } else {
+            print_human(server, &result);
+        }
+    }
+
+    if options.json {
+        println!("[{}]", json.join(","));
+    }
+
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Synchronizes with a loopback server replying with the specified leap indicator bits
+    fn synchronize_with_leap_bits(bits: u8) -> (SocketAddr, SynchronizationResult) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut request = [0; 48];
+            let (_, source) = socket.recv_from(&mut request).unwrap();
+            let mut reply = [0; 48];
+
+            // leap indicator, version 4, server mode
+            reply[0] = (bits << 6) | 0x24;
+            reply[1] = 1;
+            reply[12..16].copy_from_slice(b"LOCL");
+            // originate, receive and transmit timestamps
+            reply[24..32].copy_from_slice(&request[40..48]);
+            reply[32..40].copy_from_slice(&request[40..48]);
+            reply[40..48].copy_from_slice(&request[40..48]);
+
+            socket.send_to(&reply, source).unwrap();
+        });
+
+        let client = SntpClient::with_config(Config::default().timeout(Duration::from_secs(5)));
+        let result = client.synchronize(address).unwrap();
+        server.join().unwrap();
+
+        (address, result)
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        let command = parse_args(args(&[
+            "-6",
+            "--timeout=1.5",
+            "-r",
+            "2",
+            "--samples",
+            "4",
+            "-j",
+            "pool.ntp.org",
+            "::1",
+        ]));
+
+        assert_eq!(
+            command,
+            Ok(Command::Query(Options {
+                family: AddressFamily::V6,
+                timeout: Some(Duration::from_millis(1500)),
+                retries: 2,
+                samples: 4,
+                json: true,
+                servers: args(&["pool.ntp.org", "::1"]),
+            }))
+        );
+        assert_eq!(parse_args(args(&["-h", "pool.ntp.org"])), Ok(Command::Help));
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(parse_args(args(&[])).is_err());
+        assert!(parse_args(args(&["-t"])).is_err());
+        assert!(parse_args(args(&["-t", "-1", "pool.ntp.org"])).is_err());
+        assert!(parse_args(args(&["-s", "0", "pool.ntp.org"])).is_err());
+        assert!(parse_args(args(&["--json=yes", "pool.ntp.org"])).is_err());
+        assert!(parse_args(args(&["-x", "pool.ntp.org"])).is_err());
+    }
+
+    #[test]
+    fn errors_are_mapped_to_exit_codes_and_json() {
+        let timeout = SynchronizationError::IOError(std::io::ErrorKind::TimedOut.into());
+        let invalid = SynchronizationError::ProtocolError(ProtocolError::InvalidMode);
+
+        assert_eq!(exit_code(&timeout), 1);
+        assert_eq!(exit_code(&invalid), 3);
+        assert_eq!(exit_code(&SynchronizationError::SelectionFailed), 5);
+
+        assert_eq!(
+            to_json("a\"b", &Err(invalid)),
+            format!(
+                "{{\"server\":\"a\\\"b\",\"error\":{},\"exit_code\":3}}",
+                json_string(&SynchronizationError::from(ProtocolError::InvalidMode).to_string())
+            )
+        );
+        assert_eq!(json_string("\u{1}\n"), "\"\\u0001\\n\"");
+    }
+
+    #[test]
+    fn leap_indicators_on_the_wire_are_named() {
+        let inserted = Ok(synchronize_with_leap_bits(0b01));
+        let deleted = Ok(synchronize_with_leap_bits(0b10));
+
+        assert!(to_json("server", &inserted).contains("\"leap_indicator\":\"insert_second\""));
+        assert!(to_json("server", &deleted).contains("\"leap_indicator\":\"delete_second\""));
+    }
+}
@@ -0,0 +1,458 @@
+use crate::core_logic::Broadcast;
+use crate::packet::Packet;
+#[cfg(feature = "async")]
+use crate::AsyncSntpClient;
+use crate::{Config, SntpClient, SynchronizationError, SynchronizationResult};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Round-trip delays of the broadcast servers, measured by client exchanges
+#[derive(Clone, Debug, Default)]
+struct Calibration {
+    delays: HashMap<IpAddr, f64>,
+}
+
+impl Calibration {
+    fn delay(&self, server: IpAddr) -> Option<f64> {
+        self.delays.get(&server).copied()
+    }
+
+    fn set_delay(&mut self, server: IpAddr, delay: Duration) {
+        self.delays.insert(server, delay.as_secs_f64());
+    }
+
+    fn store(&mut self, server: IpAddr, result: &SynchronizationResult) -> f64 {
+        let delay = result.round_trip_delay().as_secs_f64().max(0.0);
+
+        self.delays.insert(server, delay);
+        delay
+    }
+}
+
+fn join_multicast(
+    group: IpAddr,
+    join_v4: impl FnOnce(std::net::Ipv4Addr) -> std::io::Result<()>,
+    join_v6: impl FnOnce(std::net::Ipv6Addr) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    match group {
+        IpAddr::V4(group) => join_v4(group),
+        IpAddr::V6(group) => join_v6(group),
+    }
+}
+
+/// Blocking listener of broadcast and multicast servers
+///
+/// Receives the unsolicited broadcast (mode 5) packets of NTP servers, like the ones sent to the
+/// broadcast address of the network or to the 224.0.1.1 and ff0X::101 multicast groups.
+///
+/// A broadcast packet only contains the time when the server sent it, so the network delay has to
+/// be known to calculate the clock offset. When the first packet of a server is received, the
+/// listener sends a regular client request to the server to measure the delay (see RFC 5905,
+/// section 8). This exchange uses the [`Config`] of the listener. The delay can be also set
+/// manually with [`BroadcastListener::set_delay`], then no request is sent to the server.
+///
+/// Packets which are not broadcast packets are silently ignored. Broadcast packets are not
+/// authenticated.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{BroadcastListener, Config};
+///
+/// let mut listener = BroadcastListener::bind("0.0.0.0:123", Config::default()).unwrap();
+/// listener.join_multicast("224.0.1.1".parse().unwrap()).unwrap();
+///
+/// for result in listener.iter() {
+///     match result {
+///         Ok(result) => println!("Clock offset: {:?}", result.clock_offset()),
+///         Err(err) => println!("Error: {}", err),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BroadcastListener {
+    socket: std::net::UdpSocket,
+    client: SntpClient,
+    calibration: Calibration,
+}
+
+impl BroadcastListener {
+    /// Creates a listener on the specified address
+    ///
+    /// Broadcast servers usually send their packets to the standard port (123), binding to it
+    /// usually requires elevated privileges. The configuration is used for the client exchanges
+    /// which measure the delay.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{BroadcastListener, Config};
+    ///
+    /// let listener = BroadcastListener::bind("0.0.0.0:123", Config::default()).unwrap();
+    /// ```
+    pub fn bind<A: std::net::ToSocketAddrs>(
+        address: A,
+        config: Config,
+    ) -> std::io::Result<BroadcastListener> {
+        Ok(BroadcastListener {
+            socket: std::net::UdpSocket::bind(address)?,
+            client: SntpClient::with_config(config),
+            calibration: Calibration::default(),
+        })
+    }
+
+    /// Joins a multicast group on the default interface
+    ///
+    /// The standard NTP multicast groups are 224.0.1.1 for IPv4 and ff0X::101 for IPv6 (e.g.
+    /// ff05::101 for site-local scope). The address family of the group must match the address
+    /// family of the listener.
+    pub fn join_multicast(&self, group: IpAddr) -> std::io::Result<()> {
+        join_multicast(
+            group,
+            |group| {
+                self.socket
+                    .join_multicast_v4(&group, &std::net::Ipv4Addr::UNSPECIFIED)
+            },
+            |group| self.socket.join_multicast_v6(&group, 0),
+        )
+    }
+
+    /// Returns with the local address the listener is bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sets the round-trip delay of a server manually
+    ///
+    /// No client exchange is needed for servers with known delay. The clock offset is calculated
+    /// by assuming that the broadcast packet travels half of the round-trip delay.
+    pub fn set_delay(&mut self, server: IpAddr, delay: Duration) {
+        self.calibration.set_delay(server, delay);
+    }
+
+    /// Waits for the next broadcast packet
+    ///
+    /// If the delay of the server is not known yet, a client request is sent to the server
+    /// first. It returns with an error if the exchange fails or the broadcast packet is not valid.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{BroadcastListener, Config};
+    ///
+    /// let mut listener = BroadcastListener::bind("0.0.0.0:123", Config::default()).unwrap();
+    /// let result = listener.receive().unwrap();
+    ///
+    /// println!("Clock offset: {:?}", result.clock_offset());
+    /// ```
+    pub fn receive(&mut self) -> Result<SynchronizationResult, SynchronizationError> {
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        loop {
+            let (bytes_received, source) = self.socket.recv_from(&mut receive_buffer)?;
+            let anchor = self.client.config.anchor();
+            let broadcast = match Packet::decode(&receive_buffer[..bytes_received], source.ip()) {
+                Ok(packet) => Broadcast::new_with_receive_time(packet, anchor.time),
+                Err(_) => continue,
+            };
+
+            if !broadcast.is_broadcast() {
+                continue;
+            }
+
+            let delay = match self.calibration.delay(source.ip()) {
+                Some(delay) => delay,
+                None => {
+                    let result = self.client.synchronize(source)?;
+                    self.calibration.store(source.ip(), &result)
+                }
+            };
+
+            let mut result = broadcast.process(delay)?;
+            result.set_clock_source(self.client.config.clock_source.clone(), anchor);
+
+            return Ok(result);
+        }
+    }
+
+    /// Returns an iterator over the results of the received broadcast packets
+    ///
+    /// The iterator never ends, it calls [`BroadcastListener::receive`] repeatedly.
+    pub fn iter(
+        &mut self,
+    ) -> impl Iterator<Item = Result<SynchronizationResult, SynchronizationError>> + '_ {
+        std::iter::from_fn(move || Some(self.receive()))
+    }
+}
+
+/// Asynchronous listener of broadcast and multicast servers
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// This is the asynchronous counterpart of [`BroadcastListener`], see its documentation for
+/// details.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{AsyncBroadcastListener, Config};
+///
+/// async fn listen() {
+///   let mut listener = AsyncBroadcastListener::bind("0.0.0.0:123", Config::default())
+///       .await
+///       .unwrap();
+///
+///   while let Some(result) = listener.next().await {
+///     match result {
+///       Ok(result) => println!("Clock offset: {:?}", result.clock_offset()),
+///       Err(err) => println!("Error: {}", err),
+///     }
+///   }
+/// }
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncBroadcastListener {
+    socket: tokio::net::UdpSocket,
+    client: AsyncSntpClient,
+    calibration: Calibration,
+}
+
+#[cfg(feature = "async")]
+impl AsyncBroadcastListener {
+    /// Creates a listener on the specified address
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(
+        address: A,
+        config: Config,
+    ) -> std::io::Result<AsyncBroadcastListener> {
+        Ok(AsyncBroadcastListener {
+            socket: tokio::net::UdpSocket::bind(address).await?,
+            client: AsyncSntpClient::with_config(config),
+            calibration: Calibration::default(),
+        })
+    }
+
+    /// Joins a multicast group on the default interface
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn join_multicast(&self, group: IpAddr) -> std::io::Result<()> {
+        join_multicast(
+            group,
+            |group| {
+                self.socket
+                    .join_multicast_v4(group, std::net::Ipv4Addr::UNSPECIFIED)
+            },
+            |group| self.socket.join_multicast_v6(&group, 0),
+        )
+    }
+
+    /// Returns with the local address the listener is bound to
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sets the round-trip delay of a server manually
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn set_delay(&mut self, server: IpAddr, delay: Duration) {
+        self.calibration.set_delay(server, delay);
+    }
+
+    /// Waits for the next broadcast packet
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub async fn receive(&mut self) -> Result<SynchronizationResult, SynchronizationError> {
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        loop {
+            let (bytes_received, source) = self.socket.recv_from(&mut receive_buffer).await?;
+            let anchor = self.client.config.anchor();
+            let broadcast = match Packet::decode(&receive_buffer[..bytes_received], source.ip()) {
+                Ok(packet) => Broadcast::new_with_receive_time(packet, anchor.time),
+                Err(_) => continue,
+            };
+
+            if !broadcast.is_broadcast() {
+                continue;
+            }
+
+            let delay = match self.calibration.delay(source.ip()) {
+                Some(delay) => delay,
+                None => {
+                    let result = self.client.synchronize(source).await?;
+                    self.calibration.store(source.ip(), &result)
+                }
+            };
+
+            let mut result = broadcast.process(delay)?;
+            result.set_clock_source(self.client.config.clock_source.clone(), anchor);
+
+            return Ok(result);
+        }
+    }
+
+    /// Waits for the next broadcast packet, like the `next` method of streams
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// It is the asynchronous counterpart of [`BroadcastListener::iter`]: it calls
+    /// [`AsyncBroadcastListener::receive`] and never returns `None`, so the results can be
+    /// consumed with a `while let` loop. Like `receive`, it can be cancelled (e.g. by a timeout)
+    /// without losing packets, unless the delay of a new server is being measured: then the
+    /// broadcast packet of that server is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncBroadcastListener, Config};
+    /// use std::time::Duration;
+    ///
+    /// async fn listen() {
+    ///   let mut listener = AsyncBroadcastListener::bind("0.0.0.0:123", Config::default())
+    ///       .await
+    ///       .unwrap();
+    ///
+    ///   while let Ok(Some(result)) =
+    ///     tokio::time::timeout(Duration::from_secs(600), listener.next()).await
+    ///   {
+    ///     println!("{:?}", result.map(|result| result.clock_offset()));
+    ///   }
+    /// }
+    /// ```
+    pub async fn next(&mut self) -> Option<Result<SynchronizationResult, SynchronizationError>> {
+        Some(self.receive().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{LoopbackServer, OffsetTimeSource};
+    use crate::packet::{Mode, SntpTimestamp};
+    use crate::{ReferenceIdentifier, ServerConfig};
+    use std::net::UdpSocket;
+    use std::thread::JoinHandle;
+    use std::time::SystemTime;
+
+    fn broadcast_packet() -> [u8; Packet::ENCODED_LEN] {
+        Packet::new(Mode::Broadcast)
+            .stratum(1)
+            .reference_identifier(ReferenceIdentifier::ASCII("LOCL".into()))
+            .unwrap()
+            .transmit_timestamp(
+                SntpTimestamp::from_systemtime(SystemTime::now() + Duration::from_secs(10))
+                    .unwrap(),
+            )
+            .encode()
+    }
+
+    /// Starts a server which sends broadcast packets to the listener and answers one request
+    fn start_server(listener: SocketAddr, broadcasts: usize) -> JoinHandle<Vec<SocketAddr>> {
+        let server = LoopbackServer::bind()
+            .config(ServerConfig::default().time_source(OffsetTimeSource(Duration::from_secs(10))));
+
+        // a client packet, which has to be ignored
+        server
+            .socket()
+            .send_to(&Packet::new(Mode::Client).encode(), listener)
+            .unwrap();
+        server
+            .socket()
+            .send_to(&broadcast_packet(), listener)
+            .unwrap();
+
+        server.serve(1, move |exchange| {
+            exchange.send(&exchange.reply());
+
+            for _ in 1..broadcasts {
+                exchange
+                    .socket
+                    .send_to(&broadcast_packet(), listener)
+                    .unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn listener_calibrates_delay_and_receives_broadcasts() {
+        let config = Config::default().timeout(Duration::from_secs(5));
+        let mut listener = BroadcastListener::bind("127.0.0.1:0", config).unwrap();
+        let server = start_server(listener.local_addr().unwrap(), 3);
+
+        for result in listener.iter().take(3) {
+            let result = result.unwrap();
+
+            assert_eq!(result.stratum(), 1);
+            assert!((result.clock_offset().as_secs_f64() - 10.0).abs() < 0.1);
+            assert!(result.round_trip_delay().as_secs_f64() < 0.1);
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn listener_uses_manual_delay() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut listener = BroadcastListener::bind("127.0.0.1:0", Config::default()).unwrap();
+
+        listener.set_delay(socket.local_addr().unwrap().ip(), Duration::from_secs(1));
+        socket
+            .send_to(&broadcast_packet(), listener.local_addr().unwrap())
+            .unwrap();
+
+        let result = listener.receive().unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 10.5).abs() < 0.1);
+        assert_eq!(result.round_trip_delay().as_secs_f64(), 1.0);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_listener_calibrates_delay_and_receives_broadcasts() {
+        let config = Config::default().timeout(Duration::from_secs(5));
+        let mut listener = AsyncBroadcastListener::bind("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let server = start_server(listener.local_addr().unwrap(), 2);
+
+        for _ in 0..2 {
+            let result = listener.receive().await.unwrap();
+
+            assert!((result.clock_offset().as_secs_f64() - 10.0).abs() < 0.1);
+        }
+
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_listener_results_can_be_consumed_in_a_loop() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut listener = AsyncBroadcastListener::bind("127.0.0.1:0", Config::default())
+            .await
+            .unwrap();
+        let mut received = 0;
+
+        listener.set_delay(socket.local_addr().unwrap().ip(), Duration::from_secs(1));
+
+        for _ in 0..3 {
+            socket
+                .send_to(&broadcast_packet(), listener.local_addr().unwrap())
+                .unwrap();
+        }
+
+        while let Ok(Some(result)) =
+            tokio::time::timeout(Duration::from_millis(200), listener.next()).await
+        {
+            assert!((result.unwrap().clock_offset().as_secs_f64() - 10.5).abs() < 0.1);
+            received += 1;
+        }
+
+        assert_eq!(received, 3);
+    }
+}
@@ -0,0 +1,685 @@
+use crate::clock_source::SharedClockSource;
+use crate::result::{SntpDateTime, SntpDuration, SynchronizationResult};
+use crate::to_server_addrs::ToServerAddrs;
+use crate::{Config, SntpClient};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "async")]
+use crate::AsyncSntpClient;
+
+/// Number of samples kept for filtering and frequency estimation
+const SAMPLE_COUNT: usize = 8;
+/// Lower bound of jitter used by the poll interval adjustment, in seconds
+const MIN_JITTER: f64 = 1e-3;
+/// Poll interval is increased if the prediction error is smaller than this multiple of jitter
+const POLL_GATE: f64 = 4.0;
+
+/// Configuration of a synchronized clock
+///
+/// It uses a builder-like pattern, similar to [`Config`].
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ClockConfig, Config, SntpClock};
+/// use std::time::Duration;
+///
+/// let config = ClockConfig::default()
+///     .client_config(Config::default().timeout(Duration::from_secs(1)))
+///     .poll_interval(Duration::from_secs(32), Duration::from_secs(1024));
+/// let clock = SntpClock::with_config(vec!["pool.ntp.org"], config);
+/// ```
+#[derive(Clone, Debug, Hash)]
+pub struct ClockConfig {
+    client_config: Config,
+    min_poll_interval: Duration,
+    max_poll_interval: Duration,
+}
+
+impl ClockConfig {
+    /// Sets the configuration of the client used for synchronization
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ClockConfig, Config};
+    /// use std::time::Duration;
+    ///
+    /// let config = ClockConfig::default().client_config(Config::default().timeout(Duration::from_secs(1)));
+    /// ```
+    pub fn client_config(self, client_config: Config) -> ClockConfig {
+        ClockConfig {
+            client_config,
+            ..self
+        }
+    }
+
+    /// Sets the range of the poll interval
+    ///
+    /// The clock adjusts the poll interval between `min` and `max`: it is doubled while the
+    /// clock predicts server time well, and halved if it doesn't. Default is 64 seconds
+    /// minimum and 1024 seconds maximum.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ClockConfig;
+    /// use std::time::Duration;
+    ///
+    /// let config = ClockConfig::default().poll_interval(Duration::from_secs(16), Duration::from_secs(256));
+    /// ```
+    pub fn poll_interval(self, min: Duration, max: Duration) -> ClockConfig {
+        ClockConfig {
+            min_poll_interval: min,
+            max_poll_interval: max.max(min),
+            ..self
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    /// Creates an instance with default configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ClockConfig;
+    ///
+    /// let config = ClockConfig::default();
+    /// ```
+    fn default() -> ClockConfig {
+        ClockConfig {
+            client_config: Config::default(),
+            min_poll_interval: Duration::from_secs(64),
+            max_poll_interval: Duration::from_secs(1024),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    instant: Instant,
+    server_time: f64,
+    delay: f64,
+}
+
+fn signed_secs_between(later: Instant, earlier: Instant) -> f64 {
+    if later >= earlier {
+        (later - earlier).as_secs_f64()
+    } else {
+        -(earlier - later).as_secs_f64()
+    }
+}
+
+/// Estimates the server time based on samples, anchored to the monotonic clock
+#[derive(Debug)]
+pub(crate) struct ClockState {
+    samples: VecDeque<Sample>,
+    frequency: f64,
+    jitter: f64,
+    poll_interval: Duration,
+    min_poll_interval: Duration,
+    max_poll_interval: Duration,
+}
+
+impl ClockState {
+    pub fn new(min_poll_interval: Duration, max_poll_interval: Duration) -> ClockState {
+        ClockState {
+            samples: VecDeque::with_capacity(SAMPLE_COUNT),
+            frequency: 0.0,
+            jitter: 0.0,
+            poll_interval: min_poll_interval,
+            min_poll_interval,
+            max_poll_interval,
+        }
+    }
+
+    /// Adds a new sample; `server_time` is the server time at `instant` as seconds since Unix epoch
+    pub fn update(&mut self, instant: Instant, server_time: f64, delay: f64) {
+        let prediction_error = self.server_time_at(instant).map(|t| server_time - t);
+
+        if self.samples.len() == SAMPLE_COUNT {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(Sample {
+            instant,
+            server_time,
+            delay: delay.abs(),
+        });
+
+        self.frequency = self.estimate_frequency();
+
+        if let Some(error) = prediction_error {
+            if error.abs() < POLL_GATE * self.jitter.max(MIN_JITTER) {
+                self.poll_interval = (self.poll_interval * 2).min(self.max_poll_interval);
+            } else {
+                self.poll_interval = (self.poll_interval / 2).max(self.min_poll_interval);
+            }
+
+            self.jitter =
+                (self.jitter.powi(2) + (error.powi(2) - self.jitter.powi(2)) / 4.0).sqrt();
+        }
+    }
+
+    /// Sample with the lowest delay, which is considered the most accurate one
+    fn best_sample(&self) -> Option<&Sample> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.delay.total_cmp(&b.delay))
+    }
+
+    /// Frequency error of the monotonic clock relative to server time, using least squares fit
+    fn estimate_frequency(&self) -> f64 {
+        let first = match self.samples.front() {
+            Some(first) => *first,
+            None => return 0.0,
+        };
+
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .map(|s| {
+                let x = signed_secs_between(s.instant, first.instant);
+
+                (x, s.server_time - first.server_time - x)
+            })
+            .collect();
+
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+
+        if variance > 0.0 {
+            covariance / variance
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated server time at `instant`, in seconds since Unix epoch
+    pub fn server_time_at(&self, instant: Instant) -> Option<f64> {
+        self.best_sample().map(|anchor| {
+            anchor.server_time
+                + signed_secs_between(instant, anchor.instant) * (1.0 + self.frequency)
+        })
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    pub fn min_poll_interval(&self) -> Duration {
+        self.min_poll_interval
+    }
+
+    fn add_result(&mut self, result: &SynchronizationResult) {
+        let instant = Instant::now();
+
+        if let Ok(timestamp) = result.datetime().unix_timestamp() {
+            self.update(
+                instant,
+                timestamp.as_secs_f64(),
+                result.round_trip_delay().as_secs_f64(),
+            );
+        }
+    }
+}
+
+/// Returns with the estimated server time relative to the configured clock source
+fn corrected_now(
+    state: &RwLock<ClockState>,
+    clock_source: &SharedClockSource,
+) -> Option<SntpDateTime> {
+    let instant = Instant::now();
+    let server_time = state.read().unwrap().server_time_at(instant)?;
+    let local_time = clock_source.now();
+    let local_secs = local_time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs_f64();
+
+    Some(SntpDateTime::with_anchor(
+        SntpDuration::from_secs_f64(server_time - local_secs),
+        clock_source.clone(),
+        local_time,
+        instant,
+    ))
+}
+
+/// Continuously synchronized clock, running on a background thread
+///
+/// It periodically synchronizes with the configured servers on a background thread and keeps
+/// an estimate of the server time, anchored to the monotonic clock of the host. Between
+/// synchronizations, the estimate is extrapolated with the estimated frequency error of the
+/// local clock, so [`SntpClock::now`] returns a corrected time at any moment without adjusting
+/// the system clock.
+///
+/// The poll interval adapts to the stability of the clock: it is increased while the
+/// extrapolation predicts the server time well and decreased if it doesn't.
+///
+/// If multiple servers are supplied, they are queried with [`SntpClient::synchronize_many`].
+///
+/// The background thread stops when the instance is dropped. Dropping might block until an
+/// in-progress synchronization finishes.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::SntpClock;
+///
+/// let clock = SntpClock::new(vec!["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org"]);
+///
+/// // ...
+///
+/// if let Some(now) = clock.now() {
+///     println!("Current time: {:?}", now.unix_timestamp());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SntpClock {
+    state: Arc<RwLock<ClockState>>,
+    clock_source: SharedClockSource,
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl SntpClock {
+    /// Starts a clock with default configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClock;
+    ///
+    /// let clock = SntpClock::new(vec!["pool.ntp.org"]);
+    /// ```
+    pub fn new<A>(servers: Vec<A>) -> SntpClock
+    where
+        A: ToServerAddrs + Send + Sync + 'static,
+    {
+        SntpClock::with_config(servers, ClockConfig::default())
+    }
+
+    /// Starts a clock with the specified configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ClockConfig, SntpClock};
+    ///
+    /// let clock = SntpClock::with_config(vec!["pool.ntp.org"], ClockConfig::default());
+    /// ```
+    pub fn with_config<A>(servers: Vec<A>, config: ClockConfig) -> SntpClock
+    where
+        A: ToServerAddrs + Send + Sync + 'static,
+    {
+        let state = Arc::new(RwLock::new(ClockState::new(
+            config.min_poll_interval,
+            config.max_poll_interval,
+        )));
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let thread_state = state.clone();
+        let thread_stop = stop.clone();
+        let clock_source = config.client_config.clock_source.clone();
+        let client = SntpClient::with_config(config.client_config);
+
+        let thread = std::thread::spawn(move || loop {
+            let result = if servers.len() == 1 {
+                client.synchronize(&servers[0])
+            } else {
+                client
+                    .synchronize_many(&servers)
+                    .map(|result| result.result().clone())
+            };
+
+            let wait = {
+                let mut state = thread_state.write().unwrap();
+
+                match result {
+                    Ok(result) => {
+                        state.add_result(&result);
+                        state.poll_interval()
+                    }
+                    Err(_) => state.min_poll_interval(),
+                }
+            };
+
+            let (stopped, condvar) = &*thread_stop;
+            let stopped = condvar
+                .wait_timeout_while(stopped.lock().unwrap(), wait, |stopped| !*stopped)
+                .unwrap()
+                .0;
+
+            if *stopped {
+                break;
+            }
+        });
+
+        SntpClock {
+            state,
+            clock_source,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns with the current, corrected date and time
+    ///
+    /// Returns `None` if no successful synchronization has happened yet.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClock;
+    ///
+    /// let clock = SntpClock::new(vec!["pool.ntp.org"]);
+    ///
+    /// if let Some(now) = clock.now() {
+    ///     println!("Current time: {:?}", now.unix_timestamp());
+    /// }
+    /// ```
+    pub fn now(&self) -> Option<SntpDateTime> {
+        corrected_now(&self.state, &self.clock_source)
+    }
+
+    /// Returns with the estimated frequency error of the local clock in parts per million
+    ///
+    /// Positive value means that the local clock is slower than the server clock.
+    pub fn frequency_ppm(&self) -> f64 {
+        self.state.read().unwrap().frequency() * 1e6
+    }
+
+    /// Returns with the estimated jitter of the synchronization
+    pub fn jitter(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.state.read().unwrap().jitter())
+    }
+
+    /// Returns with the current poll interval
+    pub fn poll_interval(&self) -> Duration {
+        self.state.read().unwrap().poll_interval()
+    }
+}
+
+impl Drop for SntpClock {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Continuously synchronized clock, running on a `tokio` task
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// This is the asynchronous counterpart of [`SntpClock`], see its documentation for details.
+/// It must be created within a `tokio` runtime. The background task is aborted when the
+/// instance is dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::AsyncSntpClock;
+///
+/// async fn run() {
+///   let clock = AsyncSntpClock::new(vec!["pool.ntp.org"]);
+///
+///   // ...
+///
+///   if let Some(now) = clock.now() {
+///     println!("Current time: {:?}", now.unix_timestamp());
+///   }
+/// }
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncSntpClock {
+    state: Arc<RwLock<ClockState>>,
+    clock_source: SharedClockSource,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncSntpClock {
+    /// Starts a clock with default configuration
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncSntpClock;
+    ///
+    /// async fn run() {
+    ///   let clock = AsyncSntpClock::new(vec!["pool.ntp.org"]);
+    /// }
+    /// ```
+    pub fn new<A>(servers: Vec<A>) -> AsyncSntpClock
+    where
+        A: ToServerAddrs + Send + Sync + 'static,
+        A::Return: Send + Sync,
+    {
+        AsyncSntpClock::with_config(servers, ClockConfig::default())
+    }
+
+    /// Starts a clock with the specified configuration
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncSntpClock, ClockConfig};
+    ///
+    /// async fn run() {
+    ///   let clock = AsyncSntpClock::with_config(vec!["pool.ntp.org"], ClockConfig::default());
+    /// }
+    /// ```
+    pub fn with_config<A>(servers: Vec<A>, config: ClockConfig) -> AsyncSntpClock
+    where
+        A: ToServerAddrs + Send + Sync + 'static,
+        A::Return: Send + Sync,
+    {
+        let state = Arc::new(RwLock::new(ClockState::new(
+            config.min_poll_interval,
+            config.max_poll_interval,
+        )));
+
+        let task_state = state.clone();
+        let clock_source = config.client_config.clock_source.clone();
+        let client = AsyncSntpClient::with_config(config.client_config);
+
+        let task = tokio::spawn(async move {
+            loop {
+                let result = if servers.len() == 1 {
+                    client.synchronize(&servers[0]).await
+                } else {
+                    client
+                        .synchronize_many(&servers)
+                        .await
+                        .map(|result| result.result().clone())
+                };
+
+                let wait = {
+                    let mut state = task_state.write().unwrap();
+
+                    match result {
+                        Ok(result) => {
+                            state.add_result(&result);
+                            state.poll_interval()
+                        }
+                        Err(_) => state.min_poll_interval(),
+                    }
+                };
+
+                tokio::time::sleep(wait).await;
+            }
+        });
+
+        AsyncSntpClock {
+            state,
+            clock_source,
+            task,
+        }
+    }
+
+    /// Returns with the current, corrected date and time
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Returns `None` if no successful synchronization has happened yet.
+    pub fn now(&self) -> Option<SntpDateTime> {
+        corrected_now(&self.state, &self.clock_source)
+    }
+
+    /// Returns with the estimated frequency error of the local clock in parts per million
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Positive value means that the local clock is slower than the server clock.
+    pub fn frequency_ppm(&self) -> f64 {
+        self.state.read().unwrap().frequency() * 1e6
+    }
+
+    /// Returns with the estimated jitter of the synchronization
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn jitter(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.state.read().unwrap().jitter())
+    }
+
+    /// Returns with the current poll interval
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn poll_interval(&self) -> Duration {
+        self.state.read().unwrap().poll_interval()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncSntpClock {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: f64 = 1_700_000_000.0;
+
+    #[test]
+    fn no_estimate_without_samples() {
+        let state = ClockState::new(Duration::from_secs(64), Duration::from_secs(1024));
+
+        assert!(state.server_time_at(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn corrected_time_follows_the_clock_source() {
+        let mut state = ClockState::new(Duration::from_secs(64), Duration::from_secs(1024));
+        let clock = crate::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+
+        state.update(Instant::now(), BASE, 0.01);
+
+        let now =
+            corrected_now(&RwLock::new(state), &SharedClockSource::new(clock.clone())).unwrap();
+
+        assert!((now.unix_timestamp().unwrap().as_secs_f64() - BASE).abs() < 0.1);
+
+        clock.advance(Duration::from_secs(10));
+
+        assert!((now.unix_timestamp().unwrap().as_secs_f64() - BASE - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn server_time_is_extrapolated_from_a_single_sample() {
+        let mut state = ClockState::new(Duration::from_secs(64), Duration::from_secs(1024));
+        let start = Instant::now();
+
+        state.update(start, BASE, 0.01);
+
+        let estimate = state
+            .server_time_at(start + Duration::from_secs(10))
+            .unwrap();
+
+        assert!((estimate - BASE - 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frequency_error_is_estimated() {
+        let mut state = ClockState::new(Duration::from_secs(64), Duration::from_secs(1024));
+        let start = Instant::now();
+
+        // server clock runs 100 ppm faster than the local one
+        for i in 0..5 {
+            let elapsed = i as f64 * 100.0;
+
+            state.update(
+                start + Duration::from_secs_f64(elapsed),
+                BASE + elapsed * 1.0001,
+                0.01,
+            );
+        }
+
+        assert!((state.frequency() - 100e-6).abs() < 1e-9);
+
+        let estimate = state
+            .server_time_at(start + Duration::from_secs(1000))
+            .unwrap();
+
+        assert!((estimate - BASE - 1000.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sample_with_the_lowest_delay_is_used_as_anchor() {
+        let mut state = ClockState::new(Duration::from_secs(64), Duration::from_secs(1024));
+        let start = Instant::now();
+
+        state.update(start, BASE, 0.01);
+        state.update(start + Duration::from_secs(100), BASE + 100.0, 0.5);
+
+        assert!(state.best_sample().unwrap().instant == start);
+    }
+
+    #[test]
+    fn poll_interval_adapts_to_prediction_error() {
+        let mut state = ClockState::new(Duration::from_secs(64), Duration::from_secs(256));
+        let start = Instant::now();
+
+        state.update(start, BASE, 0.01);
+        assert_eq!(state.poll_interval(), Duration::from_secs(64));
+
+        state.update(start + Duration::from_secs(64), BASE + 64.0, 0.01);
+        assert_eq!(state.poll_interval(), Duration::from_secs(128));
+
+        state.update(start + Duration::from_secs(192), BASE + 192.0, 0.01);
+        assert_eq!(state.poll_interval(), Duration::from_secs(256));
+
+        state.update(start + Duration::from_secs(448), BASE + 448.0, 0.01);
+        assert_eq!(state.poll_interval(), Duration::from_secs(256));
+
+        state.update(start + Duration::from_secs(704), BASE + 705.0, 0.01);
+        assert_eq!(state.poll_interval(), Duration::from_secs(128));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::server::TimeSource;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the local time
+///
+/// The clients read the transmit and receive timestamps from the clock source set in
+/// [`crate::Config::clock_source`], and the date and time returned by
+/// [`crate::SynchronizationResult::datetime`] is calculated from it as well. The default is
+/// [`SystemClock`].
+///
+/// Receive timestamps provided by the transport (see [`crate::ReceivedPacket::with_timestamp`])
+/// take precedence over the clock source.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ClockSource, Config, SntpClient};
+/// use std::time::{Duration, SystemTime};
+///
+/// #[derive(Debug)]
+/// struct DelayedClock;
+///
+/// impl ClockSource for DelayedClock {
+///     fn now(&self) -> SystemTime {
+///         SystemTime::now() - Duration::from_secs(1)
+///     }
+/// }
+///
+/// let client = SntpClient::with_config(Config::default().clock_source(DelayedClock));
+/// let result = client.synchronize("pool.ntp.org").unwrap();
+///
+/// println!("Clock offset: {} seconds", result.clock_offset().as_secs_f64());
+/// ```
+pub trait ClockSource: Debug + Send + Sync {
+    /// Returns with the current time
+    fn now(&self) -> SystemTime;
+
+    /// Returns with the current time, based on an earlier reading of the clock
+    ///
+    /// `anchor_time` is an earlier result of [`ClockSource::now`], read at `anchor_instant`. It is
+    /// used to convert synchronization results to date and time. The default implementation
+    /// ignores the anchor and returns with [`ClockSource::now`].
+    fn now_since(&self, anchor_time: SystemTime, anchor_instant: Instant) -> SystemTime {
+        let _ = (anchor_time, anchor_instant);
+
+        self.now()
+    }
+}
+
+/// System clock, the default clock source
+///
+/// Timestamps are read from the system clock, but the date and time of synchronization results
+/// is calculated with the monotonic clock from the time of synchronization, so it is not affected
+/// by later changes of the system clock.
+#[derive(Clone, Copy, Debug, Default, Hash)]
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn now_since(&self, anchor_time: SystemTime, anchor_instant: Instant) -> SystemTime {
+        anchor_time
+            .checked_add(anchor_instant.elapsed())
+            .unwrap_or_else(SystemTime::now)
+    }
+}
+
+/// Clock anchored to the monotonic clock of the host
+///
+/// The system time is read once, at creation, and the clock advances with the monotonic clock
+/// afterwards. Unlike [`SystemClock`], it is not affected by steps of the system clock, so the
+/// measured clock offset is relative to the time of creation, not to the current system clock.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, MonotonicClock, SntpClient};
+///
+/// let client = SntpClient::with_config(Config::default().clock_source(MonotonicClock::new()));
+/// ```
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct MonotonicClock {
+    anchor_time: SystemTime,
+    anchor_instant: Instant,
+}
+
+impl MonotonicClock {
+    /// Creates a clock anchored to the current system time
+    pub fn new() -> MonotonicClock {
+        MonotonicClock::with_anchor(SystemTime::now())
+    }
+
+    /// Creates a clock showing the specified time now
+    pub fn with_anchor(time: SystemTime) -> MonotonicClock {
+        MonotonicClock {
+            anchor_time: time,
+            anchor_instant: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> MonotonicClock {
+        MonotonicClock::new()
+    }
+}
+
+impl ClockSource for MonotonicClock {
+    fn now(&self) -> SystemTime {
+        SystemClock.now_since(self.anchor_time, self.anchor_instant)
+    }
+}
+
+/// Manually set clock
+///
+/// The clock only changes when it is set or advanced, so it can be used in simulations and
+/// tests. Clones share the same time, so a clone can be handed over to the code under test while
+/// the test controls the time. It can also be used as the time source of [`crate::SntpServer`].
+///
+/// # Example
+///
+/// ```
+/// use rsntp::ManualClock;
+/// use std::time::{Duration, SystemTime};
+///
+/// let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+/// let shared = clock.clone();
+///
+/// clock.advance(Duration::from_secs(10));
+///
+/// assert_eq!(shared.now(), SystemTime::UNIX_EPOCH + Duration::from_secs(10));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    time: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    /// Creates a clock showing the specified time
+    pub fn new(time: SystemTime) -> ManualClock {
+        ManualClock {
+            time: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    /// Returns with the current time of the clock
+    pub fn now(&self) -> SystemTime {
+        *self.time.lock().unwrap()
+    }
+
+    /// Sets the time of the clock
+    pub fn set(&self, time: SystemTime) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    /// Advances the clock
+    pub fn advance(&self, duration: Duration) {
+        *self.time.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    /// Creates a clock showing the current system time
+    fn default() -> ManualClock {
+        ManualClock::new(SystemTime::now())
+    }
+}
+
+impl ClockSource for ManualClock {
+    fn now(&self) -> SystemTime {
+        ManualClock::now(self)
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn now(&self) -> SystemTime {
+        ManualClock::now(self)
+    }
+}
+
+/// Reading of a clock source, taken together with the monotonic clock
+///
+/// Results are anchored to the reading taken when their reply has been received, so the time
+/// spent processing the reply is not added to their date and time.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Anchor {
+    pub time: SystemTime,
+    pub instant: Instant,
+}
+
+/// Clock source shared by the configuration and the results
+#[derive(Clone, Debug)]
+pub(crate) struct SharedClockSource(Arc<dyn ClockSource>);
+
+impl SharedClockSource {
+    pub(crate) fn new<C: ClockSource + 'static>(clock_source: C) -> SharedClockSource {
+        SharedClockSource(Arc::new(clock_source))
+    }
+
+    pub(crate) fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    /// Reads the clock source together with the monotonic clock
+    pub(crate) fn anchor(&self) -> Anchor {
+        Anchor {
+            instant: Instant::now(),
+            time: self.now(),
+        }
+    }
+
+    pub(crate) fn now_since(&self, anchor_time: SystemTime, anchor_instant: Instant) -> SystemTime {
+        self.0.now_since(anchor_time, anchor_instant)
+    }
+}
+
+impl Default for SharedClockSource {
+    fn default() -> SharedClockSource {
+        SharedClockSource::new(SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_clock_uses_monotonic_clock_since_anchor() {
+        let anchor_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let anchor_instant = Instant::now() - Duration::from_secs(5);
+        let now = SystemClock.now_since(anchor_time, anchor_instant);
+
+        assert!(now >= anchor_time + Duration::from_secs(5));
+        assert!(now < anchor_time + Duration::from_secs(6));
+    }
+
+    #[test]
+    fn monotonic_clock_advances_from_anchor() {
+        let anchor_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let clock = MonotonicClock::with_anchor(anchor_time);
+
+        assert!(clock.now() >= anchor_time);
+        assert!(clock.now() < anchor_time + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn manual_clock_ignores_anchor() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+
+        clock.advance(Duration::from_secs(3));
+
+        assert_eq!(
+            clock.now_since(SystemTime::now(), Instant::now() - Duration::from_secs(1)),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(3)
+        );
+
+        clock.set(SystemTime::UNIX_EPOCH);
+
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+    }
+}
@@ -1,66 +1,113 @@
 use crate::error::{KissCode, ProtocolError, SynchronizationError};
-use crate::packet::{LeapIndicator, Mode, Packet, ReferenceIdentifier, SntpTimestamp};
+use crate::packet::{
+    LeapIndicator, Mode, Packet, ReferenceIdentifier, SntpShortFormat, SntpTimestamp,
+};
 use crate::result::SynchronizationResult;
 use std::time::SystemTime;
 
+#[derive(Clone)]
 pub struct Request {
     packet: Packet,
+    send_timestamp: SntpTimestamp,
 }
 
 impl Request {
+    #[cfg(test)]
     pub fn new() -> Request {
         Self::new_with_transmit_time(SystemTime::now())
     }
 
     pub fn new_with_transmit_time(transmit_time: SystemTime) -> Request {
+        let timestamp = SntpTimestamp::saturating_from_systemtime(transmit_time);
+
+        Self::new_with_timestamps(timestamp, timestamp)
+    }
+
+    /// Creates a request with a random transmit timestamp, see RFC 9109
+    ///
+    /// The real send time is only kept locally, it is not sent to the server.
+    pub fn new_with_random_transmit_timestamp(send_time: SystemTime) -> Request {
+        Self::new_with_timestamps(
+            SntpTimestamp::random(),
+            SntpTimestamp::saturating_from_systemtime(send_time),
+        )
+    }
+
+    fn new_with_timestamps(
+        transmit_timestamp: SntpTimestamp,
+        send_timestamp: SntpTimestamp,
+    ) -> Request {
         Request {
             packet: Packet {
                 li: LeapIndicator::NoWarning,
+                version: Packet::VERSION,
                 mode: Mode::Client,
                 stratum: 0,
+                poll: 0,
+                precision: 0,
+                root_delay: SntpShortFormat::zero(),
+                root_dispersion: SntpShortFormat::zero(),
                 reference_identifier: ReferenceIdentifier::Empty,
                 reference_timestamp: SntpTimestamp::zero(),
                 originate_timestamp: SntpTimestamp::zero(),
                 receive_timestamp: SntpTimestamp::zero(),
-                transmit_timestamp: SntpTimestamp::from_systemtime(transmit_time),
+                transmit_timestamp,
             },
+            send_timestamp,
         }
     }
 
-    pub fn as_bytes(&self) -> [u8; Packet::ENCODED_LEN] {
-        self.packet.to_bytes()
+    /// Replaces the local send time, if a more accurate one is available (e.g. a kernel timestamp)
+    pub fn with_send_time(mut self, send_time: Option<SystemTime>) -> Request {
+        if let Some(send_time) = send_time {
+            self.send_timestamp = SntpTimestamp::saturating_from_systemtime(send_time);
+        }
+
+        self
     }
 
-    fn into_packet(self) -> Packet {
-        self.packet
+    pub fn with_version(mut self, version: u8) -> Request {
+        self.packet.version = version;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.packet.version
+    }
+
+    pub fn transmit_timestamp(&self) -> SntpTimestamp {
+        self.packet.transmit_timestamp
+    }
+
+    pub fn as_bytes(&self) -> [u8; Packet::ENCODED_LEN] {
+        self.packet.encode()
     }
 }
 
 pub struct Reply {
     request: Packet,
+    request_timestamp: SntpTimestamp,
     reply: Packet,
     reply_timestamp: SntpTimestamp,
 }
 
 impl Reply {
+    #[cfg(test)]
     pub fn new(request: Request, reply: Packet) -> Reply {
         Self::new_with_reply_time(request, reply, SystemTime::now())
     }
 
     pub fn new_with_reply_time(request: Request, reply: Packet, reply_time: SystemTime) -> Reply {
         Reply {
-            request: request.into_packet(),
+            request: request.packet,
+            request_timestamp: request.send_timestamp,
             reply,
-            reply_timestamp: SntpTimestamp::from_systemtime(reply_time),
+            reply_timestamp: SntpTimestamp::saturating_from_systemtime(reply_time),
         }
     }
 
     fn check(&self) -> Result<(), ProtocolError> {
-        if self.reply.stratum == 0 {
-            return Err(ProtocolError::KissODeath(KissCode::new(
-                &self.reply.reference_identifier,
-            )));
-        }
+        check_stratum(&self.reply)?;
 
         if self.reply.originate_timestamp != self.request.transmit_timestamp {
             return Err(ProtocolError::InvalidOriginateTimestamp);
@@ -70,7 +117,13 @@ impl Reply {
             return Err(ProtocolError::InvalidTransmitTimestamp);
         }
 
-        if self.reply.mode != Mode::Server && self.reply.mode != Mode::Broadcast {
+        // NTPv1 has no mode field, it is always zero
+        let is_version_1_reply = self.reply.version == 1 && self.reply.mode == Mode::Reserved;
+
+        if self.reply.mode != Mode::Server
+            && self.reply.mode != Mode::Broadcast
+            && !is_version_1_reply
+        {
             return Err(ProtocolError::InvalidMode);
         }
         Ok(())
@@ -79,7 +132,8 @@ impl Reply {
     pub fn process(self) -> Result<SynchronizationResult, SynchronizationError> {
         self.check()?;
 
-        let originate_ts = self.reply.originate_timestamp;
+        // the originate timestamp of the reply might be a nonce, so the local send time is used
+        let originate_ts = self.request_timestamp;
         let transmit_ts = self.reply.transmit_timestamp;
         let receive_ts = self.reply.receive_timestamp;
         let round_trip_delay_s = (self.reply_timestamp - originate_ts) - (transmit_ts - receive_ts);
@@ -88,13 +142,78 @@ impl Reply {
         Ok(SynchronizationResult::new(
             clock_offset_s,
             round_trip_delay_s,
-            self.reply.reference_identifier.clone(),
-            self.reply.li,
-            self.reply.stratum,
+            &self.reply,
+        ))
+    }
+}
+
+/// Unsolicited packet of a broadcast server
+pub struct Broadcast {
+    packet: Packet,
+    receive_timestamp: SntpTimestamp,
+}
+
+impl Broadcast {
+    pub fn new_with_receive_time(packet: Packet, receive_time: SystemTime) -> Broadcast {
+        Broadcast {
+            packet,
+            receive_timestamp: SntpTimestamp::saturating_from_systemtime(receive_time),
+        }
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.packet.mode == Mode::Broadcast
+    }
+
+    fn check(&self) -> Result<(), ProtocolError> {
+        check_stratum(&self.packet)?;
+
+        if self.packet.transmit_timestamp.is_zero() {
+            return Err(ProtocolError::InvalidTransmitTimestamp);
+        }
+
+        if !self.is_broadcast() {
+            return Err(ProtocolError::InvalidMode);
+        }
+
+        Ok(())
+    }
+
+    /// Processes the packet with the round-trip delay measured by a client exchange
+    pub fn process(
+        self,
+        round_trip_delay_s: f64,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        self.check()?;
+
+        // the packet is assumed to travel half of the round-trip delay
+        let transmit_ts = self.packet.transmit_timestamp;
+        let clock_offset_s = (transmit_ts - self.receive_timestamp) + round_trip_delay_s / 2.0;
+
+        Ok(SynchronizationResult::new(
+            clock_offset_s,
+            round_trip_delay_s,
+            &self.packet,
         ))
     }
 }
 
+fn check_stratum(packet: &Packet) -> Result<(), ProtocolError> {
+    // Kiss-o'-Death packets were introduced in NTPv4, earlier versions use stratum 0 for
+    // unsynchronized servers
+    if packet.stratum == 0 && packet.version < 4 {
+        return Err(ProtocolError::UnsynchronizedServer);
+    }
+
+    if packet.stratum == 0 {
+        return Err(ProtocolError::KissODeath(KissCode::new(
+            &packet.reference_identifier,
+        )));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -117,19 +236,27 @@ mod tests {
 
         let reply_packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Server,
             stratum: 1,
+            poll: 6,
+            precision: -20,
+            root_delay: SntpShortFormat::from_secs_f64(0.03125),
+            root_dispersion: SntpShortFormat::from_secs_f64(0.015625),
             reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
             reference_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_secs(86400),
-            ),
+            )
+            .unwrap(),
             originate_timestamp: request.packet.transmit_timestamp,
             receive_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(400),
-            ),
+            )
+            .unwrap(),
             transmit_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(400),
-            ),
+            )
+            .unwrap(),
         };
 
         let reply = Reply::new_with_reply_time(
@@ -146,6 +273,94 @@ mod tests {
         assert_eq!(result.reference_identifier().to_string(), "LOCL");
         assert_eq!(result.leap_indicator(), LeapIndicator::NoWarning);
         assert_eq!(result.stratum(), 1);
+        assert_eq!(result.poll_interval().as_secs_f64(), 64.0);
+        assert_eq!(result.precision().as_secs_f64(), 2f64.powi(-20));
+        assert_eq!(result.root_delay().as_secs_f64(), 0.03125);
+        assert_eq!(result.root_dispersion().as_secs_f64(), 0.015625);
+        assert_between!(
+            result.synchronization_distance().as_secs_f64(),
+            0.1309,
+            0.1313
+        );
+    }
+
+    #[test]
+    fn random_transmit_timestamp_is_not_used_for_offset_calculation() {
+        let now = SystemTime::now();
+        let request = Request::new_with_random_transmit_timestamp(now);
+        let nonce = request.packet.transmit_timestamp;
+
+        assert_ne!(nonce, SntpTimestamp::from_systemtime(now).unwrap());
+
+        let reply_packet = Packet {
+            li: LeapIndicator::NoWarning,
+            version: 4,
+            mode: Mode::Server,
+            stratum: 1,
+            poll: 6,
+            precision: -20,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
+            reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
+            reference_timestamp: SntpTimestamp::from_systemtime(
+                now - std::time::Duration::from_secs(86400),
+            )
+            .unwrap(),
+            originate_timestamp: nonce,
+            receive_timestamp: SntpTimestamp::from_systemtime(
+                now - std::time::Duration::from_millis(400),
+            )
+            .unwrap(),
+            transmit_timestamp: SntpTimestamp::from_systemtime(
+                now - std::time::Duration::from_millis(400),
+            )
+            .unwrap(),
+        };
+
+        let reply = Reply::new_with_reply_time(
+            request,
+            reply_packet,
+            now + std::time::Duration::from_millis(200),
+        );
+
+        let result = reply.process().unwrap();
+
+        assert_between!(result.clock_offset().as_secs_f64(), -0.51, -0.49);
+        assert_between!(result.round_trip_delay().as_secs_f64(), 0.19, 0.21);
+    }
+
+    #[test]
+    fn sync_fails_if_reply_originate_ts_does_not_match_random_transmit_ts() {
+        let now = SystemTime::now();
+        let request = Request::new_with_random_transmit_timestamp(now);
+
+        let reply_packet = Packet {
+            li: LeapIndicator::NoWarning,
+            version: 4,
+            mode: Mode::Server,
+            stratum: 1,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
+            reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
+            reference_timestamp: SntpTimestamp::from_systemtime(
+                now - std::time::Duration::from_secs(86400),
+            )
+            .unwrap(),
+            originate_timestamp: SntpTimestamp::from_systemtime(now).unwrap(),
+            receive_timestamp: SntpTimestamp::from_systemtime(now).unwrap(),
+            transmit_timestamp: SntpTimestamp::from_systemtime(now).unwrap(),
+        };
+
+        let result = Reply::new(request, reply_packet).process();
+
+        assert!(matches!(
+            result,
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidOriginateTimestamp
+            ))
+        ));
     }
 
     #[test]
@@ -155,19 +370,27 @@ mod tests {
 
         let reply_packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Server,
             stratum: 1,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
             reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
             reference_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_secs(86400),
-            ),
-            originate_timestamp: SntpTimestamp::from_systemtime(now),
+            )
+            .unwrap(),
+            originate_timestamp: SntpTimestamp::from_systemtime(now).unwrap(),
             receive_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
             transmit_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
         };
 
         let reply = Reply::new(request, reply_packet);
@@ -184,16 +407,23 @@ mod tests {
 
         let reply_packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Server,
             stratum: 1,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
             reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
             reference_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_secs(86400),
-            ),
+            )
+            .unwrap(),
             originate_timestamp: request.packet.transmit_timestamp,
             receive_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
             transmit_timestamp: SntpTimestamp::zero(),
         };
 
@@ -211,19 +441,27 @@ mod tests {
 
         let reply_packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Client,
             stratum: 1,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
             reference_identifier: ReferenceIdentifier::new_ascii([0x4c, 0x4f, 0x43, 0x4c]).unwrap(),
             reference_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_secs(86400),
-            ),
+            )
+            .unwrap(),
             originate_timestamp: request.packet.transmit_timestamp,
             receive_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
             transmit_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
         };
 
         let reply = Reply::new(request, reply_packet);
@@ -240,19 +478,27 @@ mod tests {
 
         let reply_packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Server,
             stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
             reference_identifier: ReferenceIdentifier::new_ascii([0x52, 0x41, 0x54, 0x45]).unwrap(),
             reference_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_secs(86400),
-            ),
+            )
+            .unwrap(),
             originate_timestamp: request.packet.transmit_timestamp,
             receive_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
             transmit_timestamp: SntpTimestamp::from_systemtime(
                 now - std::time::Duration::from_millis(500),
-            ),
+            )
+            .unwrap(),
         };
 
         let reply = Reply::new(request, reply_packet);
@@ -268,4 +514,92 @@ mod tests {
             panic!("Wrong error received");
         }
     }
+
+    #[test]
+    fn sync_fails_if_old_version_reply_has_stratum_0() {
+        let request = Request::new().with_version(3);
+        let now = SystemTime::now();
+
+        let reply_packet = Packet::new(Mode::Server)
+            .version(3)
+            .originate_timestamp(request.packet.transmit_timestamp)
+            .receive_timestamp(SntpTimestamp::from_systemtime(now).unwrap())
+            .transmit_timestamp(SntpTimestamp::from_systemtime(now).unwrap());
+
+        let result = Reply::new(request, reply_packet).process();
+
+        assert!(matches!(
+            result,
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::UnsynchronizedServer
+            ))
+        ));
+    }
+
+    #[test]
+    fn version_1_reply_without_mode_is_accepted() {
+        let request = Request::new().with_version(1);
+        let now = SystemTime::now();
+
+        assert_eq!(request.as_bytes()[0], 0x0b);
+
+        let reply_packet = Packet::new(Mode::Reserved)
+            .version(1)
+            .stratum(2)
+            .reference_identifier(ReferenceIdentifier::IpAddress("10.0.0.1".parse().unwrap()))
+            .unwrap()
+            .originate_timestamp(request.packet.transmit_timestamp)
+            .receive_timestamp(SntpTimestamp::from_systemtime(now).unwrap())
+            .transmit_timestamp(SntpTimestamp::from_systemtime(now).unwrap());
+
+        let result = Reply::new(request, reply_packet).process().unwrap();
+
+        assert_eq!(result.version(), 1);
+        assert_eq!(result.stratum(), 2);
+    }
+
+    #[test]
+    fn broadcast_offset_is_calculated_with_calibrated_delay() {
+        let now = SystemTime::now();
+        let packet = Packet::new(Mode::Broadcast)
+            .stratum(1)
+            .reference_identifier(ReferenceIdentifier::ASCII("LOCL".into()))
+            .unwrap()
+            .transmit_timestamp(
+                SntpTimestamp::from_systemtime(now + std::time::Duration::from_millis(400))
+                    .unwrap(),
+            );
+
+        let result = Broadcast::new_with_receive_time(packet, now)
+            .process(0.2)
+            .unwrap();
+
+        assert_between!(result.clock_offset().as_secs_f64(), 0.49, 0.51);
+        assert_between!(result.round_trip_delay().as_secs_f64(), 0.19, 0.21);
+        assert_eq!(result.stratum(), 1);
+    }
+
+    #[test]
+    fn broadcast_fails_if_it_is_not_valid() {
+        let now = SystemTime::now();
+        let valid = Packet::new(Mode::Broadcast)
+            .stratum(1)
+            .transmit_timestamp(SntpTimestamp::from_systemtime(now).unwrap());
+        let server_mode = valid.clone().mode(Mode::Server);
+        let zero_transmit_timestamp = valid.clone().transmit_timestamp(SntpTimestamp::zero());
+
+        assert!(!Broadcast::new_with_receive_time(server_mode.clone(), now).is_broadcast());
+        assert!(matches!(
+            Broadcast::new_with_receive_time(server_mode, now).process(0.0),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidMode
+            ))
+        ));
+        assert!(matches!(
+            Broadcast::new_with_receive_time(zero_transmit_timestamp, now).process(0.0),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidTransmitTimestamp
+            ))
+        ));
+    }
 }
@@ -0,0 +1,475 @@
+use crate::result::{SntpDuration, SynchronizationResult};
+use std::time::{Duration, Instant};
+
+/// Step threshold in seconds (RFC 5905 STEPT)
+const STEP_THRESHOLD: f64 = 0.128;
+/// Stepout threshold in seconds (RFC 5905 WATCH)
+const STEPOUT_THRESHOLD: f64 = 900.0;
+/// Panic threshold in seconds (RFC 5905 PANICT)
+const PANIC_THRESHOLD: f64 = 1000.0;
+/// Phase-locked loop time constant (RFC 5905 PLL)
+const PLL: f64 = 65.0;
+/// Frequency-locked loop time constant (RFC 5905 FLL)
+const FLL: i8 = MAX_POLL + 1;
+/// Averaging constant of jitter and wander (RFC 5905 AVG)
+const AVG: f64 = 4.0;
+/// Allan intercept in seconds (RFC 5905 ALLAN)
+const ALLAN: f64 = 1500.0;
+/// Limit of the poll interval adjustment counter (RFC 5905 LIMIT)
+const LIMIT: i32 = 30;
+/// Maximum frequency correction in s/s (RFC 5905 MAXFREQ)
+const MAX_FREQUENCY: f64 = 500e-6;
+/// Poll interval is increased if the offset is smaller than this multiple of jitter (RFC 5905 PGATE)
+const POLL_GATE: f64 = 4.0;
+/// Minimum poll exponent (RFC 5905 MINPOLL)
+const MIN_POLL: i8 = 4;
+/// Maximum poll exponent (RFC 5905 MAXPOLL)
+const MAX_POLL: i8 = 17;
+/// Assumed precision of the system clock in seconds, the lower bound of jitter
+const PRECISION: f64 = 1e-6;
+
+/// State of the discipline, see RFC 5905 section 11.3
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    /// No update has been received yet (NSET)
+    Unset,
+    /// Frequency has been set, but no update has been received yet (FSET)
+    FrequencySet,
+    /// An offset above the step threshold has been received, waiting for the stepout (SPIK)
+    Spike,
+    /// Measuring the initial frequency (FREQ)
+    Frequency,
+    /// Normal operation (SYNC)
+    Synchronized,
+}
+
+/// Action to be taken on the system clock
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DisciplineAction {
+    /// The update has been discarded, the clock should not be changed
+    Ignore,
+    /// The phase correction should be slewed and the frequency correction applied
+    Slew,
+    /// The clock should be stepped by the phase correction and the frequency correction applied
+    Step,
+    /// The offset is above the panic threshold (1000 s), the clock should be set manually
+    Panic,
+}
+
+/// Correction of the system clock computed by [`ClockDiscipline`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockCorrection {
+    action: DisciplineAction,
+    phase_s: f64,
+    frequency: f64,
+    poll: i8,
+}
+
+impl ClockCorrection {
+    /// Returns with the action to be taken on the system clock
+    pub fn action(&self) -> DisciplineAction {
+        self.action
+    }
+
+    /// Returns with the offset to be added to the system clock
+    ///
+    /// It is zero if the update is ignored.
+    pub fn phase_correction(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.phase_s)
+    }
+
+    /// Returns with the frequency correction of the system clock in s/s
+    ///
+    /// It is the total correction, not the change since the last update. Positive values mean
+    /// that the clock should run faster, multiply by 10^6 to get it in PPM.
+    pub fn frequency_correction(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Returns with the recommended interval until the next update
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll)
+    }
+}
+
+/// Clock discipline algorithm, the hybrid phase/frequency-locked loop of RFC 5905 section 11.3
+///
+/// It consumes successive synchronization results and computes the phase and frequency
+/// corrections of the system clock, along with the recommended poll interval. It is a pure
+/// computation, the corrections can be applied with `ClockAdjuster::apply_correction` when the
+/// `clock-adjust` feature is enabled, or in any other way.
+///
+/// Offsets above 128 ms are only stepped if they persist for 900 seconds, so single spikes are
+/// suppressed. The initial frequency is measured over 900 seconds, unless it is set with
+/// [`ClockDiscipline::with_frequency`].
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ClockDiscipline, DisciplineAction, SntpClient};
+///
+/// let client = SntpClient::new();
+/// let mut discipline = ClockDiscipline::new();
+///
+/// loop {
+///     let result = client.synchronize("pool.ntp.org").unwrap();
+///     let correction = discipline.update(&result);
+///
+///     if correction.action() != DisciplineAction::Ignore {
+///         println!(
+///             "Phase: {} s, frequency: {} PPM",
+///             correction.phase_correction().as_secs_f64(),
+///             correction.frequency_correction() * 1e6
+///         );
+///     }
+///
+///     std::thread::sleep(correction.poll_interval());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClockDiscipline {
+    state: State,
+    last_update: Option<Instant>,
+    offset: f64,
+    frequency: f64,
+    jitter: f64,
+    wander: f64,
+    poll: i8,
+    count: i32,
+}
+
+impl ClockDiscipline {
+    /// Creates a clock discipline with unknown frequency
+    pub fn new() -> ClockDiscipline {
+        ClockDiscipline::default()
+    }
+
+    /// Creates a clock discipline with a known frequency correction in s/s
+    ///
+    /// It can be used to restore the frequency of a previous run, so the initial frequency
+    /// measurement is skipped.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ClockDiscipline;
+    ///
+    /// let discipline = ClockDiscipline::with_frequency(12.5e-6);
+    /// ```
+    pub fn with_frequency(frequency: f64) -> ClockDiscipline {
+        ClockDiscipline {
+            state: State::FrequencySet,
+            frequency: frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY),
+            ..ClockDiscipline::default()
+        }
+    }
+
+    /// Updates the discipline with a synchronization result received now
+    pub fn update(&mut self, result: &SynchronizationResult) -> ClockCorrection {
+        self.update_with_offset(result.clock_offset().as_secs_f64(), Instant::now())
+    }
+
+    /// Updates the discipline with a clock offset in seconds, measured at the given time
+    ///
+    /// As the time is an argument, it can be used to run the discipline on recorded or simulated
+    /// measurements.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::{ClockDiscipline, DisciplineAction};
+    /// use std::time::Instant;
+    ///
+    /// let mut discipline = ClockDiscipline::new();
+    /// let correction = discipline.update_with_offset(-0.002, Instant::now());
+    ///
+    /// assert_eq!(correction.action(), DisciplineAction::Slew);
+    /// assert_eq!(correction.phase_correction().as_secs_f64(), -0.002);
+    /// ```
+    pub fn update_with_offset(&mut self, offset: f64, time: Instant) -> ClockCorrection {
+        if offset.abs() > PANIC_THRESHOLD || !offset.is_finite() {
+            return self.correction(DisciplineAction::Panic, offset);
+        }
+
+        let mu = self.last_update.map_or(0.0, |last_update| {
+            time.saturating_duration_since(last_update).as_secs_f64()
+        });
+        let mut frequency = 0.0;
+        let action;
+
+        if offset.abs() > STEP_THRESHOLD {
+            match self.state {
+                State::Synchronized => {
+                    self.state = State::Spike;
+                    return self.correction(DisciplineAction::Ignore, 0.0);
+                }
+                State::Frequency | State::Spike if mu < STEPOUT_THRESHOLD => {
+                    return self.correction(DisciplineAction::Ignore, 0.0);
+                }
+                State::Frequency => {
+                    frequency = (offset - self.offset) / mu;
+                }
+                _ => {}
+            }
+
+            self.count = 0;
+            self.poll = MIN_POLL;
+            action = DisciplineAction::Step;
+
+            if self.state == State::Unset {
+                self.reset(State::Frequency, time, 0.0);
+                return self.correction(action, offset);
+            }
+
+            self.reset(State::Synchronized, time, 0.0);
+        } else {
+            let difference = (offset - self.offset).abs().max(PRECISION);
+            self.jitter = average(self.jitter, difference);
+
+            match self.state {
+                State::Unset => {
+                    self.reset(State::Frequency, time, offset);
+                    return self.correction(DisciplineAction::Slew, offset);
+                }
+                State::Frequency if mu < STEPOUT_THRESHOLD => {
+                    return self.correction(DisciplineAction::Ignore, 0.0);
+                }
+                // the measured frequency is refined by the loops below, like in RFC 5905
+                State::Frequency => {
+                    frequency = (offset - self.offset) / mu;
+                }
+                _ => {}
+            }
+
+            let poll_interval = f64::from(1u32 << self.poll);
+
+            // the frequency-locked loop is only used above half of the Allan intercept
+            if poll_interval > ALLAN / 2.0 {
+                let time_constant = f64::from((FLL - self.poll).max(AVG as i8));
+                frequency += (offset - self.offset) / (mu.max(ALLAN) * time_constant);
+            }
+
+            let gain = 4.0 * PLL * poll_interval;
+            frequency += offset * mu.min(poll_interval) / (gain * gain);
+
+            action = DisciplineAction::Slew;
+            self.reset(State::Synchronized, time, offset);
+        }
+
+        let previous_frequency = self.frequency;
+        self.frequency = (self.frequency + frequency).clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        self.wander = average(self.wander, self.frequency - previous_frequency);
+
+        if self.offset.abs() < POLL_GATE * self.jitter {
+            self.count += i32::from(self.poll);
+
+            if self.count > LIMIT {
+                self.count = LIMIT;
+
+                if self.poll < MAX_POLL {
+                    self.count = 0;
+                    self.poll += 1;
+                }
+            }
+        } else {
+            self.count -= i32::from(self.poll) << 1;
+
+            if self.count < -LIMIT {
+                self.count = -LIMIT;
+
+                if self.poll > MIN_POLL {
+                    self.count = 0;
+                    self.poll -= 1;
+                }
+            }
+        }
+
+        self.correction(
+            action,
+            if action == DisciplineAction::Step {
+                offset
+            } else {
+                self.offset
+            },
+        )
+    }
+
+    fn reset(&mut self, state: State, time: Instant, offset: f64) {
+        self.state = state;
+        self.last_update = Some(time);
+        self.offset = offset;
+    }
+
+    fn correction(&self, action: DisciplineAction, phase_s: f64) -> ClockCorrection {
+        ClockCorrection {
+            action,
+            phase_s,
+            frequency: self.frequency,
+            poll: self.poll,
+        }
+    }
+
+    /// Returns with the current frequency correction in s/s
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Returns with the jitter of the clock offsets
+    ///
+    /// It is the exponentially weighted RMS of the differences between successive offsets.
+    pub fn jitter(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.jitter)
+    }
+
+    /// Returns with the wander of the frequency in s/s
+    ///
+    /// It is the exponentially weighted RMS of the differences between successive frequency
+    /// corrections, an indicator of the stability of the clock.
+    pub fn wander(&self) -> f64 {
+        self.wander
+    }
+
+    /// Returns with the recommended interval until the next update
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll)
+    }
+
+    /// Returns true if the initial frequency measurement is over and the clock is disciplined
+    pub fn is_synchronized(&self) -> bool {
+        self.state == State::Synchronized
+    }
+}
+
+impl Default for ClockDiscipline {
+    fn default() -> ClockDiscipline {
+        ClockDiscipline {
+            state: State::Unset,
+            last_update: None,
+            offset: 0.0,
+            frequency: 0.0,
+            jitter: PRECISION,
+            wander: 0.0,
+            poll: MIN_POLL,
+            count: 0,
+        }
+    }
+}
+
+/// Exponentially weighted RMS average
+fn average(average: f64, value: f64) -> f64 {
+    (average.powi(2) + (value.powi(2) - average.powi(2)) / AVG).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn initial_frequency_is_measured_over_stepout_interval() {
+        let start = Instant::now();
+        let mut discipline = ClockDiscipline::new();
+
+        let correction = discipline.update_with_offset(0.001, start);
+
+        assert_eq!(correction.action(), DisciplineAction::Slew);
+        assert_eq!(correction.phase_correction().as_secs_f64(), 0.001);
+
+        let correction = discipline.update_with_offset(0.005, start + secs(64));
+
+        assert_eq!(correction.action(), DisciplineAction::Ignore);
+        assert!(!discipline.is_synchronized());
+
+        let correction = discipline.update_with_offset(0.01, start + secs(900));
+        // the measured frequency and the contribution of the phase-locked loop
+        let gain = 4.0 * PLL * 16.0;
+        let expected = (0.01 - 0.001) / 900.0 + 0.01 * 16.0 / (gain * gain);
+
+        assert_eq!(correction.action(), DisciplineAction::Slew);
+        assert!((correction.frequency_correction() - expected).abs() < 1e-12);
+        assert!(discipline.is_synchronized());
+    }
+
+    #[test]
+    fn spikes_are_ignored_until_stepout() {
+        let start = Instant::now();
+        let mut discipline = ClockDiscipline::with_frequency(1e-6);
+
+        let correction = discipline.update_with_offset(0.0, start);
+
+        assert_eq!(correction.action(), DisciplineAction::Slew);
+        assert!(discipline.is_synchronized());
+
+        let correction = discipline.update_with_offset(0.5, start + secs(16));
+
+        assert_eq!(correction.action(), DisciplineAction::Ignore);
+
+        let correction = discipline.update_with_offset(0.5, start + secs(32));
+
+        assert_eq!(correction.action(), DisciplineAction::Ignore);
+
+        let correction = discipline.update_with_offset(0.5, start + secs(1000));
+
+        assert_eq!(correction.action(), DisciplineAction::Step);
+        assert_eq!(correction.phase_correction().as_secs_f64(), 0.5);
+        assert_eq!(correction.poll_interval(), secs(16));
+        assert!(discipline.is_synchronized());
+    }
+
+    #[test]
+    fn first_large_offset_is_stepped_and_panic_is_reported() {
+        let start = Instant::now();
+        let mut discipline = ClockDiscipline::new();
+
+        let correction = discipline.update_with_offset(5000.0, start);
+
+        assert_eq!(correction.action(), DisciplineAction::Panic);
+
+        let correction = discipline.update_with_offset(-2.0, start);
+
+        assert_eq!(correction.action(), DisciplineAction::Step);
+        assert_eq!(correction.phase_correction().as_secs_f64(), -2.0);
+    }
+
+    #[test]
+    fn frequency_is_limited() {
+        assert_eq!(
+            ClockDiscipline::with_frequency(1.0).frequency(),
+            MAX_FREQUENCY
+        );
+
+        let start = Instant::now();
+        let mut discipline = ClockDiscipline::new();
+
+        discipline.update_with_offset(0.0, start);
+        let correction = discipline.update_with_offset(0.9, start + secs(900));
+
+        assert_eq!(correction.action(), DisciplineAction::Step);
+        assert_eq!(discipline.frequency(), MAX_FREQUENCY);
+    }
+
+    #[test]
+    fn poll_interval_grows_while_offsets_are_small() {
+        let mut time = Instant::now();
+        let mut discipline = ClockDiscipline::with_frequency(0.0);
+
+        for _ in 0..20 {
+            let correction = discipline.update_with_offset(0.0, time);
+            time += correction.poll_interval();
+        }
+
+        assert!(discipline.poll_interval() > secs(1 << MIN_POLL));
+
+        let poll_interval = discipline.poll_interval();
+
+        for _ in 0..30 {
+            let correction = discipline.update_with_offset(0.1, time);
+            time += correction.poll_interval();
+        }
+
+        assert!(discipline.poll_interval() < poll_interval);
+    }
+}
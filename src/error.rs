@@ -9,6 +9,7 @@ use std::fmt::{Display, Formatter};
 /// why the request has been rejected. This enum is generally a 1-to-1 mapping to SNTP RFC kiss
 /// codes, see RFC 5905 section 7.4.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum KissCode {
     /// Unknown code
     Unknown,
@@ -39,6 +40,9 @@ pub enum KissCode {
     TinkeringWithAssociation,
     /// A step change in system time has occurred, but the association has not yet resynchronized
     StepChange,
+    /// The NTS cookie sent in the request could not be processed by the server, a new NTS key
+    /// establishment is needed
+    NtsNegativeAcknowledgment,
 }
 
 impl KissCode {
@@ -59,6 +63,7 @@ impl KissCode {
                 "RATE" => KissCode::RateExceeded,
                 "RMOT" => KissCode::TinkeringWithAssociation,
                 "STEP" => KissCode::StepChange,
+                "NTSN" => KissCode::NtsNegativeAcknowledgment,
                 _ => KissCode::Unknown,
             }
         } else {
@@ -98,6 +103,7 @@ impl Display for KissCode {
       KissCode::RateExceeded => write!(f, "Rate exceeded.  The server has temporarily denied access because the client exceeded the rate threshold"),
       KissCode::TinkeringWithAssociation => write!(f, "Somebody is tinkering with the association from a remote host"),
       KissCode::StepChange => write!(f, " step change in system time has occurred, but the association has not yet resynchronized"),
+      KissCode::NtsNegativeAcknowledgment => write!(f, "NTS cookie has not been accepted by the server"),
     }
     }
 }
@@ -107,12 +113,14 @@ impl Display for KissCode {
 /// This is a more detailed description of the error and can be used by clients who need more
 /// elaborate information about the reason for the failure.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum ProtocolError {
     /// Server reply packet is too short
     PacketIsTooShort,
     /// Server reply packet has unsupported version
     InvalidPacketVersion,
     /// Server reply packet contains invalid leap indicator
+    #[deprecated(note = "all leap indicator values are valid, this error is never returned")]
     InvalidLeapIndicator,
     /// Server reply packet contains invalid mode
     InvalidMode,
@@ -125,6 +133,25 @@ pub enum ProtocolError {
     /// Kiss-o'-Death packet received. KoD indicates that the server rejected the request and generally
     /// means that the client should stop sending request to the server.
     KissODeath(KissCode),
+    /// NTS key establishment failed, e.g. the server reported an error or did not provide any
+    /// cookies
+    NtsKeyExchangeFailed,
+    /// Server reply does not contain the unique identifier of the request
+    InvalidUniqueIdentifier,
+    /// Server reply is not authenticated or the authentication failed
+    InvalidNtsAuthenticator,
+    /// Server reply does not contain a message authentication code
+    MissingMac,
+    /// Message authentication code of the server reply is invalid or uses a different key than the
+    /// request
+    InvalidMac,
+    /// Server reply contains an extension field with invalid length
+    InvalidExtensionFieldLength,
+    /// Server reply contains an extension field which is longer than the packet
+    TruncatedExtensionField,
+    /// Server reply has stratum 0 and an NTP version earlier than 4, which means that the server
+    /// is not synchronized
+    UnsynchronizedServer,
 }
 
 impl Error for ProtocolError {
@@ -140,6 +167,7 @@ impl Display for ProtocolError {
             ProtocolError::InvalidPacketVersion => {
                 write!(f, "Server reply packet has unsupported version")
             }
+            #[allow(deprecated)]
             ProtocolError::InvalidLeapIndicator => {
                 write!(f, "Server reply packet contains invalid leap indicator")
             }
@@ -156,6 +184,157 @@ impl Display for ProtocolError {
             ProtocolError::KissODeath(code) => {
                 write!(f, "Kiss-o'-Death packet received: {code}")
             }
+            ProtocolError::NtsKeyExchangeFailed => write!(f, "NTS key establishment failed"),
+            ProtocolError::InvalidUniqueIdentifier => {
+                write!(f, "Server reply contains invalid unique identifier")
+            }
+            ProtocolError::InvalidNtsAuthenticator => {
+                write!(f, "Server reply authentication failed")
+            }
+            ProtocolError::MissingMac => {
+                write!(
+                    f,
+                    "Server reply does not contain message authentication code"
+                )
+            }
+            ProtocolError::InvalidMac => {
+                write!(
+                    f,
+                    "Server reply contains invalid message authentication code"
+                )
+            }
+            ProtocolError::InvalidExtensionFieldLength => {
+                write!(
+                    f,
+                    "Server reply contains extension field with invalid length"
+                )
+            }
+            ProtocolError::TruncatedExtensionField => {
+                write!(f, "Server reply contains truncated extension field")
+            }
+            ProtocolError::UnsynchronizedServer => write!(f, "Server is not synchronized"),
+        }
+    }
+}
+
+/// Error of decoding an NTP packet
+///
+/// Returned by [`crate::packet::Packet::decode`], it contains the offending value of the packet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// Packet is shorter than the header, contains the length of the packet
+    TooShort(usize),
+    /// Version of the packet is not supported, contains the version number
+    UnsupportedVersion(u8),
+    /// Reference identifier of a primary server or a Kiss-o'-Death packet is not an ASCII
+    /// string, contains the raw reference identifier
+    InvalidReferenceIdentifier([u8; 4]),
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooShort(length) => {
+                write!(f, "Packet is too short ({length} bytes)")
+            }
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Packet has unsupported version {version}")
+            }
+            DecodeError::InvalidReferenceIdentifier(raw) => {
+                write!(f, "Packet contains invalid reference identifier {raw:02X?}")
+            }
+        }
+    }
+}
+
+impl From<DecodeError> for ProtocolError {
+    fn from(error: DecodeError) -> ProtocolError {
+        match error {
+            DecodeError::TooShort(_) => ProtocolError::PacketIsTooShort,
+            DecodeError::UnsupportedVersion(_) => ProtocolError::InvalidPacketVersion,
+            DecodeError::InvalidReferenceIdentifier(_) => ProtocolError::InvalidReferenceIdentifier,
+        }
+    }
+}
+
+/// Error of a reference identifier which cannot be encoded into a packet
+///
+/// Returned by [`crate::packet::Packet::reference_identifier`] and
+/// [`crate::ServerConfig::reference_identifier`], as the reference identifier field of the packet
+/// is only four bytes long.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ReferenceIdentifierError {
+    /// ASCII identifier is longer than four characters, contains its length
+    TooLong(usize),
+    /// ASCII identifier contains non-ASCII characters
+    NotAscii,
+    /// IPv6 addresses cannot be encoded, the MD5 hash of the address has to be used instead
+    Ipv6Address,
+}
+
+impl Error for ReferenceIdentifierError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl Display for ReferenceIdentifierError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReferenceIdentifierError::TooLong(length) => write!(
+                f,
+                "Reference identifier is too long ({length} characters, at most 4 are allowed)"
+            ),
+            ReferenceIdentifierError::NotAscii => {
+                write!(f, "Reference identifier contains non-ASCII characters")
+            }
+            ReferenceIdentifierError::Ipv6Address => write!(
+                f,
+                "IPv6 address cannot be used as reference identifier, use its MD5 hash instead"
+            ),
+        }
+    }
+}
+
+/// Reason why a request has not been sent to the server
+///
+/// Returned by clients which keep track of server states (like [`crate::GuardedSntpClient`])
+/// when sending a request would violate the rules of RFC 5905.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SuppressionReason {
+    /// The poll interval has not elapsed since the last request to the server. Contains the
+    /// remaining time until the next request is allowed.
+    PollIntervalNotElapsed(std::time::Duration),
+    /// The server has previously sent a `RATE` Kiss-o'-Death, and the increased poll interval has
+    /// not elapsed yet. Contains the remaining time until the next request is allowed.
+    RateExceeded(std::time::Duration),
+    /// The server has previously denied access with a `DENY` or `RSTR` Kiss-o'-Death
+    AccessDenied,
+}
+
+impl Display for SuppressionReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SuppressionReason::PollIntervalNotElapsed(remaining) => write!(
+                f,
+                "Poll interval has not elapsed, next request is allowed in {} s",
+                remaining.as_secs_f64()
+            ),
+            SuppressionReason::RateExceeded(remaining) => write!(
+                f,
+                "Server rate limit exceeded, next request is allowed in {} s",
+                remaining.as_secs_f64()
+            ),
+            SuppressionReason::AccessDenied => write!(f, "Access denied by remote server"),
         }
     }
 }
@@ -164,11 +343,19 @@ impl Display for ProtocolError {
 ///
 /// Returned when synchronization fails.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SynchronizationError {
     /// An I/O error occured during the query, like socket error, timeout, etc...
     IOError(std::io::Error),
     /// SNTP protocol specific error
     ProtocolError(ProtocolError),
+    /// None of the queried servers could be selected as a time source during a multi-server
+    /// synchronization, either because all of them failed or because there was no majority of
+    /// servers agreeing on the time
+    SelectionFailed,
+    /// The request has not been sent to the server to respect its poll interval or a previously
+    /// received Kiss-o'-Death
+    Suppressed(SuppressionReason),
 }
 
 impl Error for SynchronizationError {
@@ -176,6 +363,8 @@ impl Error for SynchronizationError {
         match self {
             SynchronizationError::IOError(io_error) => Some(io_error),
             SynchronizationError::ProtocolError(protocol_error) => Some(protocol_error),
+            SynchronizationError::SelectionFailed => None,
+            SynchronizationError::Suppressed(_) => None,
         }
     }
 }
@@ -189,6 +378,12 @@ impl Display for SynchronizationError {
             SynchronizationError::ProtocolError(protocol_error) => {
                 write!(f, "Protocol error: {protocol_error}")
             }
+            SynchronizationError::SelectionFailed => {
+                write!(f, "No suitable server found")
+            }
+            SynchronizationError::Suppressed(reason) => {
+                write!(f, "Request suppressed: {reason}")
+            }
         }
     }
 }
@@ -199,6 +394,18 @@ impl From<std::io::Error> for SynchronizationError {
     }
 }
 
+impl From<SuppressionReason> for SynchronizationError {
+    fn from(reason: SuppressionReason) -> SynchronizationError {
+        SynchronizationError::Suppressed(reason)
+    }
+}
+
+impl From<DecodeError> for SynchronizationError {
+    fn from(error: DecodeError) -> SynchronizationError {
+        SynchronizationError::ProtocolError(error.into())
+    }
+}
+
 impl From<ProtocolError> for SynchronizationError {
     fn from(protocol_error: ProtocolError) -> SynchronizationError {
         SynchronizationError::ProtocolError(protocol_error)
@@ -231,6 +438,88 @@ impl SynchronizationError {
     }
 }
 
+/// Error of configuring symmetric keys
+///
+/// Returned by [`crate::KeyStore::key`] and [`crate::Config::mac_authentication`], only available
+/// when the `mac` feature is enabled.
+#[cfg(feature = "mac")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum KeyError {
+    /// Secret has invalid length for the algorithm, contains the length of the secret
+    InvalidSecretLength(usize),
+    /// Key is not in the key store, contains the key ID
+    UnknownKey(u32),
+}
+
+#[cfg(feature = "mac")]
+impl Error for KeyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+#[cfg(feature = "mac")]
+impl Display for KeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::InvalidSecretLength(length) => {
+                write!(f, "Secret has invalid length ({length} bytes)")
+            }
+            KeyError::UnknownKey(key_id) => write!(f, "Key {key_id} is not in the key store"),
+        }
+    }
+}
+
+/// Error of a system clock adjustment
+///
+/// Returned by [`crate::ClockAdjuster`], only available when the `clock-adjust` feature is enabled.
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClockAdjustmentError {
+    /// The process is not permitted to adjust the system clock, it needs the `CAP_SYS_TIME`
+    /// capability
+    PermissionDenied,
+    /// The server signals an alarm condition, so its time must not be used to adjust the clock
+    UnsynchronizedServer,
+    /// The clock offset cannot be applied to the system clock
+    InvalidOffset,
+    /// A system call failed
+    IOError(std::io::Error),
+}
+
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+impl Error for ClockAdjustmentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClockAdjustmentError::IOError(io_error) => Some(io_error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+impl Display for ClockAdjustmentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockAdjustmentError::PermissionDenied => write!(
+                f,
+                "Not permitted to adjust the system clock, CAP_SYS_TIME capability is required"
+            ),
+            ClockAdjustmentError::UnsynchronizedServer => {
+                write!(f, "Server is not synchronized, clock is not adjusted")
+            }
+            ClockAdjustmentError::InvalidOffset => {
+                write!(f, "Clock offset cannot be applied to the system clock")
+            }
+            ClockAdjustmentError::IOError(io_error) => {
+                write!(f, "Input/output error: {io_error}")
+            }
+        }
+    }
+}
+
 /// Reresents an error which occured during internal timestamp conversion
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ConversionError {
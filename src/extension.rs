@@ -0,0 +1,384 @@
+use crate::error::ProtocolError;
+use crate::packet::Packet;
+
+const HEADER_LEN: usize = 4;
+/// Minimal length of the last extension field if it is not followed by a MAC, see RFC 7822
+const MIN_LAST_FIELD_LEN: usize = 28;
+/// Maximal length of a MAC trailer (key ID and SHA-1 digest)
+const MAX_MAC_LEN: usize = 24;
+
+/// NTPv4 extension field
+///
+/// Extension fields are placed between the NTP header and the optional message authentication
+/// code, see RFC 7822. A field consists of a 16 bit type, a 16 bit length and the value. The
+/// value is padded with zeros so that the length is a multiple of 4 and the field is at least
+/// 16 bytes long (or 28 bytes if it is the last field of a packet without a MAC).
+///
+/// Extension fields can be added to the requests with [`crate::Config::extension_field`], and the
+/// fields of the server reply are available with
+/// [`crate::SynchronizationResult::extension_fields`].
+///
+/// # Example
+///
+/// ```
+/// use rsntp::ExtensionField;
+///
+/// let field = ExtensionField::new(0x2005, vec![1, 2, 3]);
+///
+/// assert_eq!(field.field_type(), 0x2005);
+/// assert_eq!(field.value(), &[1, 2, 3]);
+/// assert_eq!(field.encoded_len(), 16);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ExtensionField {
+    field_type: u16,
+    value: Vec<u8>,
+}
+
+impl ExtensionField {
+    /// Minimal length of an encoded extension field in bytes
+    pub const MIN_LEN: usize = 16;
+    /// Maximal length of the value in bytes
+    pub const MAX_VALUE_LEN: usize = 0xfffc - HEADER_LEN;
+
+    /// Creates a new extension field
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is longer than [`ExtensionField::MAX_VALUE_LEN`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::ExtensionField;
+    ///
+    /// let field = ExtensionField::new(0x2005, vec![1, 2, 3]);
+    /// ```
+    pub fn new(field_type: u16, value: Vec<u8>) -> ExtensionField {
+        assert!(
+            value.len() <= ExtensionField::MAX_VALUE_LEN,
+            "Extension field value is too long"
+        );
+
+        ExtensionField { field_type, value }
+    }
+
+    /// Returns with the type of the field
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::ExtensionField;
+    ///
+    /// assert_eq!(ExtensionField::new(0x2005, vec![]).field_type(), 0x2005);
+    /// ```
+    pub fn field_type(&self) -> u16 {
+        self.field_type
+    }
+
+    /// Returns with the value of the field
+    ///
+    /// The length of the value is not encoded separately, so for fields received from a server,
+    /// the value includes the padding.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::ExtensionField;
+    ///
+    /// assert_eq!(ExtensionField::new(0x2005, vec![1, 2]).value(), &[1, 2]);
+    /// ```
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns with the length of the encoded field, including the header and the padding
+    ///
+    /// The last field of a packet without a MAC is padded further to 28 bytes, that is not
+    /// included.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::ExtensionField;
+    ///
+    /// assert_eq!(ExtensionField::new(0x2005, vec![0; 14]).encoded_len(), 20);
+    /// ```
+    pub fn encoded_len(&self) -> usize {
+        Self::encoded_len_with_min(self.value.len(), ExtensionField::MIN_LEN)
+    }
+
+    fn encoded_len_with_min(value_len: usize, min_len: usize) -> usize {
+        ((HEADER_LEN + value_len + 3) & !3).max(min_len)
+    }
+}
+
+/// An extension field borrowed from a received packet
+pub(crate) struct RawExtensionField<'a> {
+    /// Offset of the field from the start of the data it has been parsed from
+    #[cfg_attr(not(feature = "nts"), allow(dead_code))]
+    pub offset: usize,
+    pub field_type: u16,
+    /// Value of the field, including the padding
+    pub value: &'a [u8],
+}
+
+impl RawExtensionField<'_> {
+    pub fn to_extension_field(&self) -> ExtensionField {
+        ExtensionField::new(self.field_type, self.value.to_vec())
+    }
+}
+
+/// Appends an extension field with the minimal length to the buffer
+#[cfg_attr(not(feature = "nts"), allow(dead_code))]
+pub(crate) fn encode(buffer: &mut Vec<u8>, field_type: u16, value: &[u8]) {
+    encode_with_min_len(buffer, field_type, value, ExtensionField::MIN_LEN);
+}
+
+fn encode_with_min_len(buffer: &mut Vec<u8>, field_type: u16, value: &[u8], min_len: usize) {
+    let length = ExtensionField::encoded_len_with_min(value.len(), min_len);
+
+    buffer.extend_from_slice(&field_type.to_be_bytes());
+    buffer.extend_from_slice(&(length as u16).to_be_bytes());
+    buffer.extend_from_slice(value);
+    buffer.resize(buffer.len() + length - HEADER_LEN - value.len(), 0);
+}
+
+/// Appends extension fields to a packet
+///
+/// If the fields are at the end of the packet (i.e. no MAC follows), `pad_last` should be set, so
+/// the last field is padded to 28 bytes and it can not be mistaken for a MAC.
+pub(crate) fn encode_fields(buffer: &mut Vec<u8>, fields: &[ExtensionField], pad_last: bool) {
+    for (i, field) in fields.iter().enumerate() {
+        let min_len = if i == fields.len() - 1 && pad_last {
+            MIN_LAST_FIELD_LEN
+        } else {
+            ExtensionField::MIN_LEN
+        };
+
+        encode_with_min_len(buffer, field.field_type, &field.value, min_len);
+    }
+}
+
+fn parse_field(data: &[u8], offset: usize) -> Result<RawExtensionField<'_>, ProtocolError> {
+    let remaining = data.len() - offset;
+
+    if remaining < HEADER_LEN {
+        return Err(ProtocolError::TruncatedExtensionField);
+    }
+
+    let field_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+    let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+
+    if length < HEADER_LEN || !length.is_multiple_of(4) {
+        return Err(ProtocolError::InvalidExtensionFieldLength);
+    }
+
+    if length > remaining {
+        return Err(ProtocolError::TruncatedExtensionField);
+    }
+
+    Ok(RawExtensionField {
+        offset,
+        field_type,
+        value: &data[offset + HEADER_LEN..offset + length],
+    })
+}
+
+/// Parses a sequence of extension fields, e.g. the decrypted content of an NTS authenticator
+#[cfg_attr(not(feature = "nts"), allow(dead_code))]
+pub(crate) fn parse(data: &[u8]) -> Result<Vec<RawExtensionField<'_>>, ProtocolError> {
+    let mut fields = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let field = parse_field(data, offset)?;
+
+        offset += HEADER_LEN + field.value.len();
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+/// Splits a received packet to extension fields and MAC trailer
+///
+/// Offsets of the fields are relative to the start of the packet. Following RFC 7822, anything
+/// which is not longer than the longest possible MAC is considered to be the MAC trailer, it
+/// is empty if the packet has no MAC.
+pub(crate) fn split_packet(
+    packet: &[u8],
+) -> Result<(Vec<RawExtensionField<'_>>, &[u8]), ProtocolError> {
+    if packet.len() < Packet::ENCODED_LEN {
+        return Err(ProtocolError::PacketIsTooShort);
+    }
+
+    let mut fields = Vec::new();
+    let mut offset = Packet::ENCODED_LEN;
+
+    while packet.len() - offset > MAX_MAC_LEN {
+        let field = parse_field(packet, offset)?;
+
+        if field.value.len() + HEADER_LEN < ExtensionField::MIN_LEN {
+            return Err(ProtocolError::InvalidExtensionFieldLength);
+        }
+
+        offset += HEADER_LEN + field.value.len();
+        fields.push(field);
+    }
+
+    Ok((fields, &packet[offset..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackServer;
+    use crate::{Config, SntpClient, SynchronizationError};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn encoding_and_parsing_works() {
+        let mut buffer = Vec::new();
+
+        encode(&mut buffer, 0x0104, &[1; 32]);
+        encode(&mut buffer, 0x0304, &[2; 5]);
+
+        assert_eq!(buffer.len(), 36 + 16);
+        assert_eq!(&buffer[..4], &[0x01, 0x04, 0x00, 0x24]);
+        assert_eq!(&buffer[36..40], &[0x03, 0x04, 0x00, 0x10]);
+
+        let fields = parse(&buffer).unwrap();
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].offset, 0);
+        assert_eq!(fields[0].field_type, 0x0104);
+        assert_eq!(fields[0].value, &[1; 32]);
+        assert_eq!(fields[1].offset, 36);
+        assert_eq!(fields[1].field_type, 0x0304);
+        assert_eq!(fields[1].value, &[2, 2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            parse(&buffer[..40]).err(),
+            Some(ProtocolError::TruncatedExtensionField)
+        );
+        assert_eq!(
+            parse(&[0x01, 0x04, 0x00, 0x05, 0x00]).err(),
+            Some(ProtocolError::InvalidExtensionFieldLength)
+        );
+    }
+
+    #[test]
+    fn last_field_is_padded_if_no_mac_follows() {
+        let fields = [
+            ExtensionField::new(0x2005, vec![1; 8]),
+            ExtensionField::new(0x2006, vec![2; 8]),
+        ];
+        let mut without_mac = Vec::new();
+        let mut with_mac = Vec::new();
+
+        encode_fields(&mut without_mac, &fields, true);
+        encode_fields(&mut with_mac, &fields, false);
+
+        assert_eq!(without_mac.len(), 16 + 28);
+        assert_eq!(with_mac.len(), 16 + 16);
+    }
+
+    #[test]
+    fn splitting_packet_works() {
+        let mut packet = vec![0x24; Packet::ENCODED_LEN];
+        encode_fields(
+            &mut packet,
+            &[
+                ExtensionField::new(0x2005, vec![1; 20]),
+                ExtensionField::new(0x2006, vec![2; 4]),
+            ],
+            true,
+        );
+        let fields_len = packet.len();
+        packet.extend_from_slice(&[3; 20]);
+
+        let (fields, mac) = split_packet(&packet).unwrap();
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].offset, Packet::ENCODED_LEN);
+        assert_eq!(
+            fields[0].to_extension_field(),
+            ExtensionField::new(0x2005, vec![1; 20])
+        );
+        assert_eq!(fields[1].offset, Packet::ENCODED_LEN + 24);
+        assert_eq!(fields[1].value.len(), 24);
+        assert_eq!(mac, &[3; 20]);
+
+        let (fields, mac) = split_packet(&packet[..fields_len]).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert!(mac.is_empty());
+
+        assert_eq!(
+            split_packet(&packet[..40]).err(),
+            Some(ProtocolError::PacketIsTooShort)
+        );
+        assert_eq!(
+            split_packet(&packet[..fields_len - 2]).err(),
+            Some(ProtocolError::TruncatedExtensionField)
+        );
+
+        packet[Packet::ENCODED_LEN + 3] = 12;
+        assert_eq!(
+            split_packet(&packet).err(),
+            Some(ProtocolError::InvalidExtensionFieldLength)
+        );
+    }
+
+    /// Starts a server which echoes the extension fields of the request, followed by `trailer`
+    fn start_echo_server(trailer: Vec<u8>) -> SocketAddr {
+        let server = LoopbackServer::bind();
+        let address = server.local_addr();
+
+        server.serve_forever(move |exchange| {
+            let mut reply = exchange.reply();
+
+            reply.extend_from_slice(&exchange.request[Packet::ENCODED_LEN..]);
+            reply.extend_from_slice(&trailer);
+            exchange.send(&reply);
+        });
+
+        address
+    }
+
+    #[test]
+    fn extension_fields_are_sent_and_received() {
+        let server = start_echo_server(Vec::new());
+        let config = Config::default()
+            .extension_field(ExtensionField::new(0x2005, vec![1; 16]))
+            .extension_field(ExtensionField::new(0x2006, vec![2; 3]));
+        let client = SntpClient::with_config(config);
+
+        let result = client.synchronize(server).unwrap();
+        let fields = result.extension_fields();
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0], ExtensionField::new(0x2005, vec![1; 16]));
+        assert_eq!(fields[1].field_type(), 0x2006);
+        // the last field is padded to 28 bytes
+        assert_eq!(fields[1].value().len(), 24);
+        assert_eq!(&fields[1].value()[..4], &[2, 2, 2, 0]);
+    }
+
+    #[test]
+    fn malformed_extension_field_is_rejected() {
+        let mut malformed = Vec::new();
+        encode(&mut malformed, 0x2005, &[1; 28]);
+        malformed[3] = 0x40;
+
+        let server = start_echo_server(malformed);
+        let client = SntpClient::new();
+
+        assert!(matches!(
+            client.synchronize(server),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::TruncatedExtensionField
+            ))
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::result::SynchronizationResult;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Frequency tolerance of the clocks in s/s (RFC 5905 PHI)
+pub(crate) const FREQUENCY_TOLERANCE: f64 = 15e-6;
+/// Number of stages of the clock filter register (RFC 5905 NSTAGE)
+const STAGES: usize = 8;
+/// Dispersion of empty stages in seconds (RFC 5905 MAXDISP)
+const MAX_DISPERSION: f64 = 16.0;
+
+/// Offset, delay and dispersion of a single exchange with a server
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sample {
+    pub offset: f64,
+    pub delay: f64,
+    pub dispersion: f64,
+    pub time: Instant,
+}
+
+/// Output of the clock filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FilterOutput {
+    /// Index of the selected sample, in the order the samples have been added
+    pub sample: usize,
+    pub dispersion: f64,
+    pub jitter: f64,
+    /// Number of samples the jitter has been calculated from
+    pub samples: usize,
+}
+
+/// Clock filter algorithm, see RFC 5905 section 10
+///
+/// Keeps the last eight samples in a shift register and selects the one with the minimum delay,
+/// as it is likely the most accurate one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ClockFilter {
+    register: VecDeque<(usize, Sample)>,
+    samples_added: usize,
+}
+
+impl ClockFilter {
+    pub fn add(&mut self, sample: Sample) {
+        self.register.push_front((self.samples_added, sample));
+        self.register.truncate(STAGES);
+        self.samples_added += 1;
+    }
+
+    pub fn select(&self, now: Instant) -> Option<FilterOutput> {
+        let mut stages: Vec<(usize, Sample)> = self
+            .register
+            .iter()
+            .map(|(index, sample)| {
+                let age = now.saturating_duration_since(sample.time).as_secs_f64();
+                let dispersion = sample.dispersion + FREQUENCY_TOLERANCE * age;
+
+                (
+                    *index,
+                    Sample {
+                        dispersion: dispersion.min(MAX_DISPERSION),
+                        ..*sample
+                    },
+                )
+            })
+            .collect();
+
+        stages.sort_by(|(_, a), (_, b)| a.delay.total_cmp(&b.delay));
+
+        let (selected, first) = *stages.first()?;
+
+        let dispersion = (0..STAGES)
+            .map(|i| {
+                let stage_dispersion = stages
+                    .get(i)
+                    .map_or(MAX_DISPERSION, |(_, sample)| sample.dispersion);
+
+                stage_dispersion / 2f64.powi(i as i32 + 1)
+            })
+            .sum();
+
+        let jitter = if stages.len() > 1 {
+            let sum_of_squares: f64 = stages[1..]
+                .iter()
+                .map(|(_, sample)| (sample.offset - first.offset).powi(2))
+                .sum();
+
+            (sum_of_squares / (stages.len() - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Some(FilterOutput {
+            sample: selected,
+            dispersion,
+            jitter,
+            samples: stages.len(),
+        })
+    }
+}
+
+/// Selects the best one of the results of a burst with the clock filter algorithm
+///
+/// Dispersion and jitter of the selected result are set to the output of the filter.
+pub(crate) fn select_result(results: Vec<SynchronizationResult>) -> Option<SynchronizationResult> {
+    let mut filter = ClockFilter::default();
+
+    for result in &results {
+        filter.add(result.sample());
+    }
+
+    let output = filter.select(Instant::now())?;
+    let mut result = results.into_iter().nth(output.sample)?;
+
+    result.set_filter_output(output.dispersion, output.jitter, output.samples);
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn sample(offset: f64, delay: f64, time: Instant) -> Sample {
+        Sample {
+            offset,
+            delay,
+            dispersion: 0.001,
+            time,
+        }
+    }
+
+    #[test]
+    fn empty_filter_selects_nothing() {
+        assert_eq!(ClockFilter::default().select(Instant::now()), None);
+    }
+
+    #[test]
+    fn minimum_delay_sample_is_selected() {
+        let now = Instant::now();
+        let mut filter = ClockFilter::default();
+
+        filter.add(sample(0.5, 0.3, now));
+        filter.add(sample(0.1, 0.1, now));
+        filter.add(sample(0.2, 0.2, now));
+
+        let output = filter.select(now).unwrap();
+
+        assert_eq!(output.sample, 1);
+        // (0.1^2 + 0.4^2) / 2
+        assert!((output.jitter - 0.085f64.sqrt()).abs() < 1e-9);
+        // 0.001 * (1/2 + 1/4 + 1/8) + 16 * (1/16 + 1/32 + ... + 1/256)
+        assert!((output.dispersion - (0.000875 + 1.9375)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn only_last_eight_samples_are_kept() {
+        let now = Instant::now();
+        let mut filter = ClockFilter::default();
+
+        filter.add(sample(0.0, 0.001, now));
+
+        for i in 1..=STAGES {
+            filter.add(sample(0.0, 0.1 * i as f64, now));
+        }
+
+        let output = filter.select(now).unwrap();
+
+        assert_eq!(output.sample, 1);
+        assert_eq!(output.jitter, 0.0);
+        assert!((output.dispersion - 0.001 * (1.0 - 1.0 / 256.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn dispersion_grows_with_age() {
+        let now = Instant::now();
+        let mut filter = ClockFilter::default();
+
+        filter.add(sample(0.0, 0.1, now));
+
+        let fresh = filter.select(now).unwrap();
+        let aged = filter.select(now + Duration::from_secs(1000)).unwrap();
+
+        assert!(
+            (aged.dispersion - fresh.dispersion - 0.5 * FREQUENCY_TOLERANCE * 1000.0).abs() < 1e-9
+        );
+    }
+}
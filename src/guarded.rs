@@ -0,0 +1,501 @@
+use crate::error::{KissCode, ProtocolError, SuppressionReason, SynchronizationError};
+use crate::result::SynchronizationResult;
+use crate::to_server_addrs::{ResolvedServer, ToServerAddrs};
+use crate::{Config, SntpClient};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use crate::AsyncSntpClient;
+
+/// Minimum poll interval (RFC 5905 MINPOLL, 2^4 seconds)
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1 << 4);
+/// Maximum poll interval (RFC 5905 MAXPOLL, 2^17 seconds)
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1 << 17);
+
+#[derive(Clone, Debug)]
+struct ServerState {
+    last_request: Instant,
+    poll_interval: Duration,
+    rate_exceeded: bool,
+    access_denied: bool,
+}
+
+#[derive(Debug)]
+struct ServerStates {
+    min_poll_interval: Duration,
+    states: Mutex<HashMap<SocketAddr, ServerState>>,
+}
+
+impl ServerStates {
+    fn new(min_poll_interval: Duration) -> ServerStates {
+        ServerStates {
+            min_poll_interval,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks if a request can be sent to the server and records it if it can
+    fn begin_request(&self, server: SocketAddr, now: Instant) -> Result<(), SuppressionReason> {
+        let mut states = self.states.lock().unwrap();
+
+        if let Some(state) = states.get_mut(&server) {
+            if state.access_denied {
+                return Err(SuppressionReason::AccessDenied);
+            }
+
+            let next_request = state.last_request + state.poll_interval;
+
+            if now < next_request {
+                let remaining = next_request - now;
+
+                return Err(if state.rate_exceeded {
+                    SuppressionReason::RateExceeded(remaining)
+                } else {
+                    SuppressionReason::PollIntervalNotElapsed(remaining)
+                });
+            }
+
+            state.last_request = now;
+        } else {
+            states.insert(
+                server,
+                ServerState {
+                    last_request: now,
+                    poll_interval: self.min_poll_interval,
+                    rate_exceeded: false,
+                    access_denied: false,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Updates server state based on the outcome of a request
+    fn end_request(
+        &self,
+        server: SocketAddr,
+        result: &Result<SynchronizationResult, SynchronizationError>,
+    ) {
+        let mut states = self.states.lock().unwrap();
+
+        if let Some(state) = states.get_mut(&server) {
+            match result {
+                Err(SynchronizationError::ProtocolError(ProtocolError::KissODeath(
+                    KissCode::AccessDenied,
+                ))) => {
+                    state.access_denied = true;
+                }
+                Err(SynchronizationError::ProtocolError(ProtocolError::KissODeath(
+                    KissCode::RateExceeded,
+                ))) => {
+                    state.poll_interval = (state.poll_interval * 2).min(MAX_POLL_INTERVAL);
+                    state.rate_exceeded = true;
+                }
+                Ok(_) => {
+                    state.poll_interval = self.min_poll_interval;
+                    state.rate_exceeded = false;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn reset(&self) {
+        self.states.lock().unwrap().clear();
+    }
+}
+
+fn resolve_first<A: ToServerAddrs>(
+    server_address: &A,
+    default_port: u16,
+) -> Result<SocketAddr, SynchronizationError> {
+    std::net::ToSocketAddrs::to_socket_addrs(&server_address.to_server_addrs(default_port))?
+        .next()
+        .ok_or_else(|| {
+            SynchronizationError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Server address could not be resolved",
+            ))
+        })
+}
+
+/// Blocking client which respects Kiss-o'-Death replies and poll intervals
+///
+/// It wraps an [`SntpClient`] and remembers the state of each server it communicates with,
+/// to avoid getting rate limited or banned by public servers:
+/// * It does not send a request to a server more often than the minimum poll interval, which
+///   is 16 seconds by default (RFC 5905 MINPOLL).
+/// * If a server replies with a `RATE` Kiss-o'-Death, the poll interval of that server is
+///   doubled (up to about 36 hours, RFC 5905 MAXPOLL). It is reset after a successful
+///   synchronization.
+/// * If a server replies with a `DENY` or `RSTR` Kiss-o'-Death, no further requests are sent
+///   to that server.
+///
+/// Instead of sending a request which would violate the rules above,
+/// [`SynchronizationError::Suppressed`] is returned.
+///
+/// Servers are identified by their resolved address. If the supplied server address resolves to
+/// multiple addresses, only the first one is used.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::GuardedSntpClient;
+///
+/// let client = GuardedSntpClient::new();
+/// let result = client.synchronize("pool.ntp.org");
+/// ```
+#[derive(Debug)]
+pub struct GuardedSntpClient {
+    client: SntpClient,
+    states: ServerStates,
+}
+
+impl GuardedSntpClient {
+    /// Creates a new instance with default configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::GuardedSntpClient;
+    ///
+    /// let client = GuardedSntpClient::new();
+    /// ```
+    pub fn new() -> GuardedSntpClient {
+        GuardedSntpClient::with_config(Config::default())
+    }
+
+    /// Creates a new instance with the specified configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, GuardedSntpClient};
+    ///
+    /// let client = GuardedSntpClient::with_config(Config::default());
+    /// ```
+    pub fn with_config(config: Config) -> GuardedSntpClient {
+        GuardedSntpClient {
+            client: SntpClient::with_config(config),
+            states: ServerStates::new(MIN_POLL_INTERVAL),
+        }
+    }
+
+    /// Sets the minimum poll interval
+    ///
+    /// Requests are not sent to the same server more often than this interval. Default is
+    /// 16 seconds. Setting it to a lower value is not recommended with public servers. It also
+    /// resets the state of all servers.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::GuardedSntpClient;
+    /// use std::time::Duration;
+    ///
+    /// let client = GuardedSntpClient::new().min_poll_interval(Duration::from_secs(64));
+    /// ```
+    pub fn min_poll_interval(self, interval: Duration) -> GuardedSntpClient {
+        GuardedSntpClient {
+            states: ServerStates::new(interval),
+            ..self
+        }
+    }
+
+    /// Synchronize with the server
+    ///
+    /// Works like [`SntpClient::synchronize`], but returns with
+    /// [`SynchronizationError::Suppressed`] without sending a request if the server should not
+    /// be queried at the moment.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{GuardedSntpClient, SynchronizationError};
+    ///
+    /// let client = GuardedSntpClient::new();
+    ///
+    /// match client.synchronize("pool.ntp.org") {
+    ///     Ok(result) => println!("Clock offset: {}", result.clock_offset().as_secs_f64()),
+    ///     Err(SynchronizationError::Suppressed(reason)) => println!("Not now: {}", reason),
+    ///     Err(err) => println!("Error: {}", err),
+    /// }
+    /// ```
+    pub fn synchronize<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let server = resolve_first(&server_address, self.client.config.server_port())?;
+
+        self.states.begin_request(server, Instant::now())?;
+        let result = self
+            .client
+            .synchronize(ResolvedServer::new(server, server_address.server_name()));
+        self.states.end_request(server, &result);
+
+        result
+    }
+
+    /// Forgets the state of all servers
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::GuardedSntpClient;
+    ///
+    /// let client = GuardedSntpClient::new();
+    /// client.reset();
+    /// ```
+    pub fn reset(&self) {
+        self.states.reset();
+    }
+}
+
+impl Default for GuardedSntpClient {
+    fn default() -> Self {
+        GuardedSntpClient::new()
+    }
+}
+
+/// Asynchronous client which respects Kiss-o'-Death replies and poll intervals
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// This is the asynchronous counterpart of [`GuardedSntpClient`], see its documentation for
+/// details.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{AsyncGuardedSntpClient, SynchronizationResult, SynchronizationError};
+///
+/// async fn synchronize(
+///   client: &AsyncGuardedSntpClient,
+/// ) -> Result<SynchronizationResult, SynchronizationError> {
+///   client.synchronize("pool.ntp.org").await
+/// }
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncGuardedSntpClient {
+    client: AsyncSntpClient,
+    states: ServerStates,
+}
+
+#[cfg(feature = "async")]
+impl AsyncGuardedSntpClient {
+    /// Creates a new instance with default configuration
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncGuardedSntpClient;
+    ///
+    /// let client = AsyncGuardedSntpClient::new();
+    /// ```
+    pub fn new() -> AsyncGuardedSntpClient {
+        AsyncGuardedSntpClient::with_config(Config::default())
+    }
+
+    /// Creates a new instance with the specified configuration
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncGuardedSntpClient, Config};
+    ///
+    /// let client = AsyncGuardedSntpClient::with_config(Config::default());
+    /// ```
+    pub fn with_config(config: Config) -> AsyncGuardedSntpClient {
+        AsyncGuardedSntpClient {
+            client: AsyncSntpClient::with_config(config),
+            states: ServerStates::new(MIN_POLL_INTERVAL),
+        }
+    }
+
+    /// Sets the minimum poll interval
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Requests are not sent to the same server more often than this interval. Default is
+    /// 16 seconds. Setting it to a lower value is not recommended with public servers. It also
+    /// resets the state of all servers.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncGuardedSntpClient;
+    /// use std::time::Duration;
+    ///
+    /// let client = AsyncGuardedSntpClient::new().min_poll_interval(Duration::from_secs(64));
+    /// ```
+    pub fn min_poll_interval(self, interval: Duration) -> AsyncGuardedSntpClient {
+        AsyncGuardedSntpClient {
+            states: ServerStates::new(interval),
+            ..self
+        }
+    }
+
+    /// Synchronize with the server
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Works like [`AsyncSntpClient::synchronize`], but returns with
+    /// [`SynchronizationError::Suppressed`] without sending a request if the server should not
+    /// be queried at the moment.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncGuardedSntpClient, SynchronizationResult, SynchronizationError};
+    ///
+    /// async fn synchronize(
+    ///   client: &AsyncGuardedSntpClient,
+    /// ) -> Result<SynchronizationResult, SynchronizationError> {
+    ///   client.synchronize("pool.ntp.org").await
+    /// }
+    /// ```
+    pub async fn synchronize<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let server = tokio::net::lookup_host(
+            server_address.to_server_addrs(self.client.config.server_port()),
+        )
+        .await?
+        .next()
+        .ok_or_else(|| {
+            SynchronizationError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Server address could not be resolved",
+            ))
+        })?;
+
+        self.states.begin_request(server, Instant::now())?;
+        let result = self
+            .client
+            .synchronize(ResolvedServer::new(server, server_address.server_name()))
+            .await;
+        self.states.end_request(server, &result);
+
+        result
+    }
+
+    /// Forgets the state of all servers
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncGuardedSntpClient;
+    ///
+    /// let client = AsyncGuardedSntpClient::new();
+    /// client.reset();
+    /// ```
+    pub fn reset(&self) {
+        self.states.reset();
+    }
+}
+
+#[cfg(feature = "async")]
+impl Default for AsyncGuardedSntpClient {
+    fn default() -> Self {
+        AsyncGuardedSntpClient::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kiss_o_death(kiss_code: KissCode) -> Result<SynchronizationResult, SynchronizationError> {
+        Err(SynchronizationError::ProtocolError(
+            ProtocolError::KissODeath(kiss_code),
+        ))
+    }
+
+    fn timeout() -> Result<SynchronizationResult, SynchronizationError> {
+        Err(SynchronizationError::IOError(
+            std::io::ErrorKind::TimedOut.into(),
+        ))
+    }
+
+    #[test]
+    fn min_poll_interval_is_enforced() {
+        let states = ServerStates::new(Duration::from_secs(16));
+        let server = "127.0.0.1:123".parse().unwrap();
+        let other_server = "127.0.0.2:123".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(states.begin_request(server, now).is_ok());
+        states.end_request(server, &timeout());
+
+        assert_eq!(
+            states.begin_request(server, now + Duration::from_secs(10)),
+            Err(SuppressionReason::PollIntervalNotElapsed(
+                Duration::from_secs(6)
+            ))
+        );
+        assert!(states.begin_request(other_server, now).is_ok());
+        assert!(states
+            .begin_request(server, now + Duration::from_secs(16))
+            .is_ok());
+    }
+
+    #[test]
+    fn poll_interval_is_increased_on_rate_exceeded() {
+        let states = ServerStates::new(Duration::from_secs(16));
+        let server = "127.0.0.1:123".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(states.begin_request(server, now).is_ok());
+        states.end_request(server, &kiss_o_death(KissCode::RateExceeded));
+
+        assert_eq!(
+            states.begin_request(server, now + Duration::from_secs(16)),
+            Err(SuppressionReason::RateExceeded(Duration::from_secs(16)))
+        );
+
+        let now = now + Duration::from_secs(32);
+        assert!(states.begin_request(server, now).is_ok());
+        states.end_request(server, &kiss_o_death(KissCode::RateExceeded));
+
+        assert_eq!(
+            states.begin_request(server, now + Duration::from_secs(32)),
+            Err(SuppressionReason::RateExceeded(Duration::from_secs(32)))
+        );
+        assert!(states
+            .begin_request(server, now + Duration::from_secs(64))
+            .is_ok());
+    }
+
+    #[test]
+    fn denied_server_is_not_queried_again() {
+        let states = ServerStates::new(Duration::from_secs(16));
+        let server = "127.0.0.1:123".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(states.begin_request(server, now).is_ok());
+        states.end_request(server, &kiss_o_death(KissCode::AccessDenied));
+
+        assert_eq!(
+            states.begin_request(server, now + Duration::from_secs(3600)),
+            Err(SuppressionReason::AccessDenied)
+        );
+
+        states.reset();
+
+        assert!(states
+            .begin_request(server, now + Duration::from_secs(3600))
+            .is_ok());
+    }
+}
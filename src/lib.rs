@@ -18,7 +18,7 @@ Add this to your `Cargo.toml`:
 
 ```toml
 [dependencies]
-rsntp = "4.1.1"
+rsntp = "5.0.0"
 ```
 
 Obtain the current local time with the blocking API:
@@ -111,16 +111,83 @@ the amount of dependencies significantly.
 
 ```toml
 [dependencies]
-rsntp = { version = "4.1.1", default-features = false, features = ["chrono"]  }
+rsntp = { version = "5.0.0", default-features = false, features = ["chrono"]  }
 ```
 
+## Network Time Security
+
+`rsntp` can authenticate the server with Network Time Security (NTS, RFC 8915). It is an
+optional feature, as it adds TLS and cryptography dependencies:
+
+```toml
+[dependencies]
+rsntp = { version = "5.0.0", features = ["nts"] }
+```
+
+NTS is enabled with `Config::nts()`. The client then performs the NTS key establishment with
+the supplied server and only accepts authenticated replies; `SynchronizationResult::is_authenticated()`
+can be used to check it.
+
+Servers configured with shared keys can be authenticated with symmetric key message
+authentication codes (RFC 5905, RFC 8573) using the optional `mac` feature, see
+`Config::mac_authentication()`.
+
+## Adjusting the system clock
+
+On Linux, the system clock can be stepped or slewed with the result of a synchronization using
+the optional `clock-adjust` feature:
+
+```toml
+[dependencies]
+rsntp = { version = "5.0.0", features = ["clock-adjust"] }
+```
+
+See `ClockAdjuster` for details. Adjusting the clock requires the `CAP_SYS_TIME` capability, but
+it also has a dry-run mode which does not change the clock.
+
+## Kernel timestamps
+
+On Linux, the receive and send time of the packets can be taken by the kernel instead of the
+client with the optional `kernel-timestamps` feature, which excludes the scheduling latency from
+the measurement:
+
+```toml
+[dependencies]
+rsntp = { version = "5.0.0", features = ["kernel-timestamps"] }
+```
+
+See `TimestampingTransport` for details, including hardware timestamps.
+
+## Command-line tool
+
+A simple command-line tool, similar to `sntp` or `ntpdate -q`, is available with the `cli` feature:
+
+```sh
+cargo install rsntp --features cli
+rsntp --json pool.ntp.org time.google.com
+```
+
+Run `rsntp --help` for the supported options and exit codes.
+
+## Testing
+
+The optional `testing` feature provides a scriptable, in-process mock NTP server and a virtual
+clock, so code using the clients can be tested without network access:
+
+```toml
+[dev-dependencies]
+rsntp = { version = "5.0.0", features = ["testing"] }
+```
+
+See the `testing` module for details.
+
 ## System clock assumptions
 
-`rsntp` assumes that system clock is monotonic and stable. This is especially important
-with the `SynchronizationResult::datetime()` method, as `SynchronizationResult` stores just
-an offset to the system clock. If the system clock is changed between synchronization
-and the call to this method, then offset will not be valid anymore and some undefined result
-might be returned.
+`rsntp` assumes that system clock is stable during synchronization. The date and time returned
+by the `SynchronizationResult::datetime()` method is anchored to the monotonic clock of the host
+at the time of synchronization, so changing the system clock after synchronization does not
+affect it. However, the clock offset returned by `SynchronizationResult::clock_offset()` is
+relative to the system clock, so it will not be valid anymore if the system clock is changed.
 
 ## IPv6 support
 
@@ -143,22 +210,93 @@ let unix_timestamp_utc = result.datetime().unix_timestamp();
 "##
 )]
 
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+mod adjust;
+mod broadcast;
+mod clock;
+mod clock_source;
 mod core_logic;
+mod discipline;
 mod error;
-mod packet;
+mod extension;
+mod filter;
+mod guarded;
+#[cfg(feature = "async")]
+mod join;
+#[cfg(test)]
+mod loopback;
+#[cfg(feature = "mac")]
+mod mac;
+#[cfg(feature = "nts")]
+mod nts;
+pub mod packet;
+mod random;
 mod result;
+mod retry;
+mod selection;
+mod server;
+mod session;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(all(feature = "kernel-timestamps", target_os = "linux"))]
+mod timestamping;
 mod to_server_addrs;
+mod transport;
 
-pub use error::{ConversionError, KissCode, ProtocolError, SynchronizationError};
-pub use packet::{LeapIndicator, ReferenceIdentifier};
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+pub use adjust::{AdjustmentMethod, ClockAdjuster, ClockAdjustment};
+#[cfg(feature = "async")]
+pub use broadcast::AsyncBroadcastListener;
+pub use broadcast::BroadcastListener;
+#[cfg(feature = "async")]
+pub use clock::AsyncSntpClock;
+pub use clock::{ClockConfig, SntpClock};
+pub use clock_source::{ClockSource, ManualClock, MonotonicClock, SystemClock};
+pub use discipline::{ClockCorrection, ClockDiscipline, DisciplineAction};
+#[cfg(all(feature = "clock-adjust", target_os = "linux"))]
+pub use error::ClockAdjustmentError;
+#[cfg(feature = "mac")]
+pub use error::KeyError;
+pub use error::{
+    ConversionError, KissCode, ProtocolError, ReferenceIdentifierError, SuppressionReason,
+    SynchronizationError,
+};
+pub use extension::ExtensionField;
+#[cfg(feature = "async")]
+pub use guarded::AsyncGuardedSntpClient;
+pub use guarded::GuardedSntpClient;
+#[cfg(feature = "mac")]
+pub use mac::{KeyStore, MacAlgorithm};
+#[cfg(feature = "nts")]
+pub use nts::NtsConfig;
+pub use packet::{LeapIndicator, NtpVersion, ReferenceIdentifier};
 pub use result::{SntpDateTime, SntpDuration, SynchronizationResult};
+pub use retry::RetryPolicy;
+pub use selection::{MultiSynchronizationResult, ServerDiagnostics, ServerStatus};
+#[cfg(feature = "async")]
+pub use server::AsyncSntpServer;
+pub use server::{ServerConfig, SntpServer, SystemTimeSource, TimeSource};
+#[cfg(feature = "async")]
+pub use session::AsyncSntpSession;
+pub use session::SntpSession;
+#[cfg(all(feature = "kernel-timestamps", feature = "async", target_os = "linux"))]
+pub use timestamping::AsyncTimestampingSocket;
+#[cfg(all(feature = "kernel-timestamps", target_os = "linux"))]
+pub use timestamping::{TimestampingSocket, TimestampingTransport};
 pub use to_server_addrs::ToServerAddrs;
+#[cfg(feature = "async")]
+pub use transport::{AsyncTransport, AsyncTransportSocket, AsyncUdpTransportSocket};
+pub use transport::{ReceivedPacket, Transport, TransportSocket, UdpTransport, UdpTransportSocket};
 
+use clock_source::{Anchor, SharedClockSource};
 use core_logic::{Reply, Request};
 use packet::Packet;
+use std::collections::HashMap;
 use std::default::Default;
+use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use to_server_addrs::ResolvedServer;
 
 #[cfg(feature = "async")]
 use tokio::time::timeout;
@@ -180,11 +318,22 @@ const SNTP_PORT: u16 = 123;
 /// let config = Config::default().bind_address("192.168.0.1:0".parse().unwrap()).timeout(Duration::from_secs(10));
 /// let client = SntpClient::with_config(config);
 /// ```
-#[derive(Clone, Debug, Hash)]
+#[derive(Clone, Debug)]
 pub struct Config {
     bind_address: SocketAddr,
     timeout: Duration,
     connect_ip: bool,
+    retry_policy: RetryPolicy,
+    #[cfg(feature = "nts")]
+    nts: Option<NtsConfig>,
+    #[cfg(feature = "mac")]
+    mac: Option<(KeyStore, u32)>,
+    extension_fields: Vec<ExtensionField>,
+    random_transmit_timestamp: bool,
+    version: u8,
+    burst_interval: Duration,
+    multicast_hops: u32,
+    clock_source: SharedClockSource,
 }
 
 impl Config {
@@ -233,6 +382,9 @@ impl Config {
     /// Connect the socket to the address and allow only incomming messages from the address.
     /// default is true
     ///
+    /// It does not apply to [`SntpSession`] and `AsyncSntpSession`, as their socket is shared
+    /// by all servers.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -245,6 +397,428 @@ impl Config {
     pub fn connect_ip(self, connect_ip: bool) -> Self {
         Config { connect_ip, ..self }
     }
+
+    /// Sets the retry policy
+    ///
+    /// Sets how failed synchronization attempts are retried. By default, synchronization is not
+    /// retried. See [`RetryPolicy`] for details.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, RetryPolicy, SntpClient};
+    ///
+    /// let config = Config::default().retry_policy(RetryPolicy::default().attempts(3));
+    /// let client = SntpClient::with_config(config);
+    /// ```
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> Config {
+        Config {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Enables Network Time Security
+    ///
+    /// Only available when the `nts` feature is enabled.
+    ///
+    /// With NTS enabled, the server address passed to `synchronize` is the address of the NTS key
+    /// establishment server, and all replies are authenticated; unauthenticated replies are
+    /// rejected. Keys and cookies obtained from the key establishment server are kept by the
+    /// client, so subsequent synchronizations with the same server do not need a new key
+    /// establishment. See [`NtsConfig`] for details.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, NtsConfig, SntpClient};
+    ///
+    /// let config = Config::default().nts(NtsConfig::default());
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("time.cloudflare.com").unwrap();
+    ///
+    /// assert!(result.is_authenticated());
+    /// ```
+    #[cfg(feature = "nts")]
+    pub fn nts(self, nts: NtsConfig) -> Config {
+        Config {
+            nts: Some(nts),
+            ..self
+        }
+    }
+
+    /// Enables symmetric key authentication
+    ///
+    /// Only available when the `mac` feature is enabled.
+    ///
+    /// Requests are signed with the key with `key_id`, and replies are rejected unless they are
+    /// signed with the same key (see [`ProtocolError::MissingMac`] and
+    /// [`ProtocolError::InvalidMac`]). If Network Time Security is also enabled, then NTS is used.
+    ///
+    /// Returns [`KeyError::UnknownKey`] if the key store does not contain the key with `key_id`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, KeyStore, MacAlgorithm, SntpClient};
+    ///
+    /// let key_store = KeyStore::default()
+    ///     .key(1, MacAlgorithm::AesCmac, b"0123456789abcdef".to_vec())
+    ///     .unwrap();
+    /// let config = Config::default().mac_authentication(key_store, 1).unwrap();
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("ntp.example.com").unwrap();
+    ///
+    /// assert!(result.is_authenticated());
+    /// ```
+    #[cfg(feature = "mac")]
+    pub fn mac_authentication(self, key_store: KeyStore, key_id: u32) -> Result<Config, KeyError> {
+        if !key_store.contains(key_id) {
+            return Err(KeyError::UnknownKey(key_id));
+        }
+
+        Ok(Config {
+            mac: Some((key_store, key_id)),
+            ..self
+        })
+    }
+
+    /// Adds an extension field to the requests
+    ///
+    /// Extension fields are sent in the order they have been added. Extension fields of the
+    /// server reply are available with [`SynchronizationResult::extension_fields`]. They are
+    /// only supported by NTP version 4, so they are not sent if an earlier version is selected
+    /// with [`Config::version`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, ExtensionField, SntpClient};
+    ///
+    /// let config = Config::default().extension_field(ExtensionField::new(0x2005, vec![0; 12]));
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// for field in result.extension_fields() {
+    ///     println!("Extension field of type {:#06x}", field.field_type());
+    /// }
+    /// ```
+    pub fn extension_field(mut self, field: ExtensionField) -> Config {
+        self.extension_fields.push(field);
+        self
+    }
+
+    /// Sends a random transmit timestamp
+    ///
+    /// By default, the transmit timestamp of the request contains the local time, which reveals
+    /// the state of the local clock to the server and to anybody observing the network traffic.
+    /// If this option is enabled, a random 64-bit nonce is sent instead, as recommended by
+    /// RFC 9109. The real send time is only kept locally and it is used to calculate the clock
+    /// offset, while the nonce is used to match the reply with the request.
+    ///
+    /// Default is false.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, SntpClient};
+    ///
+    /// let config = Config::default().random_transmit_timestamp(true);
+    /// let client = SntpClient::with_config(config);
+    /// ```
+    pub fn random_transmit_timestamp(self, random_transmit_timestamp: bool) -> Config {
+        Config {
+            random_transmit_timestamp,
+            ..self
+        }
+    }
+
+    /// Sets the NTP version of the requests
+    ///
+    /// Versions 1 to 4 are supported, default is 4. Servers usually reply with the version of
+    /// the request, the version of the reply is available with
+    /// [`SynchronizationResult::version`]. Network Time Security always uses version 4.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, NtpVersion, SntpClient};
+    ///
+    /// let config = Config::default().version(NtpVersion::V3);
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("legacy.example.com").unwrap();
+    ///
+    /// assert_eq!(result.version(), 3);
+    /// ```
+    pub fn version(self, version: NtpVersion) -> Config {
+        Config {
+            version: version.to_u8(),
+            ..self
+        }
+    }
+
+    /// Sets the interval between the requests of a burst
+    ///
+    /// It is used by `synchronize_with_samples` (e.g. [`SntpClient::synchronize_with_samples`])
+    /// to wait between the exchanges with the server. Default is 2 seconds, as recommended by
+    /// RFC 5905 for the initial burst. Servers might rate limit clients which send requests more
+    /// frequently.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, SntpClient};
+    /// use std::time::Duration;
+    ///
+    /// let config = Config::default().burst_interval(Duration::from_millis(500));
+    /// let client = SntpClient::with_config(config);
+    /// ```
+    pub fn burst_interval(self, burst_interval: Duration) -> Config {
+        Config {
+            burst_interval,
+            ..self
+        }
+    }
+
+    /// Sets the time-to-live (IPv4) or hop limit (IPv6) of manycast requests
+    ///
+    /// It is used by `discover` (e.g. [`SntpClient::discover`]) and limits how many routers the
+    /// request can pass on its way to the servers. Default is 127, the default of the reference
+    /// implementation. It has to be more than 1 to reach servers outside of the local network,
+    /// e.g. with the site-local ff05::101 group.
+    ///
+    /// The hop limit of IPv6 requests is only set if the `ipv6-multicast-hops` feature is
+    /// enabled, otherwise the default of the system is used, which is usually 1.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, SntpClient};
+    ///
+    /// let config = Config::default().multicast_hops(8);
+    /// let client = SntpClient::with_config(config);
+    /// ```
+    pub fn multicast_hops(self, multicast_hops: u32) -> Config {
+        Config {
+            multicast_hops,
+            ..self
+        }
+    }
+
+    /// Sets the clock source of the local time
+    ///
+    /// The transmit and receive timestamps of the exchanges are read from the clock source, unless
+    /// the transport provides the receive timestamp, and the date and time of the results is
+    /// calculated with it. Default is [`SystemClock`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, ManualClock, SntpClient};
+    /// use std::time::SystemTime;
+    ///
+    /// let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    /// let config = Config::default().clock_source(clock.clone());
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// // the local clock shows the Unix epoch, so the offset is the Unix time of the server
+    /// println!("Unix time: {}", result.clock_offset().as_secs_f64());
+    /// ```
+    pub fn clock_source<C: ClockSource + 'static>(self, clock_source: C) -> Config {
+        Config {
+            clock_source: SharedClockSource::new(clock_source),
+            ..self
+        }
+    }
+
+    /// Returns with the current time of the clock source
+    fn now(&self) -> SystemTime {
+        self.clock_source.now()
+    }
+
+    /// Reads the clock source together with the monotonic clock, when a reply is received
+    fn anchor(&self) -> Anchor {
+        self.clock_source.anchor()
+    }
+
+    /// Returns with the default port of the servers, the NTS key establishment port if NTS is used
+    fn server_port(&self) -> u16 {
+        #[cfg(feature = "nts")]
+        if self.nts.is_some() {
+            return nts::NTS_KE_PORT;
+        }
+
+        SNTP_PORT
+    }
+
+    fn new_request(&self) -> Request {
+        let request = if self.random_transmit_timestamp {
+            Request::new_with_random_transmit_timestamp(self.now())
+        } else {
+            Request::new_with_transmit_time(self.now())
+        };
+
+        #[cfg(feature = "nts")]
+        if self.nts.is_some() {
+            return request;
+        }
+
+        request.with_version(self.version)
+    }
+
+    /// Encodes the request with the extension fields, followed by a MAC if it is enabled
+    fn encode_request(&self, request: &Request) -> Vec<u8> {
+        let mut bytes = request.as_bytes().to_vec();
+        let extension_fields: &[ExtensionField] = if request.version() >= 4 {
+            &self.extension_fields
+        } else {
+            &[]
+        };
+
+        #[cfg(feature = "mac")]
+        if let Some((key_store, key_id)) = &self.mac {
+            extension::encode_fields(&mut bytes, extension_fields, false);
+
+            return key_store
+                .sign(*key_id, &bytes)
+                .expect("Key is checked when authentication is configured");
+        }
+
+        extension::encode_fields(&mut bytes, extension_fields, true);
+        bytes
+    }
+
+    #[cfg(feature = "nts")]
+    fn encode_nts_request(
+        &self,
+        request: &Request,
+        session: &mut nts::NtsSession,
+    ) -> nts::NtsRequest {
+        let mut bytes = request.as_bytes().to_vec();
+
+        extension::encode_fields(&mut bytes, &self.extension_fields, false);
+        session.request(bytes)
+    }
+
+    /// Authenticates the reply if it is enabled, returns true if the reply has been authenticated
+    #[cfg_attr(not(feature = "mac"), allow(unused_variables))]
+    fn authenticate_reply(&self, reply: &[u8]) -> Result<bool, ProtocolError> {
+        #[cfg(feature = "mac")]
+        if let Some((key_store, key_id)) = &self.mac {
+            key_store.verify(*key_id, reply)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn process_reply(
+        &self,
+        request: Request,
+        reply: &[u8],
+        server_address: SocketAddr,
+        reply_time: std::time::SystemTime,
+        anchor: Anchor,
+        authenticated: bool,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let packet = Packet::decode(reply, server_address.ip())?;
+
+        // extension fields are only supported by NTPv4, earlier versions may only have a MAC
+        let extension_fields = if packet.version >= 4 {
+            let (extension_fields, _) = extension::split_packet(reply)?;
+
+            extension_fields
+                .iter()
+                .map(|field| field.to_extension_field())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        self.process_reply_with_fields(
+            request,
+            packet,
+            reply_time,
+            anchor,
+            authenticated,
+            extension_fields,
+        )
+    }
+
+    /// Processes an NTS protected reply, only the authenticated extension fields are exposed
+    #[cfg(feature = "nts")]
+    fn process_nts_reply(
+        &self,
+        request: Request,
+        reply: &[u8],
+        server_address: SocketAddr,
+        reply_time: std::time::SystemTime,
+        anchor: Anchor,
+        extension_fields: Vec<ExtensionField>,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let packet = Packet::decode(reply, server_address.ip())?;
+
+        self.process_reply_with_fields(request, packet, reply_time, anchor, true, extension_fields)
+    }
+
+    fn process_reply_with_fields(
+        &self,
+        request: Request,
+        packet: Packet,
+        reply_time: std::time::SystemTime,
+        anchor: Anchor,
+        authenticated: bool,
+        extension_fields: Vec<ExtensionField>,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let reply = Reply::new_with_reply_time(request, packet, reply_time);
+
+        let mut result = reply.process()?;
+        result.set_clock_source(self.clock_source.clone(), anchor);
+        result.set_authenticated(authenticated);
+        result.set_extension_fields(extension_fields);
+        Ok(result)
+    }
+
+    /// Processes one of the replies to a manycast request
+    fn process_manycast_reply(
+        &self,
+        request: &Request,
+        reply: &[u8],
+        server_address: SocketAddr,
+        reply_time: std::time::SystemTime,
+        anchor: Anchor,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let authenticated = self.authenticate_reply(reply)?;
+
+        self.process_reply(
+            request.clone(),
+            reply,
+            server_address,
+            reply_time,
+            anchor,
+            authenticated,
+        )
+    }
+}
+
+impl Hash for Config {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // the clock source is not hashable, configurations differing only in it hash the same
+        self.bind_address.hash(state);
+        self.timeout.hash(state);
+        self.connect_ip.hash(state);
+        self.retry_policy.hash(state);
+        #[cfg(feature = "nts")]
+        self.nts.hash(state);
+        #[cfg(feature = "mac")]
+        self.mac.hash(state);
+        self.extension_fields.hash(state);
+        self.random_transmit_timestamp.hash(state);
+        self.version.hash(state);
+        self.burst_interval.hash(state);
+        self.multicast_hops.hash(state);
+    }
 }
 
 impl Default for Config {
@@ -262,16 +836,31 @@ impl Default for Config {
             bind_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
             timeout: Duration::from_secs(3),
             connect_ip: true,
+            retry_policy: RetryPolicy::default(),
+            #[cfg(feature = "nts")]
+            nts: None,
+            #[cfg(feature = "mac")]
+            mac: None,
+            extension_fields: Vec::new(),
+            random_transmit_timestamp: false,
+            version: Packet::VERSION,
+            burst_interval: Duration::from_secs(2),
+            multicast_hops: 127,
+            clock_source: SharedClockSource::default(),
         }
     }
 }
 
 /// Blocking client instance
 ///
-/// This is the main entry point of the blocking API.
+/// This is the main entry point of the blocking API. Packets are exchanged with the servers
+/// through a [`Transport`], which is UDP by default.
 #[derive(Clone, Debug, Hash)]
-pub struct SntpClient {
+pub struct SntpClient<T = UdpTransport> {
     config: Config,
+    transport: T,
+    #[cfg(feature = "nts")]
+    nts_sessions: nts::NtsSessions,
 }
 
 impl SntpClient {
@@ -285,9 +874,7 @@ impl SntpClient {
     /// let client = SntpClient::new();
     /// ```
     pub fn new() -> SntpClient {
-        SntpClient {
-            config: Config::default(),
-        }
+        Self::with_config(Config::default())
     }
 
     /// Creates a new instance with the specified configuration
@@ -299,16 +886,43 @@ impl SntpClient {
     /// let client = SntpClient::with_config(Config::default());
     /// ```
     pub fn with_config(config: Config) -> SntpClient {
-        SntpClient { config }
+        Self::with_transport(config, UdpTransport)
+    }
+}
+
+impl<T: Transport> SntpClient<T> {
+    /// Creates a new instance with the specified configuration and transport
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, SntpClient, UdpTransport};
+    ///
+    /// let client = SntpClient::with_transport(Config::default(), UdpTransport);
+    /// ```
+    pub fn with_transport(config: Config, transport: T) -> SntpClient<T> {
+        SntpClient {
+            config,
+            transport,
+            #[cfg(feature = "nts")]
+            nts_sessions: nts::NtsSessions::default(),
+        }
+    }
+
+    /// Returns with the transport of the client
+    pub fn transport(&self) -> &T {
+        &self.transport
     }
 
     /// Synchronize with the server
     ///
     /// Sends a request to the server, waits for the reply, and processes it. This is a blocking call
     /// and can block for a long time. After sending the request, it waits for a timeout; if no
-    /// reply is received, an error is returned.
+    /// reply is received, an error is returned. Failed attempts are retried according to the
+    /// retry policy of the configuration, see [`Config::retry_policy`].
     ///
     /// If the supplied server address resolves to multiple addresses, only the first one is used.
+    /// Use [`SntpClient::synchronize_all`] to query all of them.
     ///
     /// # Example
     ///
@@ -322,30 +936,402 @@ impl SntpClient {
         &self,
         server_address: A,
     ) -> Result<SynchronizationResult, SynchronizationError> {
-        let socket = std::net::UdpSocket::bind(self.config.bind_address)?;
-        let remote_address = server_address.to_server_addrs(SNTP_PORT);
+        let policy = &self.config.retry_policy;
+        let mut attempt = 1;
+
+        loop {
+            match self.synchronize_once(&server_address, policy.timeout(self.config.timeout)) {
+                Ok(mut result) => {
+                    result.set_attempts(attempt);
+                    return Ok(result);
+                }
+                Err(err) if attempt < policy.max_attempts() && policy.should_retry(&err) => {
+                    std::thread::sleep(policy.backoff_time(attempt));
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-        socket.set_read_timeout(Some(self.config.timeout))?;
-        if self.config.connect_ip {
-            socket.connect(&remote_address)?;
+    fn synchronize_once<A: ToServerAddrs>(
+        &self,
+        server_address: &A,
+        timeout: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        #[cfg(feature = "nts")]
+        if let Some(nts_config) = &self.config.nts {
+            return self.synchronize_nts_once(nts_config, server_address, timeout);
         }
 
-        let request = Request::new();
-        let mut receive_buffer = [0; Packet::ENCODED_LEN];
+        let remote_address = transport::select_address(
+            std::net::ToSocketAddrs::to_socket_addrs(&server_address.to_server_addrs(SNTP_PORT))?,
+            self.config.bind_address,
+        )?;
+        let mut socket = self.transport.bind(self.config.bind_address)?;
 
         if self.config.connect_ip {
-            socket.send(&request.as_bytes())?;
-        } else {
-            socket.send_to(&request.as_bytes(), &remote_address)?;
+            socket.connect(remote_address)?;
         }
-        let (bytes_received, server_address) = socket.recv_from(&mut receive_buffer)?;
 
-        let reply = Reply::new(
+        let request = self.config.new_request();
+        let request_bytes = self.config.encode_request(&request);
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        socket.send_to(&request_bytes, remote_address)?;
+        let received = socket.recv_from(&mut receive_buffer, timeout)?;
+        let anchor = self.config.anchor();
+        let request = request.with_send_time(socket.transmit_timestamp());
+        let reply_time = received.timestamp().unwrap_or(anchor.time);
+        let reply = &receive_buffer[..received.length()];
+
+        let authenticated = self.config.authenticate_reply(reply)?;
+
+        self.config.process_reply(
+            request,
+            reply,
+            received.source(),
+            reply_time,
+            anchor,
+            authenticated,
+        )
+    }
+
+    #[cfg(feature = "nts")]
+    fn synchronize_nts_once<A: ToServerAddrs>(
+        &self,
+        nts_config: &NtsConfig,
+        server_address: &A,
+        timeout: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let key_exchange_address = std::net::ToSocketAddrs::to_socket_addrs(
+            &server_address.to_server_addrs(nts::NTS_KE_PORT),
+        )?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Unable to resolve NTS key establishment server address",
+            )
+        })?;
+
+        let mut session = match self.nts_sessions.take(key_exchange_address) {
+            Some(session) => session,
+            None => nts::key_exchange(
+                nts_config,
+                &server_address.server_name(),
+                key_exchange_address,
+                timeout,
+            )?,
+        };
+
+        let request = self.config.new_request();
+        let nts_request = self.config.encode_nts_request(&request, &mut session);
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let ntp_address = session.ntp_address();
+
+        let exchange = (|| {
+            let mut socket = self.transport.bind(self.config.bind_address)?;
+            socket.connect(ntp_address)?;
+            socket.send_to(nts_request.as_bytes(), ntp_address)?;
+
+            let received = socket.recv_from(&mut receive_buffer, timeout)?;
+            let anchor = self.config.anchor();
+
+            Ok::<_, std::io::Error>((received, anchor, socket.transmit_timestamp()))
+        })();
+
+        // the remaining cookies are still valid, the session is kept for the next attempt
+        let (received, anchor, transmit_timestamp) = match exchange {
+            Ok(exchange) => exchange,
+            Err(err) => {
+                self.nts_sessions.restore(key_exchange_address, session);
+                return Err(err.into());
+            }
+        };
+        let request = request.with_send_time(transmit_timestamp);
+        let reply_time = received.timestamp().unwrap_or(anchor.time);
+        let reply = &receive_buffer[..received.length()];
+
+        let verification = session.verify_reply(reply, &nts_request);
+        self.nts_sessions
+            .store(key_exchange_address, session, &verification);
+        let extension_fields = verification?;
+
+        self.config.process_nts_reply(
             request,
-            Packet::from_bytes(&receive_buffer[..bytes_received], server_address)?,
+            reply,
+            received.source(),
+            reply_time,
+            anchor,
+            extension_fields,
+        )
+    }
+
+    /// Synchronize with multiple servers
+    ///
+    /// Queries all the supplied servers in parallel, then runs the selection and clustering
+    /// algorithms of RFC 5905 over the replies to discard falsetickers and outliers. The result
+    /// contains the combined clock offset of the surviving servers and diagnostics for each
+    /// queried server.
+    ///
+    /// If no majority of the servers agree on the time (e.g. too many of them failed), then
+    /// [`SynchronizationError::SelectionFailed`] is returned. To tolerate a single falseticker,
+    /// at least three servers should be queried.
+    ///
+    /// Each server is queried with a single exchange, so the jitter of the servers, used by the
+    /// clustering algorithm, cannot be measured by the clock filter. It is approximated with the
+    /// larger of the precision of the server and the dispersion of the exchange.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client
+    ///     .synchronize_many(&["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org"])
+    ///     .unwrap();
+    ///
+    /// println!("Clock offset: {} s", result.result().clock_offset().as_secs_f64());
+    /// ```
+    pub fn synchronize_many<A: ToServerAddrs + Sync>(
+        &self,
+        server_addresses: &[A],
+    ) -> Result<MultiSynchronizationResult, SynchronizationError>
+    where
+        T: Sync,
+    {
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = server_addresses
+                .iter()
+                .map(|server_address| scope.spawn(move || self.synchronize(server_address)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        selection::combine(results)
+    }
+
+    /// Synchronize with all addresses of a server
+    ///
+    /// Resolves the supplied server address and synchronizes with all the resulting addresses in
+    /// parallel. This is useful for pool hostnames which resolve to multiple servers. The result
+    /// of each synchronization is returned in a map keyed by the server address.
+    ///
+    /// An error is returned only if the address resolution fails. Note that addresses which
+    /// don't match the address family of the bind address (e.g. IPv6 addresses with the default
+    /// IPv4 bind address) will fail with an I/O error.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let results = client.synchronize_all("pool.ntp.org").unwrap();
+    ///
+    /// for (address, result) in results {
+    ///     if let Ok(result) = result {
+    ///         println!("{}: {} s", address, result.clock_offset().as_secs_f64());
+    ///     }
+    /// }
+    /// ```
+    pub fn synchronize_all<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+    ) -> Result<
+        HashMap<SocketAddr, Result<SynchronizationResult, SynchronizationError>>,
+        SynchronizationError,
+    >
+    where
+        T: Sync,
+    {
+        let addresses: Vec<SocketAddr> = std::net::ToSocketAddrs::to_socket_addrs(
+            &server_address.to_server_addrs(self.config.server_port()),
+        )?
+        .collect();
+        let server_name = server_address.server_name();
+
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = addresses
+                .iter()
+                .map(|address| {
+                    let server = ResolvedServer::new(*address, server_name.clone());
+
+                    scope.spawn(move || self.synchronize(server))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        Ok(addresses.into_iter().zip(results).collect())
+    }
+
+    /// Synchronize with a server using multiple samples
+    ///
+    /// Performs a burst of `samples` exchanges with the server, waiting for the burst interval
+    /// between them (see [`Config::burst_interval`]). The samples are processed with the clock
+    /// filter algorithm of RFC 5905 (section 10): the sample with the minimum round trip delay of
+    /// the last eight samples is selected, as it is likely the most accurate one. The returned
+    /// result is the one of the selected sample, with the dispersion and jitter calculated by the
+    /// filter (see [`SynchronizationResult::dispersion`] and [`SynchronizationResult::jitter`]).
+    ///
+    /// Note that the filter assumes maximum dispersion for missing samples, so the dispersion is
+    /// large if fewer than eight samples are collected.
+    ///
+    /// The server name is resolved only once, all samples are taken from the same address. Failed
+    /// exchanges are skipped and the error of the last failed exchange is returned only if all of
+    /// them failed. A Kiss-o'-Death reply stops the burst immediately.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `samples` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize_with_samples("pool.ntp.org", 8).unwrap();
+    ///
+    /// println!(
+    ///     "Clock offset: {} s, jitter: {} s",
+    ///     result.clock_offset().as_secs_f64(),
+    ///     result.jitter().as_secs_f64()
+    /// );
+    /// ```
+    pub fn synchronize_with_samples<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+        samples: usize,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        assert!(samples > 0, "At least one sample is needed");
+
+        let server = ResolvedServer::new(
+            transport::select_address(
+                std::net::ToSocketAddrs::to_socket_addrs(
+                    &server_address.to_server_addrs(self.config.server_port()),
+                )?,
+                self.config.bind_address,
+            )?,
+            server_address.server_name(),
         );
+        let mut results = Vec::new();
+        let mut last_error = None;
+
+        for sample in 0..samples {
+            if sample > 0 {
+                std::thread::sleep(self.config.burst_interval);
+            }
+
+            match self.synchronize(&server) {
+                Ok(result) => results.push(result),
+                Err(err) if err.is_kiss_of_death() => return Err(err),
+                Err(err) => last_error = Some(err),
+            }
+        }
 
-        reply.process()
+        match filter::select_result(results) {
+            Some(result) => Ok(result),
+            None => Err(last_error.expect("Either a result or an error is available")),
+        }
+    }
+
+    /// Discover servers with manycast
+    ///
+    /// Sends a single request to a multicast group (usually 224.0.1.1 or ff05::101) and collects
+    /// the unicast replies of all servers which answer within the timeout. Unlike other
+    /// functions, it always waits for the whole timeout. The servers are returned in the order of
+    /// their replies, together with the synchronization results, so they can be used for
+    /// subsequent unicast synchronization.
+    ///
+    /// Invalid and duplicate replies are ignored, so an empty list is returned if no valid reply
+    /// has been received. Network Time Security is not supported by manycast, it is not used even
+    /// if it is configured. For IPv6 groups, an IPv6 bind address needs to be set. The
+    /// time-to-live or hop limit of the request is set by [`Config::multicast_hops`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let servers = client.discover("224.0.1.1").unwrap();
+    ///
+    /// for (address, result) in servers {
+    ///     println!("{}: stratum {}", address, result.stratum());
+    /// }
+    /// ```
+    pub fn discover<A: ToServerAddrs>(
+        &self,
+        group_address: A,
+    ) -> Result<Vec<(SocketAddr, SynchronizationResult)>, SynchronizationError> {
+        let group_address = transport::select_address(
+            std::net::ToSocketAddrs::to_socket_addrs(&group_address.to_server_addrs(SNTP_PORT))?,
+            self.config.bind_address,
+        )?;
+        let mut socket = self.transport.bind(self.config.bind_address)?;
+        let request = self.config.new_request();
+        let request_bytes = self.config.encode_request(&request);
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let mut servers: Vec<(SocketAddr, SynchronizationResult)> = Vec::new();
+
+        socket.set_multicast_hops(self.config.multicast_hops)?;
+        socket.send_to(&request_bytes, group_address)?;
+        let request = request.with_send_time(socket.transmit_timestamp());
+
+        let deadline = std::time::Instant::now() + self.config.timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+
+            if remaining.is_zero() {
+                break;
+            }
+
+            let received = match socket.recv_from(&mut receive_buffer, remaining) {
+                Ok(received) => received,
+                Err(err)
+                    if err.kind() == std::io::ErrorKind::WouldBlock
+                        || err.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let anchor = self.config.anchor();
+            let reply_time = received.timestamp().unwrap_or(anchor.time);
+            let server_address = received.source();
+
+            if servers
+                .iter()
+                .any(|(address, _)| *address == server_address)
+            {
+                continue;
+            }
+
+            if let Ok(result) = self.config.process_manycast_reply(
+                &request,
+                &receive_buffer[..received.length()],
+                server_address,
+                reply_time,
+                anchor,
+            ) {
+                servers.push((server_address, result));
+            }
+        }
+
+        Ok(servers)
     }
 
     /// Sets synchronization timeout
@@ -410,10 +1396,15 @@ impl Default for SntpClient {
 ///
 /// Only available when async feature is enabled (which is the default)
 ///
-/// This is the main entry point of the asynchronous API.
+/// This is the main entry point of the asynchronous API. Packets are exchanged with the servers
+/// through an [`AsyncTransport`], which is UDP by default.
 #[cfg(feature = "async")]
-pub struct AsyncSntpClient {
+#[derive(Debug)]
+pub struct AsyncSntpClient<T = UdpTransport> {
     config: Config,
+    transport: T,
+    #[cfg(feature = "nts")]
+    nts_sessions: nts::NtsSessions,
 }
 
 #[cfg(feature = "async")]
@@ -430,9 +1421,7 @@ impl AsyncSntpClient {
     /// let client = AsyncSntpClient::new();
     /// ```
     pub fn new() -> AsyncSntpClient {
-        AsyncSntpClient {
-            config: Config::default(),
-        }
+        Self::with_config(Config::default())
     }
 
     /// Creates a new instance with the specified configuration
@@ -447,7 +1436,35 @@ impl AsyncSntpClient {
     /// let client = AsyncSntpClient::with_config(Config::default());
     /// ```
     pub fn with_config(config: Config) -> AsyncSntpClient {
-        AsyncSntpClient { config }
+        Self::with_transport(config, UdpTransport)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> AsyncSntpClient<T> {
+    /// Creates a new instance with the specified configuration and transport
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncSntpClient, Config, UdpTransport};
+    ///
+    /// let client = AsyncSntpClient::with_transport(Config::default(), UdpTransport);
+    /// ```
+    pub fn with_transport(config: Config, transport: T) -> AsyncSntpClient<T> {
+        AsyncSntpClient {
+            config,
+            transport,
+            #[cfg(feature = "nts")]
+            nts_sessions: nts::NtsSessions::default(),
+        }
+    }
+
+    /// Returns with the transport of the client
+    pub fn transport(&self) -> &T {
+        &self.transport
     }
 
     /// Synchronize with the server
@@ -455,8 +1472,9 @@ impl AsyncSntpClient {
     /// Only available when async feature is enabled (which is the default)
     ///
     /// Sends a request to the server and processes the reply. If no reply is received within timeout,
-    /// then an error is returned. If the supplied server address resolves to multiple addresses,
-    /// only the first one is used.
+    /// then an error is returned. Failed attempts are retried according to the retry policy of the
+    /// configuration, see [`Config::retry_policy`]. If the supplied server address resolves to multiple addresses,
+    /// only the first one is used. Use [`AsyncSntpClient::synchronize_all`] to query all of them.
     ///
     /// # Example
     ///
@@ -473,37 +1491,383 @@ impl AsyncSntpClient {
         &self,
         server_address: A,
     ) -> Result<SynchronizationResult, SynchronizationError> {
-        let mut receive_buffer = [0; Packet::ENCODED_LEN];
-        let remote_address = server_address.to_server_addrs(SNTP_PORT);
+        let policy = &self.config.retry_policy;
+        let mut attempt = 1;
+
+        loop {
+            match self
+                .synchronize_once(&server_address, policy.timeout(self.config.timeout))
+                .await
+            {
+                Ok(mut result) => {
+                    result.set_attempts(attempt);
+                    return Ok(result);
+                }
+                Err(err) if attempt < policy.max_attempts() && policy.should_retry(&err) => {
+                    tokio::time::sleep(policy.backoff_time(attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-        let socket = tokio::net::UdpSocket::bind(self.config.bind_address).await?;
-        if self.config.connect_ip {
-            socket.connect(&remote_address).await?;
+    async fn synchronize_once<A: ToServerAddrs>(
+        &self,
+        server_address: &A,
+        timeout_duration: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        #[cfg(feature = "nts")]
+        if let Some(nts_config) = &self.config.nts {
+            return self
+                .synchronize_nts_once(nts_config, server_address, timeout_duration)
+                .await;
         }
 
-        let request = Request::new();
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let remote_address = transport::select_address(
+            tokio::net::lookup_host(server_address.to_server_addrs(SNTP_PORT)).await?,
+            self.config.bind_address,
+        )?;
 
+        let mut socket = self.transport.bind(self.config.bind_address).await?;
         if self.config.connect_ip {
-            socket.send(&request.as_bytes()).await?;
-        } else {
-            socket.send_to(&request.as_bytes(), &remote_address).await?;
+            socket.connect(remote_address).await?;
         }
 
-        let result_future = timeout(self.config.timeout, socket.recv_from(&mut receive_buffer));
+        let request = self.config.new_request();
+        let request_bytes = self.config.encode_request(&request);
+
+        socket.send_to(&request_bytes, remote_address).await?;
+
+        let result_future = timeout(timeout_duration, socket.recv_from(&mut receive_buffer));
 
-        let (bytes_received, server_address) = result_future.await.map_err(|_| {
+        let received = result_future.await.map_err(|_| {
             std::io::Error::new(
                 std::io::ErrorKind::TimedOut,
                 "Timeout while waiting for server reply",
             )
         })??;
+        let anchor = self.config.anchor();
+        let request = request.with_send_time(socket.transmit_timestamp());
+        let reply_time = received.timestamp().unwrap_or(anchor.time);
+        let reply = &receive_buffer[..received.length()];
+
+        let authenticated = self.config.authenticate_reply(reply)?;
+
+        self.config.process_reply(
+            request,
+            reply,
+            received.source(),
+            reply_time,
+            anchor,
+            authenticated,
+        )
+    }
 
-        let reply = Reply::new(
+    #[cfg(feature = "nts")]
+    async fn synchronize_nts_once<A: ToServerAddrs>(
+        &self,
+        nts_config: &NtsConfig,
+        server_address: &A,
+        timeout_duration: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let key_exchange_address =
+            tokio::net::lookup_host(server_address.to_server_addrs(nts::NTS_KE_PORT))
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "Unable to resolve NTS key establishment server address",
+                    )
+                })?;
+
+        let mut session = match self.nts_sessions.take(key_exchange_address) {
+            Some(session) => session,
+            None => {
+                let nts_config = nts_config.clone();
+                let server_name = server_address.server_name();
+
+                tokio::task::spawn_blocking(move || {
+                    nts::key_exchange(
+                        &nts_config,
+                        &server_name,
+                        key_exchange_address,
+                        timeout_duration,
+                    )
+                })
+                .await
+                .map_err(std::io::Error::other)??
+            }
+        };
+
+        let request = self.config.new_request();
+        let nts_request = self.config.encode_nts_request(&request, &mut session);
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let ntp_address = session.ntp_address();
+
+        let exchange = async {
+            let mut socket = self.transport.bind(self.config.bind_address).await?;
+            socket.connect(ntp_address).await?;
+            socket.send_to(nts_request.as_bytes(), ntp_address).await?;
+
+            let result_future = timeout(timeout_duration, socket.recv_from(&mut receive_buffer));
+            let received = result_future.await.map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Timeout while waiting for server reply",
+                )
+            })??;
+            let anchor = self.config.anchor();
+
+            Ok::<_, std::io::Error>((received, anchor, socket.transmit_timestamp()))
+        }
+        .await;
+
+        // the remaining cookies are still valid, the session is kept for the next attempt
+        let (received, anchor, transmit_timestamp) = match exchange {
+            Ok(exchange) => exchange,
+            Err(err) => {
+                self.nts_sessions.restore(key_exchange_address, session);
+                return Err(err.into());
+            }
+        };
+        let request = request.with_send_time(transmit_timestamp);
+        let reply_time = received.timestamp().unwrap_or(anchor.time);
+        let reply = &receive_buffer[..received.length()];
+
+        let verification = session.verify_reply(reply, &nts_request);
+        self.nts_sessions
+            .store(key_exchange_address, session, &verification);
+        let extension_fields = verification?;
+
+        self.config.process_nts_reply(
             request,
-            Packet::from_bytes(&receive_buffer[..bytes_received], server_address)?,
+            reply,
+            received.source(),
+            reply_time,
+            anchor,
+            extension_fields,
+        )
+    }
+
+    /// Synchronize with multiple servers
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Queries all the supplied servers concurrently, then runs the selection and clustering
+    /// algorithms of RFC 5905 over the replies to discard falsetickers and outliers. The result
+    /// contains the combined clock offset of the surviving servers and diagnostics for each
+    /// queried server.
+    ///
+    /// If no majority of the servers agree on the time (e.g. too many of them failed), then
+    /// [`SynchronizationError::SelectionFailed`] is returned. To tolerate a single falseticker,
+    /// at least three servers should be queried.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncSntpClient, MultiSynchronizationResult, SynchronizationError};
+    ///
+    /// async fn synchronize() -> Result<MultiSynchronizationResult, SynchronizationError> {
+    ///   let client = AsyncSntpClient::new();
+    ///
+    ///   client
+    ///     .synchronize_many(&["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org"])
+    ///     .await
+    /// }
+    /// ```
+    pub async fn synchronize_many<A: ToServerAddrs>(
+        &self,
+        server_addresses: &[A],
+    ) -> Result<MultiSynchronizationResult, SynchronizationError> {
+        let results = join::join_all(
+            server_addresses
+                .iter()
+                .map(|server_address| self.synchronize(server_address)),
+        )
+        .await;
+
+        selection::combine(results)
+    }
+
+    /// Synchronize with all addresses of a server
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Resolves the supplied server address and synchronizes with all the resulting addresses
+    /// concurrently. This is useful for pool hostnames which resolve to multiple servers. The result
+    /// of each synchronization is returned in a map keyed by the server address.
+    ///
+    /// An error is returned only if the address resolution fails. Note that addresses which
+    /// don't match the address family of the bind address (e.g. IPv6 addresses with the default
+    /// IPv4 bind address) will fail with an I/O error.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{AsyncSntpClient, SynchronizationResult, SynchronizationError};
+    /// use std::collections::HashMap;
+    /// use std::net::SocketAddr;
+    ///
+    /// async fn synchronize() -> HashMap<SocketAddr, Result<SynchronizationResult, SynchronizationError>> {
+    ///   let client = AsyncSntpClient::new();
+    ///
+    ///   client.synchronize_all("pool.ntp.org").await.unwrap()
+    /// }
+    /// ```
+    pub async fn synchronize_all<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+    ) -> Result<
+        HashMap<SocketAddr, Result<SynchronizationResult, SynchronizationError>>,
+        SynchronizationError,
+    > {
+        let addresses: Vec<SocketAddr> =
+            tokio::net::lookup_host(server_address.to_server_addrs(self.config.server_port()))
+                .await?
+                .collect();
+        let server_name = server_address.server_name();
+
+        let results =
+            join::join_all(addresses.iter().map(|address| {
+                self.synchronize(ResolvedServer::new(*address, server_name.clone()))
+            }))
+            .await;
+
+        Ok(addresses.into_iter().zip(results).collect())
+    }
+
+    /// Synchronize with a server using multiple samples
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Performs a burst of `samples` exchanges with the server and selects the best sample with
+    /// the clock filter algorithm of RFC 5905. See [`SntpClient::synchronize_with_samples`] for
+    /// details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `samples` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncSntpClient;
+    ///
+    /// async fn jitter() -> f64 {
+    ///   let client = AsyncSntpClient::new();
+    ///   let result = client.synchronize_with_samples("pool.ntp.org", 8).await.unwrap();
+    ///
+    ///   result.jitter().as_secs_f64()
+    /// }
+    /// ```
+    pub async fn synchronize_with_samples<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+        samples: usize,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        assert!(samples > 0, "At least one sample is needed");
+
+        let server = ResolvedServer::new(
+            transport::select_address(
+                tokio::net::lookup_host(server_address.to_server_addrs(self.config.server_port()))
+                    .await?,
+                self.config.bind_address,
+            )?,
+            server_address.server_name(),
         );
+        let mut results = Vec::new();
+        let mut last_error = None;
+
+        for sample in 0..samples {
+            if sample > 0 {
+                tokio::time::sleep(self.config.burst_interval).await;
+            }
+
+            match self.synchronize(&server).await {
+                Ok(result) => results.push(result),
+                Err(err) if err.is_kiss_of_death() => return Err(err),
+                Err(err) => last_error = Some(err),
+            }
+        }
 
-        reply.process()
+        match filter::select_result(results) {
+            Some(result) => Ok(result),
+            None => Err(last_error.expect("Either a result or an error is available")),
+        }
+    }
+
+    /// Discover servers with manycast
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Sends a single request to a multicast group (usually 224.0.1.1 or ff05::101) and collects
+    /// the unicast replies of all servers which answer within the timeout. See
+    /// [`SntpClient::discover`] for details.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::AsyncSntpClient;
+    ///
+    /// async fn discover() {
+    ///   let client = AsyncSntpClient::new();
+    ///   let servers = client.discover("224.0.1.1").await.unwrap();
+    ///
+    ///   for (address, result) in servers {
+    ///     println!("{}: stratum {}", address, result.stratum());
+    ///   }
+    /// }
+    /// ```
+    pub async fn discover<A: ToServerAddrs>(
+        &self,
+        group_address: A,
+    ) -> Result<Vec<(SocketAddr, SynchronizationResult)>, SynchronizationError> {
+        let group_address = transport::select_address(
+            tokio::net::lookup_host(group_address.to_server_addrs(SNTP_PORT)).await?,
+            self.config.bind_address,
+        )?;
+        let mut socket = self.transport.bind(self.config.bind_address).await?;
+        let request = self.config.new_request();
+        let request_bytes = self.config.encode_request(&request);
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let mut servers: Vec<(SocketAddr, SynchronizationResult)> = Vec::new();
+
+        socket.set_multicast_hops(self.config.multicast_hops)?;
+        socket.send_to(&request_bytes, group_address).await?;
+        let request = request.with_send_time(socket.transmit_timestamp());
+
+        let deadline = tokio::time::Instant::now() + self.config.timeout;
+
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut receive_buffer)).await
+        {
+            let received = received?;
+            let anchor = self.config.anchor();
+            let reply_time = received.timestamp().unwrap_or(anchor.time);
+            let server_address = received.source();
+
+            if servers
+                .iter()
+                .any(|(address, _)| *address == server_address)
+            {
+                continue;
+            }
+
+            if let Ok(result) = self.config.process_manycast_reply(
+                &request,
+                &receive_buffer[..received.length()],
+                server_address,
+                reply_time,
+                anchor,
+            ) {
+                servers.push((server_address, result));
+            }
+        }
+
+        Ok(servers)
     }
 
     /// Sets synchronization timeout
@@ -564,3 +1928,235 @@ impl Default for AsyncSntpClient {
         AsyncSntpClient::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{LoopbackServer, OffsetTimeSource};
+    use crate::transport::UdpTransportSocket;
+    use std::sync::{Arc, Mutex};
+
+    /// Receives a request and answers it from multiple sockets, like manycast servers do
+    fn start_manycast_servers() -> (SocketAddr, Vec<SocketAddr>) {
+        let group = LoopbackServer::bind();
+        let group_address = group.local_addr();
+        let servers: Vec<_> = (0..2)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let server_addresses = servers.iter().map(|s| s.local_addr().unwrap()).collect();
+
+        group.serve(1, move |exchange| {
+            let reply = exchange.reply();
+
+            for server in &servers {
+                server.send_to(&reply, exchange.source).unwrap();
+            }
+
+            // duplicate and invalid replies are ignored
+            servers[0].send_to(&reply, exchange.source).unwrap();
+            exchange.send(&reply[..10]);
+        });
+
+        (group_address, server_addresses)
+    }
+
+    #[test]
+    fn config_hash_ignores_the_clock_source() {
+        fn hash(config: &Config) -> u64 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            config.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let config = Config::default().timeout(Duration::from_secs(1));
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+
+        assert_eq!(hash(&config), hash(&config.clone().clock_source(clock)));
+        assert_ne!(
+            hash(&config),
+            hash(&config.clone().timeout(Duration::from_secs(2)))
+        );
+    }
+
+    #[test]
+    fn discovery_collects_all_replies() {
+        let (group, servers) = start_manycast_servers();
+        let client = SntpClient::with_config(Config::default().timeout(Duration::from_millis(500)));
+
+        let discovered = client.discover(group).unwrap();
+        let addresses: Vec<_> = discovered.iter().map(|(address, _)| *address).collect();
+
+        assert_eq!(addresses, servers);
+        assert!(discovered
+            .iter()
+            .all(|(_, result)| result.clock_offset().as_secs_f64().abs() < 0.1));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_discovery_collects_all_replies() {
+        let (group, servers) = start_manycast_servers();
+        let client = crate::AsyncSntpClient::with_config(
+            Config::default().timeout(Duration::from_millis(500)),
+        );
+
+        let discovered = client.discover(group).await.unwrap();
+        let addresses: Vec<_> = discovered.iter().map(|(address, _)| *address).collect();
+
+        assert_eq!(addresses, servers);
+    }
+
+    #[test]
+    fn synchronization_with_samples_uses_clock_filter() {
+        let server = LoopbackServer::bind()
+            .config(ServerConfig::default().time_source(OffsetTimeSource(Duration::from_secs(10))));
+        let address = server.local_addr();
+        let client = SntpClient::with_config(
+            Config::default()
+                .timeout(Duration::from_secs(5))
+                .burst_interval(Duration::from_millis(10)),
+        );
+
+        let thread = server.serve(8, |exchange| exchange.send(&exchange.reply()));
+        let result = client.synchronize_with_samples(address, 8).unwrap();
+        thread.join().unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 10.0).abs() < 0.1);
+        assert!(result.jitter().as_secs_f64() < 0.1);
+        assert!(result.dispersion().as_secs_f64() > 0.0);
+        assert!(result.dispersion().as_secs_f64() < 0.1);
+    }
+
+    #[test]
+    fn random_transmit_timestamp_works_with_server() {
+        let server = LoopbackServer::bind()
+            .config(ServerConfig::default().time_source(OffsetTimeSource(Duration::from_secs(10))));
+        let address = server.local_addr();
+        let client = SntpClient::with_config(
+            Config::default()
+                .timeout(Duration::from_secs(5))
+                .random_transmit_timestamp(true),
+        );
+
+        let thread = server.serve(1, |exchange| exchange.send(&exchange.reply()));
+        let result = client.synchronize(address).unwrap();
+        thread.join().unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 10.0).abs() < 0.1);
+        assert!(result.round_trip_delay().as_secs_f64() < 0.1);
+    }
+
+    #[test]
+    fn configured_version_is_sent_and_reported() {
+        let server = LoopbackServer::bind();
+        let address = server.local_addr();
+        let thread = server.serve(4, |exchange| exchange.send(&exchange.reply()));
+
+        for version in [
+            NtpVersion::V1,
+            NtpVersion::V2,
+            NtpVersion::V3,
+            NtpVersion::V4,
+        ] {
+            let client = SntpClient::with_config(
+                Config::default()
+                    .timeout(Duration::from_secs(5))
+                    .version(version),
+            );
+            let result = client.synchronize(address).unwrap();
+
+            assert_eq!(result.version(), version.to_u8());
+        }
+
+        thread.join().unwrap();
+    }
+
+    /// UDP transport recording the calls of its sockets
+    #[derive(Clone, Default)]
+    struct RecordingTransport {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct RecordingSocket {
+        socket: UdpTransportSocket,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Transport for RecordingTransport {
+        type Socket = RecordingSocket;
+
+        fn bind(&self, address: SocketAddr) -> std::io::Result<RecordingSocket> {
+            Ok(RecordingSocket {
+                socket: Transport::bind(&UdpTransport, address)?,
+                calls: self.calls.clone(),
+            })
+        }
+    }
+
+    impl TransportSocket for RecordingSocket {
+        fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push("send".to_string());
+            self.socket.send_to(packet, address)
+        }
+
+        fn recv_from(
+            &mut self,
+            buffer: &mut [u8],
+            timeout: Duration,
+        ) -> std::io::Result<ReceivedPacket> {
+            self.calls.lock().unwrap().push("receive".to_string());
+            self.socket.recv_from(buffer, timeout)
+        }
+
+        fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push("transmit timestamp".to_string());
+            None
+        }
+
+        fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push(format!("hops {hops}"));
+            self.socket.set_multicast_hops(hops)
+        }
+    }
+
+    #[test]
+    fn discovery_sets_the_multicast_hops() {
+        let (group, servers) = start_manycast_servers();
+        let transport = RecordingTransport::default();
+        let client = SntpClient::with_transport(
+            Config::default()
+                .timeout(Duration::from_millis(500))
+                .multicast_hops(16),
+            transport.clone(),
+        );
+
+        assert_eq!(client.discover(group).unwrap().len(), servers.len());
+        assert_eq!(transport.calls.lock().unwrap()[..2], ["hops 16", "send"]);
+    }
+
+    #[test]
+    fn discovery_reads_the_transmit_timestamp_once_after_sending() {
+        let (group, servers) = start_manycast_servers();
+        let transport = RecordingTransport::default();
+        let client = SntpClient::with_transport(
+            Config::default().timeout(Duration::from_millis(500)),
+            transport.clone(),
+        );
+
+        assert_eq!(client.discover(group).unwrap().len(), servers.len());
+
+        let calls = transport.calls.lock().unwrap();
+
+        assert_eq!(calls[1..3], ["send", "transmit timestamp"]);
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|call| *call == "transmit timestamp")
+                .count(),
+            1
+        );
+    }
+}
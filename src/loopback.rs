@@ -0,0 +1,104 @@
+//! Loopback server of the tests, answering requests on a plain UDP socket in a thread
+
+use crate::packet::Packet;
+use crate::server::{ServerConfig, TimeSource};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Time source of the servers, running ahead of the system clock by the specified duration
+pub(crate) struct OffsetTimeSource(pub(crate) Duration);
+
+impl TimeSource for OffsetTimeSource {
+    fn now(&self) -> SystemTime {
+        SystemTime::now() + self.0
+    }
+}
+
+/// Request received by a [`LoopbackServer`]
+pub(crate) struct Exchange<'a> {
+    pub(crate) socket: &'a UdpSocket,
+    pub(crate) request: &'a [u8],
+    pub(crate) source: SocketAddr,
+    /// Reply created by the configuration of the server, `None` if the request is invalid
+    pub(crate) reply: Option<[u8; Packet::ENCODED_LEN]>,
+}
+
+impl Exchange<'_> {
+    /// Returns with the reply, panics if the request is invalid
+    pub(crate) fn reply(&self) -> Vec<u8> {
+        self.reply.expect("Invalid request").to_vec()
+    }
+
+    /// Sends the packet to the client
+    pub(crate) fn send(&self, packet: &[u8]) {
+        self.socket.send_to(packet, self.source).unwrap();
+    }
+}
+
+/// Server replying with [`ServerConfig::reply`], the replies can be modified by the tests
+pub(crate) struct LoopbackServer {
+    socket: UdpSocket,
+    config: ServerConfig,
+}
+
+impl LoopbackServer {
+    /// Binds the server to a free port of the IPv4 loopback address
+    pub(crate) fn bind() -> LoopbackServer {
+        LoopbackServer {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// Sets the configuration used to create the replies
+    pub(crate) fn config(self, config: ServerConfig) -> LoopbackServer {
+        LoopbackServer { config, ..self }
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Socket of the server, to send unsolicited packets
+    pub(crate) fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Calls `respond` for each of the specified number of requests
+    ///
+    /// The thread returns with the source addresses of the requests.
+    pub(crate) fn serve<F>(self, requests: usize, mut respond: F) -> JoinHandle<Vec<SocketAddr>>
+    where
+        F: FnMut(Exchange<'_>) + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            let mut buffer = [0; Packet::MAX_RECEIVE_LEN];
+            let mut sources = Vec::new();
+
+            for _ in 0..requests {
+                let (length, source) = self.socket.recv_from(&mut buffer).unwrap();
+                let request = &buffer[..length];
+                let reply = self.config.reply(request, source, self.config.now());
+
+                respond(Exchange {
+                    socket: &self.socket,
+                    request,
+                    source,
+                    reply,
+                });
+                sources.push(source);
+            }
+
+            sources
+        })
+    }
+
+    /// Calls `respond` for each request until the test process exits
+    pub(crate) fn serve_forever<F>(self, respond: F)
+    where
+        F: FnMut(Exchange<'_>) + Send + 'static,
+    {
+        self.serve(usize::MAX, respond);
+    }
+}
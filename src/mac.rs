@@ -0,0 +1,370 @@
+//! Symmetric key authentication, see RFC 5905 and RFC 8573
+use crate::error::{KeyError, ProtocolError};
+use crate::extension;
+#[cfg(test)]
+use crate::packet::Packet;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+
+const KEY_ID_LEN: usize = 4;
+
+/// Message authentication code algorithm
+///
+/// Only available when the `mac` feature is enabled.
+///
+/// AES-CMAC is recommended by RFC 8573, MD5 and SHA-1 are only supported for legacy servers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MacAlgorithm {
+    /// AES-128-CMAC, see RFC 8573. The key must be 16 bytes long.
+    AesCmac,
+    /// SHA-1 digest of the key and the packet
+    Sha1,
+    /// MD5 digest of the key and the packet
+    Md5,
+}
+
+impl MacAlgorithm {
+    fn digest_len(&self) -> usize {
+        match self {
+            MacAlgorithm::AesCmac | MacAlgorithm::Md5 => 16,
+            MacAlgorithm::Sha1 => 20,
+        }
+    }
+
+    fn digest(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            MacAlgorithm::AesCmac => {
+                use cmac::Mac;
+
+                let mut mac = cmac::Cmac::<aes::Aes128>::new_from_slice(secret)
+                    .expect("AES-CMAC key must be 16 bytes long");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            MacAlgorithm::Sha1 => {
+                use sha1::Digest;
+
+                sha1::Sha1::new()
+                    .chain_update(secret)
+                    .chain_update(data)
+                    .finalize()
+                    .to_vec()
+            }
+            MacAlgorithm::Md5 => {
+                use md5::Digest;
+
+                md5::Md5::new()
+                    .chain_update(secret)
+                    .chain_update(data)
+                    .finalize()
+                    .to_vec()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Hash)]
+struct SymmetricKey {
+    algorithm: MacAlgorithm,
+    secret: Vec<u8>,
+}
+
+/// Store of symmetric keys used for authentication
+///
+/// Only available when the `mac` feature is enabled.
+///
+/// Keys are identified by a key ID, which is sent along with the message authentication code,
+/// so the same key ID and secret must be configured on the server. It uses a builder-like
+/// pattern to add keys. See [`crate::Config::mac_authentication`] for details.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{KeyStore, MacAlgorithm};
+///
+/// let key_store = KeyStore::default()
+///     .key(1, MacAlgorithm::AesCmac, b"0123456789abcdef".to_vec())
+///     .and_then(|store| store.key(2, MacAlgorithm::Sha1, b"legacy secret".to_vec()))
+///     .unwrap();
+/// ```
+#[derive(Clone, Default, Hash)]
+pub struct KeyStore {
+    keys: BTreeMap<u32, SymmetricKey>,
+}
+
+impl KeyStore {
+    /// Adds a key to the store
+    ///
+    /// If a key with the same ID is already in the store, then it is replaced.
+    ///
+    /// Returns [`KeyError::InvalidSecretLength`] if the algorithm is [`MacAlgorithm::AesCmac`]
+    /// and the secret is not 16 bytes long.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{KeyStore, MacAlgorithm};
+    ///
+    /// let key_store = KeyStore::default()
+    ///     .key(1, MacAlgorithm::AesCmac, b"0123456789abcdef".to_vec())
+    ///     .unwrap();
+    /// ```
+    pub fn key(
+        mut self,
+        key_id: u32,
+        algorithm: MacAlgorithm,
+        secret: Vec<u8>,
+    ) -> Result<KeyStore, KeyError> {
+        if algorithm == MacAlgorithm::AesCmac && secret.len() != 16 {
+            return Err(KeyError::InvalidSecretLength(secret.len()));
+        }
+
+        self.keys.insert(key_id, SymmetricKey { algorithm, secret });
+        Ok(self)
+    }
+
+    pub(crate) fn contains(&self, key_id: u32) -> bool {
+        self.keys.contains_key(&key_id)
+    }
+
+    /// Appends the message authentication code of the packet to it
+    ///
+    /// Returns `None` if the key is not in the store.
+    pub(crate) fn sign(&self, key_id: u32, packet: &[u8]) -> Option<Vec<u8>> {
+        let key = self.keys.get(&key_id)?;
+        let mut signed = packet.to_vec();
+
+        signed.extend_from_slice(&key_id.to_be_bytes());
+        signed.extend_from_slice(&key.algorithm.digest(&key.secret, packet));
+
+        Some(signed)
+    }
+
+    /// Verifies the message authentication code at the end of a reply
+    ///
+    /// The reply must be signed with the key with `key_id`, other keys of the store are rejected.
+    pub(crate) fn verify(&self, key_id: u32, reply: &[u8]) -> Result<(), ProtocolError> {
+        let (_, trailer) = extension::split_packet(reply)?;
+        let signed_data = &reply[..reply.len() - trailer.len()];
+
+        if trailer.len() < KEY_ID_LEN {
+            return Err(ProtocolError::MissingMac);
+        }
+
+        if trailer[..KEY_ID_LEN] != key_id.to_be_bytes() {
+            return Err(ProtocolError::InvalidMac);
+        }
+
+        let key = self.keys.get(&key_id).ok_or(ProtocolError::InvalidMac)?;
+
+        if trailer.len() != KEY_ID_LEN + key.algorithm.digest_len() {
+            return Err(ProtocolError::InvalidMac);
+        }
+
+        let expected = key.algorithm.digest(&key.secret, signed_data);
+        let difference = trailer[KEY_ID_LEN..]
+            .iter()
+            .zip(expected.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+
+        if difference == 0 {
+            Ok(())
+        } else {
+            Err(ProtocolError::InvalidMac)
+        }
+    }
+}
+
+impl Debug for KeyStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // secrets are intentionally left out
+        f.debug_map()
+            .entries(self.keys.iter().map(|(id, key)| (id, key.algorithm)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackServer;
+    use crate::{Config, SntpClient, SynchronizationError};
+    use std::net::SocketAddr;
+
+    fn key_store() -> KeyStore {
+        KeyStore::default()
+            .key(1, MacAlgorithm::AesCmac, (0..16).collect())
+            .and_then(|store| store.key(2, MacAlgorithm::Sha1, b"sha1 secret".to_vec()))
+            .and_then(|store| store.key(3, MacAlgorithm::Md5, b"md5 secret".to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn known_digests_are_computed() {
+        // RFC 4493 example 2
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let message = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+
+        assert_eq!(
+            MacAlgorithm::AesCmac.digest(&key, &message),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+        assert_eq!(
+            MacAlgorithm::Md5.digest(b"", b"abc"),
+            [
+                0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1,
+                0x7f, 0x72
+            ]
+        );
+        assert_eq!(
+            MacAlgorithm::Sha1.digest(b"a", b"bc")[..4],
+            [0xa9, 0x99, 0x3e, 0x36]
+        );
+    }
+
+    #[test]
+    fn signed_packets_are_verified() {
+        let store = key_store();
+        let packet = [0x24; Packet::ENCODED_LEN];
+
+        for (key_id, len) in [(1, 68), (2, 72), (3, 68)] {
+            let signed = store.sign(key_id, &packet).unwrap();
+
+            assert_eq!(signed.len(), len);
+            assert_eq!(store.verify(key_id, &signed), Ok(()));
+
+            let mut tampered = signed.clone();
+            tampered[10] ^= 1;
+            assert_eq!(
+                store.verify(key_id, &tampered),
+                Err(ProtocolError::InvalidMac)
+            );
+        }
+
+        assert!(store.sign(4, &packet).is_none());
+    }
+
+    #[test]
+    fn missing_or_unknown_mac_is_rejected() {
+        let store = key_store();
+        let packet = [0x24; Packet::ENCODED_LEN];
+        let signed = KeyStore::default()
+            .key(4, MacAlgorithm::Md5, b"md5 secret".to_vec())
+            .unwrap()
+            .sign(4, &packet)
+            .unwrap();
+
+        assert_eq!(store.verify(4, &packet), Err(ProtocolError::MissingMac));
+        assert_eq!(store.verify(4, &signed), Err(ProtocolError::InvalidMac));
+        assert_eq!(
+            store.verify(4, &signed[..60]),
+            Err(ProtocolError::InvalidMac)
+        );
+    }
+
+    #[test]
+    fn reply_signed_with_other_key_of_the_store_is_rejected() {
+        let store = key_store();
+        let signed = store.sign(2, &[0x24; Packet::ENCODED_LEN]).unwrap();
+
+        assert_eq!(store.verify(2, &signed), Ok(()));
+        assert_eq!(store.verify(1, &signed), Err(ProtocolError::InvalidMac));
+        assert_eq!(store.verify(3, &signed), Err(ProtocolError::InvalidMac));
+    }
+
+    #[test]
+    fn invalid_key_configuration_is_reported() {
+        assert_eq!(
+            KeyStore::default()
+                .key(1, MacAlgorithm::AesCmac, vec![0; 15])
+                .unwrap_err(),
+            KeyError::InvalidSecretLength(15)
+        );
+        assert_eq!(
+            Config::default()
+                .mac_authentication(key_store(), 4)
+                .unwrap_err(),
+            KeyError::UnknownKey(4)
+        );
+    }
+
+    /// Starts a server which verifies requests and signs replies with the supplied key
+    fn start_server(reply_key_store: KeyStore, reply_key_id: Option<u32>) -> SocketAddr {
+        let server = LoopbackServer::bind();
+        let address = server.local_addr();
+
+        server.serve_forever(move |exchange| {
+            let key_id = u32::from_be_bytes(exchange.request[48..52].try_into().unwrap());
+            assert_eq!(key_store().verify(key_id, exchange.request), Ok(()));
+
+            let reply = match reply_key_id {
+                Some(key_id) => reply_key_store.sign(key_id, &exchange.reply()).unwrap(),
+                None => exchange.reply(),
+            };
+
+            exchange.send(&reply);
+        });
+
+        address
+    }
+
+    #[test]
+    fn authenticated_synchronization_works() {
+        for key_id in 1..=3 {
+            let server = start_server(key_store(), Some(key_id));
+            let client = SntpClient::with_config(
+                Config::default()
+                    .mac_authentication(key_store(), key_id)
+                    .unwrap(),
+            );
+
+            let result = client.synchronize(server).unwrap();
+
+            assert!(result.is_authenticated());
+            assert_eq!(result.stratum(), 1);
+        }
+    }
+
+    #[test]
+    fn unauthenticated_reply_is_rejected() {
+        let unsigned_server = start_server(KeyStore::default(), None);
+        let wrong_key = KeyStore::default()
+            .key(1, MacAlgorithm::AesCmac, vec![0; 16])
+            .unwrap();
+        let wrongly_signed_server = start_server(wrong_key, Some(1));
+        let other_key_server = start_server(key_store(), Some(2));
+        let client = SntpClient::with_config(
+            Config::default()
+                .mac_authentication(key_store(), 1)
+                .unwrap(),
+        );
+
+        assert!(matches!(
+            client.synchronize(unsigned_server),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::MissingMac
+            ))
+        ));
+        assert!(matches!(
+            client.synchronize(wrongly_signed_server),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidMac
+            ))
+        ));
+        assert!(matches!(
+            client.synchronize(other_key_server),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidMac
+            ))
+        ));
+    }
+}
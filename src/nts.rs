@@ -0,0 +1,920 @@
+//! Network Time Security (NTS) client, see RFC 8915
+use crate::error::{KissCode, ProtocolError, SynchronizationError};
+use crate::extension::{self, ExtensionField};
+use crate::random::random_bytes;
+use aes_siv::siv::Aes128Siv;
+use aes_siv::KeyInit;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub(crate) const NTS_KE_PORT: u16 = 4460;
+
+const NTP_PORT: u16 = 123;
+const ALPN_PROTOCOL: &[u8] = b"ntske/1";
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+const PROTOCOL_ID_NTPV4: u16 = 0;
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+const MAX_COOKIES: usize = 8;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const UNIQUE_IDENTIFIER_LEN: usize = 32;
+
+const RECORD_END_OF_MESSAGE: u16 = 0;
+const RECORD_NEXT_PROTOCOL: u16 = 1;
+const RECORD_ERROR: u16 = 2;
+const RECORD_WARNING: u16 = 3;
+const RECORD_AEAD_ALGORITHM: u16 = 4;
+const RECORD_NEW_COOKIE: u16 = 5;
+const RECORD_SERVER: u16 = 6;
+const RECORD_PORT: u16 = 7;
+
+const FIELD_UNIQUE_IDENTIFIER: u16 = 0x0104;
+const FIELD_COOKIE: u16 = 0x0204;
+const FIELD_COOKIE_PLACEHOLDER: u16 = 0x0304;
+const FIELD_AUTHENTICATOR: u16 = 0x0404;
+
+/// Network Time Security configuration
+///
+/// Only available when the `nts` feature is enabled.
+///
+/// When set in the client configuration (see [`crate::Config::nts`]), the client authenticates
+/// the server with Network Time Security (RFC 8915). The address passed to `synchronize` is then
+/// the address of the NTS key establishment server (default port is 4460), which hands out the
+/// keys and the address of the NTP server to be used.
+///
+/// By default, the TLS certificate of the key establishment server is verified against the
+/// Mozilla root certificates.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, NtsConfig, SntpClient};
+///
+/// let client = SntpClient::with_config(Config::default().nts(NtsConfig::default()));
+/// let result = client.synchronize("time.cloudflare.com").unwrap();
+///
+/// assert!(result.is_authenticated());
+/// ```
+#[derive(Clone, Debug, Default, Hash)]
+pub struct NtsConfig {
+    root_certificates: Vec<Vec<u8>>,
+}
+
+impl NtsConfig {
+    /// Adds a trusted root certificate
+    ///
+    /// The certificate must be DER encoded. Once a root certificate is added, only the added
+    /// certificates are trusted, the default Mozilla root certificates are not used anymore.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::NtsConfig;
+    ///
+    /// let certificate = std::fs::read("ca.der").unwrap();
+    /// let config = NtsConfig::default().root_certificate(certificate);
+    /// ```
+    pub fn root_certificate(mut self, certificate: Vec<u8>) -> NtsConfig {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    fn tls_config(&self) -> Result<rustls::ClientConfig, SynchronizationError> {
+        let mut roots = rustls::RootCertStore::empty();
+
+        if self.root_certificates.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        } else {
+            for certificate in &self.root_certificates {
+                roots.add(certificate.clone().into()).map_err(tls_error)?;
+            }
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        Ok(config)
+    }
+}
+
+fn tls_error(error: rustls::Error) -> SynchronizationError {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error).into()
+}
+
+struct Record {
+    critical: bool,
+    record_type: u16,
+    body: Vec<u8>,
+}
+
+impl Record {
+    fn new(critical: bool, record_type: u16, body: &[u8]) -> Record {
+        Record {
+            critical,
+            record_type,
+            body: body.to_vec(),
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> std::io::Result<Record> {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let mut body = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut body)?;
+
+        Ok(Record {
+            critical: record_type & 0x8000 != 0,
+            record_type: record_type & 0x7fff,
+            body,
+        })
+    }
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let record_type = if self.critical {
+            self.record_type | 0x8000
+        } else {
+            self.record_type
+        };
+
+        buffer.extend_from_slice(&record_type.to_be_bytes());
+        buffer.extend_from_slice(&(self.body.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&self.body);
+    }
+
+    fn body_as_u16(&self) -> Result<u16, ProtocolError> {
+        match self.body.as_slice() {
+            [high, low] => Ok(u16::from_be_bytes([*high, *low])),
+            _ => Err(ProtocolError::NtsKeyExchangeFailed),
+        }
+    }
+}
+
+fn key_exchange_request() -> Vec<u8> {
+    let mut request = Vec::new();
+
+    Record::new(true, RECORD_NEXT_PROTOCOL, &PROTOCOL_ID_NTPV4.to_be_bytes()).encode(&mut request);
+    Record::new(
+        true,
+        RECORD_AEAD_ALGORITHM,
+        &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+    )
+    .encode(&mut request);
+    Record::new(true, RECORD_END_OF_MESSAGE, &[]).encode(&mut request);
+
+    request
+}
+
+/// Result of a successful key establishment, before the keys are exported
+#[derive(Default)]
+struct KeyExchangeResponse {
+    cookies: Vec<Vec<u8>>,
+    server: Option<String>,
+    port: Option<u16>,
+}
+
+fn read_key_exchange_response<R: Read>(
+    reader: &mut R,
+) -> Result<KeyExchangeResponse, SynchronizationError> {
+    let mut response = KeyExchangeResponse::default();
+    let mut protocol_negotiated = false;
+    let mut algorithm_negotiated = false;
+
+    loop {
+        let record = Record::read(reader)?;
+
+        match record.record_type {
+            RECORD_END_OF_MESSAGE => break,
+            RECORD_NEXT_PROTOCOL => {
+                if record.body_as_u16()? != PROTOCOL_ID_NTPV4 {
+                    return Err(ProtocolError::NtsKeyExchangeFailed.into());
+                }
+                protocol_negotiated = true;
+            }
+            RECORD_AEAD_ALGORITHM => {
+                if record.body_as_u16()? != AEAD_AES_SIV_CMAC_256 {
+                    return Err(ProtocolError::NtsKeyExchangeFailed.into());
+                }
+                algorithm_negotiated = true;
+            }
+            RECORD_NEW_COOKIE => response.cookies.push(record.body),
+            RECORD_SERVER => {
+                let server = String::from_utf8(record.body)
+                    .map_err(|_| ProtocolError::NtsKeyExchangeFailed)?;
+                response.server = Some(server);
+            }
+            RECORD_PORT => response.port = Some(record.body_as_u16()?),
+            RECORD_ERROR | RECORD_WARNING => return Err(ProtocolError::NtsKeyExchangeFailed.into()),
+            _ if record.critical => return Err(ProtocolError::NtsKeyExchangeFailed.into()),
+            _ => {} // unknown non-critical records are ignored
+        }
+    }
+
+    if !protocol_negotiated || !algorithm_negotiated || response.cookies.is_empty() {
+        return Err(ProtocolError::NtsKeyExchangeFailed.into());
+    }
+
+    Ok(response)
+}
+
+/// Performs the NTS key establishment with the server and returns with a new session
+///
+/// This is a blocking operation; the async client runs it on the blocking thread pool.
+pub(crate) fn key_exchange(
+    config: &NtsConfig,
+    server_name: &str,
+    address: SocketAddr,
+    timeout: Duration,
+) -> Result<NtsSession, SynchronizationError> {
+    if server_name.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Server name is required for NTS key establishment",
+        )
+        .into());
+    }
+
+    let server_name = rustls::pki_types::ServerName::try_from(server_name.to_string())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let connection = rustls::ClientConnection::new(Arc::new(config.tls_config()?), server_name)
+        .map_err(tls_error)?;
+
+    let socket = TcpStream::connect_timeout(&address, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+
+    let mut stream = rustls::StreamOwned::new(connection, socket);
+
+    stream.write_all(&key_exchange_request())?;
+    stream.flush()?;
+
+    let response = read_key_exchange_response(&mut stream)?;
+
+    if stream.conn.alpn_protocol() != Some(ALPN_PROTOCOL) {
+        return Err(ProtocolError::NtsKeyExchangeFailed.into());
+    }
+
+    let c2s_key = export_key(&stream.conn, 0)?;
+    let s2c_key = export_key(&stream.conn, 1)?;
+
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+
+    let port = response.port.unwrap_or(NTP_PORT);
+    let ntp_address = match response.server {
+        Some(server) => (server.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Unable to resolve NTP server address",
+                )
+            })?,
+        None => SocketAddr::new(address.ip(), port),
+    };
+
+    Ok(NtsSession {
+        ntp_address,
+        c2s: Aes128Siv::new(&c2s_key.into()),
+        s2c: Aes128Siv::new(&s2c_key.into()),
+        cookies: response.cookies,
+    })
+}
+
+fn export_key(
+    connection: &rustls::ClientConnection,
+    direction: u8,
+) -> Result<[u8; KEY_LEN], SynchronizationError> {
+    let mut context = [0; 5];
+
+    context[..2].copy_from_slice(&PROTOCOL_ID_NTPV4.to_be_bytes());
+    context[2..4].copy_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+    context[4] = direction;
+
+    connection
+        .export_keying_material([0; KEY_LEN], EXPORTER_LABEL, Some(&context))
+        .map_err(tls_error)
+}
+
+/// Keys and cookies obtained from the key establishment server
+pub(crate) struct NtsSession {
+    ntp_address: SocketAddr,
+    // AEAD_AES_SIV_CMAC_256 uses AES-128 for both the CMAC and CTR halves of the key
+    c2s: Aes128Siv,
+    s2c: Aes128Siv,
+    cookies: Vec<Vec<u8>>,
+}
+
+/// An NTS protected request
+pub(crate) struct NtsRequest {
+    bytes: Vec<u8>,
+    unique_identifier: [u8; UNIQUE_IDENTIFIER_LEN],
+}
+
+impl NtsRequest {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl NtsSession {
+    pub fn ntp_address(&self) -> SocketAddr {
+        self.ntp_address
+    }
+
+    /// Creates an NTS protected request from an NTP packet
+    ///
+    /// The packet is the NTP header, optionally followed by extension fields. Uses up one cookie
+    /// and asks the server for enough new ones to refill the cookie jar.
+    pub fn request(&mut self, packet: Vec<u8>) -> NtsRequest {
+        let unique_identifier = random_bytes::<UNIQUE_IDENTIFIER_LEN>();
+        let cookie = self.cookies.pop().unwrap_or_default();
+        let placeholders = MAX_COOKIES.saturating_sub(self.cookies.len() + 1);
+        let mut bytes = packet;
+
+        extension::encode(&mut bytes, FIELD_UNIQUE_IDENTIFIER, &unique_identifier);
+        extension::encode(&mut bytes, FIELD_COOKIE, &cookie);
+        for _ in 0..placeholders {
+            extension::encode(&mut bytes, FIELD_COOKIE_PLACEHOLDER, &vec![0; cookie.len()]);
+        }
+
+        let nonce = random_bytes::<NONCE_LEN>();
+        let ciphertext = self
+            .c2s
+            .encrypt([&bytes[..], &nonce], &[])
+            .expect("Number of associated data components is within the limit");
+        extension::encode(
+            &mut bytes,
+            FIELD_AUTHENTICATOR,
+            &authenticator_value(&nonce, &ciphertext),
+        );
+
+        NtsRequest {
+            bytes,
+            unique_identifier,
+        }
+    }
+
+    /// Authenticates a reply and stores the new cookies it contains
+    ///
+    /// Only the NTS specific parts are checked, the NTP header must be validated separately.
+    /// Returns with the authenticated extension fields: the ones before the authenticator and
+    /// the encrypted ones. Fields after the authenticator are not covered by the authentication,
+    /// so they are dropped (RFC 8915, section 5.7).
+    pub fn verify_reply(
+        &mut self,
+        reply: &[u8],
+        request: &NtsRequest,
+    ) -> Result<Vec<ExtensionField>, ProtocolError> {
+        let (fields, _) = extension::split_packet(reply)?;
+        let authenticator = fields
+            .iter()
+            .find(|field| field.field_type == FIELD_AUTHENTICATOR);
+        let authenticated_len = authenticator.map_or(reply.len(), |field| field.offset);
+
+        // the unique identifier must be part of the associated data
+        let unique_identifier = fields
+            .iter()
+            .find(|field| {
+                field.field_type == FIELD_UNIQUE_IDENTIFIER && field.offset < authenticated_len
+            })
+            .ok_or(ProtocolError::InvalidUniqueIdentifier)?;
+
+        if unique_identifier.value != request.unique_identifier {
+            return Err(ProtocolError::InvalidUniqueIdentifier);
+        }
+
+        // NTS negative acknowledgment is not authenticated, only the unique identifier is echoed
+        if reply[1] == 0 && &reply[12..16] == b"NTSN" {
+            return Err(ProtocolError::KissODeath(
+                KissCode::NtsNegativeAcknowledgment,
+            ));
+        }
+
+        let authenticator = authenticator.ok_or(ProtocolError::InvalidNtsAuthenticator)?;
+        let (nonce, ciphertext) = parse_authenticator_value(authenticator.value)
+            .ok_or(ProtocolError::InvalidNtsAuthenticator)?;
+        let associated_data = &reply[..authenticator.offset];
+        let plaintext = self
+            .s2c
+            .decrypt([associated_data, nonce], ciphertext)
+            .map_err(|_| ProtocolError::InvalidNtsAuthenticator)?;
+
+        let encrypted_fields = extension::parse(&plaintext)?;
+
+        for field in &encrypted_fields {
+            if field.field_type == FIELD_COOKIE && self.cookies.len() < MAX_COOKIES {
+                self.cookies.push(field.value.to_vec());
+            }
+        }
+
+        Ok(fields
+            .iter()
+            .filter(|field| field.offset < authenticator.offset)
+            .chain(&encrypted_fields)
+            .map(|field| field.to_extension_field())
+            .collect())
+    }
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn authenticator_value(nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut value = Vec::new();
+
+    value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    value.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    value.extend_from_slice(nonce);
+    value.resize(4 + padded_len(nonce.len()), 0);
+    value.extend_from_slice(ciphertext);
+    value.resize(
+        4 + padded_len(nonce.len()) + padded_len(ciphertext.len()),
+        0,
+    );
+
+    value
+}
+
+fn parse_authenticator_value(value: &[u8]) -> Option<(&[u8], &[u8])> {
+    if value.len() < 4 {
+        return None;
+    }
+
+    let nonce_len = u16::from_be_bytes([value[0], value[1]]) as usize;
+    let ciphertext_len = u16::from_be_bytes([value[2], value[3]]) as usize;
+    let ciphertext_start = 4 + padded_len(nonce_len);
+
+    if ciphertext_start + ciphertext_len > value.len() {
+        return None;
+    }
+
+    Some((
+        &value[4..4 + nonce_len],
+        &value[ciphertext_start..ciphertext_start + ciphertext_len],
+    ))
+}
+
+/// NTS sessions of a client, keyed by the address of the key establishment server
+///
+/// Sessions are shared between the clones of a client. They are not part of the client
+/// configuration, so they are ignored by `Hash`.
+#[derive(Clone, Default)]
+pub(crate) struct NtsSessions(Arc<Mutex<HashMap<SocketAddr, NtsSession>>>);
+
+impl NtsSessions {
+    pub fn take(&self, key_exchange_address: SocketAddr) -> Option<NtsSession> {
+        self.0.lock().unwrap().remove(&key_exchange_address)
+    }
+
+    /// Stores the session after an exchange, unless it can not be used anymore
+    pub fn store<T>(
+        &self,
+        key_exchange_address: SocketAddr,
+        session: NtsSession,
+        result: &Result<T, ProtocolError>,
+    ) {
+        let rejected = matches!(
+            result,
+            Err(ProtocolError::KissODeath(
+                KissCode::NtsNegativeAcknowledgment
+            ))
+        );
+
+        if !rejected {
+            self.restore(key_exchange_address, session);
+        }
+    }
+
+    /// Puts the session back after a failed exchange, e.g. a lost reply, if it has cookies left
+    pub fn restore(&self, key_exchange_address: SocketAddr, session: NtsSession) {
+        if !session.cookies.is_empty() {
+            self.0.lock().unwrap().insert(key_exchange_address, session);
+        }
+    }
+}
+
+impl Debug for NtsSessions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NtsSessions")
+            .field("servers", &self.0.lock().unwrap().keys())
+            .finish()
+    }
+}
+
+impl Hash for NtsSessions {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+    use crate::{Config, ServerConfig, SntpClient, SynchronizationResult};
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+
+    const UNAUTHENTICATED_FIELD: u16 = 0x2005;
+
+    #[derive(Clone, Copy)]
+    enum Behaviour {
+        Valid,
+        TamperedAuthenticator,
+        NegativeAcknowledgment,
+        FieldAfterAuthenticator,
+        FirstReplyLost,
+        UniqueIdentifierAfterAuthenticator,
+    }
+
+    /// Local NTS key establishment and NTP server stand-in
+    ///
+    /// Cookies simply contain the keys, so the NTP server does not need to keep any state.
+    struct TestServer {
+        certificate: Vec<u8>,
+        key_exchange_address: SocketAddr,
+        key_exchanges: Arc<AtomicUsize>,
+    }
+
+    impl TestServer {
+        fn start(behaviour: Behaviour) -> TestServer {
+            TestServer::start_with_name(behaviour, "127.0.0.1")
+        }
+
+        /// Starts a server with a certificate which is only valid for the server name
+        fn start_with_name(behaviour: Behaviour, server_name: &str) -> TestServer {
+            let rcgen::CertifiedKey { cert, key_pair } =
+                rcgen::generate_simple_self_signed(vec![server_name.to_string()]).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut tls_config = rustls::ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![cert.der().clone()],
+                    rustls::pki_types::PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+                )
+                .unwrap();
+            tls_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+            let ntp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let ntp_port = ntp_socket.local_addr().unwrap().port();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let key_exchange_address = listener.local_addr().unwrap();
+            let key_exchanges = Arc::new(AtomicUsize::new(0));
+            let counter = key_exchanges.clone();
+            let tls_config = Arc::new(tls_config);
+
+            std::thread::spawn(move || {
+                for socket in listener.incoming() {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    serve_key_exchange(tls_config.clone(), socket.unwrap(), ntp_port);
+                }
+            });
+            std::thread::spawn(move || serve_ntp(ntp_socket, behaviour));
+
+            TestServer {
+                certificate: cert.der().to_vec(),
+                key_exchange_address,
+                key_exchanges,
+            }
+        }
+
+        fn config(&self) -> Config {
+            Config::default().nts(NtsConfig::default().root_certificate(self.certificate.clone()))
+        }
+    }
+
+    fn serve_key_exchange(tls_config: Arc<rustls::ServerConfig>, socket: TcpStream, port: u16) {
+        let connection = rustls::ServerConnection::new(tls_config).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, socket);
+
+        while Record::read(&mut stream).unwrap().record_type != RECORD_END_OF_MESSAGE {}
+
+        let mut cookie = [0; 2 * KEY_LEN];
+        for (direction, key) in cookie.chunks_mut(KEY_LEN).enumerate() {
+            let context = [0, 0, 0, AEAD_AES_SIV_CMAC_256 as u8, direction as u8];
+            stream
+                .conn
+                .export_keying_material(key, EXPORTER_LABEL, Some(&context))
+                .unwrap();
+        }
+
+        let mut response = Vec::new();
+        Record::new(true, RECORD_NEXT_PROTOCOL, &[0, 0]).encode(&mut response);
+        Record::new(true, RECORD_AEAD_ALGORITHM, &[0, 15]).encode(&mut response);
+        for _ in 0..MAX_COOKIES {
+            Record::new(false, RECORD_NEW_COOKIE, &cookie).encode(&mut response);
+        }
+        Record::new(false, RECORD_SERVER, b"127.0.0.1").encode(&mut response);
+        Record::new(false, RECORD_PORT, &port.to_be_bytes()).encode(&mut response);
+        Record::new(true, RECORD_END_OF_MESSAGE, &[]).encode(&mut response);
+
+        stream.write_all(&response).unwrap();
+        stream.flush().unwrap();
+    }
+
+    fn serve_ntp(socket: UdpSocket, behaviour: Behaviour) {
+        let mut buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let mut lost = matches!(behaviour, Behaviour::FirstReplyLost);
+
+        loop {
+            let (len, source) = socket.recv_from(&mut buffer).unwrap();
+            if std::mem::take(&mut lost) {
+                continue;
+            }
+
+            let request = &buffer[..len];
+            let (fields, _) = extension::split_packet(request).unwrap();
+            let field = |field_type| fields.iter().find(|f| f.field_type == field_type).unwrap();
+
+            let cookie = field(FIELD_COOKIE).value;
+            let mut c2s = Aes128Siv::new_from_slice(&cookie[..KEY_LEN]).unwrap();
+            let mut s2c = Aes128Siv::new_from_slice(&cookie[KEY_LEN..]).unwrap();
+
+            let authenticator = field(FIELD_AUTHENTICATOR);
+            let (nonce, ciphertext) = parse_authenticator_value(authenticator.value).unwrap();
+            let associated_data = &request[..authenticator.offset];
+            assert!(c2s.decrypt([associated_data, nonce], ciphertext).is_ok());
+
+            let header = ServerConfig::default()
+                .reply(&request[..Packet::ENCODED_LEN], source, SystemTime::now())
+                .unwrap();
+            let mut reply = header.to_vec();
+            let unique_identifier = field(FIELD_UNIQUE_IDENTIFIER).value;
+            let late_identifier =
+                matches!(behaviour, Behaviour::UniqueIdentifierAfterAuthenticator);
+            if !late_identifier {
+                extension::encode(&mut reply, FIELD_UNIQUE_IDENTIFIER, unique_identifier);
+            }
+
+            if let Behaviour::NegativeAcknowledgment = behaviour {
+                reply[1] = 0;
+                reply[12..16].copy_from_slice(b"NTSN");
+            } else {
+                let mut cookies = Vec::new();
+                for field in fields
+                    .iter()
+                    .filter(|f| f.field_type != FIELD_UNIQUE_IDENTIFIER)
+                {
+                    if field.field_type == FIELD_COOKIE
+                        || field.field_type == FIELD_COOKIE_PLACEHOLDER
+                    {
+                        extension::encode(&mut cookies, FIELD_COOKIE, cookie);
+                    }
+                }
+
+                let nonce = random_bytes::<NONCE_LEN>();
+                let mut ciphertext = s2c.encrypt([&reply[..], &nonce], &cookies).unwrap();
+                if let Behaviour::TamperedAuthenticator = behaviour {
+                    ciphertext[0] ^= 1;
+                }
+
+                extension::encode(
+                    &mut reply,
+                    FIELD_AUTHENTICATOR,
+                    &authenticator_value(&nonce, &ciphertext),
+                );
+
+                if let Behaviour::FieldAfterAuthenticator = behaviour {
+                    extension::encode(&mut reply, UNAUTHENTICATED_FIELD, &[0xaa; 12]);
+                }
+
+                if late_identifier {
+                    extension::encode(&mut reply, FIELD_UNIQUE_IDENTIFIER, unique_identifier);
+                }
+            }
+
+            socket.send_to(&reply, source).unwrap();
+        }
+    }
+
+    fn check_result(result: &SynchronizationResult) {
+        assert!(result.is_authenticated());
+        assert!(
+            result
+                .clock_offset()
+                .abs_as_std_duration()
+                .unwrap()
+                .as_secs_f64()
+                < 0.1
+        );
+        assert_eq!(result.stratum(), 1);
+    }
+
+    #[test]
+    fn nts_synchronization_works() {
+        let server = TestServer::start(Behaviour::Valid);
+        let client = SntpClient::with_config(server.config());
+
+        for _ in 0..3 {
+            check_result(&client.synchronize(server.key_exchange_address).unwrap());
+        }
+
+        assert_eq!(server.key_exchanges.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_nts_synchronization_works() {
+        let server = TestServer::start(Behaviour::Valid);
+        let client = crate::AsyncSntpClient::with_config(server.config());
+
+        for _ in 0..3 {
+            check_result(
+                &client
+                    .synchronize(server.key_exchange_address)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        assert_eq!(server.key_exchanges.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn synchronize_all_uses_the_server_name() {
+        let server = TestServer::start_with_name(Behaviour::Valid, "localhost");
+        let client = SntpClient::with_config(server.config());
+
+        let results = client
+            .synchronize_all(("localhost", server.key_exchange_address.port()))
+            .unwrap();
+
+        check_result(results[&server.key_exchange_address].as_ref().unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_synchronize_all_uses_the_server_name() {
+        let server = TestServer::start_with_name(Behaviour::Valid, "localhost");
+        let client = crate::AsyncSntpClient::with_config(server.config());
+
+        let results = client
+            .synchronize_all(("localhost", server.key_exchange_address.port()))
+            .await
+            .unwrap();
+
+        check_result(results[&server.key_exchange_address].as_ref().unwrap());
+    }
+
+    #[test]
+    fn guarded_client_uses_the_server_name() {
+        let server = TestServer::start_with_name(Behaviour::Valid, "localhost");
+        let client = crate::GuardedSntpClient::with_config(server.config());
+
+        let result = client
+            .synchronize(("localhost", server.key_exchange_address.port()))
+            .unwrap();
+
+        check_result(&result);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_guarded_client_uses_the_server_name() {
+        let server = TestServer::start_with_name(Behaviour::Valid, "localhost");
+        let client = crate::AsyncGuardedSntpClient::with_config(server.config());
+
+        let result = client
+            .synchronize(("localhost", server.key_exchange_address.port()))
+            .await
+            .unwrap();
+
+        check_result(&result);
+    }
+
+    #[test]
+    fn tampered_reply_is_rejected() {
+        let server = TestServer::start(Behaviour::TamperedAuthenticator);
+        let client = SntpClient::with_config(server.config());
+
+        let result = client.synchronize(server.key_exchange_address);
+
+        assert!(matches!(
+            result,
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidNtsAuthenticator
+            ))
+        ));
+    }
+
+    #[test]
+    fn fields_after_the_authenticator_are_not_exposed() {
+        let server = TestServer::start(Behaviour::FieldAfterAuthenticator);
+        let client = SntpClient::with_config(server.config());
+
+        let result = client.synchronize(server.key_exchange_address).unwrap();
+        let field_types: Vec<u16> = result
+            .extension_fields()
+            .iter()
+            .map(|field| field.field_type())
+            .collect();
+
+        check_result(&result);
+        assert!(field_types.contains(&FIELD_UNIQUE_IDENTIFIER));
+        assert!(field_types.contains(&FIELD_COOKIE));
+        assert!(!field_types.contains(&FIELD_AUTHENTICATOR));
+        assert!(!field_types.contains(&UNAUTHENTICATED_FIELD));
+    }
+
+    #[test]
+    fn unique_identifier_after_the_authenticator_is_rejected() {
+        let server = TestServer::start(Behaviour::UniqueIdentifierAfterAuthenticator);
+        let client = SntpClient::with_config(server.config());
+
+        let result = client.synchronize(server.key_exchange_address);
+
+        assert!(matches!(
+            result,
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidUniqueIdentifier
+            ))
+        ));
+    }
+
+    #[test]
+    fn negative_acknowledgment_drops_the_session() {
+        let server = TestServer::start(Behaviour::NegativeAcknowledgment);
+        let client = SntpClient::with_config(server.config());
+
+        for _ in 0..2 {
+            let result = client.synchronize(server.key_exchange_address);
+
+            assert!(matches!(
+                result,
+                Err(SynchronizationError::ProtocolError(
+                    ProtocolError::KissODeath(KissCode::NtsNegativeAcknowledgment)
+                ))
+            ));
+        }
+
+        assert_eq!(server.key_exchanges.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn lost_reply_keeps_the_session() {
+        let server = TestServer::start(Behaviour::FirstReplyLost);
+        let client = SntpClient::with_config(
+            server
+                .config()
+                .timeout(std::time::Duration::from_millis(200)),
+        );
+
+        assert!(matches!(
+            client.synchronize(server.key_exchange_address),
+            Err(SynchronizationError::IOError(_))
+        ));
+
+        check_result(&client.synchronize(server.key_exchange_address).unwrap());
+        assert_eq!(server.key_exchanges.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn missing_server_name_is_rejected() {
+        struct Unnamed(SocketAddr);
+
+        impl crate::ToServerAddrs for Unnamed {
+            type Return = SocketAddr;
+
+            fn to_server_addrs(&self, _default_port: u16) -> SocketAddr {
+                self.0
+            }
+        }
+
+        let server = TestServer::start(Behaviour::Valid);
+        let client = SntpClient::with_config(server.config());
+
+        let result = client.synchronize(Unnamed(server.key_exchange_address));
+
+        assert!(matches!(
+            result,
+            Err(SynchronizationError::IOError(err)) if err.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        assert_eq!(server.key_exchanges.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn untrusted_certificate_is_rejected() {
+        let server = TestServer::start(Behaviour::Valid);
+        let client = SntpClient::with_config(Config::default().nts(NtsConfig::default()));
+
+        let result = client.synchronize(server.key_exchange_address);
+
+        assert!(matches!(result, Err(SynchronizationError::IOError(_))));
+    }
+}
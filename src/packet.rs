@@ -1,59 +1,139 @@
-use crate::error::ProtocolError;
+//! NTP packet encoding and decoding
+//!
+//! This is the codec used by the clients and the server of this crate. It can be used to build and
+//! parse NTP packets directly, e.g. in packet capture tools or test fixtures.
+//!
+//! Only the 48 bytes long header is handled, data following it (extension fields, message
+//! authentication code) is ignored during decoding.
+//!
+//! # Example
+//!
+//! ```
+//! use rsntp::packet::{Mode, Packet, SntpTimestamp};
+//! use std::net::Ipv4Addr;
+//! use std::time::SystemTime;
+//!
+//! let request = Packet::new(Mode::Client)
+//!     .poll(6)
+//!     .transmit_timestamp(SntpTimestamp::from_systemtime(SystemTime::now()).unwrap());
+//! let encoded = request.encode();
+//!
+//! let decoded = Packet::decode(&encoded, Ipv4Addr::LOCALHOST.into()).unwrap();
+//!
+//! assert_eq!(decoded.mode, Mode::Client);
+//! assert_eq!(decoded.transmit_timestamp, request.transmit_timestamp);
+//! ```
+pub use crate::error::DecodeError;
+use crate::error::{ConversionError, ReferenceIdentifierError};
 use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 use std::ops::Sub;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
+/// NTP timestamp
+///
+/// Fixed point number of seconds since 1900-01-01 00:00:00, with 32 bits for the integer and
+/// 32 bits for the fractional part. Timestamps with the most significant bit cleared are assumed
+/// to belong to the era starting in 2036, see RFC 4330, section 3.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct SntpTimestamp(u128);
 
 impl SntpTimestamp {
     const UNIX_EPOCH: u128 = 2_208_988_800;
+    /// End of the era starting in 2036, later timestamps cannot be encoded
+    const END_OF_ERA_1: u128 = 0x0002_0000_0000_0000_0000;
 
+    /// Creates a zero timestamp, which means that the time is unknown
     pub fn zero() -> SntpTimestamp {
         SntpTimestamp(0)
     }
 
-    pub fn from_systemtime(system_time: SystemTime) -> SntpTimestamp {
-        let duration_since_unix_epoch = system_time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        let seconds = duration_since_unix_epoch.as_secs() as u128 + SntpTimestamp::UNIX_EPOCH;
-        let subsec_nanos =
-            ((duration_since_unix_epoch.subsec_nanos() as u128) << 32) / 1_000_000_000;
+    /// Creates a timestamp from a system time
+    ///
+    /// Returns an error if the system time is earlier than the NTP epoch (1900-01-01) or it is
+    /// later than the end of the era starting in 2036 (2172-03-15), as such timestamps cannot be
+    /// encoded.
+    pub fn from_systemtime(system_time: SystemTime) -> Result<SntpTimestamp, ConversionError> {
+        let unix_epoch = SntpTimestamp::UNIX_EPOCH << 32;
+        let timestamp = match system_time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since_unix_epoch) => unix_epoch + SntpTimestamp::fixed_point(since_unix_epoch),
+            Err(before_unix_epoch) => unix_epoch
+                .checked_sub(SntpTimestamp::fixed_point(before_unix_epoch.duration()))
+                .ok_or(ConversionError::Overflow)?,
+        };
+
+        if timestamp >= SntpTimestamp::END_OF_ERA_1 {
+            return Err(ConversionError::Overflow);
+        }
 
-        SntpTimestamp((seconds << 32) + subsec_nanos)
+        Ok(SntpTimestamp(timestamp))
     }
 
-    pub fn is_zero(&self) -> bool {
-        self.0 == 0
+    /// Creates a timestamp from a system time, clamped to the range of the encodable timestamps
+    pub(crate) fn saturating_from_systemtime(system_time: SystemTime) -> SntpTimestamp {
+        SntpTimestamp::from_systemtime(system_time).unwrap_or_else(|_| {
+            if system_time < SystemTime::UNIX_EPOCH {
+                // the smallest non-zero timestamp, zero means that the time is unknown
+                SntpTimestamp(1)
+            } else {
+                SntpTimestamp(SntpTimestamp::END_OF_ERA_1 - 1)
+            }
+        })
     }
 
-    fn from_bytes(bytes: [u8; 8]) -> SntpTimestamp {
-        let timestamp = u64::from_be_bytes(bytes);
+    /// Converts a duration to the fixed point format of the timestamps
+    fn fixed_point(duration: Duration) -> u128 {
+        let subsec_fraction = ((duration.subsec_nanos() as u128) << 32) / 1_000_000_000;
+
+        ((duration.as_secs() as u128) << 32) + subsec_fraction
+    }
 
-        if (timestamp & 0x8000_0000_0000_0000) == 0 {
-            SntpTimestamp(timestamp as u128 + 0x0001_0000_0000_0000_0000)
+    /// Creates a random timestamp, which can be used as a nonce instead of the real time
+    pub(crate) fn random() -> SntpTimestamp {
+        SntpTimestamp::from_bits(crate::random::random_u64())
+    }
+
+    /// Creates a timestamp from its raw, 64 bits wire format
+    pub fn from_bits(bits: u64) -> SntpTimestamp {
+        if bits == 0 {
+            SntpTimestamp::zero()
+        } else if (bits & 0x8000_0000_0000_0000) == 0 {
+            SntpTimestamp(bits as u128 + 0x0001_0000_0000_0000_0000)
         } else {
-            SntpTimestamp(timestamp as u128)
+            SntpTimestamp(bits as u128)
         }
     }
 
-    fn to_bytes(self) -> [u8; 8] {
-        assert!(self.0 < 0x0002_0000_0000_0000_0000);
+    /// Returns the raw, 64 bits wire format of the timestamp
+    pub fn to_bits(self) -> u64 {
+        assert!(self.0 < SntpTimestamp::END_OF_ERA_1);
 
-        let timestamp = if self.0 < 0x0001_0000_0000_0000_0000 {
+        if self.0 < 0x0001_0000_0000_0000_0000 {
             self.0 as u64
         } else {
             (self.0 & 0x7fff_ffff_ffff_ffff) as u64
-        };
+        }
+    }
+
+    /// Returns true if the timestamp is zero
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn from_bytes(bytes: [u8; 8]) -> SntpTimestamp {
+        SntpTimestamp::from_bits(u64::from_be_bytes(bytes))
+    }
 
-        timestamp.to_be_bytes()
+    fn to_bytes(self) -> [u8; 8] {
+        self.to_bits().to_be_bytes()
     }
 }
 
 impl Sub<SntpTimestamp> for SntpTimestamp {
     type Output = f64;
 
+    /// Difference of the timestamps in seconds
     fn sub(self, rhs: SntpTimestamp) -> Self::Output {
         if self.0 >= rhs.0 {
             (self.0 - rhs.0) as f64 / 4294967296.0
@@ -63,6 +143,48 @@ impl Sub<SntpTimestamp> for SntpTimestamp {
     }
 }
 
+/// NTP short format
+///
+/// Fixed point number of seconds, with 16 bits for the integer and 16 bits for the fractional
+/// part. Used for the root delay and root dispersion.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SntpShortFormat(u32);
+
+impl SntpShortFormat {
+    /// Creates a zero value
+    pub fn zero() -> SntpShortFormat {
+        SntpShortFormat(0)
+    }
+
+    /// Creates a value from seconds, negative values are converted to zero
+    pub fn from_secs_f64(secs: f64) -> SntpShortFormat {
+        SntpShortFormat((secs * 65536.0) as u32)
+    }
+
+    /// Returns the value in seconds
+    pub fn as_secs_f64(&self) -> f64 {
+        self.0 as f64 / 65536.0
+    }
+
+    /// Creates a value from its raw, 32 bits wire format
+    pub fn from_bits(bits: u32) -> SntpShortFormat {
+        SntpShortFormat(bits)
+    }
+
+    /// Returns the raw, 32 bits wire format of the value
+    pub fn to_bits(self) -> u32 {
+        self.0
+    }
+
+    fn from_bytes(bytes: [u8; 4]) -> SntpShortFormat {
+        SntpShortFormat(u32::from_be_bytes(bytes))
+    }
+
+    fn to_bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+}
+
 /// Leap indicator
 ///
 /// Indicator of an impending leap second to be inserted/deleted in the last minute of the current day.
@@ -82,48 +204,103 @@ pub enum LeapIndicator {
 }
 
 impl LeapIndicator {
-    fn from_u8(raw: u8) -> Result<LeapIndicator, ProtocolError> {
-        match raw {
-            0 => Ok(LeapIndicator::NoWarning),
-            1 => Ok(LeapIndicator::LastMinuteHas59Seconds),
-            2 => Ok(LeapIndicator::LastMinuteHas61Seconds),
-            3 => Ok(LeapIndicator::AlarmCondition),
-            _ => Err(ProtocolError::InvalidLeapIndicator),
+    fn from_u8(raw: u8) -> LeapIndicator {
+        match raw & 0x03 {
+            0 => LeapIndicator::NoWarning,
+            1 => LeapIndicator::LastMinuteHas61Seconds,
+            2 => LeapIndicator::LastMinuteHas59Seconds,
+            _ => LeapIndicator::AlarmCondition,
         }
     }
 
     fn to_u8(self) -> u8 {
         match self {
             LeapIndicator::NoWarning => 0,
-            LeapIndicator::LastMinuteHas59Seconds => 1,
-            LeapIndicator::LastMinuteHas61Seconds => 2,
+            LeapIndicator::LastMinuteHas61Seconds => 1,
+            LeapIndicator::LastMinuteHas59Seconds => 2,
             LeapIndicator::AlarmCondition => 3,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Association mode
+///
+/// See RFC 5905, section 7.3. Clients send requests in [`Mode::Client`] and servers reply in
+/// [`Mode::Server`], other modes are only supported by the packet codec.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Mode {
+    /// Reserved, mode 0
+    Reserved,
+    /// Symmetric active, mode 1
+    SymmetricActive,
+    /// Symmetric passive, mode 2
+    SymmetricPassive,
+    /// Client, mode 3
     Client,
+    /// Server, mode 4
     Server,
+    /// Broadcast server, mode 5
     Broadcast,
+    /// NTP control message, mode 6
+    Control,
+    /// Reserved for private use, mode 7
+    Private,
 }
 
 impl Mode {
-    fn from_u8(raw: u8) -> Result<Mode, ProtocolError> {
-        match raw {
-            3 => Ok(Mode::Client),
-            4 => Ok(Mode::Server),
-            5 => Ok(Mode::Broadcast),
-            _ => Err(ProtocolError::InvalidMode),
+    /// Creates a mode from its numeric value, only the lowest 3 bits are used
+    pub fn from_u8(raw: u8) -> Mode {
+        match raw & 0x07 {
+            0 => Mode::Reserved,
+            1 => Mode::SymmetricActive,
+            2 => Mode::SymmetricPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::Control,
+            _ => Mode::Private,
         }
     }
 
-    fn to_u8(self) -> u8 {
+    /// Returns the numeric value of the mode
+    pub fn to_u8(self) -> u8 {
         match self {
+            Mode::Reserved => 0,
+            Mode::SymmetricActive => 1,
+            Mode::SymmetricPassive => 2,
             Mode::Client => 3,
             Mode::Server => 4,
             Mode::Broadcast => 5,
+            Mode::Control => 6,
+            Mode::Private => 7,
+        }
+    }
+}
+
+/// NTP version
+///
+/// Versions 1 to 4 are supported, see [`crate::Config::version`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum NtpVersion {
+    /// NTP version 1 (RFC 1059)
+    V1,
+    /// NTP version 2 (RFC 1119)
+    V2,
+    /// NTP version 3 (RFC 1305)
+    V3,
+    /// NTP version 4 (RFC 5905)
+    #[default]
+    V4,
+}
+
+impl NtpVersion {
+    /// Returns the numeric value of the version
+    pub fn to_u8(self) -> u8 {
+        match self {
+            NtpVersion::V1 => 1,
+            NtpVersion::V2 => 2,
+            NtpVersion::V3 => 3,
+            NtpVersion::V4 => 4,
         }
     }
 }
@@ -134,6 +311,10 @@ impl Mode {
 /// * For IPv4 secondary servers, the value is the IPv4 address of the synchronization source.
 /// * For IPv6 secondary servers, the value is the first 32 bits of the MD5 hash of the IPv6 address of the
 ///   synchronization source
+///
+/// Only identifiers passing [`ReferenceIdentifier::validate`] can be encoded into a packet: ASCII
+/// strings must be at most four characters long, and IPv6 sources must be identified by
+/// [`ReferenceIdentifier::MD5Hash`] instead of their address.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum ReferenceIdentifier {
     /// No reference identifier. Currently should not used in public API
@@ -147,9 +328,9 @@ pub enum ReferenceIdentifier {
 }
 
 impl ReferenceIdentifier {
-    pub(crate) fn new_ascii(raw: [u8; 4]) -> Result<ReferenceIdentifier, ProtocolError> {
+    pub(crate) fn new_ascii(raw: [u8; 4]) -> Result<ReferenceIdentifier, DecodeError> {
         if !raw.is_ascii() {
-            return Err(ProtocolError::InvalidReferenceIdentifier);
+            return Err(DecodeError::InvalidReferenceIdentifier(raw));
         }
 
         Ok(ReferenceIdentifier::ASCII(
@@ -159,16 +340,58 @@ impl ReferenceIdentifier {
         ))
     }
 
-    pub(crate) fn new_ipv4_address(raw: [u8; 4]) -> Result<ReferenceIdentifier, ProtocolError> {
+    pub(crate) fn new_ipv4_address(raw: [u8; 4]) -> Result<ReferenceIdentifier, DecodeError> {
         Ok(ReferenceIdentifier::IpAddress(IpAddr::from(raw)))
     }
 
-    pub(crate) fn new_ipv6_hash(raw: [u8; 4]) -> Result<ReferenceIdentifier, ProtocolError> {
+    pub(crate) fn new_ipv6_hash(raw: [u8; 4]) -> Result<ReferenceIdentifier, DecodeError> {
         Ok(ReferenceIdentifier::MD5Hash(u32::from_be_bytes(raw)))
     }
 
-    fn is_empty(&self) -> bool {
-        matches!(self, ReferenceIdentifier::Empty)
+    /// Checks whether the reference identifier can be encoded into a packet
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::{ReferenceIdentifier, ReferenceIdentifierError};
+    ///
+    /// assert_eq!(ReferenceIdentifier::ASCII("GPS".into()).validate(), Ok(()));
+    /// assert_eq!(
+    ///     ReferenceIdentifier::ASCII("GOOGLE".into()).validate(),
+    ///     Err(ReferenceIdentifierError::TooLong(6))
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), ReferenceIdentifierError> {
+        match self {
+            ReferenceIdentifier::ASCII(s) if !s.is_ascii() => {
+                Err(ReferenceIdentifierError::NotAscii)
+            }
+            ReferenceIdentifier::ASCII(s) if s.len() > 4 => {
+                Err(ReferenceIdentifierError::TooLong(s.len()))
+            }
+            ReferenceIdentifier::IpAddress(IpAddr::V6(_)) => {
+                Err(ReferenceIdentifierError::Ipv6Address)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 4] {
+        match self {
+            ReferenceIdentifier::Empty => [0; 4],
+            ReferenceIdentifier::ASCII(s) => {
+                let mut raw = [0; 4];
+
+                for (target, source) in raw.iter_mut().zip(s.bytes()) {
+                    *target = source;
+                }
+
+                raw
+            }
+            ReferenceIdentifier::IpAddress(IpAddr::V4(addr)) => addr.octets(),
+            ReferenceIdentifier::IpAddress(IpAddr::V6(_)) => [0; 4],
+            ReferenceIdentifier::MD5Hash(hash) => hash.to_be_bytes(),
+        }
     }
 }
 
@@ -183,42 +406,208 @@ impl Display for ReferenceIdentifier {
     }
 }
 
+/// NTP packet header
+///
+/// All header fields are public, so a packet can be created with a struct literal as well as with
+/// [`Packet::new`] and the builder-like methods.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Packet {
+    /// Leap indicator
     pub li: LeapIndicator,
+    /// Version number
+    pub version: u8,
+    /// Association mode
     pub mode: Mode,
+    /// Stratum, 0 for Kiss-o'-Death packets and 1 for primary servers
     pub stratum: u8,
+    /// Maximum interval between successive messages in log2 seconds
+    pub poll: i8,
+    /// Precision of the system clock in log2 seconds
+    pub precision: i8,
+    /// Total round-trip delay to the reference clock
+    pub root_delay: SntpShortFormat,
+    /// Total dispersion to the reference clock
+    pub root_dispersion: SntpShortFormat,
+    /// Reference identifier, or kiss code in Kiss-o'-Death packets
     pub reference_identifier: ReferenceIdentifier,
+    /// Time when the system clock was last set or corrected
     pub reference_timestamp: SntpTimestamp,
+    /// Time at the client when the request departed for the server
     pub originate_timestamp: SntpTimestamp,
+    /// Time at the server when the request arrived from the client
     pub receive_timestamp: SntpTimestamp,
+    /// Time at the server when the reply departed for the client
     pub transmit_timestamp: SntpTimestamp,
 }
 
 impl Packet {
+    /// Length of the encoded packet header
     pub const ENCODED_LEN: usize = 48;
+    /// Size of the receive buffers, large enough for extension fields and MAC
+    pub const MAX_RECEIVE_LEN: usize = 2048;
+    /// Default version number of the packets
+    pub const VERSION: u8 = 4;
+
+    /// Creates a packet with the given mode
+    ///
+    /// The version number is [`Packet::VERSION`], all other fields are zero or empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rsntp::packet::{Mode, Packet};
+    /// use rsntp::ReferenceIdentifier;
+    ///
+    /// let packet = Packet::new(Mode::Server)
+    ///     .stratum(1)
+    ///     .reference_identifier(ReferenceIdentifier::ASCII("GPS".into()))
+    ///     .unwrap();
+    /// ```
+    pub fn new(mode: Mode) -> Packet {
+        Packet {
+            li: LeapIndicator::NoWarning,
+            version: Packet::VERSION,
+            mode,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
+            reference_identifier: ReferenceIdentifier::Empty,
+            reference_timestamp: SntpTimestamp::zero(),
+            originate_timestamp: SntpTimestamp::zero(),
+            receive_timestamp: SntpTimestamp::zero(),
+            transmit_timestamp: SntpTimestamp::zero(),
+        }
+    }
+
+    /// Sets the leap indicator
+    pub fn leap_indicator(self, li: LeapIndicator) -> Packet {
+        Packet { li, ..self }
+    }
+
+    /// Sets the version number
+    ///
+    /// Only the lowest 3 bits are encoded and only versions 1 to 4 can be decoded, see
+    /// [`Packet::encode`].
+    pub fn version(self, version: u8) -> Packet {
+        Packet { version, ..self }
+    }
 
-    pub fn from_bytes(data: &[u8], server_address: SocketAddr) -> Result<Packet, ProtocolError> {
+    /// Sets the association mode
+    pub fn mode(self, mode: Mode) -> Packet {
+        Packet { mode, ..self }
+    }
+
+    /// Sets the stratum
+    pub fn stratum(self, stratum: u8) -> Packet {
+        Packet { stratum, ..self }
+    }
+
+    /// Sets the poll interval in log2 seconds
+    pub fn poll(self, poll: i8) -> Packet {
+        Packet { poll, ..self }
+    }
+
+    /// Sets the precision in log2 seconds
+    pub fn precision(self, precision: i8) -> Packet {
+        Packet { precision, ..self }
+    }
+
+    /// Sets the root delay
+    pub fn root_delay(self, root_delay: Duration) -> Packet {
+        Packet {
+            root_delay: SntpShortFormat::from_secs_f64(root_delay.as_secs_f64()),
+            ..self
+        }
+    }
+
+    /// Sets the root dispersion
+    pub fn root_dispersion(self, root_dispersion: Duration) -> Packet {
+        Packet {
+            root_dispersion: SntpShortFormat::from_secs_f64(root_dispersion.as_secs_f64()),
+            ..self
+        }
+    }
+
+    /// Sets the reference identifier
+    ///
+    /// Returns an error if the reference identifier cannot be encoded, see
+    /// [`ReferenceIdentifier::validate`].
+    pub fn reference_identifier(
+        self,
+        reference_identifier: ReferenceIdentifier,
+    ) -> Result<Packet, ReferenceIdentifierError> {
+        reference_identifier.validate()?;
+
+        Ok(Packet {
+            reference_identifier,
+            ..self
+        })
+    }
+
+    /// Sets the reference timestamp
+    pub fn reference_timestamp(self, reference_timestamp: SntpTimestamp) -> Packet {
+        Packet {
+            reference_timestamp,
+            ..self
+        }
+    }
+
+    /// Sets the originate timestamp
+    pub fn originate_timestamp(self, originate_timestamp: SntpTimestamp) -> Packet {
+        Packet {
+            originate_timestamp,
+            ..self
+        }
+    }
+
+    /// Sets the receive timestamp
+    pub fn receive_timestamp(self, receive_timestamp: SntpTimestamp) -> Packet {
+        Packet {
+            receive_timestamp,
+            ..self
+        }
+    }
+
+    /// Sets the transmit timestamp
+    pub fn transmit_timestamp(self, transmit_timestamp: SntpTimestamp) -> Packet {
+        Packet {
+            transmit_timestamp,
+            ..self
+        }
+    }
+
+    /// Decodes a packet header
+    ///
+    /// The source address of the packet is needed to interpret the reference identifier of
+    /// secondary servers, which is an IPv4 address for IPv4 sources and a hash for IPv6 sources,
+    /// regardless of the version of the packet.
+    ///
+    /// Data after the header is ignored. Versions 1 to 4 are supported.
+    pub fn decode(data: &[u8], source: IpAddr) -> Result<Packet, DecodeError> {
         if data.len() < Packet::ENCODED_LEN {
-            return Err(ProtocolError::PacketIsTooShort);
+            return Err(DecodeError::TooShort(data.len()));
         }
 
         let version = (data[0] >> 3) & 0x07;
 
         match version {
-            3 | 4 => {} // OK
-            _ => return Err(ProtocolError::InvalidPacketVersion),
+            1..=4 => {} // OK
+            _ => return Err(DecodeError::UnsupportedVersion(version)),
         }
 
-        let li = LeapIndicator::from_u8(data[0] >> 6)?;
-        let mode = Mode::from_u8(data[0] & 0x07)?;
+        let li = LeapIndicator::from_u8(data[0] >> 6);
+        let mode = Mode::from_u8(data[0]);
         let stratum = data[1];
+        let poll = data[2] as i8;
+        let precision = data[3] as i8;
 
         let raw_reference_identifier = data[12..16].try_into().unwrap();
 
         let reference_identifier = if stratum == 0 || stratum == 1 {
             ReferenceIdentifier::new_ascii(raw_reference_identifier)?
-        } else if server_address.is_ipv4() {
+        } else if source.is_ipv4() {
             ReferenceIdentifier::new_ipv4_address(raw_reference_identifier)?
         } else {
             ReferenceIdentifier::new_ipv6_hash(raw_reference_identifier)?
@@ -226,8 +615,13 @@ impl Packet {
 
         Ok(Packet {
             li,
+            version,
             mode,
             stratum,
+            poll,
+            precision,
+            root_delay: SntpShortFormat::from_bytes(data[4..8].try_into().unwrap()),
+            root_dispersion: SntpShortFormat::from_bytes(data[8..12].try_into().unwrap()),
             reference_identifier,
             reference_timestamp: SntpTimestamp::from_bytes(data[16..24].try_into().unwrap()),
             originate_timestamp: SntpTimestamp::from_bytes(data[24..32].try_into().unwrap()),
@@ -236,18 +630,29 @@ impl Packet {
         })
     }
 
-    pub fn to_bytes(&self) -> [u8; Packet::ENCODED_LEN] {
-        const SNTP_VERSION_CONSTANT: u8 = 0x20;
+    /// Encodes the packet header
+    ///
+    /// Decoding the encoded packet gives back the same packet only if it is valid:
+    ///
+    /// * Only the lowest 3 bits of the version number are encoded, and [`Packet::decode`] accepts
+    ///   versions 1 to 4 only.
+    /// * The reference identifier has to pass [`ReferenceIdentifier::validate`], as it is checked
+    ///   by [`Packet::reference_identifier`] only. Otherwise ASCII strings are truncated to four
+    ///   bytes and IPv6 addresses are encoded as zero.
+    /// * The type of the decoded reference identifier depends on the stratum and the source
+    ///   address (see [`Packet::decode`]), so it has to match them, e.g. the reference identifier
+    ///   of a stratum 1 packet has to be [`ReferenceIdentifier::ASCII`].
+    pub fn encode(&self) -> [u8; Packet::ENCODED_LEN] {
         let mut binary = [0; Packet::ENCODED_LEN];
 
-        binary[0] = self.li.to_u8() << 6 | SNTP_VERSION_CONSTANT | self.mode.to_u8();
+        binary[0] = self.li.to_u8() << 6 | (self.version & 0x07) << 3 | self.mode.to_u8();
         binary[1] = self.stratum;
+        binary[2] = self.poll as u8;
+        binary[3] = self.precision as u8;
+        binary[4..8].copy_from_slice(&self.root_delay.to_bytes());
+        binary[8..12].copy_from_slice(&self.root_dispersion.to_bytes());
 
-        assert!(
-            self.reference_identifier.is_empty(),
-            "Reference identifier should be empty for client packets"
-        );
-
+        binary[12..16].copy_from_slice(&self.reference_identifier.to_bytes());
         binary[16..24].copy_from_slice(&self.reference_timestamp.to_bytes());
         binary[24..32].copy_from_slice(&self.originate_timestamp.to_bytes());
         binary[32..40].copy_from_slice(&self.receive_timestamp.to_bytes());
@@ -260,6 +665,7 @@ impl Packet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::ProtocolError;
     use std::time::Duration;
 
     #[test]
@@ -273,6 +679,7 @@ mod tests {
     #[test]
     fn zero_timestamp_is_zero() {
         assert!(SntpTimestamp::zero().is_zero());
+        assert!(SntpTimestamp::from_bytes([0; 8]).is_zero());
     }
 
     #[test]
@@ -292,11 +699,13 @@ mod tests {
         // 2004-09-27, 03:11:08
         let before_2036 = SntpTimestamp::from_systemtime(
             SystemTime::UNIX_EPOCH + Duration::new(1096254668, 213_800_999),
-        );
+        )
+        .unwrap();
         // 2040:06-01 08:00:00
         let after_2036 = SntpTimestamp::from_systemtime(
             SystemTime::UNIX_EPOCH + Duration::new(2222150400, 500_000_000),
-        );
+        )
+        .unwrap();
 
         assert_eq!(
             before_2036.to_bytes(),
@@ -309,15 +718,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn timestamp_from_systemtime_before_unix_epoch_works() {
+        // 1969-12-31 23:59:59.5
+        let before_unix_epoch =
+            SntpTimestamp::from_systemtime(SystemTime::UNIX_EPOCH - Duration::from_millis(500))
+                .unwrap();
+        let ntp_epoch = SystemTime::UNIX_EPOCH - Duration::from_secs(2_208_988_800);
+
+        assert_eq!(
+            before_unix_epoch.to_bytes(),
+            [0x83, 0xaa, 0x7e, 0x7f, 0x80, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            SntpTimestamp::from_systemtime(ntp_epoch - Duration::from_secs(1)),
+            Err(ConversionError::Overflow)
+        );
+        assert_eq!(
+            SntpTimestamp::saturating_from_systemtime(ntp_epoch - Duration::from_secs(1)).to_bits(),
+            1
+        );
+    }
+
+    #[test]
+    fn timestamp_from_systemtime_after_era_1_is_rejected() {
+        // 2172-03-15 06:28:16
+        let end_of_era_1 = SystemTime::UNIX_EPOCH + Duration::from_secs((1 << 33) - 2_208_988_800);
+
+        assert!(SntpTimestamp::from_systemtime(end_of_era_1 - Duration::from_nanos(1)).is_ok());
+        assert_eq!(
+            SntpTimestamp::from_systemtime(end_of_era_1),
+            Err(ConversionError::Overflow)
+        );
+    }
+
     #[test]
     fn subtracting_timestamps_works_correctly() {
         let now = SystemTime::now();
         let past = now - Duration::from_secs(3600);
         let future = now + Duration::from_secs(3600);
 
-        let now_sntp = SntpTimestamp::from_systemtime(now);
-        let past_sntp = SntpTimestamp::from_systemtime(past);
-        let future_sntp = SntpTimestamp::from_systemtime(future);
+        let now_sntp = SntpTimestamp::from_systemtime(now).unwrap();
+        let past_sntp = SntpTimestamp::from_systemtime(past).unwrap();
+        let future_sntp = SntpTimestamp::from_systemtime(future).unwrap();
 
         assert_eq!(future_sntp - now_sntp, 3600.0);
         assert_eq!(future_sntp - past_sntp, 7200.0);
@@ -335,11 +778,21 @@ mod tests {
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
-        let packet = Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap();
+        let packet = Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
 
         assert_eq!(packet.li, LeapIndicator::NoWarning);
         assert_eq!(packet.mode, Mode::Client);
         assert_eq!(packet.stratum, 2);
+        assert_eq!(packet.poll, 10);
+        assert_eq!(packet.precision, -20);
+        assert_eq!(
+            packet.root_delay,
+            SntpShortFormat::from_bytes([0x00, 0x00, 0x02, 0x86])
+        );
+        assert_eq!(
+            packet.root_dispersion,
+            SntpShortFormat::from_bytes([0x00, 0x00, 0x0b, 0x33])
+        );
         assert_eq!(
             packet.reference_identifier,
             ReferenceIdentifier::IpAddress(IpAddr::from([0xcc, 0x7b, 0x02, 0x48]))
@@ -372,24 +825,56 @@ mod tests {
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
-        Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap();
+        Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
     }
 
     #[test]
     fn decoding_a_packet_with_wrong_version_fails() {
         let raw = [
-            0x12, 0x02, 0x0a, 0xec, 0x00, 0x00, 0x02, 0x86, 0x00, 0x00, 0x0b, 0x33, 0xcc, 0x7b,
+            0x2a, 0x02, 0x0a, 0xec, 0x00, 0x00, 0x02, 0x86, 0x00, 0x00, 0x0b, 0x33, 0xcc, 0x7b,
             0x02, 0x48, 0xc5, 0x02, 0x02, 0xac, 0x41, 0x6e, 0x15, 0x87, 0xc5, 0x02, 0x04, 0xec,
             0xee, 0xd3, 0x3c, 0x52, 0xc5, 0x02, 0x04, 0xeb, 0xd9, 0xd8, 0xd7, 0x9d, 0xc5, 0x02,
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
         assert_eq!(
-            Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap_err(),
-            ProtocolError::InvalidPacketVersion
+            Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap_err(),
+            DecodeError::UnsupportedVersion(5)
+        );
+
+        let mut raw = Packet::new(Mode::Server).encode();
+        raw[0] = 0x04;
+
+        assert_eq!(
+            Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap_err(),
+            DecodeError::UnsupportedVersion(0)
         );
     }
 
+    #[test]
+    fn decoding_packets_with_old_versions_works() {
+        for version in 1..=3 {
+            let packet = Packet::new(Mode::Server)
+                .version(version)
+                .stratum(2)
+                .reference_identifier(ReferenceIdentifier::IpAddress("10.0.0.1".parse().unwrap()))
+                .unwrap();
+
+            assert_eq!(
+                Packet::decode(&packet.encode(), "127.0.0.1".parse().unwrap()).unwrap(),
+                packet
+            );
+
+            // reference identifier of IPv6 sources is a hash even if the version predates IPv6
+            assert_eq!(
+                Packet::decode(&packet.encode(), std::net::Ipv6Addr::LOCALHOST.into())
+                    .unwrap()
+                    .reference_identifier,
+                ReferenceIdentifier::MD5Hash(0x0a00_0001)
+            );
+        }
+    }
+
     #[test]
     fn decoding_a_short_packet_fails() {
         let raw = [
@@ -398,13 +883,13 @@ mod tests {
         ];
 
         assert_eq!(
-            Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap_err(),
-            ProtocolError::PacketIsTooShort
+            Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap_err(),
+            DecodeError::TooShort(16)
         );
     }
 
     #[test]
-    fn decoding_a_packet_with_illegal_mode_fails() {
+    fn decoding_a_packet_with_reserved_mode_works() {
         let raw = [
             0x20, 0x02, 0x0a, 0xec, 0x00, 0x00, 0x02, 0x86, 0x00, 0x00, 0x0b, 0x33, 0xcc, 0x7b,
             0x02, 0x48, 0xc5, 0x02, 0x02, 0xac, 0x41, 0x6e, 0x15, 0x87, 0xc5, 0x02, 0x04, 0xec,
@@ -413,8 +898,76 @@ mod tests {
         ];
 
         assert_eq!(
-            Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap_err(),
-            ProtocolError::InvalidMode
+            Packet::decode(&raw, "127.0.0.1".parse().unwrap())
+                .unwrap()
+                .mode,
+            Mode::Reserved
+        );
+    }
+
+    #[test]
+    fn all_modes_are_encoded_and_decoded() {
+        for raw in 0..8 {
+            let mode = Mode::from_u8(raw);
+            let packet = Packet::new(mode)
+                .stratum(2)
+                .reference_identifier(ReferenceIdentifier::IpAddress("10.0.0.1".parse().unwrap()))
+                .unwrap();
+            let encoded = packet.encode();
+
+            assert_eq!(mode.to_u8(), raw);
+            assert_eq!(encoded[0], 0x20 | raw);
+            assert_eq!(
+                Packet::decode(&encoded, "127.0.0.1".parse().unwrap()).unwrap(),
+                packet
+            );
+        }
+    }
+
+    #[test]
+    fn packet_builder_round_trips() {
+        let now = SystemTime::now();
+        let packet = Packet::new(Mode::Client)
+            .leap_indicator(LeapIndicator::LastMinuteHas59Seconds)
+            .version(3)
+            .mode(Mode::SymmetricActive)
+            .stratum(3)
+            .poll(6)
+            .precision(-18)
+            .root_delay(Duration::from_millis(125))
+            .root_dispersion(Duration::from_millis(250))
+            .reference_identifier(ReferenceIdentifier::MD5Hash(0x01020304))
+            .unwrap()
+            .reference_timestamp(SntpTimestamp::from_systemtime(now).unwrap())
+            .originate_timestamp(SntpTimestamp::from_bits(0x0102_0304_0506_0708))
+            .receive_timestamp(SntpTimestamp::from_bits(0xc502_04eb_d9d8_d79d))
+            .transmit_timestamp(
+                SntpTimestamp::from_systemtime(now + Duration::from_secs(1)).unwrap(),
+            );
+        let encoded = packet.encode();
+
+        assert_eq!(encoded[0], 0x99);
+        assert_eq!(packet.root_delay.to_bits(), 0x2000);
+        assert_eq!(packet.originate_timestamp.to_bits(), 0x0102_0304_0506_0708);
+        assert_eq!(
+            Packet::decode(&encoded, std::net::Ipv6Addr::LOCALHOST.into()).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn decoding_invalid_ascii_reference_identifier_fails() {
+        let mut raw = Packet::new(Mode::Server).stratum(1).encode();
+
+        raw[12..16].copy_from_slice(&[0x4c, 0x4f, 0xc3, 0x4c]);
+
+        assert_eq!(
+            Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap_err(),
+            DecodeError::InvalidReferenceIdentifier([0x4c, 0x4f, 0xc3, 0x4c])
+        );
+        assert_eq!(
+            ProtocolError::from(DecodeError::InvalidReferenceIdentifier([0; 4])),
+            ProtocolError::InvalidReferenceIdentifier
         );
     }
 
@@ -422,8 +975,13 @@ mod tests {
     fn encoding_a_packet_works() {
         let packet = Packet {
             li: LeapIndicator::NoWarning,
+            version: 4,
             mode: Mode::Client,
             stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: SntpShortFormat::zero(),
+            root_dispersion: SntpShortFormat::zero(),
             reference_identifier: ReferenceIdentifier::Empty,
             reference_timestamp: SntpTimestamp::from_bytes([
                 0xc5, 0x02, 0x02, 0xac, 0x41, 0x6e, 0x15, 0x87,
@@ -440,7 +998,7 @@ mod tests {
         };
 
         assert_eq!(
-            packet.to_bytes().to_vec(),
+            packet.encode().to_vec(),
             vec![
                 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                 0x00, 0x00, 0xc5, 0x02, 0x02, 0xac, 0x41, 0x6e, 0x15, 0x87, 0xc5, 0x02, 0x04, 0xec,
@@ -450,13 +1008,90 @@ mod tests {
         );
     }
     #[test]
-    #[should_panic]
-    fn encoding_a_packet_with_non_empty_reference_identifier_fails() {
+    fn encoding_reference_identifier_works() {
+        assert_eq!(ReferenceIdentifier::Empty.to_bytes(), [0, 0, 0, 0]);
+        assert_eq!(
+            ReferenceIdentifier::ASCII("LOCL".into()).to_bytes(),
+            [0x4c, 0x4f, 0x43, 0x4c]
+        );
+        assert_eq!(
+            ReferenceIdentifier::ASCII("GPS".into()).to_bytes(),
+            [0x47, 0x50, 0x53, 0x00]
+        );
+        assert_eq!(
+            ReferenceIdentifier::IpAddress(IpAddr::from([192, 168, 0, 1])).to_bytes(),
+            [192, 168, 0, 1]
+        );
+        assert_eq!(
+            ReferenceIdentifier::MD5Hash(0x01020304).to_bytes(),
+            [0x01, 0x02, 0x03, 0x04]
+        );
+    }
+
+    #[test]
+    fn unencodable_reference_identifiers_are_rejected() {
+        let packet = Packet::new(Mode::Server);
+
+        assert_eq!(
+            packet
+                .clone()
+                .reference_identifier(ReferenceIdentifier::ASCII("GOOGLE".into()))
+                .unwrap_err(),
+            ReferenceIdentifierError::TooLong(6)
+        );
+        assert_eq!(
+            packet
+                .clone()
+                .reference_identifier(ReferenceIdentifier::ASCII("GPSé".into()))
+                .unwrap_err(),
+            ReferenceIdentifierError::NotAscii
+        );
+        assert_eq!(
+            packet
+                .reference_identifier(ReferenceIdentifier::IpAddress("::1".parse().unwrap()))
+                .unwrap_err(),
+            ReferenceIdentifierError::Ipv6Address
+        );
+    }
+
+    #[test]
+    fn encoding_invalid_packets_is_lossy() {
+        let packet = Packet::new(Mode::Server)
+            .version(12)
+            .stratum(1)
+            .reference_identifier(ReferenceIdentifier::ASCII("LOCL".into()))
+            .unwrap();
+
+        assert_eq!(
+            Packet::decode(&packet.encode(), "127.0.0.1".parse().unwrap()).unwrap(),
+            packet.clone().version(4)
+        );
+
+        let packet = Packet {
+            reference_identifier: ReferenceIdentifier::ASCII("GOOGLE".into()),
+            ..packet.version(4)
+        };
+
+        assert_eq!(
+            Packet::decode(&packet.encode(), "127.0.0.1".parse().unwrap())
+                .unwrap()
+                .reference_identifier,
+            ReferenceIdentifier::ASCII("GOOG".into())
+        );
+    }
+
+    #[test]
+    fn encoding_a_server_packet_works() {
         let packet = Packet {
             li: LeapIndicator::NoWarning,
-            mode: Mode::Client,
-            stratum: 0,
-            reference_identifier: ReferenceIdentifier::ASCII("abcd".into()),
+            version: 4,
+            mode: Mode::Server,
+            stratum: 1,
+            poll: 10,
+            precision: -20,
+            root_delay: SntpShortFormat::from_bytes([0x00, 0x00, 0x02, 0x86]),
+            root_dispersion: SntpShortFormat::from_bytes([0x00, 0x00, 0x0b, 0x33]),
+            reference_identifier: ReferenceIdentifier::ASCII("LOCL".into()),
             reference_timestamp: SntpTimestamp::from_bytes([
                 0xc5, 0x02, 0x02, 0xac, 0x41, 0x6e, 0x15, 0x87,
             ]),
@@ -471,7 +1106,19 @@ mod tests {
             ]),
         };
 
-        let _ = packet.to_bytes();
+        let encoded = packet.encode();
+
+        assert_eq!(
+            encoded[..16].to_vec(),
+            vec![
+                0x24, 0x01, 0x0a, 0xec, 0x00, 0x00, 0x02, 0x86, 0x00, 0x00, 0x0b, 0x33, 0x4c, 0x4f,
+                0x43, 0x4c
+            ]
+        );
+        assert_eq!(
+            Packet::decode(&encoded, "127.0.0.1".parse().unwrap()).unwrap(),
+            packet
+        );
     }
 
     #[test]
@@ -483,7 +1130,7 @@ mod tests {
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
-        let packet = Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap();
+        let packet = Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
 
         assert_eq!(
             packet.reference_identifier,
@@ -500,7 +1147,7 @@ mod tests {
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
-        let packet = Packet::from_bytes(&raw, "127.0.0.1:1234".parse().unwrap()).unwrap();
+        let packet = Packet::decode(&raw, "127.0.0.1".parse().unwrap()).unwrap();
 
         assert_eq!(
             packet.reference_identifier,
@@ -517,8 +1164,7 @@ mod tests {
             0x04, 0xeb, 0xd9, 0xdc, 0xb5, 0x78,
         ];
 
-        let packet =
-            Packet::from_bytes(&raw, (std::net::Ipv6Addr::LOCALHOST, 1234).into()).unwrap();
+        let packet = Packet::decode(&raw, std::net::Ipv6Addr::LOCALHOST.into()).unwrap();
 
         assert_eq!(
             packet.reference_identifier,
@@ -0,0 +1,29 @@
+/// Returns with random bytes from the operating system's random number generator
+///
+/// # Panics
+///
+/// Panics if the random number generator of the operating system is not available.
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).expect("Operating system random source is not available");
+    bytes
+}
+
+/// Returns with a random 64-bit number from the operating system's random number generator
+///
+/// The numbers are unpredictable, so they can be used as nonces (e.g. the random transmit
+/// timestamp of RFC 9109).
+pub(crate) fn random_u64() -> u64 {
+    u64::from_ne_bytes(random_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_numbers_differ() {
+        assert_ne!(random_u64(), random_u64());
+        assert_ne!(random_bytes::<16>(), random_bytes::<16>());
+    }
+}
@@ -1,8 +1,11 @@
+use crate::clock_source::{Anchor, SharedClockSource};
 use crate::error::ConversionError;
-use crate::packet::{LeapIndicator, ReferenceIdentifier};
+use crate::extension::ExtensionField;
+use crate::filter::{Sample, FREQUENCY_TOLERANCE};
+use crate::packet::{LeapIndicator, Packet, ReferenceIdentifier};
 #[cfg(all(feature = "chrono", feature = "time"))]
 use std::convert::TryInto;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 /// Represents a signed duration value.
 ///
@@ -130,14 +133,50 @@ impl TryInto<time::Duration> for SntpDuration {
 ///
 /// If `chrono` crate support is enabled then it will have [`TryInto<chrono::DateTime<Utc>>`] implemented.
 /// If `time` crate support is enabled then it will have [`TryInto<time::OffsetDateTime>`] implemented.
-#[derive(Debug, Clone, Copy)]
+///
+/// The local time is read from the clock source of the synchronization (see
+/// [`crate::Config::clock_source`]). With the default [`crate::SystemClock`], it is anchored to
+/// the monotonic clock of the host at the time of synchronization, so the returned values are not
+/// affected by changes of the system clock after synchronization. Since it keeps the clock source to
+/// read the local time on conversion, it is `Clone` but not `Copy`.
+#[derive(Debug, Clone)]
 pub struct SntpDateTime {
     offset: SntpDuration,
+    clock_source: SharedClockSource,
+    anchor_system_time: SystemTime,
+    anchor_instant: Instant,
 }
 
 impl SntpDateTime {
+    #[cfg(test)]
     pub(crate) fn new(offset: SntpDuration) -> SntpDateTime {
-        SntpDateTime { offset }
+        SntpDateTime::with_anchor(
+            offset,
+            SharedClockSource::default(),
+            SystemTime::now(),
+            Instant::now(),
+        )
+    }
+
+    pub(crate) fn with_anchor(
+        offset: SntpDuration,
+        clock_source: SharedClockSource,
+        anchor_system_time: SystemTime,
+        anchor_instant: Instant,
+    ) -> SntpDateTime {
+        SntpDateTime {
+            offset,
+            clock_source,
+            anchor_system_time,
+            anchor_instant,
+        }
+    }
+
+    /// Local time, read from the clock source since the anchor
+    fn local_time(&self) -> Result<SystemTime, ConversionError> {
+        Ok(self
+            .clock_source
+            .now_since(self.anchor_system_time, self.anchor_instant))
     }
 
     /// Returns with the duration since Unix epoch i.e. Unix timestamp
@@ -146,10 +185,8 @@ impl SntpDateTime {
     /// the date is not representable with a Unix timestamp (like it is
     /// before Unix epoch).
     ///
-    /// Note that the function uses the actual system time during execution
-    /// so assumes that it is monotonic. If the time has been changed
-    /// between the actual synchronization and the call of this function,
-    /// then it may return with undefined results.
+    /// The function uses the monotonic clock to calculate the time elapsed since
+    /// synchronization, so changes of the system clock do not affect the result.
     ///
     /// ```no_run
     /// use rsntp::SntpClient;
@@ -160,17 +197,8 @@ impl SntpDateTime {
     /// let unix_timetamp_utc = result.datetime().unix_timestamp().unwrap();
     /// ```
     pub fn unix_timestamp(&self) -> Result<std::time::Duration, ConversionError> {
-        let now = SystemTime::now();
-
-        let corrected = if self.offset.signum() >= 0 {
-            now.checked_add(self.offset.abs_as_std_duration()?)
-                .ok_or(ConversionError::Overflow)?
-        } else {
-            now.checked_sub(self.offset.abs_as_std_duration()?)
-                .ok_or(ConversionError::Overflow)?
-        };
-
-        corrected
+        self.clone()
+            .into_system_time()?
             .duration_since(SystemTime::UNIX_EPOCH)
             .map_err(|_| ConversionError::Overflow)
     }
@@ -206,12 +234,14 @@ impl TryInto<std::time::SystemTime> for SntpDateTime {
     type Error = ConversionError;
 
     fn try_into(self) -> Result<std::time::SystemTime, ConversionError> {
+        let local_time = self.local_time()?;
+
         if self.offset.signum() > 0 {
-            SystemTime::now()
+            local_time
                 .checked_add(self.offset.abs_as_std_duration()?)
                 .ok_or(ConversionError::Overflow)
         } else {
-            SystemTime::now()
+            local_time
                 .checked_sub(self.offset.abs_as_std_duration()?)
                 .ok_or(ConversionError::Overflow)
         }
@@ -225,7 +255,7 @@ impl TryInto<chrono::DateTime<chrono::Utc>> for SntpDateTime {
     fn try_into(self) -> Result<chrono::DateTime<chrono::Utc>, ConversionError> {
         let chrono_offset: chrono::Duration = self.offset.try_into()?;
 
-        chrono::Utc::now()
+        chrono::DateTime::<chrono::Utc>::from(self.local_time()?)
             .checked_add_signed(chrono_offset)
             .ok_or(ConversionError::Overflow)
     }
@@ -238,7 +268,7 @@ impl TryInto<time::OffsetDateTime> for SntpDateTime {
     fn try_into(self) -> Result<time::OffsetDateTime, ConversionError> {
         let time_offset: time::Duration = self.offset.try_into()?;
 
-        time::OffsetDateTime::now_utc()
+        time::OffsetDateTime::from(self.local_time()?)
             .checked_add(time_offset)
             .ok_or(ConversionError::Overflow)
     }
@@ -256,22 +286,96 @@ pub struct SynchronizationResult {
     reference_identifier: ReferenceIdentifier,
     leap_indicator: LeapIndicator,
     stratum: u8,
+    version: u8,
+    poll: i8,
+    precision: i8,
+    root_delay_s: f64,
+    root_dispersion_s: f64,
+    dispersion_s: f64,
+    jitter_s: f64,
+    jitter_samples: usize,
+    attempts: u32,
+    authenticated: bool,
+    extension_fields: Vec<ExtensionField>,
+    clock_source: SharedClockSource,
+    anchor_system_time: SystemTime,
+    anchor_instant: Instant,
 }
 
 impl SynchronizationResult {
     pub(crate) fn new(
         clock_offset_s: f64,
         round_trip_delay_s: f64,
-        reference_identifier: ReferenceIdentifier,
-        leap_indicator: LeapIndicator,
-        stratum: u8,
+        reply: &Packet,
     ) -> SynchronizationResult {
         SynchronizationResult {
             clock_offset_s,
             round_trip_delay_s,
-            reference_identifier,
-            leap_indicator,
-            stratum,
+            reference_identifier: reply.reference_identifier.clone(),
+            leap_indicator: reply.li,
+            stratum: reply.stratum,
+            version: reply.version,
+            poll: reply.poll,
+            precision: reply.precision,
+            root_delay_s: reply.root_delay.as_secs_f64(),
+            root_dispersion_s: reply.root_dispersion.as_secs_f64(),
+            dispersion_s: 2f64.powi(reply.precision as i32)
+                + FREQUENCY_TOLERANCE * round_trip_delay_s.abs(),
+            jitter_s: 0.0,
+            jitter_samples: 1,
+            attempts: 1,
+            authenticated: false,
+            extension_fields: Vec::new(),
+            clock_source: SharedClockSource::default(),
+            anchor_system_time: SystemTime::now(),
+            anchor_instant: Instant::now(),
+        }
+    }
+
+    pub(crate) fn sample(&self) -> Sample {
+        Sample {
+            offset: self.clock_offset_s,
+            delay: self.round_trip_delay_s,
+            dispersion: self.dispersion_s,
+            time: self.anchor_instant,
+        }
+    }
+
+    /// Sets the clock source of the synchronization, the result is anchored to its reading taken
+    /// when the reply has been received
+    pub(crate) fn set_clock_source(&mut self, clock_source: SharedClockSource, anchor: Anchor) {
+        self.anchor_instant = anchor.instant;
+        self.anchor_system_time = anchor.time;
+        self.clock_source = clock_source;
+    }
+
+    pub(crate) fn set_filter_output(&mut self, dispersion_s: f64, jitter_s: f64, samples: usize) {
+        self.dispersion_s = dispersion_s;
+        self.jitter_s = jitter_s;
+        self.jitter_samples = samples;
+    }
+
+    /// Returns with the jitter measured by the clock filter, `None` for a single sample
+    pub(crate) fn measured_jitter(&self) -> Option<f64> {
+        (self.jitter_samples > 1).then_some(self.jitter_s)
+    }
+
+    pub(crate) fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts;
+    }
+
+    pub(crate) fn set_authenticated(&mut self, authenticated: bool) {
+        self.authenticated = authenticated;
+    }
+
+    pub(crate) fn set_extension_fields(&mut self, extension_fields: Vec<ExtensionField>) {
+        self.extension_fields = extension_fields;
+    }
+
+    pub(crate) fn with_clock_offset(&self, clock_offset_s: f64) -> SynchronizationResult {
+        SynchronizationResult {
+            clock_offset_s,
+            ..self.clone()
         }
     }
 
@@ -336,8 +440,10 @@ impl SynchronizationResult {
 
     /// Returns with the current UTC date and time, based on the synchronized SNTP timestamp.
     ///
-    /// This is the current UTC date and time, calculated by adding clock offset the UTC time. To be accurate,
-    /// use the returned value immediately.
+    /// This is the current UTC date and time, calculated by adding clock offset the UTC time. With the
+    /// default clock source, the returned value is anchored to the monotonic clock at the time of
+    /// synchronization, so it is not affected by later changes of the system clock. However, it does not compensate for the drift of
+    /// the local clock, so the accuracy decreases as time passes after synchronization.
     ///
     /// # Example
     ///
@@ -351,7 +457,12 @@ impl SynchronizationResult {
     /// let unix_timetamp_utc = result.datetime().unix_timestamp().unwrap();
     /// ```
     pub fn datetime(&self) -> SntpDateTime {
-        SntpDateTime::new(self.clock_offset())
+        SntpDateTime::with_anchor(
+            self.clock_offset(),
+            self.clock_source.clone(),
+            self.anchor_system_time,
+            self.anchor_instant,
+        )
     }
 
     /// Returns with the leap indicator
@@ -403,6 +514,242 @@ impl SynchronizationResult {
     pub fn stratum(&self) -> u8 {
         self.stratum
     }
+
+    /// Returns with the NTP version of the server reply
+    ///
+    /// Servers usually reply with the version of the request, which can be selected with
+    /// [`crate::Config::version`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// assert_eq!(result.version(), 4);
+    /// ```
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns with the poll interval reported by the server
+    ///
+    /// This is the maximum interval between successive messages the server suggests, decoded from the
+    /// poll exponent of the reply (i.e. the interval is 2<sup>poll</sup> seconds).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// println!("Poll interval: {} s", result.poll_interval().as_secs_f64());
+    /// ```
+    pub fn poll_interval(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(2f64.powi(self.poll.into()))
+    }
+
+    /// Returns with the precision of the server clock
+    ///
+    /// This is decoded from the precision exponent of the reply (i.e. the precision is
+    /// 2<sup>precision</sup> seconds). For instance, a precision of about one microsecond is
+    /// returned for a server with a precision exponent of -20.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// println!("Server precision: {} s", result.precision().as_secs_f64());
+    /// ```
+    pub fn precision(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(2f64.powi(self.precision.into()))
+    }
+
+    /// Returns with the root delay
+    ///
+    /// This is the total round-trip delay from the server to the reference clock (i.e. to the
+    /// stratum 0 source), as reported by the server.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// println!("Root delay: {} ms", result.root_delay().as_secs_f64() * 1000.0);
+    /// ```
+    pub fn root_delay(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.root_delay_s)
+    }
+
+    /// Returns with the root dispersion
+    ///
+    /// This is the total dispersion (i.e. the maximum error) from the server to the reference
+    /// clock, as reported by the server.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// println!("Root dispersion: {} ms", result.root_dispersion().as_secs_f64() * 1000.0);
+    /// ```
+    pub fn root_dispersion(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.root_dispersion_s)
+    }
+
+    /// Returns with the synchronization distance
+    ///
+    /// Synchronization distance (also known as root distance, see RFC 5905 section 11.2) is the
+    /// maximum error of the synchronized time relative to the reference clock. It is calculated as
+    /// half of the total delay (root delay plus round trip delay) plus the total dispersion (root
+    /// dispersion plus the dispersion of this measurement, which is made up of the server
+    /// precision and the frequency tolerance over the round trip delay).
+    ///
+    /// Servers with a large synchronization distance should be considered unreliable.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// if result.synchronization_distance().as_secs_f64() > 1.0 {
+    ///     println!("Server is too far from its reference clock");
+    /// }
+    /// ```
+    pub fn synchronization_distance(&self) -> SntpDuration {
+        let delay = self.round_trip_delay_s.abs();
+
+        SntpDuration::from_secs_f64(
+            (self.root_delay_s + delay) / 2.0
+                + self.root_dispersion_s
+                + self.dispersion_s
+                + self.jitter_s,
+        )
+    }
+
+    /// Returns with the dispersion of the clock offset
+    ///
+    /// This is the maximum error of the measurement, caused by the precision of the server clock
+    /// and the frequency tolerance over the round trip delay. For results of
+    /// [`crate::SntpClient::synchronize_with_samples`], it is the dispersion calculated by the
+    /// clock filter algorithm (RFC 5905 section 10).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize_with_samples("pool.ntp.org", 8).unwrap();
+    ///
+    /// println!("Dispersion: {} ms", result.dispersion().as_secs_f64() * 1000.0);
+    /// ```
+    pub fn dispersion(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.dispersion_s)
+    }
+
+    /// Returns with the jitter of the clock offset
+    ///
+    /// Jitter is the root mean square of the differences between the clock offsets of the samples
+    /// collected by [`crate::SntpClient::synchronize_with_samples`] and the selected one. It is
+    /// zero for results of a single exchange.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize_with_samples("pool.ntp.org", 8).unwrap();
+    ///
+    /// println!("Jitter: {} ms", result.jitter().as_secs_f64() * 1000.0);
+    /// ```
+    pub fn jitter(&self) -> SntpDuration {
+        SntpDuration::from_secs_f64(self.jitter_s)
+    }
+
+    /// Returns with the number of attempts needed for the synchronization
+    ///
+    /// It is 1 if the synchronization succeeded at the first attempt, greater if it has been
+    /// retried according to the retry policy (see [`crate::RetryPolicy`]).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{Config, RetryPolicy, SntpClient};
+    ///
+    /// let config = Config::default().retry_policy(RetryPolicy::default().attempts(3));
+    /// let client = SntpClient::with_config(config);
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// println!("Synchronized after {} attempt(s)", result.attempts());
+    /// ```
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns true if the server reply has been cryptographically authenticated
+    ///
+    /// Replies are only authenticated if it is enabled in the client configuration, i.e. with
+    /// Network Time Security (`nts` feature) or symmetric key authentication (`mac` feature).
+    /// Otherwise, it is always false.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// if !result.is_authenticated() {
+    ///     println!("Warning: time is not authenticated");
+    /// }
+    /// ```
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Returns with the extension fields of the server reply
+    ///
+    /// Extension fields are defined in RFC 7822. It is empty if the server reply did not contain
+    /// any extension fields.
+    ///
+    /// With Network Time Security, only the authenticated fields are returned: the ones preceding
+    /// the NTS authenticator and the encrypted ones.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client.synchronize("pool.ntp.org").unwrap();
+    ///
+    /// for field in result.extension_fields() {
+    ///     println!("Extension field of type {:#06x}", field.field_type());
+    /// }
+    /// ```
+    pub fn extension_fields(&self) -> &[ExtensionField] {
+        &self.extension_fields
+    }
 }
 
 #[cfg(test)]
@@ -500,6 +847,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sntp_date_time_is_anchored_to_monotonic_clock() {
+        let anchor_system_time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let datetime = SntpDateTime::with_anchor(
+            SntpDuration::from_secs_f64(-100.0),
+            SharedClockSource::default(),
+            anchor_system_time,
+            Instant::now(),
+        );
+
+        let timestamp = datetime.unix_timestamp().unwrap();
+
+        assert!(timestamp >= std::time::Duration::from_secs(900));
+        assert!(timestamp < std::time::Duration::from_secs(901));
+    }
+
+    #[test]
+    fn setting_clock_source_uses_both_readings_of_the_anchor() {
+        let packet = crate::packet::Packet::new(crate::packet::Mode::Server);
+        let mut result = SynchronizationResult::new(0.0, 0.0, &packet);
+        let clock_source = SharedClockSource::default();
+        let mut anchor = clock_source.anchor();
+
+        // the reply has been received 10 seconds ago
+        anchor.time -= std::time::Duration::from_secs(10);
+        anchor.instant -= std::time::Duration::from_secs(10);
+        result.set_clock_source(clock_source, anchor);
+
+        let datetime = result.datetime().into_system_time().unwrap();
+        let now = SystemTime::now();
+        let difference = datetime
+            .duration_since(now)
+            .unwrap_or_else(|err| err.duration());
+
+        assert!(difference < std::time::Duration::from_secs(1));
+    }
+
     #[cfg(feature = "chrono")]
     #[test]
     fn sntp_date_time_converting_to_chrono_datetime_works() {
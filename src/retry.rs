@@ -0,0 +1,309 @@
+use crate::error::{KissCode, ProtocolError, SynchronizationError};
+use crate::random::random_u64;
+use std::time::Duration;
+
+/// Retry policy
+///
+/// Describes how many times and when a failed synchronization is retried. A synchronization
+/// attempt is retried if no reply has been received within the attempt timeout, or if a
+/// Kiss-o'-Death reply has been received with one of the kiss codes configured to be retried.
+///
+/// Between attempts, the client waits for an exponentially increasing backoff time, optionally
+/// with a random jitter.
+///
+/// It uses a builder-like pattern similar to [`crate::Config`]. The default policy makes
+/// a single attempt, i.e. it does not retry.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, KissCode, RetryPolicy, SntpClient};
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy::default()
+///     .attempts(3)
+///     .attempt_timeout(Duration::from_secs(1))
+///     .retry_on_kiss_code(KissCode::AssociationNotYetSynchronized);
+/// let config = Config::default().retry_policy(policy);
+/// let client = SntpClient::with_config(config);
+/// ```
+#[derive(Clone, Debug, Hash)]
+pub struct RetryPolicy {
+    attempts: u32,
+    attempt_timeout: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retry_kiss_codes: Vec<KissCode>,
+}
+
+impl RetryPolicy {
+    /// Sets the maximum number of attempts
+    ///
+    /// This includes the first attempt, i.e. `1` means no retries. Zero is treated as one.
+    /// Default is 1.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::RetryPolicy;
+    ///
+    /// let policy = RetryPolicy::default().attempts(3);
+    /// ```
+    pub fn attempts(self, attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts: attempts.max(1),
+            ..self
+        }
+    }
+
+    /// Sets the timeout of a single attempt
+    ///
+    /// Sets the time the client waits for a reply in each attempt. By default, the timeout of
+    /// the client configuration is used.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::RetryPolicy;
+    /// use std::time::Duration;
+    ///
+    /// let policy = RetryPolicy::default().attempt_timeout(Duration::from_millis(500));
+    /// ```
+    pub fn attempt_timeout(self, timeout: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempt_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets the backoff times
+    ///
+    /// The wait time before the first retry is `initial`, and it is doubled before each further
+    /// retry, up to `max`. Default is 1 second initial and 16 seconds maximum backoff.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::RetryPolicy;
+    /// use std::time::Duration;
+    ///
+    /// let policy = RetryPolicy::default().backoff(Duration::from_millis(200), Duration::from_secs(2));
+    /// ```
+    pub fn backoff(self, initial: Duration, max: Duration) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Enables or disables backoff jitter
+    ///
+    /// If enabled, the backoff time is randomized between half and full of the calculated
+    /// exponential backoff time. It avoids synchronized retries of many clients. Enabled by default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::RetryPolicy;
+    ///
+    /// let policy = RetryPolicy::default().jitter(false);
+    /// ```
+    pub fn jitter(self, jitter: bool) -> RetryPolicy {
+        RetryPolicy { jitter, ..self }
+    }
+
+    /// Retry if a Kiss-o'-Death reply is received with the specified kiss code
+    ///
+    /// By default, Kiss-o'-Death replies are not retried. Be careful with retrying kiss codes like
+    /// [`KissCode::RateExceeded`] or [`KissCode::AccessDenied`], servers might block clients ignoring
+    /// them.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{KissCode, RetryPolicy};
+    ///
+    /// let policy = RetryPolicy::default().retry_on_kiss_code(KissCode::AssociationNotYetSynchronized);
+    /// ```
+    pub fn retry_on_kiss_code(mut self, kiss_code: KissCode) -> RetryPolicy {
+        if !self.retry_kiss_codes.contains(&kiss_code) {
+            self.retry_kiss_codes.push(kiss_code);
+        }
+
+        self
+    }
+
+    pub(crate) fn max_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub(crate) fn timeout(&self, default: Duration) -> Duration {
+        self.attempt_timeout.unwrap_or(default)
+    }
+
+    pub(crate) fn should_retry(&self, error: &SynchronizationError) -> bool {
+        match error {
+            SynchronizationError::IOError(io_error) => matches!(
+                io_error.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ),
+            SynchronizationError::ProtocolError(ProtocolError::KissODeath(kiss_code)) => {
+                self.retry_kiss_codes.contains(kiss_code)
+            }
+            _ => false,
+        }
+    }
+
+    /// Backoff time before the specified retry (starting with 1)
+    pub(crate) fn backoff_time(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .checked_mul(1 << retry.saturating_sub(1).min(31))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+
+        if self.jitter {
+            let factor = 0.5 + (random_u64() as f64 / u64::MAX as f64) / 2.0;
+
+            exponential.mul_f64(factor)
+        } else {
+            exponential
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Creates a policy which does not retry
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::RetryPolicy;
+    ///
+    /// let policy = RetryPolicy::default();
+    /// ```
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            attempt_timeout: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(16),
+            jitter: true,
+            retry_kiss_codes: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackServer;
+    use crate::{Config, SntpClient};
+    use std::net::SocketAddr;
+
+    /// Drops the first request and answers the second one, returns with the server address
+    fn serve_after_lost_request() -> (SocketAddr, std::thread::JoinHandle<Vec<SocketAddr>>) {
+        let server = LoopbackServer::bind();
+        let address = server.local_addr();
+        let mut lost = false;
+        let handle = server.serve(2, move |exchange| {
+            if lost {
+                exchange.send(&exchange.reply());
+            }
+
+            lost = true;
+        });
+
+        (address, handle)
+    }
+
+    fn retrying_config() -> Config {
+        Config::default()
+            .bind_address("127.0.0.1:0".parse().unwrap())
+            .retry_policy(
+                RetryPolicy::default()
+                    .attempts(3)
+                    .attempt_timeout(Duration::from_millis(200))
+                    .backoff(Duration::from_millis(10), Duration::from_millis(10))
+                    .jitter(false),
+            )
+    }
+
+    #[test]
+    fn backoff_is_exponential() {
+        let policy = RetryPolicy::default()
+            .backoff(Duration::from_secs(1), Duration::from_secs(5))
+            .jitter(false);
+
+        assert_eq!(policy.backoff_time(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff_time(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff_time(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff_time(4), Duration::from_secs(5));
+        assert_eq!(policy.backoff_time(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_in_range() {
+        let policy = RetryPolicy::default().backoff(Duration::from_secs(4), Duration::from_secs(4));
+
+        for _ in 0..100 {
+            let backoff = policy.backoff_time(1);
+
+            assert!(backoff >= Duration::from_secs(2));
+            assert!(backoff <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn timeouts_and_configured_kiss_codes_are_retried() {
+        let policy = RetryPolicy::default().retry_on_kiss_code(KissCode::StepChange);
+
+        assert!(policy.should_retry(&SynchronizationError::IOError(
+            std::io::ErrorKind::TimedOut.into()
+        )));
+        assert!(policy.should_retry(&SynchronizationError::IOError(
+            std::io::ErrorKind::WouldBlock.into()
+        )));
+        assert!(policy.should_retry(&SynchronizationError::ProtocolError(
+            ProtocolError::KissODeath(KissCode::StepChange)
+        )));
+
+        assert!(!policy.should_retry(&SynchronizationError::IOError(
+            std::io::ErrorKind::ConnectionRefused.into()
+        )));
+        assert!(!policy.should_retry(&SynchronizationError::ProtocolError(
+            ProtocolError::KissODeath(KissCode::RateExceeded)
+        )));
+        assert!(!policy.should_retry(&SynchronizationError::ProtocolError(
+            ProtocolError::InvalidMode
+        )));
+    }
+
+    #[test]
+    fn lost_request_is_retried() {
+        let (address, handle) = serve_after_lost_request();
+        let client = SntpClient::with_config(retrying_config());
+
+        let result = client.synchronize(address).unwrap();
+
+        handle.join().unwrap();
+
+        assert_eq!(result.attempts(), 2);
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_lost_request_is_retried() {
+        let (address, handle) = serve_after_lost_request();
+        let client = crate::AsyncSntpClient::with_config(retrying_config());
+
+        let result = client.synchronize(address).await.unwrap();
+
+        handle.join().unwrap();
+
+        assert_eq!(result.attempts(), 2);
+    }
+}
@@ -0,0 +1,475 @@
+use crate::error::SynchronizationError;
+use crate::packet::LeapIndicator;
+use crate::result::SynchronizationResult;
+
+/// Maximum stratum number, servers at or above this stratum are unsynchronized
+const MAX_STRATUM: u8 = 16;
+/// Maximum synchronization distance of an acceptable server in seconds (RFC 5905 MAXDIST)
+const MAX_DISTANCE: f64 = 1.0;
+/// Minimum number of survivors the clustering algorithm keeps (RFC 5905 NMIN)
+const MIN_SURVIVORS: usize = 3;
+
+/// Status of a server after the selection process
+///
+/// Describes the role of a queried server in the result of a multi-server synchronization.
+/// Statuses follow the terminology of RFC 5905 section 11.2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ServerStatus {
+    /// Synchronization with the server failed, see the error for details
+    Failed,
+    /// Server replied, but it is not fit for synchronization (e.g. it is unsynchronized or its
+    /// synchronization distance is too large)
+    Unfit,
+    /// Server time is not consistent with the majority of servers
+    Falseticker,
+    /// Server is a truechimer but it has been discarded by the clustering algorithm
+    Outlier,
+    /// Server is a survivor and its offset is used to calculate the combined offset
+    Survivor,
+    /// Server is a survivor and has been chosen as a system peer, i.e. its data (except the clock
+    /// offset) is used in the combined result
+    SystemPeer,
+}
+
+/// Outcome of a query to a single server during a multi-server synchronization
+#[derive(Debug)]
+pub struct ServerDiagnostics {
+    result: Result<SynchronizationResult, SynchronizationError>,
+    status: ServerStatus,
+}
+
+impl ServerDiagnostics {
+    /// Returns with the result of the synchronization with this server
+    pub fn result(&self) -> Result<&SynchronizationResult, &SynchronizationError> {
+        self.result.as_ref()
+    }
+
+    /// Returns with the status of the server after the selection process
+    pub fn status(&self) -> ServerStatus {
+        self.status
+    }
+}
+
+/// Results of a multi-server synchronization.
+///
+/// Contains a combined synchronization result, calculated from the servers survived the selection
+/// and clustering algorithms, and per-server diagnostics.
+#[derive(Debug)]
+pub struct MultiSynchronizationResult {
+    result: SynchronizationResult,
+    servers: Vec<ServerDiagnostics>,
+}
+
+impl MultiSynchronizationResult {
+    /// Returns with the combined synchronization result
+    ///
+    /// The clock offset is the weighted average of the survivor clock offsets, where weights are
+    /// the reciprocals of the synchronization distances. All other data is taken from the system
+    /// peer.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client
+    ///     .synchronize_many(&["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org"])
+    ///     .unwrap();
+    ///
+    /// println!("Clock offset: {} s", result.result().clock_offset().as_secs_f64());
+    /// ```
+    pub fn result(&self) -> &SynchronizationResult {
+        &self.result
+    }
+
+    /// Returns with the per-server diagnostics
+    ///
+    /// The diagnostics are in the same order as the server addresses were supplied.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::SntpClient;
+    ///
+    /// let client = SntpClient::new();
+    /// let result = client
+    ///     .synchronize_many(&["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org"])
+    ///     .unwrap();
+    ///
+    /// for server in result.servers() {
+    ///     println!("{:?}", server.status());
+    /// }
+    /// ```
+    pub fn servers(&self) -> &[ServerDiagnostics] {
+        &self.servers
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Candidate {
+    pub offset: f64,
+    pub distance: f64,
+    pub jitter: f64,
+    pub stratum: u8,
+}
+
+impl Candidate {
+    fn new(result: &SynchronizationResult) -> Option<Candidate> {
+        let distance = result.synchronization_distance().as_secs_f64();
+
+        if result.stratum() == 0
+            || result.stratum() >= MAX_STRATUM
+            || result.leap_indicator() == LeapIndicator::AlarmCondition
+            || !distance.is_finite()
+            || distance > MAX_DISTANCE
+        {
+            return None;
+        }
+
+        // a single sample has no measured jitter, it is approximated with the larger of the
+        // precision of the server and the dispersion of the sample
+        let jitter = result.measured_jitter().unwrap_or_else(|| {
+            result
+                .precision()
+                .as_secs_f64()
+                .max(result.dispersion().as_secs_f64())
+        });
+
+        Some(Candidate {
+            offset: result.clock_offset().as_secs_f64(),
+            distance,
+            jitter,
+            stratum: result.stratum(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Selection {
+    pub statuses: Vec<ServerStatus>,
+    pub system_peer: usize,
+    pub offset: f64,
+}
+
+/// Intersection algorithm (RFC 5905 section 11.2.1)
+///
+/// Returns with the intersection interval of the majority of candidates, or `None` if there is
+/// no majority.
+fn intersection(candidates: &[Candidate]) -> Option<(f64, f64)> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Edge {
+        Lower,
+        Midpoint,
+        Upper,
+    }
+
+    let n = candidates.len();
+    let mut edges: Vec<(f64, Edge)> = candidates
+        .iter()
+        .flat_map(|c| {
+            [
+                (c.offset - c.distance, Edge::Lower),
+                (c.offset, Edge::Midpoint),
+                (c.offset + c.distance, Edge::Upper),
+            ]
+        })
+        .collect();
+
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    for allow in (0..).take_while(|allow| 2 * allow < n) {
+        let mut found = 0;
+        let mut chime = 0;
+        let mut low = None;
+
+        for &(edge, kind) in edges.iter() {
+            match kind {
+                Edge::Lower => chime += 1,
+                Edge::Upper => chime -= 1,
+                Edge::Midpoint => {}
+            }
+
+            if chime >= n - allow {
+                low = Some(edge);
+                break;
+            }
+
+            if kind == Edge::Midpoint {
+                found += 1;
+            }
+        }
+
+        chime = 0;
+        let mut high = None;
+
+        for &(edge, kind) in edges.iter().rev() {
+            match kind {
+                Edge::Upper => chime += 1,
+                Edge::Lower => chime -= 1,
+                Edge::Midpoint => {}
+            }
+
+            if chime >= n - allow {
+                high = Some(edge);
+                break;
+            }
+
+            if kind == Edge::Midpoint {
+                found += 1;
+            }
+        }
+
+        if found > allow {
+            continue;
+        }
+
+        if let (Some(low), Some(high)) = (low, high) {
+            if high >= low {
+                return Some((low, high));
+            }
+        }
+    }
+
+    None
+}
+
+/// Selection jitter of a survivor, i.e. the RMS of offset differences to other survivors
+fn selection_jitter(survivors: &[(usize, Candidate)], offset: f64) -> f64 {
+    let sum: f64 = survivors
+        .iter()
+        .map(|(_, other)| (other.offset - offset).powi(2))
+        .sum();
+
+    (sum / (survivors.len() - 1) as f64).sqrt()
+}
+
+/// Runs selection, clustering and combine algorithms (RFC 5905 section 11.2)
+///
+/// Candidates which are `None` are treated as unfit. Returns `None` if no majority of
+/// truechimers can be found.
+pub(crate) fn select(candidates: &[Option<Candidate>]) -> Option<Selection> {
+    let fit: Vec<(usize, Candidate)> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(index, candidate)| candidate.map(|candidate| (index, candidate)))
+        .collect();
+
+    let fit_candidates: Vec<Candidate> = fit.iter().map(|(_, candidate)| *candidate).collect();
+    let (low, high) = intersection(&fit_candidates)?;
+
+    let mut statuses = vec![ServerStatus::Unfit; candidates.len()];
+    let (mut survivors, falsetickers): (Vec<_>, Vec<_>) =
+        fit.into_iter().partition(|(_, candidate)| {
+            candidate.offset + candidate.distance >= low
+                && candidate.offset - candidate.distance <= high
+        });
+
+    for (index, _) in falsetickers {
+        statuses[index] = ServerStatus::Falseticker;
+    }
+
+    while survivors.len() > MIN_SURVIVORS {
+        let (worst, max_selection_jitter) = survivors
+            .iter()
+            .enumerate()
+            .map(|(position, (_, candidate))| {
+                (position, selection_jitter(&survivors, candidate.offset))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let min_peer_jitter = survivors
+            .iter()
+            .map(|(_, candidate)| candidate.jitter)
+            .min_by(|a, b| a.total_cmp(b))
+            .unwrap();
+
+        if max_selection_jitter < min_peer_jitter {
+            break;
+        }
+
+        let (index, _) = survivors.remove(worst);
+        statuses[index] = ServerStatus::Outlier;
+    }
+
+    let metric = |c: &Candidate| c.stratum as f64 * MAX_DISTANCE + c.distance;
+    let (system_peer, _) = *survivors
+        .iter()
+        .min_by(|(_, a), (_, b)| metric(a).total_cmp(&metric(b)))
+        .unwrap();
+
+    let (weighted_offset, weight_sum) = survivors.iter().fold(
+        (0.0, 0.0),
+        |(weighted_offset, weight_sum), (_, candidate)| {
+            let weight = 1.0 / candidate.distance;
+
+            (
+                weighted_offset + candidate.offset * weight,
+                weight_sum + weight,
+            )
+        },
+    );
+
+    for (index, _) in survivors.iter() {
+        statuses[*index] = ServerStatus::Survivor;
+    }
+    statuses[system_peer] = ServerStatus::SystemPeer;
+
+    Some(Selection {
+        statuses,
+        system_peer,
+        offset: weighted_offset / weight_sum,
+    })
+}
+
+/// Combines the results of individual synchronizations into a multi-server result
+pub(crate) fn combine(
+    results: Vec<Result<SynchronizationResult, SynchronizationError>>,
+) -> Result<MultiSynchronizationResult, SynchronizationError> {
+    let candidates: Vec<Option<Candidate>> = results
+        .iter()
+        .map(|result| result.as_ref().ok().and_then(Candidate::new))
+        .collect();
+
+    let selection = select(&candidates).ok_or(SynchronizationError::SelectionFailed)?;
+
+    let result = results[selection.system_peer]
+        .as_ref()
+        .unwrap()
+        .with_clock_offset(selection.offset);
+
+    let servers = results
+        .into_iter()
+        .zip(selection.statuses)
+        .map(|(result, status)| ServerDiagnostics {
+            status: if result.is_err() {
+                ServerStatus::Failed
+            } else {
+                status
+            },
+            result,
+        })
+        .collect();
+
+    Ok(MultiSynchronizationResult { result, servers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(offset: f64, distance: f64) -> Option<Candidate> {
+        Some(Candidate {
+            offset,
+            distance,
+            jitter: 0.01,
+            stratum: 2,
+        })
+    }
+
+    fn result(offset: f64, delay: f64) -> SynchronizationResult {
+        let packet = crate::packet::Packet::new(crate::packet::Mode::Server)
+            .stratum(2)
+            .precision(-20);
+
+        SynchronizationResult::new(offset, delay, &packet)
+    }
+
+    #[test]
+    fn candidate_jitter_is_approximated_for_a_single_sample() {
+        let result = result(0.1, 0.02);
+        let candidate = Candidate::new(&result).unwrap();
+
+        assert_eq!(candidate.jitter, result.dispersion().as_secs_f64());
+        assert!(candidate.jitter > result.precision().as_secs_f64());
+    }
+
+    #[test]
+    fn candidate_jitter_is_measured_by_the_clock_filter() {
+        // a full burst, missing samples would make the dispersion too large
+        let samples = (0..8)
+            .map(|i| result(0.1 + 0.01 * i as f64, 0.02 + 0.001 * i as f64))
+            .collect();
+        let result = crate::filter::select_result(samples).unwrap();
+        let candidate = Candidate::new(&result).unwrap();
+
+        assert_eq!(candidate.jitter, result.jitter().as_secs_f64());
+        assert!(candidate.jitter > result.dispersion().as_secs_f64());
+    }
+
+    #[test]
+    fn intersection_of_agreeing_candidates_works() {
+        let candidates = [
+            candidate(0.10, 0.05).unwrap(),
+            candidate(0.12, 0.05).unwrap(),
+            candidate(0.11, 0.05).unwrap(),
+        ];
+
+        let (low, high) = intersection(&candidates).unwrap();
+
+        assert!((low - 0.07).abs() < 1e-9);
+        assert!((high - 0.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn falseticker_is_discarded() {
+        let selection = select(&[
+            candidate(0.10, 0.05),
+            candidate(5.00, 0.05),
+            candidate(0.11, 0.05),
+        ])
+        .unwrap();
+
+        assert_eq!(selection.statuses[1], ServerStatus::Falseticker);
+        assert_ne!(selection.statuses[0], ServerStatus::Falseticker);
+        assert_ne!(selection.statuses[2], ServerStatus::Falseticker);
+        assert!((selection.offset - 0.105).abs() < 1e-9);
+    }
+
+    #[test]
+    fn selection_fails_without_majority() {
+        assert_eq!(
+            select(&[candidate(0.10, 0.05), candidate(5.00, 0.05)]),
+            None
+        );
+        assert_eq!(select(&[None, None]), None);
+        assert_eq!(select(&[]), None);
+    }
+
+    #[test]
+    fn unfit_candidates_are_ignored() {
+        let selection = select(&[None, candidate(0.10, 0.05), candidate(0.12, 0.05)]).unwrap();
+
+        assert_eq!(selection.statuses[0], ServerStatus::Unfit);
+        assert!((selection.offset - 0.11).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clustering_removes_outliers() {
+        let selection = select(&[
+            candidate(0.100, 0.5),
+            candidate(0.101, 0.5),
+            candidate(0.099, 0.5),
+            candidate(0.100, 0.5),
+            candidate(0.400, 0.5),
+        ])
+        .unwrap();
+
+        assert_eq!(selection.statuses[4], ServerStatus::Outlier);
+        assert!((selection.offset - 0.100).abs() < 1e-9);
+    }
+
+    #[test]
+    fn system_peer_has_the_lowest_distance() {
+        let selection = select(&[
+            candidate(0.10, 0.05),
+            candidate(0.11, 0.01),
+            candidate(0.12, 0.05),
+        ])
+        .unwrap();
+
+        assert_eq!(selection.system_peer, 1);
+        assert_eq!(selection.statuses[1], ServerStatus::SystemPeer);
+        assert_eq!(selection.statuses[0], ServerStatus::Survivor);
+    }
+}
@@ -0,0 +1,688 @@
+use crate::error::ReferenceIdentifierError;
+use crate::packet::{
+    LeapIndicator, Mode, Packet, ReferenceIdentifier, SntpShortFormat, SntpTimestamp,
+};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Source of time for a server
+///
+/// A server answers requests with the time provided by its time source. The default source
+/// is [`SystemTimeSource`], which uses the system clock, but any clock can be used by
+/// implementing this trait.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ServerConfig, TimeSource};
+/// use std::time::{Duration, SystemTime};
+///
+/// struct OneHourAhead;
+///
+/// impl TimeSource for OneHourAhead {
+///     fn now(&self) -> SystemTime {
+///         SystemTime::now() + Duration::from_secs(3600)
+///     }
+/// }
+///
+/// let config = ServerConfig::default().time_source(OneHourAhead);
+/// ```
+pub trait TimeSource: Send + Sync {
+    /// Returns with the current time
+    fn now(&self) -> SystemTime;
+
+    /// Returns with the time the source was last set or corrected
+    ///
+    /// It is sent as the reference timestamp of replies. Default implementation returns with the
+    /// current time.
+    fn reference_time(&self) -> SystemTime {
+        self.now()
+    }
+}
+
+/// Time source which uses the system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Server configuration
+///
+/// Contains the data a server sends in its replies and the time source it uses. It uses
+/// a builder-like pattern similar to [`crate::Config`].
+///
+/// By default, the server announces itself as a stratum 1 server with `LOCL` reference
+/// identifier (i.e. undisciplined local clock) and uses the system clock as time source.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ReferenceIdentifier, ServerConfig, SntpServer};
+///
+/// let config = ServerConfig::default()
+///     .stratum(2)
+///     .reference_identifier(ReferenceIdentifier::IpAddress("192.168.0.1".parse().unwrap()))
+///     .unwrap();
+/// let server = SntpServer::bind("0.0.0.0:123", config).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ServerConfig {
+    stratum: u8,
+    reference_identifier: ReferenceIdentifier,
+    leap_indicator: LeapIndicator,
+    precision: i8,
+    poll: i8,
+    root_delay: Duration,
+    root_dispersion: Duration,
+    time_source: Arc<dyn TimeSource>,
+}
+
+impl ServerConfig {
+    /// Sets the stratum of the server
+    ///
+    /// Default is 1, i.e. primary server.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ServerConfig;
+    ///
+    /// let config = ServerConfig::default().stratum(2);
+    /// ```
+    pub fn stratum(self, stratum: u8) -> ServerConfig {
+        ServerConfig { stratum, ..self }
+    }
+
+    /// Sets the reference identifier of the server
+    ///
+    /// Default is `LOCL`. For primary servers (stratum 1), it should be an ASCII identifier of the
+    /// reference clock, for secondary servers it should identify the upstream server. Returns an
+    /// error if the reference identifier cannot be encoded, see [`ReferenceIdentifier::validate`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ReferenceIdentifier, ServerConfig};
+    ///
+    /// let config = ServerConfig::default()
+    ///     .reference_identifier(ReferenceIdentifier::ASCII("GPS".into()))
+    ///     .unwrap();
+    /// ```
+    pub fn reference_identifier(
+        self,
+        reference_identifier: ReferenceIdentifier,
+    ) -> Result<ServerConfig, ReferenceIdentifierError> {
+        reference_identifier.validate()?;
+
+        Ok(ServerConfig {
+            reference_identifier,
+            ..self
+        })
+    }
+
+    /// Sets the leap indicator sent by the server
+    ///
+    /// Default is [`LeapIndicator::NoWarning`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{LeapIndicator, ServerConfig};
+    ///
+    /// let config = ServerConfig::default().leap_indicator(LeapIndicator::LastMinuteHas61Seconds);
+    /// ```
+    pub fn leap_indicator(self, leap_indicator: LeapIndicator) -> ServerConfig {
+        ServerConfig {
+            leap_indicator,
+            ..self
+        }
+    }
+
+    /// Sets the precision of the server clock as a power of two exponent in seconds
+    ///
+    /// Default is -20, i.e. about one microsecond.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ServerConfig;
+    ///
+    /// let config = ServerConfig::default().precision(-10);
+    /// ```
+    pub fn precision(self, precision: i8) -> ServerConfig {
+        ServerConfig { precision, ..self }
+    }
+
+    /// Sets the poll interval sent by the server as a power of two exponent in seconds
+    ///
+    /// Default is 6, i.e. 64 seconds.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ServerConfig;
+    ///
+    /// let config = ServerConfig::default().poll(10);
+    /// ```
+    pub fn poll(self, poll: i8) -> ServerConfig {
+        ServerConfig { poll, ..self }
+    }
+
+    /// Sets the root delay and root dispersion sent by the server
+    ///
+    /// Default is zero for both.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ServerConfig;
+    /// use std::time::Duration;
+    ///
+    /// let config = ServerConfig::default().root_metrics(Duration::from_millis(10), Duration::from_millis(5));
+    /// ```
+    pub fn root_metrics(self, root_delay: Duration, root_dispersion: Duration) -> ServerConfig {
+        ServerConfig {
+            root_delay,
+            root_dispersion,
+            ..self
+        }
+    }
+
+    /// Sets the time source of the server
+    ///
+    /// Default is [`SystemTimeSource`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ServerConfig, SystemTimeSource};
+    ///
+    /// let config = ServerConfig::default().time_source(SystemTimeSource);
+    /// ```
+    pub fn time_source<T: TimeSource + 'static>(self, time_source: T) -> ServerConfig {
+        ServerConfig {
+            time_source: Arc::new(time_source),
+            ..self
+        }
+    }
+
+    /// Returns with the current time of the time source
+    #[cfg(test)]
+    pub(crate) fn now(&self) -> SystemTime {
+        self.time_source.now()
+    }
+
+    /// Creates a reply for the request, or `None` if the request should be ignored
+    ///
+    /// Requests are also ignored if the time of the time source cannot be encoded, e.g. it is
+    /// earlier than 1900.
+    pub(crate) fn reply(
+        &self,
+        request: &[u8],
+        source: SocketAddr,
+        receive_time: SystemTime,
+    ) -> Option<[u8; Packet::ENCODED_LEN]> {
+        let request = Packet::decode(request, source.ip()).ok()?;
+
+        // NTPv1 has no mode field, it is always zero
+        let is_version_1_request = request.version == 1 && request.mode == Mode::Reserved;
+
+        if request.mode != Mode::Client && !is_version_1_request {
+            return None;
+        }
+
+        let reply = Packet {
+            li: self.leap_indicator,
+            version: request.version,
+            mode: Mode::Server,
+            stratum: self.stratum,
+            poll: self.poll,
+            precision: self.precision,
+            root_delay: SntpShortFormat::from_secs_f64(self.root_delay.as_secs_f64()),
+            root_dispersion: SntpShortFormat::from_secs_f64(self.root_dispersion.as_secs_f64()),
+            reference_identifier: self.reference_identifier.clone(),
+            reference_timestamp: SntpTimestamp::from_systemtime(self.time_source.reference_time())
+                .ok()?,
+            originate_timestamp: request.transmit_timestamp,
+            receive_timestamp: SntpTimestamp::from_systemtime(receive_time).ok()?,
+            transmit_timestamp: SntpTimestamp::from_systemtime(self.time_source.now()).ok()?,
+        };
+
+        Some(reply.encode())
+    }
+}
+
+impl Default for ServerConfig {
+    /// Creates an instance with default configuration
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::ServerConfig;
+    ///
+    /// let config = ServerConfig::default();
+    /// ```
+    fn default() -> ServerConfig {
+        ServerConfig {
+            stratum: 1,
+            reference_identifier: ReferenceIdentifier::ASCII("LOCL".into()),
+            leap_indicator: LeapIndicator::NoWarning,
+            precision: -20,
+            poll: 6,
+            root_delay: Duration::ZERO,
+            root_dispersion: Duration::ZERO,
+            time_source: Arc::new(SystemTimeSource),
+        }
+    }
+}
+
+impl Debug for ServerConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("stratum", &self.stratum)
+            .field("reference_identifier", &self.reference_identifier)
+            .field("leap_indicator", &self.leap_indicator)
+            .field("precision", &self.precision)
+            .field("poll", &self.poll)
+            .field("root_delay", &self.root_delay)
+            .field("root_dispersion", &self.root_dispersion)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Blocking SNTP server
+///
+/// Answers client (mode 3) requests with server (mode 4) replies. Packets which are not valid
+/// client requests are silently ignored.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{ServerConfig, SntpServer};
+///
+/// let server = SntpServer::bind("0.0.0.0:123", ServerConfig::default()).unwrap();
+///
+/// server.run().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct SntpServer {
+    socket: std::net::UdpSocket,
+    config: ServerConfig,
+}
+
+impl SntpServer {
+    /// Creates a server listening on the specified address
+    ///
+    /// Note that binding to the standard port (123) usually requires elevated privileges.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ServerConfig, SntpServer};
+    ///
+    /// let server = SntpServer::bind("0.0.0.0:123", ServerConfig::default()).unwrap();
+    /// ```
+    pub fn bind<A: std::net::ToSocketAddrs>(
+        address: A,
+        config: ServerConfig,
+    ) -> std::io::Result<SntpServer> {
+        Ok(SntpServer {
+            socket: std::net::UdpSocket::bind(address)?,
+            config,
+        })
+    }
+
+    /// Returns with the local address the server is listening on
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for a single packet and answers it if it is a valid request
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ServerConfig, SntpServer};
+    ///
+    /// let server = SntpServer::bind("0.0.0.0:123", ServerConfig::default()).unwrap();
+    ///
+    /// loop {
+    ///     if let Err(err) = server.serve_one() {
+    ///         println!("Error: {}", err);
+    ///     }
+    /// }
+    /// ```
+    pub fn serve_one(&self) -> std::io::Result<()> {
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let (bytes_received, source) = self.socket.recv_from(&mut receive_buffer)?;
+        let receive_time = self.config.time_source.now();
+
+        if let Some(reply) =
+            self.config
+                .reply(&receive_buffer[..bytes_received], source, receive_time)
+        {
+            self.socket.send_to(&reply, source)?;
+        }
+
+        Ok(())
+    }
+
+    /// Answers requests until a fatal I/O error occurs
+    ///
+    /// Errors which only concern a single packet are ignored, like invalid requests: replies
+    /// which cannot be sent (e.g. to a spoofed broadcast source) and receive errors caused by
+    /// earlier replies or by oversized packets. It returns with the first error of the socket
+    /// which is not related to a single packet.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rsntp::{ServerConfig, SntpServer};
+    ///
+    /// let server = SntpServer::bind("0.0.0.0:123", ServerConfig::default()).unwrap();
+    ///
+    /// server.run().unwrap();
+    /// ```
+    pub fn run(&self) -> std::io::Result<()> {
+        run_server(
+            &self.config,
+            |buffer| self.socket.recv_from(buffer),
+            |packet, address| self.socket.send_to(packet, address),
+        )
+    }
+}
+
+/// Returns whether a receive error only concerns a single packet, so the server can go on
+fn is_packet_error(err: &std::io::Error) -> bool {
+    // Windows reports oversized datagrams with WSAEMSGSIZE
+    const WSAEMSGSIZE: i32 = 10040;
+
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut
+    ) || (cfg!(windows) && err.raw_os_error() == Some(WSAEMSGSIZE))
+}
+
+/// Answers the requests returned by `receive` with `send` until a fatal receive error occurs
+fn run_server<R, S>(config: &ServerConfig, mut receive: R, mut send: S) -> std::io::Result<()>
+where
+    R: FnMut(&mut [u8]) -> std::io::Result<(usize, SocketAddr)>,
+    S: FnMut(&[u8], SocketAddr) -> std::io::Result<usize>,
+{
+    let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+    loop {
+        let (bytes_received, source) = match receive(&mut receive_buffer) {
+            Ok(received) => received,
+            Err(err) if is_packet_error(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        let receive_time = config.time_source.now();
+
+        if let Some(reply) = config.reply(&receive_buffer[..bytes_received], source, receive_time) {
+            // a failed reply only affects its client, fatal errors are reported by the next receive
+            let _ = send(&reply, source);
+        }
+    }
+}
+
+/// Asynchronous SNTP server
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// This is the asynchronous counterpart of [`SntpServer`], see its documentation for details.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{AsyncSntpServer, ServerConfig};
+///
+/// async fn serve() -> std::io::Result<()> {
+///   let server = AsyncSntpServer::bind("0.0.0.0:123", ServerConfig::default()).await?;
+///
+///   server.run().await
+/// }
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncSntpServer {
+    socket: tokio::net::UdpSocket,
+    config: ServerConfig,
+}
+
+#[cfg(feature = "async")]
+impl AsyncSntpServer {
+    /// Creates a server listening on the specified address
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Note that binding to the standard port (123) usually requires elevated privileges.
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(
+        address: A,
+        config: ServerConfig,
+    ) -> std::io::Result<AsyncSntpServer> {
+        Ok(AsyncSntpServer {
+            socket: tokio::net::UdpSocket::bind(address).await?,
+            config,
+        })
+    }
+
+    /// Returns with the local address the server is listening on
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for a single packet and answers it if it is a valid request
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    pub async fn serve_one(&self) -> std::io::Result<()> {
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+        let (bytes_received, source) = self.socket.recv_from(&mut receive_buffer).await?;
+        let receive_time = self.config.time_source.now();
+
+        if let Some(reply) =
+            self.config
+                .reply(&receive_buffer[..bytes_received], source, receive_time)
+        {
+            self.socket.send_to(&reply, source).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Answers requests until a fatal I/O error occurs
+    ///
+    /// Only available when async feature is enabled (which is the default)
+    ///
+    /// Errors which only concern a single packet are ignored, see [`SntpServer::run`].
+    pub async fn run(&self) -> std::io::Result<()> {
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        loop {
+            let (bytes_received, source) = match self.socket.recv_from(&mut receive_buffer).await {
+                Ok(received) => received,
+                Err(err) if is_packet_error(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            let receive_time = self.config.time_source.now();
+
+            if let Some(reply) =
+                self.config
+                    .reply(&receive_buffer[..bytes_received], source, receive_time)
+            {
+                // a failed reply only affects its client, fatal errors are reported by the next
+                // receive
+                let _ = self.socket.send_to(&reply, source).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::OffsetTimeSource;
+    use crate::{Config, ManualClock, SntpClient};
+
+    fn client() -> SntpClient {
+        SntpClient::with_config(Config::default().timeout(Duration::from_secs(5)))
+    }
+
+    #[test]
+    fn server_answers_client_requests() {
+        let config = ServerConfig::default()
+            .stratum(2)
+            .reference_identifier(ReferenceIdentifier::IpAddress("10.0.0.1".parse().unwrap()))
+            .unwrap()
+            .leap_indicator(LeapIndicator::LastMinuteHas61Seconds)
+            .root_metrics(Duration::from_millis(250), Duration::from_millis(125));
+        let server = SntpServer::bind("127.0.0.1:0", config).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let thread = std::thread::spawn(move || server.serve_one().unwrap());
+        let result = client().synchronize(address).unwrap();
+        thread.join().unwrap();
+
+        assert_eq!(result.stratum(), 2);
+        assert_eq!(result.reference_identifier().to_string(), "10.0.0.1");
+        assert_eq!(
+            result.leap_indicator(),
+            LeapIndicator::LastMinuteHas61Seconds
+        );
+        assert_eq!(result.root_delay().as_secs_f64(), 0.25);
+        assert_eq!(result.root_dispersion().as_secs_f64(), 0.125);
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+    }
+
+    #[test]
+    fn unencodable_reference_identifier_is_rejected() {
+        assert_eq!(
+            ServerConfig::default()
+                .reference_identifier(ReferenceIdentifier::ASCII("LOCAL".into()))
+                .err(),
+            Some(ReferenceIdentifierError::TooLong(5))
+        );
+        assert_eq!(
+            ServerConfig::default()
+                .reference_identifier(ReferenceIdentifier::IpAddress("::1".parse().unwrap()))
+                .err(),
+            Some(ReferenceIdentifierError::Ipv6Address)
+        );
+    }
+
+    #[test]
+    fn server_uses_its_time_source() {
+        let config = ServerConfig::default().time_source(OffsetTimeSource(Duration::from_secs(10)));
+        let server = SntpServer::bind("127.0.0.1:0", config).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let thread = std::thread::spawn(move || server.serve_one().unwrap());
+        let result = client().synchronize(address).unwrap();
+        thread.join().unwrap();
+
+        assert_eq!(result.reference_identifier().to_string(), "LOCL");
+        assert!((result.clock_offset().as_secs_f64() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn server_goes_on_after_packet_errors() {
+        let client_address: SocketAddr = "127.0.0.1:10123".parse().unwrap();
+        let request = Packet::new(Mode::Client)
+            .transmit_timestamp(SntpTimestamp::from_systemtime(SystemTime::now()).unwrap())
+            .encode();
+        let mut received = vec![
+            Ok(client_address),
+            Err(std::io::ErrorKind::ConnectionReset),
+            Ok(client_address),
+            Err(std::io::ErrorKind::NotConnected),
+        ]
+        .into_iter();
+        let mut sent = Vec::new();
+
+        let result = run_server(
+            &ServerConfig::default(),
+            |buffer| match received.next().unwrap() {
+                Ok(source) => {
+                    buffer[..request.len()].copy_from_slice(&request);
+                    Ok((request.len(), source))
+                }
+                Err(kind) => Err(kind.into()),
+            },
+            |packet, address| {
+                sent.push(address);
+
+                // the first reply cannot be sent, like the ones to spoofed broadcast sources
+                if sent.len() == 1 {
+                    Err(std::io::ErrorKind::PermissionDenied.into())
+                } else {
+                    Ok(packet.len())
+                }
+            },
+        );
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+        assert_eq!(sent, [client_address, client_address]);
+    }
+
+    #[test]
+    fn requests_are_dropped_if_the_time_cannot_be_encoded() {
+        // earlier than the NTP epoch in 1900
+        let clock =
+            ManualClock::new(SystemTime::UNIX_EPOCH - Duration::from_secs(80 * 365 * 86400));
+        let config = ServerConfig::default().time_source(clock);
+        let request = Packet::new(Mode::Client)
+            .transmit_timestamp(SntpTimestamp::from_systemtime(SystemTime::now()).unwrap())
+            .encode();
+
+        assert_eq!(
+            config.reply(&request, "127.0.0.1:10123".parse().unwrap(), config.now()),
+            None
+        );
+    }
+
+    #[test]
+    fn server_ignores_non_client_packets() {
+        let config = ServerConfig::default();
+        let mut packet = [0; Packet::ENCODED_LEN];
+
+        packet[0] = 0x24; // version 4, server mode
+
+        assert!(config
+            .reply(&packet, "127.0.0.1:123".parse().unwrap(), SystemTime::now())
+            .is_none());
+        assert!(config
+            .reply(
+                &packet[..10],
+                "127.0.0.1:123".parse().unwrap(),
+                SystemTime::now()
+            )
+            .is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_server_answers_client_requests() {
+        let server = AsyncSntpServer::bind("127.0.0.1:0", ServerConfig::default())
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        let client = crate::AsyncSntpClient::new();
+
+        let (served, result) = tokio::join!(server.serve_one(), client.synchronize(address));
+
+        served.unwrap();
+        assert_eq!(result.unwrap().stratum(), 1);
+    }
+}
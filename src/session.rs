@@ -0,0 +1,689 @@
+use crate::error::SynchronizationError;
+use crate::packet::Packet;
+use crate::result::SynchronizationResult;
+use crate::to_server_addrs::ToServerAddrs;
+use crate::transport::{self, Transport, TransportSocket, UdpTransport};
+use crate::Config;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use crate::clock_source::{Anchor, SharedClockSource};
+#[cfg(feature = "async")]
+use crate::core_logic::Request;
+#[cfg(feature = "async")]
+use crate::transport::{AsyncTransport, AsyncTransportSocket, ReceivedPacket};
+#[cfg(feature = "async")]
+use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::marker::PhantomData;
+#[cfg(feature = "async")]
+use std::net::SocketAddr;
+#[cfg(feature = "async")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::Poll;
+#[cfg(feature = "async")]
+use std::time::SystemTime;
+#[cfg(feature = "async")]
+use tokio::sync::{mpsc, oneshot};
+
+/// Returns with the originate timestamp of a reply, if it is long enough to be a reply
+fn originate_timestamp(reply: &[u8]) -> Option<u64> {
+    let bytes = reply.get(24..32)?;
+
+    if reply.len() < Packet::ENCODED_LEN {
+        return None;
+    }
+
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Returns an error if the configuration is not supported by sessions
+fn check_config(config: &Config) -> std::io::Result<()> {
+    #[cfg(feature = "nts")]
+    if config.nts.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Network Time Security is not supported by sessions",
+        ));
+    }
+
+    let _ = config;
+
+    Ok(())
+}
+
+fn timeout_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "Timeout while waiting for server reply",
+    )
+}
+
+/// Blocking client with a persistent socket
+///
+/// Unlike [`crate::SntpClient`], which binds a new socket for each exchange, a session binds a
+/// single socket and uses it for all of its synchronizations, so the requests are sent from the
+/// same local port. Replies are matched to the request by their source address and originate
+/// timestamp, late replies to earlier requests are ignored.
+///
+/// Network Time Security is not supported, binding fails if it is enabled in the configuration.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, SntpSession};
+///
+/// // the requests are sent from the fixed local port 12345
+/// let config = Config::default().bind_address("0.0.0.0:12345".parse().unwrap());
+/// let mut session = SntpSession::bind(config).unwrap();
+///
+/// for server in ["0.pool.ntp.org", "1.pool.ntp.org"] {
+///     let result = session.synchronize(server).unwrap();
+///
+///     println!("{}: {} seconds", server, result.clock_offset().as_secs_f64());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SntpSession<T: Transport = UdpTransport> {
+    config: Config,
+    socket: T::Socket,
+}
+
+impl SntpSession {
+    /// Creates a session with a UDP socket bound to the bind address of the configuration
+    pub fn bind(config: Config) -> std::io::Result<SntpSession> {
+        Self::bind_with_transport(config, UdpTransport)
+    }
+}
+
+impl<T: Transport> SntpSession<T> {
+    /// Creates a session with a socket of the transport, bound to the bind address of the
+    /// configuration
+    pub fn bind_with_transport(config: Config, transport: T) -> std::io::Result<SntpSession<T>> {
+        check_config(&config)?;
+
+        let socket = transport.bind(config.bind_address)?;
+
+        Ok(SntpSession { config, socket })
+    }
+
+    /// Synchronize with the server
+    ///
+    /// It works the same way as [`crate::SntpClient::synchronize`], including the retries, but
+    /// the request is sent on the socket of the session. The socket is shared by all servers, so
+    /// it is never connected and [`Config::connect_ip`] does not apply, replies are matched by
+    /// their source address and originate timestamp instead.
+    pub fn synchronize<A: ToServerAddrs>(
+        &mut self,
+        server_address: A,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let policy = self.config.retry_policy.clone();
+        let mut attempt = 1;
+
+        loop {
+            match self.synchronize_once(&server_address, policy.timeout(self.config.timeout)) {
+                Ok(mut result) => {
+                    result.set_attempts(attempt);
+                    return Ok(result);
+                }
+                Err(err) if attempt < policy.max_attempts() && policy.should_retry(&err) => {
+                    std::thread::sleep(policy.backoff_time(attempt));
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn synchronize_once<A: ToServerAddrs>(
+        &mut self,
+        server_address: &A,
+        timeout: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let remote_address = transport::select_address(
+            std::net::ToSocketAddrs::to_socket_addrs(
+                &server_address.to_server_addrs(self.config.server_port()),
+            )?,
+            self.config.bind_address,
+        )?;
+        let request = self.config.new_request();
+        let request_bytes = self.config.encode_request(&request);
+        let transmit_timestamp = request.transmit_timestamp().to_bits();
+        let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        self.socket.send_to(&request_bytes, remote_address)?;
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Err(timeout_error().into());
+            }
+
+            let received = self.socket.recv_from(&mut receive_buffer, remaining)?;
+            let anchor = self.config.anchor();
+            let reply = &receive_buffer[..received.length()];
+
+            if received.source() != remote_address
+                || originate_timestamp(reply) != Some(transmit_timestamp)
+            {
+                continue;
+            }
+
+            let request = request.with_send_time(self.socket.transmit_timestamp());
+            let reply_time = received.timestamp().unwrap_or(anchor.time);
+            let authenticated = self.config.authenticate_reply(reply)?;
+
+            return self.config.process_reply(
+                request,
+                reply,
+                received.source(),
+                reply_time,
+                anchor,
+                authenticated,
+            );
+        }
+    }
+}
+
+/// Reply waiting to be processed by the request it belongs to
+#[cfg(feature = "async")]
+type PendingReply = (Vec<u8>, SystemTime, Anchor);
+
+/// Requests waiting for a reply, by server address and transmit timestamp
+#[cfg(feature = "async")]
+type PendingRequests = HashMap<(SocketAddr, u64), oneshot::Sender<PendingReply>>;
+
+/// Error kind and message of the failure of the socket, `io::Error` is not `Clone`
+#[cfg(feature = "async")]
+type SocketFailure = Arc<Mutex<Option<(std::io::ErrorKind, String)>>>;
+
+/// Request to be sent by the socket task, the transmit timestamp of the socket is returned
+#[cfg(feature = "async")]
+struct OutgoingRequest {
+    packet: Vec<u8>,
+    address: SocketAddr,
+    sent: oneshot::Sender<std::io::Result<Option<SystemTime>>>,
+}
+
+/// Event of the socket task
+#[cfg(feature = "async")]
+enum SocketEvent {
+    Send(Option<OutgoingRequest>),
+    Receive(std::io::Result<ReceivedPacket>),
+}
+
+/// Removes the request from the pending ones when the exchange ends or it is cancelled
+#[cfg(feature = "async")]
+struct PendingGuard<'a> {
+    pending: &'a Mutex<PendingRequests>,
+    key: (SocketAddr, u64),
+}
+
+#[cfg(feature = "async")]
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Asynchronous client with a persistent socket
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// It is the asynchronous counterpart of [`SntpSession`], but it supports concurrent
+/// synchronizations: the socket is owned by a background task, which sends the requests and
+/// dispatches the replies to the pending requests by their source address and originate
+/// timestamp. Therefore it must be created within a Tokio runtime. The background task is stopped
+/// when the session is dropped.
+///
+/// The background task cancels the `recv_from` future of the socket to send a request, so the
+/// sockets of the transport must support it without losing packets, like the ones of
+/// [`UdpTransport`]. If receiving fails with an error other than an ICMP error of an earlier
+/// request, the session cannot be used any more: the pending and later synchronizations fail
+/// immediately with that error.
+///
+/// Concurrent requests to the same server are distinguished by their transmit timestamp. If it
+/// is not unique, e.g. because of a manually set clock source, the synchronization fails with an
+/// error of kind [`std::io::ErrorKind::AlreadyExists`]. Enabling
+/// [`Config::random_transmit_timestamp`] avoids this.
+///
+/// Network Time Security is not supported, binding fails if it is enabled in the configuration.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{AsyncSntpSession, Config};
+///
+/// async fn poll() {
+///     let session = AsyncSntpSession::bind(Config::default()).await.unwrap();
+///
+///     let (first, second) = tokio::join!(
+///         session.synchronize("0.pool.ntp.org"),
+///         session.synchronize("1.pool.ntp.org")
+///     );
+///
+///     println!("{:?} {:?}", first.unwrap().clock_offset(), second.unwrap().clock_offset());
+/// }
+/// ```
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncSntpSession<T: AsyncTransport = UdpTransport> {
+    config: Config,
+    local_address: Option<SocketAddr>,
+    requests: mpsc::UnboundedSender<OutgoingRequest>,
+    pending: Arc<Mutex<PendingRequests>>,
+    failure: SocketFailure,
+    socket_task: tokio::task::JoinHandle<()>,
+    transport: PhantomData<fn() -> T>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncSntpSession {
+    /// Creates a session with a UDP socket bound to the bind address of the configuration
+    pub async fn bind(config: Config) -> std::io::Result<AsyncSntpSession> {
+        check_config(&config)?;
+
+        let socket = AsyncTransport::bind(&UdpTransport, config.bind_address).await?;
+        let local_address = socket.local_addr()?;
+
+        Ok(AsyncSntpSession::start(config, socket, Some(local_address)))
+    }
+
+    /// Returns with the local address of the socket
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.local_address
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> AsyncSntpSession<T>
+where
+    T::Socket: 'static,
+{
+    /// Creates a session with a socket of the transport, bound to the bind address of the
+    /// configuration
+    pub async fn bind_with_transport(
+        config: Config,
+        transport: T,
+    ) -> std::io::Result<AsyncSntpSession<T>> {
+        check_config(&config)?;
+
+        let socket = transport.bind(config.bind_address).await?;
+
+        Ok(AsyncSntpSession::start(config, socket, None))
+    }
+
+    fn start(
+        config: Config,
+        socket: T::Socket,
+        local_address: Option<SocketAddr>,
+    ) -> AsyncSntpSession<T> {
+        let (requests, request_receiver) = mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(PendingRequests::new()));
+        let failure = SocketFailure::default();
+        let socket_task = tokio::spawn(run_socket(
+            socket,
+            request_receiver,
+            pending.clone(),
+            failure.clone(),
+            config.clock_source.clone(),
+        ));
+
+        AsyncSntpSession {
+            config,
+            local_address,
+            requests,
+            pending,
+            failure,
+            socket_task,
+            transport: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> AsyncSntpSession<T> {
+    /// Synchronize with the server
+    ///
+    /// It works the same way as [`crate::AsyncSntpClient::synchronize`], including the retries,
+    /// but the request is sent on the socket of the session. It can be called concurrently. The
+    /// socket is shared by all servers, so it is never connected and [`Config::connect_ip`] does
+    /// not apply, replies are matched by their source address and originate timestamp instead.
+    pub async fn synchronize<A: ToServerAddrs>(
+        &self,
+        server_address: A,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        let policy = &self.config.retry_policy;
+        let mut attempt = 1;
+
+        loop {
+            match self
+                .synchronize_once(&server_address, policy.timeout(self.config.timeout))
+                .await
+            {
+                Ok(mut result) => {
+                    result.set_attempts(attempt);
+                    return Ok(result);
+                }
+                Err(err) if attempt < policy.max_attempts() && policy.should_retry(&err) => {
+                    tokio::time::sleep(policy.backoff_time(attempt)).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn synchronize_once<A: ToServerAddrs>(
+        &self,
+        server_address: &A,
+        timeout: Duration,
+    ) -> Result<SynchronizationResult, SynchronizationError> {
+        if self.failure.lock().unwrap().is_some() {
+            return Err(self.socket_error().into());
+        }
+
+        let remote_address = transport::select_address(
+            tokio::net::lookup_host(server_address.to_server_addrs(self.config.server_port()))
+                .await?,
+            self.config.bind_address,
+        )?;
+        let request = self.config.new_request();
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let (sent_sender, sent_receiver) = oneshot::channel();
+        let _guard = self.register(&request, remote_address, reply_sender)?;
+
+        self.requests
+            .send(OutgoingRequest {
+                packet: self.config.encode_request(&request),
+                address: remote_address,
+                sent: sent_sender,
+            })
+            .map_err(|_| self.socket_error())?;
+
+        let transmit_timestamp = sent_receiver.await.map_err(|_| self.socket_error())??;
+        let (reply, reply_time, anchor) = tokio::time::timeout(timeout, reply_receiver)
+            .await
+            .map_err(|_| timeout_error())?
+            .map_err(|_| self.socket_error())?;
+
+        let request = request.with_send_time(transmit_timestamp);
+        let authenticated = self.config.authenticate_reply(&reply)?;
+
+        self.config.process_reply(
+            request,
+            &reply,
+            remote_address,
+            reply_time,
+            anchor,
+            authenticated,
+        )
+    }
+
+    fn register(
+        &self,
+        request: &Request,
+        remote_address: SocketAddr,
+        reply_sender: oneshot::Sender<PendingReply>,
+    ) -> std::io::Result<PendingGuard<'_>> {
+        let key = (remote_address, request.transmit_timestamp().to_bits());
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.contains_key(&key) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "A request with the same transmit timestamp is already pending",
+            ));
+        }
+
+        pending.insert(key, reply_sender);
+
+        Ok(PendingGuard {
+            pending: &self.pending,
+            key,
+        })
+    }
+
+    /// Returns with the error which stopped the socket task
+    fn socket_error(&self) -> std::io::Error {
+        match &*self.failure.lock().unwrap() {
+            Some((kind, message)) => std::io::Error::new(
+                *kind,
+                format!("The socket of the session has failed: {message}"),
+            ),
+            None => std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "The socket of the session has failed",
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport> Drop for AsyncSntpSession<T> {
+    fn drop(&mut self) {
+        self.socket_task.abort();
+    }
+}
+
+/// Sends the requests of a session and dispatches the replies to the pending requests
+#[cfg(feature = "async")]
+async fn run_socket<S: AsyncTransportSocket>(
+    mut socket: S,
+    mut requests: mpsc::UnboundedReceiver<OutgoingRequest>,
+    pending: Arc<Mutex<PendingRequests>>,
+    failure: SocketFailure,
+    clock_source: SharedClockSource,
+) {
+    let mut receive_buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+    loop {
+        let event = {
+            let mut receive = std::pin::pin!(socket.recv_from(&mut receive_buffer));
+
+            std::future::poll_fn(|cx| {
+                if let Poll::Ready(request) = requests.poll_recv(cx) {
+                    return Poll::Ready(SocketEvent::Send(request));
+                }
+
+                receive.as_mut().poll(cx).map(SocketEvent::Receive)
+            })
+            .await
+        };
+
+        let received = match event {
+            SocketEvent::Send(Some(request)) => {
+                let result = socket
+                    .send_to(&request.packet, request.address)
+                    .await
+                    .map(|()| socket.transmit_timestamp());
+
+                let _ = request.sent.send(result);
+                continue;
+            }
+            // the session has been dropped
+            SocketEvent::Send(None) => break,
+            SocketEvent::Receive(Ok(received)) => received,
+            // ICMP errors of earlier requests, the socket is still usable
+            SocketEvent::Receive(Err(err))
+                if err.kind() == std::io::ErrorKind::ConnectionRefused
+                    || err.kind() == std::io::ErrorKind::ConnectionReset =>
+            {
+                continue
+            }
+            SocketEvent::Receive(Err(err)) => {
+                *failure.lock().unwrap() = Some((err.kind(), err.to_string()));
+                break;
+            }
+        };
+        let anchor = clock_source.anchor();
+        let reply_time = received.timestamp().unwrap_or(anchor.time);
+        let reply = &receive_buffer[..received.length()];
+
+        let Some(originate_timestamp) = originate_timestamp(reply) else {
+            continue;
+        };
+
+        // duplicates and late replies have no pending request, they are dropped
+        if let Some(sender) = pending
+            .lock()
+            .unwrap()
+            .remove(&(received.source(), originate_timestamp))
+        {
+            let _ = sender.send((reply.to_vec(), reply_time, anchor));
+        }
+    }
+
+    // the pending requests fail instead of waiting for the timeout
+    pending.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackServer;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn originate_timestamp_is_read_from_replies() {
+        let reply = Packet::new(crate::packet::Mode::Server)
+            .originate_timestamp(crate::packet::SntpTimestamp::from_bits(
+                0x1234_5678_9abc_def0,
+            ))
+            .encode();
+
+        assert_eq!(originate_timestamp(&reply), Some(0x1234_5678_9abc_def0));
+        assert_eq!(originate_timestamp(&reply[..40]), None);
+    }
+
+    /// Answers the requests, sending a stale reply before each one
+    fn serve(requests: usize) -> (SocketAddr, std::thread::JoinHandle<Vec<SocketAddr>>) {
+        let server = LoopbackServer::bind();
+        let address = server.local_addr();
+        let handle = server.serve(requests, |exchange| {
+            let reply = exchange.reply();
+            let mut stale = reply.clone();
+
+            stale[24..32].copy_from_slice(&[0xff; 8]);
+
+            exchange.send(&stale);
+            exchange.send(&reply);
+        });
+
+        (address, handle)
+    }
+
+    #[test]
+    fn session_uses_one_socket() {
+        let (address, handle) = serve(3);
+        let mut session =
+            SntpSession::bind(Config::default().bind_address("127.0.0.1:0".parse().unwrap()))
+                .unwrap();
+
+        for _ in 0..3 {
+            let result = session.synchronize(address).unwrap();
+
+            assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+        }
+
+        let sources = handle.join().unwrap();
+
+        assert!(sources.iter().all(|source| *source == sources[0]));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_session_supports_concurrent_requests() {
+        let servers: Vec<_> = (0..4).map(|_| serve(2)).collect();
+
+        let session = AsyncSntpSession::bind(
+            Config::default()
+                .bind_address("127.0.0.1:0".parse().unwrap())
+                .random_transmit_timestamp(true),
+        )
+        .await
+        .unwrap();
+        let requests = servers
+            .iter()
+            .flat_map(|(address, _)| [*address, *address])
+            .map(|address| session.synchronize(address));
+
+        for result in crate::join::join_all(requests).await {
+            assert!(result.unwrap().clock_offset().as_secs_f64().abs() < 0.1);
+        }
+
+        let local_address = session.local_addr().unwrap();
+
+        for (_, handle) in servers {
+            assert!(handle
+                .join()
+                .unwrap()
+                .iter()
+                .all(|source| *source == local_address));
+        }
+    }
+
+    /// Transport with sockets that fail to receive
+    #[cfg(feature = "async")]
+    struct BrokenTransport;
+
+    #[cfg(feature = "async")]
+    struct BrokenSocket;
+
+    #[cfg(feature = "async")]
+    impl AsyncTransport for BrokenTransport {
+        type Socket = BrokenSocket;
+
+        async fn bind(&self, _address: SocketAddr) -> std::io::Result<BrokenSocket> {
+            Ok(BrokenSocket)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl AsyncTransportSocket for BrokenSocket {
+        async fn connect(&mut self, _address: SocketAddr) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn send_to(&mut self, _packet: &[u8], _address: SocketAddr) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn recv_from(&mut self, _buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_session_fails_immediately_after_socket_failure() {
+        let session = AsyncSntpSession::bind_with_transport(
+            Config::default().timeout(Duration::from_secs(10)),
+            BrokenTransport,
+        )
+        .await
+        .unwrap();
+
+        for _ in 0..2 {
+            let start = Instant::now();
+
+            match session.synchronize("127.0.0.1:123").await {
+                Err(SynchronizationError::IOError(err)) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied)
+                }
+                other => panic!("Unexpected result: {other:?}"),
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//! Utilities for testing code which depends on `rsntp`
+//!
+//! Only available when the `testing` feature is enabled.
+//!
+//! [`MockServer`] is an in-process, scriptable NTP server. It is a transport, so it can be used
+//! with both [`crate::SntpClient`] and [`crate::AsyncSntpClient`] instead of the network. Its
+//! sockets report the send and receive times of the packets, like kernel timestamps, and the
+//! timestamps of the replies are calculated from the send time of the request, so the clock
+//! offset and round-trip delay measured by the client are exactly the configured ones, regardless
+//! of the scheduling of the test.
+//!
+//! [`VirtualClock`] is a manually controlled clock, which can be used as the clock of the mock
+//! server, as the clock source of the clients (see [`crate::Config::clock_source`]) or as the time
+//! source of a real [`crate::SntpServer`].
+//!
+//! # Example
+//!
+//! ```
+//! use rsntp::testing::{MockReply, MockServer};
+//! use rsntp::{Config, SntpClient};
+//! use std::time::Duration;
+//!
+//! let server = MockServer::new()
+//!     .offset(2.5)
+//!     .delay(Duration::from_millis(30), Duration::from_millis(10));
+//! let client = SntpClient::with_transport(Config::default(), server.clone());
+//!
+//! let result = client.synchronize("192.0.2.1").unwrap();
+//!
+//! // the asymmetry of the delay shows up as an error of half of the difference
+//! assert!((result.clock_offset().as_secs_f64() - 2.51).abs() < 1e-6);
+//! assert!((result.round_trip_delay().as_secs_f64() - 0.04).abs() < 1e-6);
+//!
+//! server.script([MockReply::KissODeath(*b"RATE")]);
+//!
+//! assert!(client.synchronize("192.0.2.1").unwrap_err().is_kiss_of_death());
+//! ```
+use crate::error::ReferenceIdentifierError;
+use crate::packet::{LeapIndicator, Mode, Packet, ReferenceIdentifier, SntpTimestamp};
+use crate::transport::{ReceivedPacket, Transport, TransportSocket};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "async")]
+use crate::transport::{AsyncTransport, AsyncTransportSocket};
+
+/// Manually controlled clock
+///
+/// It is the same type as [`crate::ManualClock`], re-exported under the name used by the
+/// testing utilities.
+///
+/// # Example
+///
+/// ```
+/// use rsntp::testing::VirtualClock;
+/// use std::time::{Duration, SystemTime};
+///
+/// let clock = VirtualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+/// let shared = clock.clone();
+///
+/// clock.advance(Duration::from_secs(60));
+///
+/// assert_eq!(shared.now(), SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_060));
+/// ```
+pub use crate::clock_source::ManualClock as VirtualClock;
+
+/// Behaviour of [`MockServer`] for a single request
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MockReply {
+    /// A regular reply is sent
+    Normal,
+    /// The request or the reply is lost, no reply is received
+    Lost,
+    /// The reply is received twice
+    Duplicate,
+    /// The reply to the previous request is sent, as if it had been delayed in the network
+    Stale,
+    /// A Kiss-o'-Death reply is sent with the specified kiss code (e.g. `*b"RATE"`)
+    ///
+    /// The code is sent as is in the reference identifier, so non-ASCII codes can be used to
+    /// test malformed replies.
+    KissODeath([u8; 4]),
+    /// The specified bytes are sent instead of the reply
+    Malformed(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum ServerClock {
+    Offset(f64),
+    Virtual(VirtualClock),
+}
+
+#[derive(Debug)]
+struct MockState {
+    clock: ServerClock,
+    client_clock: Option<VirtualClock>,
+    forward_delay: Duration,
+    backward_delay: Duration,
+    stratum: u8,
+    leap_indicator: LeapIndicator,
+    reference_identifier: ReferenceIdentifier,
+    script: VecDeque<MockReply>,
+    requests: Vec<Packet>,
+}
+
+/// Reply sent to a socket of the mock server
+type QueuedReply = (Vec<u8>, SocketAddr, SystemTime);
+
+/// In-process, scriptable NTP server
+///
+/// It implements [`Transport`] (and [`AsyncTransport`] if the `async` feature is enabled), so
+/// it can be passed to the clients with `with_transport`. Clones share the same state, so the
+/// server can be scripted and inspected while it is used by a client.
+///
+/// The server clock is either offset from the client clock by a fixed amount (see
+/// [`MockServer::offset`]) or driven by a [`VirtualClock`] (see [`MockServer::clock`]). The client
+/// clock is the system clock, unless a virtual one is set with [`MockServer::client_clock`].
+/// Network delays are simulated without waiting, the client only sees them in the timestamps.
+///
+/// By default, every request is answered with a regular reply. Other behaviours can be
+/// scripted for the next requests with [`MockServer::script`]. Lost replies are reported as
+/// timeouts immediately by the blocking client, while the asynchronous client waits for its
+/// timeout.
+#[derive(Clone, Debug)]
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockServer {
+    /// Creates a server with the same time as the client and no network delay
+    pub fn new() -> MockServer {
+        MockServer::default()
+    }
+
+    /// Sets the offset of the server clock from the client clock in seconds
+    ///
+    /// Positive values mean that the server clock is ahead of the client clock. Default is zero.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset is not finite or it is too large to be represented as a duration.
+    pub fn offset(self, offset: f64) -> MockServer {
+        assert!(
+            Duration::try_from_secs_f64(offset.abs()).is_ok(),
+            "Offset must be a finite number of seconds"
+        );
+
+        self.state().clock = ServerClock::Offset(offset);
+        self
+    }
+
+    /// Uses a virtual clock as the server clock
+    ///
+    /// The clock is advanced by the simulated network delays of each exchange.
+    pub fn clock(self, clock: VirtualClock) -> MockServer {
+        self.state().clock = ServerClock::Virtual(clock);
+        self
+    }
+
+    /// Uses a virtual clock as the client clock
+    ///
+    /// The send and receive times reported by the sockets are read from this clock, so it should
+    /// be the clock source of the client as well (see [`crate::Config::clock_source`]). Default
+    /// is the system clock.
+    pub fn client_clock(self, clock: VirtualClock) -> MockServer {
+        self.state().client_clock = Some(clock);
+        self
+    }
+
+    /// Sets the network delay from the client to the server and back
+    ///
+    /// Asymmetric delays cause an error of half of their difference in the clock offset measured
+    /// by the client. Default is zero.
+    pub fn delay(self, forward: Duration, backward: Duration) -> MockServer {
+        {
+            let mut state = self.state();
+
+            state.forward_delay = forward;
+            state.backward_delay = backward;
+        }
+
+        self
+    }
+
+    /// Sets the stratum of the server, default is 1
+    pub fn stratum(self, stratum: u8) -> MockServer {
+        self.state().stratum = stratum;
+        self
+    }
+
+    /// Sets the leap indicator sent by the server, default is [`LeapIndicator::NoWarning`]
+    pub fn leap_indicator(self, leap_indicator: LeapIndicator) -> MockServer {
+        self.state().leap_indicator = leap_indicator;
+        self
+    }
+
+    /// Sets the reference identifier of the server, default is `MOCK`
+    ///
+    /// Returns an error if the reference identifier cannot be encoded, see
+    /// [`ReferenceIdentifier::validate`].
+    pub fn reference_identifier(
+        self,
+        reference_identifier: ReferenceIdentifier,
+    ) -> Result<MockServer, ReferenceIdentifierError> {
+        reference_identifier.validate()?;
+        self.state().reference_identifier = reference_identifier;
+
+        Ok(self)
+    }
+
+    /// Scripts the behaviour of the server for the next requests
+    ///
+    /// The behaviours are appended to the ones not used yet, each request consumes one of them.
+    /// Requests without a scripted behaviour get a [`MockReply::Normal`] reply.
+    pub fn script<I: IntoIterator<Item = MockReply>>(&self, replies: I) {
+        self.state().script.extend(replies);
+    }
+
+    /// Returns with the requests received by the server
+    pub fn requests(&self) -> Vec<Packet> {
+        self.state().requests.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    fn client_now(&self) -> SystemTime {
+        self.state()
+            .client_clock
+            .as_ref()
+            .map_or_else(SystemTime::now, VirtualClock::now)
+    }
+
+    /// Processes a request, returns with the replies and their receive time on the client clock
+    ///
+    /// The send time is tracked separately from the transmit timestamp of the request, which is
+    /// a random nonce if [`crate::Config::random_transmit_timestamp`] is enabled.
+    fn exchange(
+        &self,
+        request: &[u8],
+        address: SocketAddr,
+        send_time: SystemTime,
+    ) -> Vec<QueuedReply> {
+        let mut state = self.state();
+
+        let request = match Packet::decode(request, address.ip()) {
+            Ok(request) if request.mode == Mode::Client => request,
+            _ => return Vec::new(),
+        };
+
+        let behaviour = state.script.pop_front().unwrap_or(MockReply::Normal);
+        let previous_request = state.requests.last().cloned();
+
+        state.requests.push(request.clone());
+
+        let forward_delay = state.forward_delay;
+        let backward_delay = state.backward_delay;
+
+        let server_time = match &state.clock {
+            ServerClock::Offset(offset) => add_secs(send_time + forward_delay, *offset),
+            ServerClock::Virtual(clock) => {
+                clock.advance(forward_delay);
+                let server_time = clock.now();
+                clock.advance(backward_delay);
+                server_time
+            }
+        };
+        let receive_time = send_time + forward_delay + backward_delay;
+
+        let reply = Packet::new(Mode::Server)
+            .version(request.version)
+            .leap_indicator(state.leap_indicator)
+            .stratum(state.stratum)
+            .reference_identifier(state.reference_identifier.clone())
+            .expect("Reference identifier is validated when it is set")
+            .reference_timestamp(SntpTimestamp::saturating_from_systemtime(server_time))
+            .originate_timestamp(request.transmit_timestamp)
+            .receive_timestamp(SntpTimestamp::saturating_from_systemtime(server_time))
+            .transmit_timestamp(SntpTimestamp::saturating_from_systemtime(server_time));
+
+        let replies = match behaviour {
+            MockReply::Normal => vec![reply.encode().to_vec()],
+            MockReply::Lost => Vec::new(),
+            MockReply::Duplicate => vec![reply.encode().to_vec(), reply.encode().to_vec()],
+            MockReply::Stale => {
+                let originate_timestamp = previous_request
+                    .map_or(SntpTimestamp::zero(), |previous| {
+                        previous.transmit_timestamp
+                    });
+
+                vec![reply
+                    .originate_timestamp(originate_timestamp)
+                    .encode()
+                    .to_vec()]
+            }
+            MockReply::KissODeath(code) => {
+                let mut reply = reply
+                    .leap_indicator(LeapIndicator::AlarmCondition)
+                    .stratum(0)
+                    .encode()
+                    .to_vec();
+
+                reply[12..16].copy_from_slice(&code);
+
+                vec![reply]
+            }
+            MockReply::Malformed(bytes) => vec![bytes],
+        };
+
+        replies
+            .into_iter()
+            .map(|reply| (reply, address, receive_time))
+            .collect()
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> MockServer {
+        MockServer {
+            state: Arc::new(Mutex::new(MockState {
+                clock: ServerClock::Offset(0.0),
+                client_clock: None,
+                forward_delay: Duration::ZERO,
+                backward_delay: Duration::ZERO,
+                stratum: 1,
+                leap_indicator: LeapIndicator::NoWarning,
+                reference_identifier: ReferenceIdentifier::ASCII("MOCK".to_string()),
+                script: VecDeque::new(),
+                requests: Vec::new(),
+            })),
+        }
+    }
+}
+
+fn add_secs(time: SystemTime, secs: f64) -> SystemTime {
+    let duration = Duration::from_secs_f64(secs.abs());
+
+    if secs >= 0.0 {
+        time + duration
+    } else {
+        time - duration
+    }
+}
+
+/// Socket of [`MockServer`], holding the replies not received yet
+#[derive(Debug)]
+pub struct MockSocket {
+    server: MockServer,
+    replies: VecDeque<QueuedReply>,
+    send_time: Option<SystemTime>,
+}
+
+impl MockSocket {
+    fn send(&mut self, packet: &[u8], address: SocketAddr) {
+        let send_time = self.server.client_now();
+        let replies = self.server.exchange(packet, address, send_time);
+
+        self.send_time = Some(send_time);
+        self.replies.extend(replies);
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Option<ReceivedPacket> {
+        let (reply, source, receive_time) = self.replies.pop_front()?;
+        let length = reply.len().min(buffer.len());
+
+        buffer[..length].copy_from_slice(&reply[..length]);
+        Some(ReceivedPacket::new(length, source).with_timestamp(receive_time))
+    }
+}
+
+impl Transport for MockServer {
+    type Socket = MockSocket;
+
+    fn bind(&self, _address: SocketAddr) -> std::io::Result<MockSocket> {
+        Ok(MockSocket {
+            server: self.clone(),
+            replies: VecDeque::new(),
+            send_time: None,
+        })
+    }
+}
+
+impl TransportSocket for MockSocket {
+    fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        self.send(packet, address);
+        Ok(())
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        _timeout: Duration,
+    ) -> std::io::Result<ReceivedPacket> {
+        self.receive(buffer).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "No reply from the mock server",
+            )
+        })
+    }
+
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        self.send_time
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for MockServer {
+    type Socket = MockSocket;
+
+    async fn bind(&self, address: SocketAddr) -> std::io::Result<MockSocket> {
+        Transport::bind(self, address)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransportSocket for MockSocket {
+    async fn connect(&mut self, _address: SocketAddr) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        self.send(packet, address);
+        Ok(())
+    }
+
+    async fn recv_from(&mut self, buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+        match self.receive(buffer) {
+            Some(received) => Ok(received),
+            // lost replies never arrive, the client times out
+            None => std::future::pending().await,
+        }
+    }
+
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        self.send_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ProtocolError, SynchronizationError};
+    use crate::{Config, SntpClient};
+
+    const SERVER: &str = "192.0.2.1";
+
+    fn client(server: &MockServer) -> SntpClient<MockServer> {
+        SntpClient::with_transport(Config::default(), server.clone())
+    }
+
+    #[test]
+    fn offset_and_asymmetric_delay_are_simulated() {
+        let server = MockServer::new()
+            .offset(-100.0)
+            .delay(Duration::from_millis(10), Duration::from_millis(50))
+            .leap_indicator(LeapIndicator::LastMinuteHas61Seconds);
+
+        let result = client(&server).synchronize(SERVER).unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() + 100.02).abs() < 1e-6);
+        assert!((result.round_trip_delay().as_secs_f64() - 0.06).abs() < 1e-6);
+        assert_eq!(result.stratum(), 1);
+        assert_eq!(
+            result.leap_indicator(),
+            LeapIndicator::LastMinuteHas61Seconds
+        );
+        assert_eq!(result.reference_identifier().to_string(), "MOCK");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn virtual_clock_is_used_as_server_clock() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        let clock = VirtualClock::new(time);
+        let server = MockServer::new()
+            .clock(clock.clone())
+            .client_clock(VirtualClock::new(time))
+            .delay(Duration::from_secs(1), Duration::from_secs(1));
+
+        let result = client(&server).synchronize(SERVER).unwrap();
+
+        assert_eq!(clock.now(), time + Duration::from_secs(2));
+        assert!(result.clock_offset().as_secs_f64().abs() < 1e-6);
+        assert!((result.round_trip_delay().as_secs_f64() - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn random_transmit_timestamp_does_not_affect_the_offset() {
+        let server = MockServer::new()
+            .offset(2.5)
+            .delay(Duration::from_millis(20), Duration::from_millis(20));
+        let client = SntpClient::with_transport(
+            Config::default().random_transmit_timestamp(true),
+            server.clone(),
+        );
+
+        let result = client.synchronize(SERVER).unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 2.5).abs() < 1e-6);
+        assert!((result.round_trip_delay().as_secs_f64() - 0.04).abs() < 1e-6);
+    }
+
+    #[test]
+    fn virtual_clock_is_used_as_client_clock() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let client_clock = VirtualClock::new(time);
+        let server = MockServer::new()
+            .clock(VirtualClock::new(time + Duration::from_secs(30)))
+            .client_clock(client_clock.clone())
+            .delay(Duration::from_millis(100), Duration::from_millis(100));
+        let client = SntpClient::with_transport(
+            Config::default().clock_source(client_clock.clone()),
+            server.clone(),
+        );
+
+        let result = client.synchronize(SERVER).unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 30.0).abs() < 1e-6);
+        assert!((result.round_trip_delay().as_secs_f64() - 0.2).abs() < 1e-6);
+
+        client_clock.advance(Duration::from_secs(3600));
+
+        let datetime = result.datetime().into_system_time().unwrap();
+        let expected = time + Duration::from_secs(3630);
+
+        assert!(
+            datetime
+                .max(expected)
+                .duration_since(datetime.min(expected))
+                .unwrap()
+                < Duration::from_micros(1)
+        );
+    }
+
+    #[test]
+    fn non_ascii_kiss_codes_are_sent_as_is() {
+        let server = MockServer::new();
+        let client = client(&server);
+
+        server.script([MockReply::KissODeath([0xff; 4])]);
+
+        assert!(client.synchronize(SERVER).is_err());
+        assert!(client.synchronize(SERVER).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Offset must be a finite number of seconds")]
+    fn invalid_offset_is_rejected_when_it_is_set() {
+        let _ = MockServer::new().offset(f64::NAN);
+    }
+
+    #[test]
+    fn scripted_failures_are_reported() {
+        let server = MockServer::new();
+        let client = client(&server);
+
+        server.script([
+            MockReply::Lost,
+            MockReply::Normal,
+            MockReply::Stale,
+            MockReply::KissODeath(*b"DENY"),
+            MockReply::Malformed(vec![0x24; 20]),
+        ]);
+
+        assert!(matches!(
+            client.synchronize(SERVER),
+            Err(SynchronizationError::IOError(err)) if err.kind() == std::io::ErrorKind::TimedOut
+        ));
+        assert!(client.synchronize(SERVER).is_ok());
+        assert!(matches!(
+            client.synchronize(SERVER),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::InvalidOriginateTimestamp
+            ))
+        ));
+        assert!(client.synchronize(SERVER).unwrap_err().is_kiss_of_death());
+        assert!(matches!(
+            client.synchronize(SERVER),
+            Err(SynchronizationError::ProtocolError(
+                ProtocolError::PacketIsTooShort
+            ))
+        ));
+        assert!(client.synchronize(SERVER).is_ok());
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[test]
+    fn duplicate_replies_are_received() {
+        let server = MockServer::new();
+        let mut socket = Transport::bind(&server, "0.0.0.0:0".parse().unwrap()).unwrap();
+        let request = Packet::new(Mode::Client)
+            .transmit_timestamp(SntpTimestamp::from_systemtime(SystemTime::now()).unwrap())
+            .encode();
+        let mut buffer = [0; Packet::MAX_RECEIVE_LEN];
+
+        server.script([MockReply::Duplicate]);
+        TransportSocket::send_to(&mut socket, &request, "192.0.2.1:123".parse().unwrap()).unwrap();
+
+        let first = TransportSocket::recv_from(&mut socket, &mut buffer, Duration::ZERO).unwrap();
+        let second = TransportSocket::recv_from(&mut socket, &mut buffer, Duration::ZERO).unwrap();
+
+        assert_eq!(first, second);
+        assert!(TransportSocket::recv_from(&mut socket, &mut buffer, Duration::ZERO).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_client_works_with_mock_server() {
+        let server = MockServer::new().offset(1.0);
+        let client = crate::AsyncSntpClient::with_transport(
+            Config::default().timeout(Duration::from_millis(100)),
+            server.clone(),
+        );
+
+        server.script([MockReply::Lost]);
+
+        assert!(client.synchronize(SERVER).await.is_err());
+
+        let result = client.synchronize(SERVER).await.unwrap();
+
+        assert!((result.clock_offset().as_secs_f64() - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,531 @@
+use crate::transport::{self, ReceivedPacket, Transport, TransportSocket};
+use std::mem::{size_of, zeroed};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "async")]
+use crate::transport::{AsyncTransport, AsyncTransportSocket};
+
+/// UDP transport using kernel timestamps
+///
+/// It enables `SO_TIMESTAMPING` on the sockets, so the receive time of the replies and the send
+/// time of the requests are taken by the kernel instead of the client. This excludes the latency
+/// of the scheduler and the system calls from the measurement. If `SO_TIMESTAMPING` is not
+/// supported, it falls back to `SO_TIMESTAMPNS`, which only provides receive timestamps.
+///
+/// Hardware timestamps can be requested with [`TimestampingTransport::hardware`]. They are only
+/// available if hardware timestamping is enabled on the network interface (e.g. with
+/// `hwstamp_ctl`) and the clock of the interface is synchronized to the system clock (e.g. with
+/// `phc2sys`), otherwise software timestamps are used.
+///
+/// Only available on Linux, when the `kernel-timestamps` feature is enabled.
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, SntpClient, TimestampingTransport};
+///
+/// let client = SntpClient::with_transport(Config::default(), TimestampingTransport::new());
+/// let result = client.synchronize("pool.ntp.org").unwrap();
+///
+/// println!("Clock offset: {} seconds", result.clock_offset().as_secs_f64());
+/// ```
+#[derive(Clone, Copy, Debug, Default, Hash)]
+pub struct TimestampingTransport {
+    hardware: bool,
+}
+
+impl TimestampingTransport {
+    /// Creates a transport using software timestamps
+    pub fn new() -> TimestampingTransport {
+        TimestampingTransport::default()
+    }
+
+    /// Enables hardware timestamps
+    ///
+    /// Hardware timestamps are preferred if they are available, software timestamps are used
+    /// otherwise. Default is false.
+    pub fn hardware(self, hardware: bool) -> TimestampingTransport {
+        TimestampingTransport { hardware }
+    }
+
+    fn bind_socket(&self, address: SocketAddr) -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(address)?;
+
+        enable_timestamps(socket.as_raw_fd(), self.hardware)?;
+
+        Ok(socket)
+    }
+}
+
+/// UDP socket of [`TimestampingTransport`]
+#[derive(Debug)]
+pub struct TimestampingSocket {
+    socket: UdpSocket,
+    hardware: bool,
+    connected: bool,
+    transmit_timestamp: Option<SystemTime>,
+}
+
+impl Transport for TimestampingTransport {
+    type Socket = TimestampingSocket;
+
+    fn bind(&self, address: SocketAddr) -> std::io::Result<TimestampingSocket> {
+        Ok(TimestampingSocket {
+            socket: self.bind_socket(address)?,
+            hardware: self.hardware,
+            connected: false,
+            transmit_timestamp: None,
+        })
+    }
+}
+
+impl TransportSocket for TimestampingSocket {
+    fn connect(&mut self, address: SocketAddr) -> std::io::Result<()> {
+        self.socket.connect(address)?;
+        self.connected = true;
+        Ok(())
+    }
+
+    fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        self.transmit_timestamp = None;
+
+        if self.connected {
+            self.socket.send(packet)?;
+        } else {
+            self.socket.send_to(packet, address)?;
+        }
+
+        Ok(())
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> std::io::Result<ReceivedPacket> {
+        self.socket.set_read_timeout(Some(timeout))?;
+
+        let fd = self.socket.as_raw_fd();
+        let message = receive(fd, buffer, 0, self.hardware)?;
+
+        // the transmit timestamp has been queued before the reply arrived
+        self.transmit_timestamp = self
+            .transmit_timestamp
+            .or_else(|| receive_transmit_timestamp(fd, self.hardware));
+
+        Ok(message.into_received_packet())
+    }
+
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        // the timestamp might not have been read yet, e.g. right after sending
+        self.transmit_timestamp = self
+            .transmit_timestamp
+            .or_else(|| receive_transmit_timestamp(self.socket.as_raw_fd(), self.hardware));
+
+        self.transmit_timestamp
+    }
+
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        if self.socket.local_addr()?.is_ipv6() {
+            transport::set_multicast_hops_v6(&self.socket, hops)
+        } else {
+            self.socket.set_multicast_ttl_v4(hops)
+        }
+    }
+}
+
+/// Asynchronous UDP socket of [`TimestampingTransport`]
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncTimestampingSocket {
+    socket: tokio::net::UdpSocket,
+    hardware: bool,
+    connected: bool,
+    transmit_timestamp: Option<SystemTime>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for TimestampingTransport {
+    type Socket = AsyncTimestampingSocket;
+
+    async fn bind(&self, address: SocketAddr) -> std::io::Result<AsyncTimestampingSocket> {
+        let socket = self.bind_socket(address)?;
+
+        socket.set_nonblocking(true)?;
+
+        Ok(AsyncTimestampingSocket {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            hardware: self.hardware,
+            connected: false,
+            transmit_timestamp: None,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransportSocket for AsyncTimestampingSocket {
+    async fn connect(&mut self, address: SocketAddr) -> std::io::Result<()> {
+        self.socket.connect(address).await?;
+        self.connected = true;
+        Ok(())
+    }
+
+    async fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        self.transmit_timestamp = None;
+
+        if self.connected {
+            self.socket.send(packet).await?;
+        } else {
+            self.socket.send_to(packet, address).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn recv_from(&mut self, buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+        let fd = self.socket.as_raw_fd();
+        let hardware = self.hardware;
+        let message = self
+            .socket
+            .async_io(tokio::io::Interest::READABLE, || {
+                receive(fd, buffer, 0, hardware)
+            })
+            .await?;
+
+        self.transmit_timestamp = self
+            .transmit_timestamp
+            .or_else(|| receive_transmit_timestamp(fd, hardware));
+
+        Ok(message.into_received_packet())
+    }
+
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        // the timestamp might not have been read yet, e.g. right after sending
+        self.transmit_timestamp = self
+            .transmit_timestamp
+            .or_else(|| receive_transmit_timestamp(self.socket.as_raw_fd(), self.hardware));
+
+        self.transmit_timestamp
+    }
+
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        if self.socket.local_addr()?.is_ipv6() {
+            transport::set_multicast_hops_v6(&self.socket, hops)
+        } else {
+            self.socket.set_multicast_ttl_v4(hops)
+        }
+    }
+}
+
+/// Message received with `recvmsg`
+struct Message {
+    length: usize,
+    source: Option<SocketAddr>,
+    timestamp: Option<SystemTime>,
+}
+
+impl Message {
+    fn into_received_packet(self) -> ReceivedPacket {
+        let source = self
+            .source
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let packet = ReceivedPacket::new(self.length, source);
+
+        match self.timestamp {
+            Some(timestamp) => packet.with_timestamp(timestamp),
+            None => packet,
+        }
+    }
+}
+
+fn set_socket_option(fd: RawFd, name: libc::c_int, value: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: the option value is a valid c_int for the duration of the call
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn enable_timestamps(fd: RawFd, hardware: bool) -> std::io::Result<()> {
+    let mut flags = libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+
+    if hardware {
+        flags |= libc::SOF_TIMESTAMPING_RAW_HARDWARE
+            | libc::SOF_TIMESTAMPING_RX_HARDWARE
+            | libc::SOF_TIMESTAMPING_TX_HARDWARE;
+    }
+
+    set_socket_option(fd, libc::SO_TIMESTAMPING, flags as libc::c_int)
+        .or_else(|_| set_socket_option(fd, libc::SO_TIMESTAMPNS, 1))
+}
+
+/// Receives a message with `recvmsg`, along with its kernel timestamp
+fn receive(
+    fd: RawFd,
+    buffer: &mut [u8],
+    flags: libc::c_int,
+    hardware: bool,
+) -> std::io::Result<Message> {
+    // SAFETY: sockaddr_storage is a plain C struct, all zero is a valid value
+    let mut address: libc::sockaddr_storage = unsafe { zeroed() };
+    // u64 elements keep the control messages aligned
+    let mut control = [0u64; 64];
+    let mut iovec = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    // SAFETY: msghdr is a plain C struct, all zero is a valid value
+    let mut header: libc::msghdr = unsafe { zeroed() };
+
+    header.msg_name = &mut address as *mut libc::sockaddr_storage as *mut libc::c_void;
+    header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    header.msg_iov = &mut iovec;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = size_of::<[u64; 64]>() as _;
+
+    // SAFETY: the header points to the address, the buffer and the control buffer, which are
+    // valid for the duration of the call and their lengths are set accordingly
+    let length = unsafe { libc::recvmsg(fd, &mut header, flags) };
+
+    if length < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut timestamp = None;
+    // SAFETY: the header has been filled by recvmsg, its control buffer is still alive
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&header) };
+
+    while !cmsg.is_null() {
+        // SAFETY: cmsg is not null, CMSG_FIRSTHDR and CMSG_NXTHDR only return pointers to
+        // complete control message headers within the control buffer
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        // SAFETY: cmsg points to a valid control message header
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+
+        if level == libc::SOL_SOCKET && kind == libc::SCM_TIMESTAMPNS {
+            // SAFETY: the kernel sends a timespec in SCM_TIMESTAMPNS messages, the data is not
+            // necessarily aligned
+            let time = unsafe { (data as *const libc::timespec).read_unaligned() };
+
+            timestamp = timestamp.or_else(|| to_systemtime(&time));
+        } else if level == libc::SOL_SOCKET && kind == libc::SCM_TIMESTAMPING {
+            // software, deprecated and raw hardware timestamps
+            // SAFETY: the kernel sends three timespecs in SCM_TIMESTAMPING messages
+            let times = unsafe { (data as *const [libc::timespec; 3]).read_unaligned() };
+            let hardware_time = if hardware {
+                to_systemtime(&times[2])
+            } else {
+                None
+            };
+
+            timestamp = hardware_time.or_else(|| to_systemtime(&times[0]));
+        }
+
+        // SAFETY: cmsg is a valid control message header of the header's control buffer
+        cmsg = unsafe { libc::CMSG_NXTHDR(&header, cmsg) };
+    }
+
+    Ok(Message {
+        length: length as usize,
+        source: to_socket_addr(&address),
+        timestamp,
+    })
+}
+
+/// Reads the transmit timestamps from the error queue, returns with the last one
+fn receive_transmit_timestamp(fd: RawFd, hardware: bool) -> Option<SystemTime> {
+    let mut buffer = [0; 64];
+    let mut timestamp = None;
+
+    while let Ok(message) = receive(
+        fd,
+        &mut buffer,
+        libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        hardware,
+    ) {
+        timestamp = message.timestamp.or(timestamp);
+    }
+
+    timestamp
+}
+
+fn to_systemtime(time: &libc::timespec) -> Option<SystemTime> {
+    if time.tv_sec == 0 && time.tv_nsec == 0 {
+        return None;
+    }
+
+    let duration = Duration::new(time.tv_sec.try_into().ok()?, time.tv_nsec.try_into().ok()?);
+
+    SystemTime::UNIX_EPOCH.checked_add(duration)
+}
+
+fn to_socket_addr(address: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match address.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family is AF_INET, so the storage contains a sockaddr_in
+            let address =
+                unsafe { *(address as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                u16::from_be(address.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family is AF_INET6, so the storage contains a sockaddr_in6
+            let address =
+                unsafe { *(address as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::LoopbackServer;
+    use crate::{Config, SntpClient};
+
+    /// Answers a single request, returns with the address of the server
+    fn serve_once() -> (SocketAddr, std::thread::JoinHandle<Vec<SocketAddr>>) {
+        let server = LoopbackServer::bind();
+
+        (
+            server.local_addr(),
+            server.serve(1, |exchange| exchange.send(&exchange.reply())),
+        )
+    }
+
+    #[test]
+    fn kernel_receive_timestamp_is_used() {
+        let (address, handle) = serve_once();
+        let mut socket = Transport::bind(
+            &TimestampingTransport::new(),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let request = crate::packet::Packet::new(crate::packet::Mode::Client)
+            .transmit_timestamp(
+                crate::packet::SntpTimestamp::from_systemtime(SystemTime::now()).unwrap(),
+            )
+            .encode();
+        let mut buffer = [0; 1024];
+
+        TransportSocket::send_to(&mut socket, &request, address).unwrap();
+
+        let received =
+            TransportSocket::recv_from(&mut socket, &mut buffer, Duration::from_secs(5)).unwrap();
+        let timestamp = received.timestamp().unwrap();
+
+        handle.join().unwrap();
+
+        assert_eq!(received.length(), 48);
+        assert_eq!(received.source(), address);
+        assert!(timestamp <= SystemTime::now());
+        assert!(timestamp > SystemTime::now() - Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kernel_transmit_timestamp_is_used() {
+        let (address, handle) = serve_once();
+        let mut socket = Transport::bind(
+            &TimestampingTransport::new(),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let request = crate::packet::Packet::new(crate::packet::Mode::Client)
+            .transmit_timestamp(
+                crate::packet::SntpTimestamp::from_systemtime(SystemTime::now()).unwrap(),
+            )
+            .encode();
+        let mut buffer = [0; 1024];
+        let before_send = SystemTime::now();
+
+        TransportSocket::send_to(&mut socket, &request, address).unwrap();
+
+        let received =
+            TransportSocket::recv_from(&mut socket, &mut buffer, Duration::from_secs(5)).unwrap();
+        let transmit_timestamp = TransportSocket::transmit_timestamp(&mut socket).unwrap();
+
+        handle.join().unwrap();
+
+        assert!(transmit_timestamp >= before_send);
+        assert!(transmit_timestamp <= received.timestamp().unwrap());
+    }
+
+    #[test]
+    fn client_uses_kernel_transmit_timestamp_as_send_time() {
+        let (address, handle) = serve_once();
+        // the clock source is far off, so only kernel timestamps give a correct offset
+        let clock = crate::ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        let client = SntpClient::with_transport(
+            Config::default()
+                .bind_address("127.0.0.1:0".parse().unwrap())
+                .clock_source(clock),
+            TimestampingTransport::new(),
+        );
+
+        let result = client.synchronize(address).unwrap();
+
+        handle.join().unwrap();
+
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+        assert!(result.round_trip_delay().as_secs_f64() < 0.1);
+    }
+
+    #[test]
+    fn client_synchronizes_with_kernel_timestamps() {
+        let (address, handle) = serve_once();
+        let client = SntpClient::with_transport(
+            Config::default().bind_address("127.0.0.1:0".parse().unwrap()),
+            TimestampingTransport::new().hardware(true),
+        );
+
+        let result = client.synchronize(address).unwrap();
+
+        handle.join().unwrap();
+
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+        assert!(result.round_trip_delay().as_secs_f64() < 0.1);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_client_synchronizes_with_kernel_timestamps() {
+        let (address, handle) = serve_once();
+        let client = crate::AsyncSntpClient::with_transport(
+            Config::default().bind_address("127.0.0.1:0".parse().unwrap()),
+            TimestampingTransport::new(),
+        );
+
+        let result = client.synchronize(address).await.unwrap();
+
+        handle.join().unwrap();
+
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+    }
+}
@@ -39,6 +39,15 @@ pub trait ToServerAddrs {
 
     #[doc(hidden)]
     fn to_server_addrs(&self, default_port: u16) -> Self::Return;
+
+    /// Host name or IP address of the server without the port
+    ///
+    /// It is used as the TLS server name of Network Time Security. The default implementation
+    /// returns an empty name, which is rejected by the NTS key establishment.
+    #[doc(hidden)]
+    fn server_name(&self) -> String {
+        String::new()
+    }
 }
 
 impl ToServerAddrs for SocketAddr {
@@ -47,6 +56,10 @@ impl ToServerAddrs for SocketAddr {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.ip().to_string()
+    }
 }
 
 impl ToServerAddrs for SocketAddrV4 {
@@ -55,6 +68,10 @@ impl ToServerAddrs for SocketAddrV4 {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.ip().to_string()
+    }
 }
 
 impl ToServerAddrs for SocketAddrV6 {
@@ -63,6 +80,10 @@ impl ToServerAddrs for SocketAddrV6 {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.ip().to_string()
+    }
 }
 
 impl ToServerAddrs for IpAddr {
@@ -71,6 +92,10 @@ impl ToServerAddrs for IpAddr {
     fn to_server_addrs(&self, default_port: u16) -> Self::Return {
         (*self, default_port)
     }
+
+    fn server_name(&self) -> String {
+        self.to_string()
+    }
 }
 
 impl ToServerAddrs for (IpAddr, u16) {
@@ -79,6 +104,10 @@ impl ToServerAddrs for (IpAddr, u16) {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.0.to_string()
+    }
 }
 
 impl ToServerAddrs for Ipv4Addr {
@@ -87,6 +116,10 @@ impl ToServerAddrs for Ipv4Addr {
     fn to_server_addrs(&self, default_port: u16) -> Self::Return {
         (*self, default_port)
     }
+
+    fn server_name(&self) -> String {
+        self.to_string()
+    }
 }
 
 impl ToServerAddrs for (Ipv4Addr, u16) {
@@ -95,6 +128,10 @@ impl ToServerAddrs for (Ipv4Addr, u16) {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.0.to_string()
+    }
 }
 
 impl ToServerAddrs for Ipv6Addr {
@@ -103,6 +140,10 @@ impl ToServerAddrs for Ipv6Addr {
     fn to_server_addrs(&self, default_port: u16) -> Self::Return {
         (*self, default_port)
     }
+
+    fn server_name(&self) -> String {
+        self.to_string()
+    }
 }
 
 impl ToServerAddrs for (Ipv6Addr, u16) {
@@ -111,6 +152,10 @@ impl ToServerAddrs for (Ipv6Addr, u16) {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.0.to_string()
+    }
 }
 
 impl ToServerAddrs for str {
@@ -130,6 +175,16 @@ impl ToServerAddrs for str {
             self.to_string()
         }
     }
+
+    fn server_name(&self) -> String {
+        if self.parse::<Ipv6Addr>().is_ok() {
+            self.to_string()
+        } else if let Some(address) = self.strip_prefix('[') {
+            address.split(']').next().unwrap_or_default().to_string()
+        } else {
+            self.split(':').next().unwrap_or_default().to_string()
+        }
+    }
 }
 
 impl ToServerAddrs for String {
@@ -138,6 +193,10 @@ impl ToServerAddrs for String {
     fn to_server_addrs(&self, default_port: u16) -> Self::Return {
         (**self).to_server_addrs(default_port)
     }
+
+    fn server_name(&self) -> String {
+        (**self).server_name()
+    }
 }
 
 impl<'a> ToServerAddrs for (&'a str, u16) {
@@ -146,6 +205,10 @@ impl<'a> ToServerAddrs for (&'a str, u16) {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         *self
     }
+
+    fn server_name(&self) -> String {
+        self.0.to_string()
+    }
 }
 
 impl ToServerAddrs for (String, u16) {
@@ -154,6 +217,10 @@ impl ToServerAddrs for (String, u16) {
     fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
         self.0.clone() + ":" + &self.1.to_string()
     }
+
+    fn server_name(&self) -> String {
+        self.0.clone()
+    }
 }
 
 impl<T: ToServerAddrs + ?Sized> ToServerAddrs for &T {
@@ -162,6 +229,36 @@ impl<T: ToServerAddrs + ?Sized> ToServerAddrs for &T {
     fn to_server_addrs(&self, default_port: u16) -> Self::Return {
         (**self).to_server_addrs(default_port)
     }
+
+    fn server_name(&self) -> String {
+        (**self).server_name()
+    }
+}
+
+/// Server address resolved in advance, along with the name of the server
+///
+/// Used to run multiple exchanges against the same address without resolving the name again.
+pub(crate) struct ResolvedServer {
+    address: SocketAddr,
+    name: String,
+}
+
+impl ResolvedServer {
+    pub(crate) fn new(address: SocketAddr, name: String) -> ResolvedServer {
+        ResolvedServer { address, name }
+    }
+}
+
+impl ToServerAddrs for ResolvedServer {
+    type Return = SocketAddr;
+
+    fn to_server_addrs(&self, _default_port: u16) -> Self::Return {
+        self.address
+    }
+
+    fn server_name(&self) -> String {
+        self.name.clone()
+    }
 }
 
 #[cfg(test)]
@@ -228,4 +325,18 @@ mod tests {
         assert_eq!("[::1]".to_string().to_server_addrs(456), "[::1]:456");
         assert_eq!("[::1]:1234".to_string().to_server_addrs(456), "[::1]:1234");
     }
+
+    #[test]
+    fn server_name_works() {
+        assert_eq!("time.example.com".server_name(), "time.example.com");
+        assert_eq!("time.example.com:4460".server_name(), "time.example.com");
+        assert_eq!(("time.example.com", 4460).server_name(), "time.example.com");
+        assert_eq!("127.0.0.1:1234".server_name(), "127.0.0.1");
+        assert_eq!("::1".server_name(), "::1");
+        assert_eq!("[::1]:1234".server_name(), "::1");
+        assert_eq!(
+            SocketAddr::from(([127, 0, 0, 1], 1234)).server_name(),
+            "127.0.0.1"
+        );
+    }
 }
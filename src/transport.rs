@@ -0,0 +1,726 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "async")]
+use std::future::Future;
+
+/// Packet received by a transport socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ReceivedPacket {
+    length: usize,
+    source: SocketAddr,
+    timestamp: Option<SystemTime>,
+}
+
+impl ReceivedPacket {
+    /// Creates a packet with its length and source address
+    ///
+    /// The receive time is taken by the client when the packet is returned by the transport,
+    /// unless a more accurate timestamp is set with [`ReceivedPacket::with_timestamp`].
+    pub fn new(length: usize, source: SocketAddr) -> ReceivedPacket {
+        ReceivedPacket {
+            length,
+            source,
+            timestamp: None,
+        }
+    }
+
+    /// Sets the time at which the packet has been received
+    pub fn with_timestamp(self, timestamp: SystemTime) -> ReceivedPacket {
+        ReceivedPacket {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    /// Returns with the number of bytes received
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Returns with the address of the sender
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// Returns with the receive time of the packet, if it is known by the transport
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
+}
+
+/// Transport used by [`crate::SntpClient`] to exchange packets with servers
+///
+/// The client binds a new socket for each exchange, using the bind address of its
+/// configuration. It can be implemented to use in-memory channels in tests, to tunnel NTP over
+/// other networks, or to reuse a pre-bound socket. The default implementation is
+/// [`UdpTransport`].
+///
+/// # Example
+///
+/// ```no_run
+/// use rsntp::{Config, ReceivedPacket, SntpClient, Transport, TransportSocket};
+/// use std::net::{SocketAddr, UdpSocket};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// /// Uses the same, already bound socket for all exchanges
+/// #[derive(Clone)]
+/// struct SharedSocket(Arc<UdpSocket>);
+///
+/// impl Transport for SharedSocket {
+///     type Socket = SharedSocket;
+///
+///     fn bind(&self, _address: SocketAddr) -> std::io::Result<SharedSocket> {
+///         Ok(self.clone())
+///     }
+/// }
+///
+/// impl TransportSocket for SharedSocket {
+///     fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+///         self.0.send_to(packet, address).map(|_| ())
+///     }
+///
+///     fn recv_from(
+///         &mut self,
+///         buffer: &mut [u8],
+///         timeout: Duration,
+///     ) -> std::io::Result<ReceivedPacket> {
+///         self.0.set_read_timeout(Some(timeout))?;
+///
+///         let (length, source) = self.0.recv_from(buffer)?;
+///
+///         Ok(ReceivedPacket::new(length, source))
+///     }
+/// }
+///
+/// let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+/// let transport = SharedSocket(Arc::new(socket));
+/// let client = SntpClient::with_transport(Config::default(), transport);
+/// let result = client.synchronize("pool.ntp.org").unwrap();
+/// ```
+pub trait Transport {
+    /// Socket used for a single exchange
+    type Socket: TransportSocket;
+
+    /// Creates a socket bound to the local address
+    fn bind(&self, address: SocketAddr) -> std::io::Result<Self::Socket>;
+}
+
+/// Socket of a [`Transport`]
+pub trait TransportSocket {
+    /// Restricts the socket to exchange packets with the server address only
+    ///
+    /// It is called before sending the request if [`crate::Config::connect_ip`] is enabled. The
+    /// default implementation does nothing.
+    fn connect(&mut self, address: SocketAddr) -> std::io::Result<()> {
+        let _ = address;
+        Ok(())
+    }
+
+    /// Sends a packet to the address
+    fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()>;
+
+    /// Receives a packet into the buffer
+    ///
+    /// If no packet is received within the timeout, an error of kind
+    /// [`std::io::ErrorKind::TimedOut`] or [`std::io::ErrorKind::WouldBlock`] should be returned.
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> std::io::Result<ReceivedPacket>;
+
+    /// Returns with the time the last packet was sent, if it is known
+    ///
+    /// It is called after the reply has been received, or right after sending a manycast request
+    /// (see [`crate::SntpClient::discover`]). If it returns a time, e.g. a kernel timestamp, it is
+    /// used instead of the transmit timestamp of the request to calculate the clock offset. The
+    /// default implementation returns `None`.
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Sets the time-to-live (IPv4) or hop limit (IPv6) of the multicast packets sent
+    ///
+    /// It is called before sending the request of [`crate::SntpClient::discover`], with the value
+    /// set in [`crate::Config::multicast_hops`]. The default implementation does nothing.
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        let _ = hops;
+
+        Ok(())
+    }
+}
+
+/// Asynchronous transport used by [`crate::AsyncSntpClient`] to exchange packets with servers
+///
+/// Only available when async feature is enabled (which is the default)
+///
+/// It is the asynchronous counterpart of [`Transport`]. Timeouts are handled by the client, so the
+/// socket does not need to implement them. The default implementation is [`UdpTransport`].
+#[cfg(feature = "async")]
+pub trait AsyncTransport: Send + Sync {
+    /// Socket used for a single exchange
+    type Socket: AsyncTransportSocket + Send;
+
+    /// Creates a socket bound to the local address
+    fn bind(
+        &self,
+        address: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<Self::Socket>> + Send;
+}
+
+/// Socket of an [`AsyncTransport`]
+///
+/// Only available when async feature is enabled (which is the default)
+#[cfg(feature = "async")]
+pub trait AsyncTransportSocket {
+    /// Restricts the socket to exchange packets with the server address only
+    ///
+    /// It is called before sending the request if [`crate::Config::connect_ip`] is enabled.
+    fn connect(&mut self, address: SocketAddr) -> impl Future<Output = std::io::Result<()>> + Send;
+
+    /// Sends a packet to the address
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        address: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<()>> + Send;
+
+    /// Receives a packet into the buffer
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<ReceivedPacket>> + Send;
+
+    /// Returns with the time the last packet was sent, if it is known
+    ///
+    /// See [`TransportSocket::transmit_timestamp`]. The default implementation returns `None`.
+    fn transmit_timestamp(&mut self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Sets the time-to-live (IPv4) or hop limit (IPv6) of the multicast packets sent
+    ///
+    /// See [`TransportSocket::set_multicast_hops`]. The default implementation does nothing.
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        let _ = hops;
+
+        Ok(())
+    }
+}
+
+/// UDP transport, the default transport of the clients
+///
+/// It binds a new UDP socket for each exchange.
+#[derive(Clone, Copy, Debug, Default, Hash)]
+pub struct UdpTransport;
+
+/// UDP socket of [`UdpTransport`]
+#[derive(Debug)]
+pub struct UdpTransportSocket {
+    socket: std::net::UdpSocket,
+    connected: bool,
+}
+
+impl Transport for UdpTransport {
+    type Socket = UdpTransportSocket;
+
+    fn bind(&self, address: SocketAddr) -> std::io::Result<UdpTransportSocket> {
+        Ok(UdpTransportSocket {
+            socket: std::net::UdpSocket::bind(address)?,
+            connected: false,
+        })
+    }
+}
+
+impl TransportSocket for UdpTransportSocket {
+    fn connect(&mut self, address: SocketAddr) -> std::io::Result<()> {
+        self.socket.connect(address)?;
+        self.connected = true;
+        Ok(())
+    }
+
+    fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        if self.connected {
+            self.socket.send(packet)?;
+        } else {
+            self.socket.send_to(packet, address)?;
+        }
+
+        Ok(())
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> std::io::Result<ReceivedPacket> {
+        self.socket.set_read_timeout(Some(timeout))?;
+
+        let (length, source) = self.socket.recv_from(buffer)?;
+
+        Ok(ReceivedPacket::new(length, source))
+    }
+
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        if self.socket.local_addr()?.is_ipv6() {
+            set_multicast_hops_v6(&self.socket, hops)
+        } else {
+            self.socket.set_multicast_ttl_v4(hops)
+        }
+    }
+}
+
+/// Asynchronous UDP socket of [`UdpTransport`]
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncUdpTransportSocket {
+    socket: tokio::net::UdpSocket,
+    connected: bool,
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for UdpTransport {
+    type Socket = AsyncUdpTransportSocket;
+
+    async fn bind(&self, address: SocketAddr) -> std::io::Result<AsyncUdpTransportSocket> {
+        Ok(AsyncUdpTransportSocket {
+            socket: tokio::net::UdpSocket::bind(address).await?,
+            connected: false,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncUdpTransportSocket {
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransportSocket for AsyncUdpTransportSocket {
+    async fn connect(&mut self, address: SocketAddr) -> std::io::Result<()> {
+        self.socket.connect(address).await?;
+        self.connected = true;
+        Ok(())
+    }
+
+    async fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+        if self.connected {
+            self.socket.send(packet).await?;
+        } else {
+            self.socket.send_to(packet, address).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn recv_from(&mut self, buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+        let (length, source) = self.socket.recv_from(buffer).await?;
+
+        Ok(ReceivedPacket::new(length, source))
+    }
+
+    fn set_multicast_hops(&mut self, hops: u32) -> std::io::Result<()> {
+        if self.socket.local_addr()?.is_ipv6() {
+            set_multicast_hops_v6(&self.socket, hops)
+        } else {
+            self.socket.set_multicast_ttl_v4(hops)
+        }
+    }
+}
+
+/// Sets the multicast hop limit of an IPv6 socket
+///
+/// The standard library cannot set it, so it is only set when the `ipv6-multicast-hops` feature
+/// is enabled.
+#[cfg(feature = "ipv6-multicast-hops")]
+pub(crate) fn set_multicast_hops_v6<'s, S>(socket: &'s S, hops: u32) -> std::io::Result<()>
+where
+    socket2::SockRef<'s>: From<&'s S>,
+{
+    socket2::SockRef::from(socket).set_multicast_hops_v6(hops)
+}
+
+/// Keeps the default multicast hop limit of the system, see the feature enabled variant
+#[cfg(not(feature = "ipv6-multicast-hops"))]
+pub(crate) fn set_multicast_hops_v6<S>(socket: &S, hops: u32) -> std::io::Result<()> {
+    let _ = (socket, hops);
+
+    Ok(())
+}
+
+/// Selects the server address to use from the resolved ones
+///
+/// Addresses with the same family as the bind address are preferred, as the others are likely
+/// unreachable.
+pub(crate) fn select_address<I: IntoIterator<Item = SocketAddr>>(
+    addresses: I,
+    bind_address: SocketAddr,
+) -> std::io::Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = addresses.into_iter().collect();
+
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4() == bind_address.is_ipv4())
+        .or_else(|| addresses.first())
+        .copied()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Unable to resolve server address",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, ServerConfig, SntpClient, SynchronizationError};
+    use std::collections::{HashMap, VecDeque};
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+
+    /// In-memory transport answering requests with a server configuration
+    #[derive(Clone, Default)]
+    struct MemoryTransport {
+        server: ServerConfig,
+        sent: Arc<Mutex<Vec<SocketAddr>>>,
+        silent_address: Option<SocketAddr>,
+        /// Error returned by the sockets when a request is sent
+        send_error: Option<ErrorKind>,
+    }
+
+    struct MemorySocket {
+        transport: MemoryTransport,
+        replies: VecDeque<(Vec<u8>, SocketAddr)>,
+    }
+
+    impl MemorySocket {
+        fn send(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+            if let Some(kind) = self.transport.send_error {
+                return Err(kind.into());
+            }
+
+            self.transport.sent.lock().unwrap().push(address);
+
+            if self.transport.silent_address == Some(address) {
+                return Ok(());
+            }
+
+            if let Some(reply) = self
+                .transport
+                .server
+                .reply(packet, address, SystemTime::now())
+            {
+                self.replies.push_back((reply.to_vec(), address));
+            }
+
+            Ok(())
+        }
+
+        fn receive(&mut self, buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+            let (reply, source) = self
+                .replies
+                .pop_front()
+                .ok_or_else(|| std::io::Error::from(ErrorKind::TimedOut))?;
+
+            buffer[..reply.len()].copy_from_slice(&reply);
+            Ok(ReceivedPacket::new(reply.len(), source))
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        type Socket = MemorySocket;
+
+        fn bind(&self, _address: SocketAddr) -> std::io::Result<MemorySocket> {
+            Ok(MemorySocket {
+                transport: self.clone(),
+                replies: VecDeque::new(),
+            })
+        }
+    }
+
+    impl TransportSocket for MemorySocket {
+        fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+            self.send(packet, address)
+        }
+
+        fn recv_from(
+            &mut self,
+            buffer: &mut [u8],
+            _timeout: Duration,
+        ) -> std::io::Result<ReceivedPacket> {
+            self.receive(buffer)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl AsyncTransport for MemoryTransport {
+        type Socket = MemorySocket;
+
+        async fn bind(&self, address: SocketAddr) -> std::io::Result<MemorySocket> {
+            Transport::bind(self, address)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    impl AsyncTransportSocket for MemorySocket {
+        async fn connect(&mut self, _address: SocketAddr) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        async fn send_to(&mut self, packet: &[u8], address: SocketAddr) -> std::io::Result<()> {
+            self.send(packet, address)
+        }
+
+        async fn recv_from(&mut self, buffer: &mut [u8]) -> std::io::Result<ReceivedPacket> {
+            self.receive(buffer)
+        }
+    }
+
+    #[test]
+    fn client_uses_the_transport() {
+        let transport = MemoryTransport::default();
+        let client = SntpClient::with_transport(Config::default(), transport.clone());
+
+        let result = client.synchronize("192.0.2.1").unwrap();
+
+        assert_eq!(result.stratum(), 1);
+        assert!(result.clock_offset().as_secs_f64().abs() < 0.1);
+
+        let servers = client.discover("192.0.2.2").unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(
+            *client.transport().sent.lock().unwrap(),
+            vec![
+                "192.0.2.1:123".parse::<SocketAddr>().unwrap(),
+                "192.0.2.2:123".parse::<SocketAddr>().unwrap()
+            ]
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_client_uses_the_transport() {
+        let transport = MemoryTransport::default();
+        let client = crate::AsyncSntpClient::with_transport(Config::default(), transport.clone());
+
+        let result = client.synchronize("192.0.2.1").await.unwrap();
+
+        assert_eq!(result.stratum(), 1);
+        assert_eq!(transport.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn errors_of_the_transport_are_reported() {
+        let failing = SntpClient::with_transport(
+            Config::default(),
+            MemoryTransport {
+                send_error: Some(ErrorKind::PermissionDenied),
+                ..MemoryTransport::default()
+            },
+        );
+        let silent = SntpClient::with_transport(
+            Config::default(),
+            MemoryTransport {
+                silent_address: Some(POOL_ADDRESSES[0]),
+                ..MemoryTransport::default()
+            },
+        );
+
+        assert!(matches!(
+            failing.synchronize("192.0.2.1"),
+            Err(SynchronizationError::IOError(err)) if err.kind() == ErrorKind::PermissionDenied
+        ));
+        assert!(matches!(
+            silent.synchronize(POOL_ADDRESSES[0]),
+            Err(SynchronizationError::IOError(err)) if err.kind() == ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn kiss_o_death_and_resolver_errors_are_reported() {
+        let transport = MemoryTransport {
+            server: ServerConfig::default().stratum(0),
+            ..MemoryTransport::default()
+        };
+        let client = SntpClient::with_transport(Config::default(), transport);
+
+        // stratum 0 is a Kiss-o'-Death
+        assert!(client
+            .synchronize("192.0.2.1")
+            .unwrap_err()
+            .is_kiss_of_death());
+        assert!(matches!(
+            client.synchronize("[::1"),
+            Err(SynchronizationError::IOError(_))
+        ));
+    }
+
+    static POOL_ADDRESSES: [SocketAddr; 3] = [
+        SocketAddr::V4(std::net::SocketAddrV4::new(
+            std::net::Ipv4Addr::new(192, 0, 2, 1),
+            123,
+        )),
+        SocketAddr::V4(std::net::SocketAddrV4::new(
+            std::net::Ipv4Addr::new(192, 0, 2, 2),
+            123,
+        )),
+        SocketAddr::V4(std::net::SocketAddrV4::new(
+            std::net::Ipv4Addr::new(192, 0, 2, 3),
+            123,
+        )),
+    ];
+
+    /// Server name resolving to multiple addresses, like a pool
+    struct Pool;
+
+    impl crate::ToServerAddrs for Pool {
+        type Return = &'static [SocketAddr];
+
+        fn to_server_addrs(&self, _default_port: u16) -> &'static [SocketAddr] {
+            &POOL_ADDRESSES
+        }
+
+        fn server_name(&self) -> String {
+            "pool.example.com".to_string()
+        }
+    }
+
+    fn check_pool_results(
+        results: HashMap<SocketAddr, Result<crate::SynchronizationResult, SynchronizationError>>,
+    ) {
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[&POOL_ADDRESSES[0]].as_ref().unwrap().stratum(), 1);
+        assert_eq!(results[&POOL_ADDRESSES[1]].as_ref().unwrap().stratum(), 1);
+        assert!(matches!(
+            &results[&POOL_ADDRESSES[2]],
+            Err(SynchronizationError::IOError(err)) if err.kind() == ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn all_addresses_are_synchronized() {
+        let transport = MemoryTransport {
+            silent_address: Some(POOL_ADDRESSES[2]),
+            ..MemoryTransport::default()
+        };
+        let client = SntpClient::with_transport(Config::default(), transport.clone());
+
+        check_pool_results(client.synchronize_all(Pool).unwrap());
+
+        let mut sent = transport.sent.lock().unwrap().clone();
+        sent.sort();
+        assert_eq!(sent, POOL_ADDRESSES);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_all_addresses_are_synchronized() {
+        let transport = MemoryTransport {
+            silent_address: Some(POOL_ADDRESSES[2]),
+            ..MemoryTransport::default()
+        };
+        let client = crate::AsyncSntpClient::with_transport(Config::default(), transport.clone());
+
+        check_pool_results(client.synchronize_all(Pool).await.unwrap());
+        assert_eq!(transport.sent.lock().unwrap().len(), 3);
+    }
+
+    /// Server address counting how many times it is resolved
+    #[derive(Default)]
+    struct CountingAddress(std::sync::atomic::AtomicUsize);
+
+    impl crate::ToServerAddrs for CountingAddress {
+        type Return = SocketAddr;
+
+        fn to_server_addrs(&self, default_port: u16) -> SocketAddr {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            SocketAddr::from(([192, 0, 2, 1], default_port))
+        }
+
+        fn server_name(&self) -> String {
+            "192.0.2.1".to_string()
+        }
+    }
+
+    #[test]
+    fn samples_are_taken_from_a_single_resolved_address() {
+        let transport = MemoryTransport::default();
+        let client = SntpClient::with_transport(
+            Config::default().burst_interval(Duration::ZERO),
+            transport.clone(),
+        );
+        let address = CountingAddress::default();
+
+        client.synchronize_with_samples(&address, 4).unwrap();
+
+        assert_eq!(address.0.into_inner(), 1);
+        assert_eq!(
+            *transport.sent.lock().unwrap(),
+            vec!["192.0.2.1:123".parse::<SocketAddr>().unwrap(); 4]
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_samples_are_taken_from_a_single_resolved_address() {
+        let transport = MemoryTransport::default();
+        let client = crate::AsyncSntpClient::with_transport(
+            Config::default().burst_interval(Duration::ZERO),
+            transport.clone(),
+        );
+        let address = CountingAddress::default();
+
+        client.synchronize_with_samples(&address, 4).await.unwrap();
+
+        assert_eq!(address.0.into_inner(), 1);
+        assert_eq!(transport.sent.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn multicast_ttl_is_set_for_ipv4() {
+        let mut socket = Transport::bind(&UdpTransport, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        socket.set_multicast_hops(16).unwrap();
+        assert_eq!(socket.socket.multicast_ttl_v4().unwrap(), 16);
+    }
+
+    #[cfg(feature = "ipv6-multicast-hops")]
+    #[test]
+    fn multicast_hop_limit_is_set_for_ipv6() {
+        let mut socket = Transport::bind(&UdpTransport, "[::1]:0".parse().unwrap()).unwrap();
+
+        socket.set_multicast_hops(8).unwrap();
+        assert_eq!(
+            socket2::SockRef::from(&socket.socket)
+                .multicast_hops_v6()
+                .unwrap(),
+            8
+        );
+    }
+
+    #[test]
+    fn address_with_the_family_of_the_bind_address_is_selected() {
+        let v4: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:123".parse().unwrap();
+
+        assert_eq!(
+            select_address(vec![v6, v4], "0.0.0.0:0".parse().unwrap()).unwrap(),
+            v4
+        );
+        assert_eq!(
+            select_address(vec![v4, v6], "[::]:0".parse().unwrap()).unwrap(),
+            v6
+        );
+        assert_eq!(
+            select_address(vec![v6], "0.0.0.0:0".parse().unwrap()).unwrap(),
+            v6
+        );
+        assert!(select_address(vec![], "0.0.0.0:0".parse().unwrap()).is_err());
+    }
+}